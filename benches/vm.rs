@@ -0,0 +1,92 @@
+#![feature(test)]
+
+/*
+Compares running functions as bytecode with walking the syntax tree.
+
+Each script is loaded once, such that only running it is measured.
+The `ast_` benchmarks remove the bytecode to run like before it was added.
+*/
+
+extern crate test;
+extern crate dyon;
+
+use std::sync::Arc;
+use test::Bencher;
+use dyon::{load, Module, Runtime};
+
+fn module(source: &str, bytecode: bool) -> Arc<Module> {
+    let mut module = Module::new();
+    load(source, &mut module).unwrap_or_else(|err| panic!("{}", err));
+    if !bytecode {
+        for f in &mut module.functions {
+            f.code = None;
+        }
+    }
+    Arc::new(module)
+}
+
+fn bench(b: &mut Bencher, source: &str, bytecode: bool) {
+    let module = module(source, bytecode);
+    let mut rt = Runtime::new();
+    b.iter(|| rt.run(&module).unwrap_or_else(|err| panic!("{}", err)));
+}
+
+#[bench]
+fn vm_add(b: &mut Bencher) {
+    bench(b, "source/bench/add.dyon", true);
+}
+
+#[bench]
+fn ast_add(b: &mut Bencher) {
+    bench(b, "source/bench/add.dyon", false);
+}
+
+#[bench]
+fn vm_call(b: &mut Bencher) {
+    bench(b, "source/bench/call.dyon", true);
+}
+
+#[bench]
+fn ast_call(b: &mut Bencher) {
+    bench(b, "source/bench/call.dyon", false);
+}
+
+#[bench]
+fn vm_sum(b: &mut Bencher) {
+    bench(b, "source/bench/sum.dyon", true);
+}
+
+#[bench]
+fn ast_sum(b: &mut Bencher) {
+    bench(b, "source/bench/sum.dyon", false);
+}
+
+#[bench]
+fn vm_primes(b: &mut Bencher) {
+    bench(b, "source/bench/primes.dyon", true);
+}
+
+#[bench]
+fn ast_primes(b: &mut Bencher) {
+    bench(b, "source/bench/primes.dyon", false);
+}
+
+#[bench]
+fn vm_object(b: &mut Bencher) {
+    bench(b, "source/bench/object.dyon", true);
+}
+
+#[bench]
+fn ast_object(b: &mut Bencher) {
+    bench(b, "source/bench/object.dyon", false);
+}
+
+#[bench]
+fn vm_array(b: &mut Bencher) {
+    bench(b, "source/bench/array.dyon", true);
+}
+
+#[bench]
+fn ast_array(b: &mut Bencher) {
+    bench(b, "source/bench/array.dyon", false);
+}
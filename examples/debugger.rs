@@ -0,0 +1,103 @@
+extern crate dyon;
+
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use dyon::{error, load, Module, Runtime};
+use dyon::debugger::{Breakpoint, DebugHandler, Debugger, Location, Step};
+
+const HELP: &'static str = "\
+c            continue
+s            step in
+n            step over
+o            step out
+l            print locals
+cu           print current objects
+bt           print backtrace
+b <line>     add breakpoint in current file
+b <f>:<line> add breakpoint in file
+d <line>     remove breakpoint in current file
+q            quit";
+
+struct Cli;
+
+impl DebugHandler for Cli {
+    fn pause(
+        &mut self,
+        rt: &Runtime,
+        location: &Location,
+        breakpoints: &mut Vec<Breakpoint>
+    ) -> Step {
+        let file = location.file.clone().unwrap_or(Arc::new("".into()));
+        println!("{}:{}:{} in `{}`", file, location.line, location.column, location.fn_name);
+        let stdin = io::stdin();
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                return Step::Continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("c"), _) => return Step::Continue,
+                (Some("s"), _) => return Step::In,
+                (Some("n"), _) => return Step::Over,
+                (Some("o"), _) => return Step::Out,
+                (Some("l"), _) => {
+                    for (name, val) in rt.debug_locals() {
+                        println!("{} = {}", name, val);
+                    }
+                }
+                (Some("cu"), _) => {
+                    for (name, val) in rt.debug_currents() {
+                        println!("~ {} = {}", name, val);
+                    }
+                }
+                (Some("bt"), _) => print!("{}", rt.stack_trace()),
+                (Some("b"), Some(arg)) => {
+                    let (f, n) = match arg.rfind(':') {
+                        Some(i) => (Arc::new(arg[..i].into()), &arg[i + 1..]),
+                        None => (file.clone(), arg),
+                    };
+                    match n.parse() {
+                        Ok(n) => breakpoints.push(Breakpoint { file: f, line: n }),
+                        Err(_) => println!("Expected line number"),
+                    }
+                }
+                (Some("d"), Some(arg)) => {
+                    match arg.parse::<usize>() {
+                        Ok(n) => breakpoints.retain(|bp| !(bp.file == file && bp.line == n)),
+                        Err(_) => println!("Expected line number"),
+                    }
+                }
+                (Some("q"), _) => ::std::process::exit(0),
+                _ => println!("{}", HELP),
+            }
+        }
+    }
+}
+
+fn main() {
+    let file = match env::args().nth(1) {
+        Some(file) => file,
+        None => {
+            println!("dyon debugger\nUsage: debugger <file> [<line>...]");
+            return;
+        }
+    };
+    let mut module = Module::new();
+    if error(load(&file, &mut module)) { return; }
+    let mut debugger = Debugger::new(Box::new(Cli));
+    let lines: Vec<usize> = env::args().skip(2).filter_map(|arg| arg.parse().ok()).collect();
+    if lines.len() == 0 {
+        // Pause at the first statement.
+        debugger.step = Step::In;
+    }
+    for line in lines {
+        debugger.add_breakpoint(Arc::new(file.clone()), line);
+    }
+    let mut rt = Runtime::new();
+    rt.debugger = Some(debugger);
+    error(rt.run(&Arc::new(module)));
+}
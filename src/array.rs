@@ -0,0 +1,332 @@
+/*
+Persistent vector used to store the items of an array.
+
+Items are stored in a tree of 32 items per leaf and 32 children per branch,
+plus a tail with the last items that is not yet in the tree.
+Nodes are shared between copies of an array and copied on write,
+so `push`, `pop` and setting an item copy O(log n) nodes when the array is shared.
+
+Since an array is stored as `Arc<PersistentVec>`, the usual `Arc::make_mut`
+gives a mutable array without copying the items.
+*/
+
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::Index;
+use std::sync::Arc;
+
+use Variable;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node {
+    Branch(Vec<Arc<Node>>),
+    Leaf(Vec<Variable>),
+}
+
+/// Stores the items of an array.
+#[derive(Clone)]
+pub struct PersistentVec {
+    len: usize,
+    /// Bits to shift an index to get the child of root.
+    shift: usize,
+    root: Arc<Node>,
+    /// Copied when the array is shared, at most 32 items.
+    tail: Vec<Variable>,
+}
+
+impl PersistentVec {
+    /// Creates a new empty array.
+    pub fn new() -> PersistentVec {
+        PersistentVec {
+            len: 0,
+            shift: BITS,
+            root: Arc::new(Node::Branch(vec![])),
+            tail: vec![],
+        }
+    }
+
+    /// Returns the number of items.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the array has no items.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Index of the first item in tail.
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH { 0 } else { ((self.len - 1) >> BITS) << BITS }
+    }
+
+    /// Returns the leaf containing an item, and the index of first item in leaf.
+    fn leaf(&self, ind: usize) -> (&[Variable], usize) {
+        let offset = self.tail_offset();
+        if ind >= offset { return (&self.tail, offset); }
+        let mut node = &*self.root;
+        let mut level = self.shift;
+        loop {
+            match *node {
+                Node::Branch(ref children) => {
+                    node = &children[(ind >> level) & MASK];
+                    level -= BITS;
+                }
+                Node::Leaf(ref items) => return (items, ind & !MASK),
+            }
+        }
+    }
+
+    /// Gets an item.
+    pub fn get(&self, ind: usize) -> Option<&Variable> {
+        if ind >= self.len { return None; }
+        let (leaf, start) = self.leaf(ind);
+        Some(&leaf[ind - start])
+    }
+
+    /// Gets a mutable item, copying the nodes on the path when shared.
+    pub fn get_mut(&mut self, ind: usize) -> Option<&mut Variable> {
+        if ind >= self.len { return None; }
+        let offset = self.tail_offset();
+        if ind >= offset {
+            return Some(&mut self.tail[ind - offset]);
+        }
+        let mut node = &mut self.root;
+        let mut level = self.shift;
+        loop {
+            match *Arc::make_mut(node) {
+                Node::Branch(ref mut children) => {
+                    node = &mut children[(ind >> level) & MASK];
+                    level -= BITS;
+                }
+                Node::Leaf(ref mut items) => return Some(&mut items[ind & MASK]),
+            }
+        }
+    }
+
+    /// Returns the first item.
+    pub fn first(&self) -> Option<&Variable> {
+        self.get(0)
+    }
+
+    /// Returns the last item.
+    pub fn last(&self) -> Option<&Variable> {
+        self.tail.last()
+    }
+
+    /// Adds an item to the end.
+    pub fn push(&mut self, item: Variable) {
+        if self.len - self.tail_offset() < WIDTH {
+            self.tail.push(item);
+            self.len += 1;
+            return;
+        }
+
+        // Move the full tail into the tree.
+        let tail = mem::replace(&mut self.tail, Vec::with_capacity(WIDTH));
+        let leaf = Arc::new(Node::Leaf(tail));
+        if (self.len >> BITS) > (1 << self.shift) {
+            // Root is full, add a level.
+            let path = new_path(self.shift, leaf);
+            let root = mem::replace(&mut self.root, Arc::new(Node::Branch(vec![])));
+            self.root = Arc::new(Node::Branch(vec![root, path]));
+            self.shift += BITS;
+        } else {
+            push_leaf(self.len, self.shift, &mut self.root, leaf);
+        }
+        self.tail.push(item);
+        self.len += 1;
+    }
+
+    /// Removes the last item.
+    pub fn pop(&mut self) -> Option<Variable> {
+        if self.len == 0 { return None; }
+        if self.len - self.tail_offset() > 1 || self.len == 1 {
+            self.len -= 1;
+            return self.tail.pop();
+        }
+
+        // Move the last leaf of the tree into tail.
+        let item = self.tail.pop();
+        self.tail = self.leaf(self.len - 2).0.to_vec();
+        pop_leaf(self.len, self.shift, &mut self.root);
+        self.len -= 1;
+        if self.shift > BITS {
+            let child = match *self.root {
+                Node::Branch(ref children) if children.len() == 1 => Some(children[0].clone()),
+                _ => None,
+            };
+            if let Some(child) = child {
+                self.root = child;
+                self.shift -= BITS;
+            }
+        }
+        item
+    }
+
+    /// Removes all items.
+    pub fn clear(&mut self) {
+        *self = PersistentVec::new();
+    }
+
+    /// Swaps two items.
+    ///
+    /// Panics if an index is out of bounds.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        if a == b { return; }
+        let va = self[a].clone();
+        let vb = mem::replace(self.get_mut(b).unwrap(), va);
+        *self.get_mut(a).unwrap() = vb;
+    }
+
+    /// Reverses the order of items.
+    pub fn reverse(&mut self) {
+        *self = self.iter().rev().cloned().collect();
+    }
+
+    /// Iterates over items.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter {
+            vec: self,
+            front: 0,
+            back: self.len,
+            leaf: &[],
+            leaf_start: 0,
+        }
+    }
+
+    /// Copies the items into a vector.
+    pub fn to_vec(&self) -> Vec<Variable> {
+        self.iter().cloned().collect()
+    }
+}
+
+fn new_path(level: usize, node: Arc<Node>) -> Arc<Node> {
+    if level == 0 { node } else { Arc::new(Node::Branch(vec![new_path(level - BITS, node)])) }
+}
+
+/// Adds a leaf to the tree, where `len` is the number of items before pushing.
+fn push_leaf(len: usize, level: usize, node: &mut Arc<Node>, leaf: Arc<Node>) {
+    let sub = ((len - 1) >> level) & MASK;
+    if let Node::Branch(ref mut children) = *Arc::make_mut(node) {
+        if level == BITS {
+            children.push(leaf);
+        } else if sub < children.len() {
+            push_leaf(len, level - BITS, &mut children[sub], leaf);
+        } else {
+            children.push(new_path(level - BITS, leaf));
+        }
+    }
+}
+
+/// Removes the last leaf of the tree, returns `true` if the node becomes empty.
+fn pop_leaf(len: usize, level: usize, node: &mut Arc<Node>) -> bool {
+    let sub = ((len - 2) >> level) & MASK;
+    if let Node::Branch(ref mut children) = *Arc::make_mut(node) {
+        if level == BITS || pop_leaf(len, level - BITS, &mut children[sub]) {
+            children.pop();
+        }
+        children.is_empty()
+    } else {
+        false
+    }
+}
+
+impl Default for PersistentVec {
+    fn default() -> PersistentVec {
+        PersistentVec::new()
+    }
+}
+
+impl Index<usize> for PersistentVec {
+    type Output = Variable;
+
+    fn index(&self, ind: usize) -> &Variable {
+        match self.get(ind) {
+            Some(item) => item,
+            None => panic!("Index out of bounds: the len is {} but the index is {}",
+                           self.len, ind),
+        }
+    }
+}
+
+impl PartialEq for PersistentVec {
+    fn eq(&self, other: &PersistentVec) -> bool {
+        self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl fmt::Debug for PersistentVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl From<Vec<Variable>> for PersistentVec {
+    fn from(items: Vec<Variable>) -> PersistentVec {
+        items.into_iter().collect()
+    }
+}
+
+impl FromIterator<Variable> for PersistentVec {
+    fn from_iter<I: IntoIterator<Item = Variable>>(iter: I) -> PersistentVec {
+        let mut vec = PersistentVec::new();
+        for item in iter { vec.push(item); }
+        vec
+    }
+}
+
+impl<'a> IntoIterator for &'a PersistentVec {
+    type Item = &'a Variable;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Iterates over the items of an array.
+pub struct Iter<'a> {
+    vec: &'a PersistentVec,
+    front: usize,
+    back: usize,
+    /// The current leaf of front.
+    leaf: &'a [Variable],
+    leaf_start: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Variable;
+
+    fn next(&mut self) -> Option<&'a Variable> {
+        if self.front >= self.back { return None; }
+        let ind = self.front;
+        if ind < self.leaf_start || ind >= self.leaf_start + self.leaf.len() {
+            let (leaf, start) = self.vec.leaf(ind);
+            self.leaf = leaf;
+            self.leaf_start = start;
+        }
+        self.front += 1;
+        Some(&self.leaf[ind - self.leaf_start])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back - self.front;
+        (n, Some(n))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<&'a Variable> {
+        if self.front >= self.back { return None; }
+        self.back -= 1;
+        self.vec.get(self.back)
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
@@ -1,6 +1,6 @@
-use std::cell::Cell;
 use std::sync::Arc;
 
+use AtomicFnIndex;
 use FnIndex;
 use super::{
     AssignOp,
@@ -10,7 +10,9 @@ use super::{
     Expression,
     ForN,
     Id,
+    InterpolationPart,
     Item,
+    Match,
 };
 
 pub fn infer(block: &Block, name: &str) -> Option<Expression> {
@@ -20,7 +22,7 @@ pub fn infer(block: &Block, name: &str) -> Option<Expression> {
         let source_range = item.source_range;
         Expression::Call(Call {
             name: Arc::new("len".into()),
-            f_index: Cell::new(FnIndex::None),
+            f_index: AtomicFnIndex::new(FnIndex::None),
             args: vec![
                 Expression::Item(item)
             ],
@@ -162,6 +164,17 @@ fn infer_expr(
                 if res.is_some() { return res; }
             }
         }
+        Match(ref match_expr) => {
+            return infer_match(match_expr, name, decls)
+        }
+        Interpolation(ref interpolation) => {
+            for part in &interpolation.parts {
+                if let InterpolationPart::Expression(ref expr) = *part {
+                    let res = infer_expr(expr, name, decls);
+                    if res.is_some() { return res; }
+                }
+            }
+        }
         Compare(ref cmp_expr) => {
             let left = infer_expr(&cmp_expr.left, name, decls);
             if left.is_some() { return left; }
@@ -280,6 +293,27 @@ fn infer_for_n(
     res
 }
 
+fn infer_match(
+    match_expr: &Match,
+    name: &str,
+    decls: &mut Vec<Arc<String>>
+) -> Option<Item> {
+    let res = infer_expr(&match_expr.expr, name, decls);
+    if res.is_some() { return res; }
+    for arm in &match_expr.arms {
+        let st = decls.len();
+        if let Some(binding) = arm.pattern.binding() {
+            // Check for declaration of same name.
+            if &**binding == name { continue; }
+            decls.push(binding.clone());
+        }
+        let res = infer_expr(&arm.expr, name, decls);
+        decls.truncate(st);
+        if res.is_some() { return res; }
+    }
+    None
+}
+
 fn infer_block(
     block: &Block,
     name: &str,
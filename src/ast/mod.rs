@@ -1,11 +1,15 @@
 use std::sync::Arc;
-use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use range::Range;
 use piston_meta::bootstrap::Convert;
 use piston_meta::MetaData;
 
+use bytecode;
+use AtomicFnIndex;
 use FnIndex;
 use Module;
+use Symbol;
+use object::SlotCache;
 use Type;
 use Variable;
 
@@ -20,21 +24,39 @@ pub fn convert(
     module: &mut Module
 ) -> Result<(), ()> {
     let mut convert = Convert::new(data);
+    let start = module.functions.len();
 
     loop {
         if let Ok((range, function)) =
         Function::from_meta_data(&file, &source, "fn", convert, ignored) {
             convert.update(range);
             module.register(function);
+        } else if let Ok((range, record)) =
+        Record::from_meta_data(&file, "record", convert, ignored) {
+            convert.update(range);
+            module.records.push(record);
         } else if convert.remaining_data_len() > 0 {
             return Err(());
         } else {
             break;
         }
     }
+    // Use declared records in signatures of the new functions.
+    let records = module.record_types();
+    for f in &mut module.functions[start..] {
+        for arg in &mut f.args {
+            arg.ty = arg.ty.records(&records);
+        }
+        f.ret = f.ret.records(&records);
+    }
     for (i, f) in module.functions.iter().enumerate() {
         f.resolve_locals(i, module);
     }
+    for f in &mut module.functions {
+        if f.code.is_none() {
+            f.code = Some(Arc::new(bytecode::compile(f)));
+        }
+    }
     Ok(())
 }
 
@@ -47,10 +69,64 @@ pub struct Function {
     pub currents: Vec<Current>,
     pub block: Block,
     pub ret: Type,
-    pub resolved: Cell<bool>,
+    pub resolved: AtomicFlag,
+    /// The compiled body, executed instead of walking the block.
+    pub code: Option<Arc<bytecode::Code>>,
     pub source_range: Range,
 }
 
+/// A flag that is set when loading, which can be read from several threads.
+#[derive(Debug, Default)]
+pub struct AtomicFlag(AtomicBool);
+
+impl AtomicFlag {
+    pub fn new(val: bool) -> AtomicFlag {
+        AtomicFlag(AtomicBool::new(val))
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, val: bool) {
+        self.0.store(val, Ordering::Relaxed)
+    }
+}
+
+impl Clone for AtomicFlag {
+    fn clone(&self) -> AtomicFlag {
+        AtomicFlag::new(self.get())
+    }
+}
+
+/// A slot that is set when loading, which can be read from several threads.
+#[derive(Debug)]
+pub struct AtomicSlot(AtomicUsize);
+
+impl AtomicSlot {
+    pub fn new(slot: Option<usize>) -> AtomicSlot {
+        AtomicSlot(AtomicUsize::new(slot.unwrap_or(usize::MAX)))
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            slot => Some(slot),
+        }
+    }
+
+    pub fn set(&self, slot: Option<usize>) {
+        self.0.store(slot.unwrap_or(usize::MAX), Ordering::Relaxed)
+    }
+}
+
+impl Clone for AtomicSlot {
+    fn clone(&self) -> AtomicSlot {
+        AtomicSlot::new(self.get())
+    }
+}
+
 impl Function {
     pub fn from_meta_data(
         file: &Arc<String>,
@@ -115,8 +191,7 @@ impl Function {
                 let item = Expression::Item(Item {
                         name: Arc::new("return".into()),
                         current: false,
-                        stack_id: Cell::new(None),
-                        static_stack_id: Cell::new(None),
+                        slot: AtomicSlot::new(None),
                         try: false,
                         ids: vec![],
                         try_ids: vec![],
@@ -143,7 +218,8 @@ impl Function {
         }
         let ret = try!(ret.ok_or(()));
         Ok((convert.subtract(start), Function {
-            resolved: Cell::new(false),
+            resolved: AtomicFlag::new(false),
+            code: None,
             name: name,
             file: file.clone(),
             source: source.clone(),
@@ -343,7 +419,98 @@ impl Grab {
         };
         // Use environment outside closure.
         let mut tmp_stack: Vec<_> = stack[0..last].into();
-        self.expr.resolve_locals(relative, &mut tmp_stack, closure_stack, module)
+        // Slots are relative to the frame where the grab is evaluated.
+        let mut tmp_closure_stack: Vec<_> = closure_stack[0..d].into();
+        self.expr.resolve_locals(relative, &mut tmp_stack, &mut tmp_closure_stack, module)
+    }
+}
+
+/// A record declaration, an object type with named fields.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: Arc<String>,
+    pub file: Arc<String>,
+    pub fields: Vec<(Arc<String>, Type)>,
+    pub source_range: Range,
+}
+
+impl Record {
+    pub fn from_meta_data(
+        file: &Arc<String>,
+        node: &str,
+        mut convert: Convert,
+        ignored: &mut Vec<Range>
+    ) -> Result<(Range, Record), ()> {
+        let start = convert.clone();
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut name: Option<Arc<String>> = None;
+        let mut fields: Vec<(Arc<String>, Type)> = vec![];
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = convert.meta_string("name") {
+                convert.update(range);
+                name = Some(val);
+            } else if let Ok((range, val)) = Record::field_from_meta_data(
+                    "field", convert, ignored) {
+                convert.update(range);
+                fields.push(val);
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+
+        let name = try!(name.ok_or(()));
+        Ok((convert.subtract(start), Record {
+            name: name,
+            file: file.clone(),
+            fields: fields,
+            source_range: convert.source(start).unwrap(),
+        }))
+    }
+
+    fn field_from_meta_data(
+        node: &str,
+        mut convert: Convert,
+        ignored: &mut Vec<Range>
+    ) -> Result<(Range, (Arc<String>, Type)), ()> {
+        let start = convert.clone();
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut name: Option<Arc<String>> = None;
+        let mut ty: Option<Type> = None;
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = convert.meta_string("name") {
+                convert.update(range);
+                name = Some(val);
+            } else if let Ok((range, val)) = Type::from_meta_data(
+                    "type", convert, ignored) {
+                convert.update(range);
+                ty = Some(val);
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+
+        let name = try!(name.ok_or(()));
+        let ty = try!(ty.ok_or(()));
+        Ok((convert.subtract(start), (name, ty)))
+    }
+
+    /// Returns the type of values of the record.
+    pub fn ty(&self) -> Type {
+        Type::Record(self.name.clone(), Arc::new(self.fields.clone()))
     }
 }
 
@@ -527,6 +694,7 @@ pub enum Expression {
     BinOp(Box<BinOpExpression>),
     Assign(Box<Assign>),
     Text(Text),
+    Interpolation(Box<Interpolation>),
     Number(Number),
     Vec4(Vec4),
     Bool(Bool),
@@ -541,6 +709,7 @@ pub enum Expression {
     Any(Box<ForN>),
     All(Box<ForN>),
     If(Box<If>),
+    Match(Box<Match>),
     Compare(Box<Compare>),
     UnOp(Box<UnOpExpression>),
     Norm(Box<Norm>),
@@ -597,8 +766,7 @@ impl Expression {
                 let item = Expression::Item(Item {
                         name: Arc::new("return".into()),
                         current: false,
-                        stack_id: Cell::new(None),
-                        static_stack_id: Cell::new(None),
+                        slot: AtomicSlot::new(None),
                         try: false,
                         ids: vec![],
                         try_ids: vec![],
@@ -647,6 +815,10 @@ impl Expression {
                     text: val,
                     source_range: convert.source(start).unwrap(),
                 }));
+            } else if let Ok((range, val)) = Interpolation::from_meta_data(
+                    file, source, convert, ignored) {
+                convert.update(range);
+                result = Some(Expression::Interpolation(Box::new(val)));
             } else if let Ok((range, val)) = convert.meta_f64("num") {
                 convert.update(range);
                 result = Some(Expression::Number(Number {
@@ -742,6 +914,10 @@ impl Expression {
                     file, source, convert, ignored) {
                 convert.update(range);
                 result = Some(Expression::If(Box::new(val)));
+            } else if let Ok((range, val)) = Match::from_meta_data(
+                    file, source, convert, ignored) {
+                convert.update(range);
+                result = Some(Expression::Match(Box::new(val)));
             } else if let Ok((range, val)) = Compare::from_meta_data(
                     file, source, convert, ignored) {
                 convert.update(range);
@@ -799,6 +975,7 @@ impl Expression {
             BinOp(ref binop) => binop.source_range,
             Assign(ref assign) => assign.source_range,
             Text(ref text) => text.source_range,
+            Interpolation(ref interpolation) => interpolation.source_range,
             Number(ref num) => num.source_range,
             Vec4(ref vec4) => vec4.source_range,
             Bool(ref b) => b.source_range,
@@ -813,6 +990,7 @@ impl Expression {
             Any(ref for_n_expr) => for_n_expr.source_range,
             All(ref for_n_expr) => for_n_expr.source_range,
             If(ref if_expr) => if_expr.source_range,
+            Match(ref match_expr) => match_expr.source_range,
             Compare(ref comp) => comp.source_range,
             Norm(ref norm) => norm.source_range,
             UnOp(ref unop) => unop.source_range,
@@ -857,6 +1035,8 @@ impl Expression {
             BinOp(ref binop) => binop.resolve_locals(relative, stack, closure_stack, module),
             Assign(ref assign) => assign.resolve_locals(relative, stack, closure_stack, module),
             Text(_) => {}
+            Interpolation(ref interpolation) =>
+                interpolation.resolve_locals(relative, stack, closure_stack, module),
             Number(_) => {}
             Vec4(ref vec4) => vec4.resolve_locals(relative, stack, closure_stack, module),
             Bool(_) => {}
@@ -880,6 +1060,8 @@ impl Expression {
             All(ref for_n_expr) =>
                 for_n_expr.resolve_locals(relative, stack, closure_stack, module),
             If(ref if_expr) => if_expr.resolve_locals(relative, stack, closure_stack, module),
+            Match(ref match_expr) =>
+                match_expr.resolve_locals(relative, stack, closure_stack, module),
             Compare(ref comp) => comp.resolve_locals(relative, stack, closure_stack, module),
             Norm(ref norm) => norm.resolve_locals(relative, stack, closure_stack, module),
             UnOp(ref unop) => unop.resolve_locals(relative, stack, closure_stack, module),
@@ -951,7 +1133,7 @@ impl Link {
 
 #[derive(Debug, Clone)]
 pub struct Object {
-    pub key_values: Vec<(Arc<String>, Expression)>,
+    pub key_values: Vec<(Symbol, Expression)>,
     pub source_range: Range,
 }
 
@@ -994,7 +1176,7 @@ impl Object {
         source: &Arc<String>,
         mut convert: Convert,
         ignored: &mut Vec<Range>)
-    -> Result<(Range, (Arc<String>, Expression)), ()> {
+    -> Result<(Range, (Symbol, Expression)), ()> {
         let start = convert.clone();
         let node = "key_value";
         let start_range = try!(convert.start_node(node));
@@ -1022,7 +1204,7 @@ impl Object {
 
         let key = try!(key.ok_or(()));
         let value = try!(value.ok_or(()));
-        Ok((convert.subtract(start), (key, value)))
+        Ok((convert.subtract(start), (Symbol::from(key), value)))
     }
 
     pub fn resolve_locals(
@@ -1426,7 +1608,8 @@ pub enum UnOp {
 
 #[derive(Debug, Clone)]
 pub enum Id {
-    String(Range, Arc<String>),
+    /// Stores the slot where the key was found last time, as an inline cache.
+    String(Range, Symbol, SlotCache),
     F64(Range, f64),
     Expression(Expression),
 }
@@ -1434,7 +1617,7 @@ pub enum Id {
 impl Id {
     pub fn source_range(&self) -> Range {
         match *self {
-            Id::String(range, _) => range,
+            Id::String(range, _, _) => range,
             Id::F64(range, _) => range,
             Id::Expression(ref expr) => expr.source_range(),
         }
@@ -1448,7 +1631,7 @@ impl Id {
         module: &Module
     ) -> bool {
         match *self {
-            Id::String(_, _) => false,
+            Id::String(_, _, _) => false,
             Id::F64(_, _) => false,
             Id::Expression(ref expr) => {
                 let st = stack.len();
@@ -1463,8 +1646,8 @@ impl Id {
 #[derive(Debug, Clone)]
 pub struct Item {
     pub name: Arc<String>,
-    pub stack_id: Cell<Option<usize>>,
-    pub static_stack_id: Cell<Option<usize>>,
+    /// Slot relative to the start of the function or closure frame.
+    pub slot: AtomicSlot,
     pub current: bool,
     pub try: bool,
     pub ids: Vec<Id>,
@@ -1478,8 +1661,7 @@ impl Item {
         Item {
             name: name,
             current: false,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            slot: AtomicSlot::new(None),
             try: false,
             ids: vec![],
             try_ids: vec![],
@@ -1492,8 +1674,7 @@ impl Item {
         Item {
             name: self.name.clone(),
             current: self.current,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            slot: AtomicSlot::new(None),
             try: self.try,
             ids: self.ids.iter().take(n).map(|id| id.clone()).collect(),
             try_ids: {
@@ -1545,7 +1726,8 @@ impl Item {
             } else if let Ok((range, val)) = convert.meta_string("id") {
                 let start_id = convert;
                 convert.update(range);
-                ids.push(Id::String(convert.source(start_id).unwrap(), val));
+                ids.push(Id::String(convert.source(start_id).unwrap(),
+                                    Symbol::from(val), SlotCache::new()));
             } else if let Ok((range, val)) = convert.meta_f64("id") {
                 let start_id = convert;
                 convert.update(range);
@@ -1568,8 +1750,7 @@ impl Item {
         let name = try!(name.ok_or(()));
         Ok((convert.subtract(start), Item {
             name: name,
-            stack_id: Cell::new(None),
-            static_stack_id: Cell::new(None),
+            slot: AtomicSlot::new(None),
             current: current,
             try: try,
             ids: ids,
@@ -1585,13 +1766,13 @@ impl Item {
         closure_stack: &mut Vec<usize>,
         module: &Module
     ) {
-        // println!("TEST item resolve {} {:?}", self.name, stack);
         let st = stack.len();
-        for (i, n) in stack.iter().rev().enumerate() {
+        // Locals are only visible inside the current function or closure.
+        let frame = closure_stack.last().map(|&x| x).unwrap_or(0);
+        for (i, n) in stack.iter().enumerate().skip(frame).rev() {
             if let &Some(ref n) = n {
                 if &**n == &**self.name {
-                    // println!("TEST set {} {}", self.name, i + 1);
-                    self.static_stack_id.set(Some(i + 1));
+                    self.slot.set(Some(i - frame));
                     break;
                 }
             }
@@ -1671,7 +1852,7 @@ impl Go {
 pub struct Call {
     pub name: Arc<String>,
     pub args: Vec<Expression>,
-    pub f_index: Cell<FnIndex>,
+    pub f_index: AtomicFnIndex,
     /// A custom source, such as when calling a function inside a loaded module.
     pub custom_source: Option<Arc<String>>,
     pub source_range: Range,
@@ -1737,7 +1918,7 @@ impl Call {
         Ok((convert.subtract(start), Call {
             name: name,
             args: args,
-            f_index: Cell::new(FnIndex::None),
+            f_index: AtomicFnIndex::new(FnIndex::None),
             custom_source: None,
             source_range: convert.source(start).unwrap(),
         }))
@@ -1804,7 +1985,7 @@ impl Call {
         Ok((convert.subtract(start), Call {
             name: Arc::new(name),
             args: args,
-            f_index: Cell::new(FnIndex::None),
+            f_index: AtomicFnIndex::new(FnIndex::None),
             custom_source: None,
             source_range: convert.source(start).unwrap(),
         }))
@@ -1983,11 +2164,9 @@ impl CallClosure {
                 n.push_str(&name);
             } else {
                 let last = item.ids.len() - 1;
-                if let Id::String(_, ref mut n) = item.ids[last] {
+                if let Id::String(_, ref mut n, _) = item.ids[last] {
                     // Append name to last id.
-                    let n = Arc::make_mut(n);
-                    n.push_str("__");
-                    n.push_str(&name);
+                    *n = Symbol::from(format!("{}__{}", n, name));
                 }
             }
         }
@@ -2741,10 +2920,33 @@ pub struct ForN {
     pub end: Expression,
     pub block: Block,
     pub label: Option<Arc<String>>,
+    /// Whether to split the range across threads.
+    pub par: bool,
     pub source_range: Range,
 }
 
 impl ForN {
+    /// Returns the slot of the array when the body is `arr[i]`, where `i` is the counter.
+    ///
+    /// Short loops like this read packed arrays directly.
+    pub fn indexed_slot(&self) -> Option<usize> {
+        if self.block.expressions.len() != 1 { return None; }
+        let item = match self.block.expressions[0] {
+            Expression::Item(ref item) => item,
+            _ => return None
+        };
+        let slot = match item.slot.get() {
+            Some(slot) => slot,
+            None => return None
+        };
+        if item.try || item.ids.len() != 1 || item.name == self.name { return None; }
+        match item.ids[0] {
+            Id::Expression(Expression::Item(ref ind)) if ind.name == self.name &&
+                ind.ids.len() == 0 && !ind.try => Some(slot),
+            _ => None
+        }
+    }
+
     pub fn from_meta_data(
         file: &Arc<String>,
         source: &Arc<String>,
@@ -2759,6 +2961,7 @@ impl ForN {
         let mut indices: Vec<(Arc<String>, Option<Expression>, Option<Expression>)> = vec![];
         let mut block: Option<Block> = None;
         let mut label: Option<Arc<String>> = None;
+        let mut par = false;
         loop {
             if let Ok(range) = convert.end_node(node) {
                 convert.update(range);
@@ -2770,6 +2973,9 @@ impl ForN {
             } else if let Ok((range, val)) = convert.meta_string("label") {
                 convert.update(range);
                 label = Some(val);
+            } else if let Ok((range, val)) = convert.meta_bool("par") {
+                convert.update(range);
+                par = val;
             } else if let Ok((range, val)) = convert.meta_string("name") {
                 convert.update(range);
                 let mut start_expr: Option<Expression> = None;
@@ -2797,6 +3003,7 @@ impl ForN {
             convert.subtract(start),
             convert.source(start).unwrap(),
             label,
+            par,
             &indices,
             block
         )
@@ -2807,6 +3014,7 @@ impl ForN {
         range: Range,
         source_range: Range,
         label: Option<Arc<String>>,
+        par: bool,
         indices: &[(Arc<String>, Option<Expression>, Option<Expression>)],
         mut block: Option<Block>
     ) -> Result<(Range, ForN), ()> {
@@ -2822,6 +3030,7 @@ impl ForN {
                 range,
                 source_range,
                 None,
+                false,
                 &indices[1..],
                 block
             ));
@@ -2856,6 +3065,7 @@ impl ForN {
             end: end_expr,
             block: block,
             label: label,
+            par: par,
             source_range: source_range,
         }))
     }
@@ -3124,6 +3334,302 @@ impl If {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub expr: Expression,
+    pub arms: Vec<MatchArm>,
+    pub source_range: Range,
+}
+
+impl Match {
+    pub fn from_meta_data(
+        file: &Arc<String>,
+        source: &Arc<String>,
+        mut convert: Convert,
+        ignored: &mut Vec<Range>)
+    -> Result<(Range, Match), ()> {
+        let start = convert.clone();
+        let node = "match";
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut expr: Option<Expression> = None;
+        let mut arms: Vec<MatchArm> = vec![];
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = Expression::from_meta_data(
+                file, source, "expr", convert, ignored) {
+                convert.update(range);
+                expr = Some(val);
+            } else if let Ok((range, val)) = MatchArm::from_meta_data(
+                    file, source, convert, ignored) {
+                convert.update(range);
+                arms.push(val);
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+
+        let expr = try!(expr.ok_or(()));
+        Ok((convert.subtract(start), Match {
+            expr: expr,
+            arms: arms,
+            source_range: convert.source(start).unwrap(),
+        }))
+    }
+
+    pub fn resolve_locals(
+        &self,
+        relative: usize,
+        stack: &mut Vec<Option<Arc<String>>>,
+        closure_stack: &mut Vec<usize>,
+        module: &Module
+    ) {
+        let st = stack.len();
+        self.expr.resolve_locals(relative, stack, closure_stack, module);
+        stack.truncate(st);
+        for arm in &self.arms {
+            if let Some(name) = arm.pattern.binding() {
+                stack.push(Some(name.clone()));
+            }
+            arm.expr.resolve_locals(relative, stack, closure_stack, module);
+            stack.truncate(st);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub expr: Expression,
+    pub source_range: Range,
+}
+
+impl MatchArm {
+    pub fn from_meta_data(
+        file: &Arc<String>,
+        source: &Arc<String>,
+        mut convert: Convert,
+        ignored: &mut Vec<Range>)
+    -> Result<(Range, MatchArm), ()> {
+        let start = convert.clone();
+        let node = "arm";
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut pattern: Option<Pattern> = None;
+        let mut expr: Option<Expression> = None;
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = convert.meta_string("some") {
+                convert.update(range);
+                pattern = Some(Pattern::Some(val));
+            } else if let Ok((range, val)) = convert.meta_string("ok") {
+                convert.update(range);
+                pattern = Some(Pattern::Ok(val));
+            } else if let Ok((range, val)) = convert.meta_string("err") {
+                convert.update(range);
+                pattern = Some(Pattern::Err(val));
+            } else if let Ok((range, _)) = convert.meta_bool("none") {
+                convert.update(range);
+                pattern = Some(Pattern::None);
+            } else if let Ok((range, _)) = convert.meta_bool("wildcard") {
+                convert.update(range);
+                pattern = Some(Pattern::Wildcard);
+            } else if let Ok((range, val)) = Pattern::value_from_meta_data(
+                    convert, ignored) {
+                convert.update(range);
+                pattern = Some(Pattern::Value(val));
+            } else if let Ok((range, val)) = Expression::from_meta_data(
+                file, source, "expr", convert, ignored) {
+                convert.update(range);
+                expr = Some(val);
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+
+        let pattern = try!(pattern.ok_or(()));
+        let expr = try!(expr.ok_or(()));
+        Ok((convert.subtract(start), MatchArm {
+            pattern: pattern,
+            expr: expr,
+            source_range: convert.source(start).unwrap(),
+        }))
+    }
+}
+
+/// The pattern of an arm in a `match` expression.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `some(x)`, binds the value inside the option.
+    Some(Arc<String>),
+    /// `none()`.
+    None,
+    /// `ok(x)`, binds the value inside the result.
+    Ok(Arc<String>),
+    /// `err(e)`, binds the error message.
+    Err(Arc<String>),
+    /// A number, string or bool that must be equal to the value.
+    Value(Variable),
+    /// `_`, matches any value.
+    Wildcard,
+}
+
+impl Pattern {
+    fn value_from_meta_data(
+        mut convert: Convert,
+        ignored: &mut Vec<Range>)
+    -> Result<(Range, Variable), ()> {
+        let start = convert.clone();
+        let node = "pattern";
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut value: Option<Variable> = None;
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = convert.meta_f64("num") {
+                convert.update(range);
+                value = Some(Variable::f64(val));
+            } else if let Ok((range, val)) = convert.meta_string("text") {
+                convert.update(range);
+                value = Some(Variable::Text(val));
+            } else if let Ok((range, val)) = convert.meta_bool("bool") {
+                convert.update(range);
+                value = Some(Variable::bool(val));
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+
+        let value = try!(value.ok_or(()));
+        Ok((convert.subtract(start), value))
+    }
+
+    /// Returns the name of the variable declared by the pattern.
+    pub fn binding(&self) -> Option<&Arc<String>> {
+        match *self {
+            Pattern::Some(ref name) |
+            Pattern::Ok(ref name) |
+            Pattern::Err(ref name) => Some(name),
+            Pattern::None | Pattern::Value(_) | Pattern::Wildcard => None,
+        }
+    }
+}
+
+/// A string literal with embedded expressions, for example `$"hp: {hp}"`.
+#[derive(Debug, Clone)]
+pub struct Interpolation {
+    pub parts: Vec<InterpolationPart>,
+    pub source_range: Range,
+}
+
+/// A part of an interpolated string.
+#[derive(Debug, Clone)]
+pub enum InterpolationPart {
+    /// Text with escapes replaced.
+    Text(Arc<String>),
+    /// An expression that is formatted like `str`.
+    Expression(Expression),
+}
+
+impl Interpolation {
+    pub fn from_meta_data(
+        file: &Arc<String>,
+        source: &Arc<String>,
+        mut convert: Convert,
+        ignored: &mut Vec<Range>)
+    -> Result<(Range, Interpolation), ()> {
+        let start = convert.clone();
+        let node = "interpolation";
+        let start_range = try!(convert.start_node(node));
+        convert.update(start_range);
+
+        let mut parts: Vec<InterpolationPart> = vec![];
+        // Puts text together with escaped characters.
+        let mut text = String::new();
+        loop {
+            if let Ok(range) = convert.end_node(node) {
+                convert.update(range);
+                break;
+            } else if let Ok((range, val)) = convert.meta_string("text") {
+                convert.update(range);
+                text.push_str(&val);
+            } else if let Ok((range, val)) = Expression::from_meta_data(
+                file, source, "expr", convert, ignored) {
+                convert.update(range);
+                if text.len() > 0 {
+                    parts.push(InterpolationPart::Text(Arc::new(text.clone())));
+                    text.clear();
+                }
+                parts.push(InterpolationPart::Expression(val));
+            } else if let Ok((range, _)) = convert.meta_bool("escape_quote") {
+                convert.update(range);
+                text.push('"');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_backslash") {
+                convert.update(range);
+                text.push('\\');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_newline") {
+                convert.update(range);
+                text.push('\n');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_tab") {
+                convert.update(range);
+                text.push('\t');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_return") {
+                convert.update(range);
+                text.push('\r');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_open") {
+                convert.update(range);
+                text.push('{');
+            } else if let Ok((range, _)) = convert.meta_bool("escape_close") {
+                convert.update(range);
+                text.push('}');
+            } else {
+                let range = convert.ignore();
+                convert.update(range);
+                ignored.push(range);
+            }
+        }
+        if text.len() > 0 {
+            parts.push(InterpolationPart::Text(Arc::new(text)));
+        }
+
+        Ok((convert.subtract(start), Interpolation {
+            parts: parts,
+            source_range: convert.source(start).unwrap(),
+        }))
+    }
+
+    pub fn resolve_locals(
+        &self,
+        relative: usize,
+        stack: &mut Vec<Option<Arc<String>>>,
+        closure_stack: &mut Vec<usize>,
+        module: &Module
+    ) {
+        let st = stack.len();
+        for part in &self.parts {
+            if let InterpolationPart::Expression(ref expr) = *part {
+                expr.resolve_locals(relative, stack, closure_stack, module);
+                stack.truncate(st);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Compare {
     pub op: CompareOp,
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use Symbol;
+
 use super::{
     Array,
     ArrayFill,
@@ -15,8 +17,12 @@ use super::{
     Go,
     Id,
     If,
+    Interpolation,
+    InterpolationPart,
     Item,
     Link,
+    Match,
+    MatchArm,
     Object,
     Norm,
     Number,
@@ -70,8 +76,7 @@ pub fn number(expr: &Expression, name: &Arc<String>, val: f64) -> Expression {
                 E::Item(Item {
                     name: item.name.clone(),
                     current: item.current,
-                    stack_id: item.stack_id.clone(),
-                    static_stack_id: item.static_stack_id.clone(),
+                    slot: item.slot.clone(),
                     try: item.try.clone(),
                     ids: new_ids,
                     try_ids: item.try_ids.clone(),
@@ -91,7 +96,7 @@ pub fn number(expr: &Expression, name: &Arc<String>, val: f64) -> Expression {
             }))
         }
         E::Object(ref obj_expr) => {
-            let mut new_key_values: Vec<(Arc<String>, Expression)> = vec![];
+            let mut new_key_values: Vec<(Symbol, Expression)> = vec![];
             for key_value in &obj_expr.key_values {
                 new_key_values.push((key_value.0.clone(),
                     number(&key_value.1, name, val)));
@@ -226,6 +231,41 @@ pub fn number(expr: &Expression, name: &Arc<String>, val: f64) -> Expression {
                 source_range: if_expr.source_range,
             }))
         }
+        E::Match(ref match_expr) => {
+            let mut new_arms: Vec<MatchArm> = vec![];
+            for arm in &match_expr.arms {
+                // A binding with same name hides the item.
+                let expr = if arm.pattern.binding() == Some(name) {
+                    arm.expr.clone()
+                } else {
+                    number(&arm.expr, name, val)
+                };
+                new_arms.push(MatchArm {
+                    pattern: arm.pattern.clone(),
+                    expr: expr,
+                    source_range: arm.source_range,
+                });
+            }
+            E::Match(Box::new(Match {
+                expr: number(&match_expr.expr, name, val),
+                arms: new_arms,
+                source_range: match_expr.source_range,
+            }))
+        }
+        E::Interpolation(ref interpolation) => {
+            let mut new_parts: Vec<InterpolationPart> = vec![];
+            for part in &interpolation.parts {
+                new_parts.push(match *part {
+                    InterpolationPart::Text(_) => part.clone(),
+                    InterpolationPart::Expression(ref expr) =>
+                        InterpolationPart::Expression(number(expr, name, val)),
+                });
+            }
+            E::Interpolation(Box::new(Interpolation {
+                parts: new_parts,
+                source_range: interpolation.source_range,
+            }))
+        }
         E::Compare(ref cmp_expr) => {
             E::Compare(Box::new(Compare {
                 op: cmp_expr.op.clone(),
@@ -335,6 +375,7 @@ fn number_for_n(for_n_expr: &ForN, name: &Arc<String>, val: f64) -> ForN {
                 .map(|start| number(start, name, val)),
             end: number(&for_n_expr.end, name, val),
             block: number_block(&for_n_expr.block, name, val),
+            par: for_n_expr.par,
             source_range: for_n_expr.source_range,
         }
     }
@@ -0,0 +1,663 @@
+/*
+Compiles function bodies to a flat list of instructions.
+
+The instructions are executed by `Runtime::execute` in a loop,
+instead of walking the AST recursively for every expression.
+Expressions that have no instruction are evaluated with the AST,
+which keeps the stack layout identical to the AST walker.
+
+Compiled to instructions:
+
+- numbers, bools, texts and constants
+- locals, and indexing into locals without `?`
+- objects, arrays, `[value; length]`, links and vec4 without swizzles
+- unary, binary and compare operators
+- assignments, `return`, `break` and `continue`
+- blocks, `if`, `for` and `for n` loops
+- short loops such as `sum`, `min` and `any`
+- calls to loaded, intrinsic and external functions
+
+Evaluated with the AST:
+
+- `go`, closures and calling closures
+- swizzles, `?`, `match`, string interpolation and norms
+- short loops with `par`, and short loops over `arr[i]` that read packed arrays
+- items with `?` or declared as current objects
+*/
+
+use std::sync::Arc;
+use range::Range;
+
+use ast;
+use FnIndex;
+use intrinsics;
+use Symbol;
+use Variable;
+
+/// Stores the compiled body of a function.
+#[derive(Debug)]
+pub struct Code {
+    /// The instructions to execute.
+    pub instructions: Vec<Instruction>,
+    /// Calls used by `CallBegin` and `CallEnd`.
+    pub calls: Vec<ast::Call>,
+    /// Loops used by `Break`, `Continue` and `Eval`.
+    pub loops: Vec<Loop>,
+}
+
+/// A constant pushed by an instruction.
+#[derive(Debug)]
+pub enum Constant {
+    F64(f64),
+    Bool(bool),
+    Text(Arc<String>),
+    Vec4([f32; 4]),
+    /// The value of `none()`.
+    None,
+}
+
+impl Constant {
+    /// Returns the constant as a variable.
+    #[inline(always)]
+    pub fn to_variable(&self) -> Variable {
+        match *self {
+            Constant::F64(x) => Variable::f64(x),
+            Constant::Bool(x) => Variable::bool(x),
+            Constant::Text(ref x) => Variable::Text(x.clone()),
+            Constant::Vec4(x) => Variable::Vec4(x),
+            Constant::None => Variable::Option(None),
+        }
+    }
+}
+
+/// Information about a loop, used to jump on `break` and `continue`.
+#[derive(Debug)]
+pub struct Loop {
+    /// The label of the loop.
+    pub label: Option<Arc<String>>,
+    /// The closest loop surrounding this loop.
+    pub parent: Option<usize>,
+    /// The number of scopes while running the loop body.
+    pub depth: usize,
+    /// Where to jump on `break`.
+    pub break_target: usize,
+    /// Where to jump on `continue`.
+    pub continue_target: usize,
+}
+
+/// The kind of a short loop, which combines the values of the body.
+#[derive(Debug, Clone, Copy)]
+pub enum ShortLoop {
+    Sum,
+    SumVec4,
+    Prod,
+    Min,
+    Max,
+    Any,
+    All,
+    Sift,
+}
+
+#[derive(Debug)]
+pub enum Instruction {
+    /// Pushes a constant.
+    Push(Constant),
+    /// Pushes no value, used by expressions that returns nothing.
+    PushNone,
+    /// Pops a value.
+    Pop,
+    /// Pushes a reference to a local variable, by slot in the frame.
+    Local(usize),
+    /// Evaluates an expression using the AST, with the innermost loop.
+    Eval(Box<ast::Expression>, Option<usize>),
+    /// Enters a new scope.
+    ScopeEnter,
+    /// Exits a scope.
+    ScopeExit,
+    /// Exits the scope of a block, keeping the value of the block.
+    BlockExit,
+    /// Jumps to an instruction.
+    Jump(usize),
+    /// Pops a condition and jumps if it is false.
+    JumpIfFalse(usize, Range, &'static str),
+    /// Jumps if the value is `true`, used by lazy `||`.
+    OrElse(usize),
+    /// Jumps if the value is `false`, used by lazy `&&`.
+    AndAlso(usize),
+    /// Binary operator.
+    BinOp(ast::BinOp, Range),
+    /// Comparison operator.
+    Compare(ast::CompareOp, Range),
+    /// Unary operator.
+    UnOp(ast::UnOp, Range),
+    /// Assigns the value to the left expression, pushing no value.
+    Assign(ast::AssignOp, Box<ast::Expression>, Range),
+    /// Assigns the value to a local variable by slot in the frame, pushing no value.
+    AssignLocal(ast::AssignOp, usize, Range),
+    /// Assigns the return value and returns from the function.
+    Return(Box<ast::Expression>, Range),
+    /// Pops a value and fails if it is something.
+    ExpectNone(Range, &'static str),
+    /// Fails if the value on top is nothing, without popping it.
+    ExpectSome(Range, &'static str),
+    /// Pops values into a new array.
+    Array(usize),
+    /// Pops the value and the length of `[value; length]`.
+    ArrayFill(Range),
+    /// Pops values into a new object, with the keys in order.
+    Object(Vec<(Symbol, Range)>),
+    /// Pops values into a new link.
+    Link(Vec<Range>),
+    /// Pops a value that must be something and puts it on the stack.
+    ///
+    /// Used where the AST walker keeps values on the stack while evaluating,
+    /// such that locals have the same slots.
+    PushStack(Range, &'static str),
+    /// Takes the four components of a vec4 from the stack.
+    Vec4(Vec<Range>),
+    /// Looks up the ids of a local by slot in the frame,
+    /// taking the index expressions from the stack.
+    Index(Box<ast::Item>, usize, usize),
+    /// Consumes fuel at the start of a loop iteration.
+    ConsumeFuel(Range),
+    /// Breaks a loop.
+    Break(usize),
+    /// Continues a loop.
+    Continue(usize),
+    /// Pops start and end of a `for` loop, declaring the counter.
+    ForNStart(Arc<String>, Range),
+    /// Jumps if the counter reached the end.
+    ForNCheck(usize, Range),
+    /// Pushes the initial value of a short loop.
+    ShortLoop(ShortLoop),
+    /// Pops the value of the body and combines it with the value of the short loop,
+    /// breaking the loop when the value is known.
+    Accumulate(ShortLoop, Range, usize),
+    /// Increments the counter and jumps if it did not reach the end.
+    ForNIncrement(usize, Range),
+    /// Prepares a call to a loaded function, before arguments are evaluated.
+    CallBegin(usize),
+    /// Puts an argument on the stack, whether it must be something.
+    PushArg(Range, bool),
+    /// Calls the function with the arguments on the stack.
+    CallEnd(usize),
+    /// Calls a loaded function in tail position, reusing the current frame.
+    ///
+    /// Works like `CallEnd` when the frame can not be reused.
+    TailCall(usize),
+}
+
+struct Compiler {
+    code: Code,
+    /// Loops surrounding the current instruction.
+    loops: Vec<usize>,
+    /// The current number of scopes.
+    depth: usize,
+}
+
+/// Compiles the body of a function.
+///
+/// The locals of the function must be resolved first.
+pub fn compile(f: &ast::Function) -> Code {
+    let mut compiler = Compiler {
+        code: Code {
+            instructions: vec![],
+            calls: vec![],
+            loops: vec![],
+        },
+        loops: vec![],
+        depth: 0,
+    };
+    compiler.block(&f.block, true);
+    compiler.code
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.code.instructions.push(instruction);
+        self.code.instructions.len() - 1
+    }
+
+    fn pc(&self) -> usize {
+        self.code.instructions.len()
+    }
+
+    fn patch(&mut self, ind: usize, target: usize) {
+        use self::Instruction::*;
+
+        match self.code.instructions[ind] {
+            Jump(ref mut t) |
+            JumpIfFalse(ref mut t, _, _) |
+            OrElse(ref mut t) |
+            AndAlso(ref mut t) |
+            ForNCheck(ref mut t, _) => *t = target,
+            _ => panic!("Can not patch instruction")
+        }
+    }
+
+    fn innermost_loop(&self) -> Option<usize> {
+        self.loops.last().cloned()
+    }
+
+    fn find_loop(&self, label: &Option<Arc<String>>) -> Option<usize> {
+        match *label {
+            None => self.innermost_loop(),
+            Some(ref label) => {
+                for &l in self.loops.iter().rev() {
+                    if self.code.loops[l].label.as_ref() == Some(label) {
+                        return Some(l);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn eval(&mut self, expr: &ast::Expression) {
+        let innermost = self.innermost_loop();
+        self.emit(Instruction::Eval(Box::new(expr.clone()), innermost));
+    }
+
+    /// Returns `true` if a block needs a scope to clean up the stack.
+    fn needs_scope(block: &ast::Block) -> bool {
+        use ast::Expression as E;
+
+        block.expressions.iter().any(|expr| match *expr {
+            E::Assign(ref assign) => assign.op == ast::AssignOp::Assign,
+            E::Swizzle(_) => true,
+            _ => false
+        })
+    }
+
+    /// Compiles a block that leaves its value.
+    ///
+    /// The last expression is in tail position for the body of a function.
+    fn block(&mut self, block: &ast::Block, tail: bool) {
+        let scope = Compiler::needs_scope(block);
+        if scope {
+            self.emit(Instruction::ScopeEnter);
+            self.depth += 1;
+        }
+        if block.expressions.len() == 0 {
+            self.emit(Instruction::PushNone);
+        }
+        for (i, expr) in block.expressions.iter().enumerate() {
+            if i + 1 < block.expressions.len() {
+                self.statement(expr);
+            } else if tail {
+                self.tail_expression(expr);
+            } else {
+                self.expression(expr);
+            }
+        }
+        if scope {
+            self.depth -= 1;
+            self.emit(Instruction::BlockExit);
+        }
+    }
+
+    /// Compiles a block where the value is ignored.
+    fn statements(&mut self, block: &ast::Block) {
+        let scope = Compiler::needs_scope(block);
+        if scope {
+            self.emit(Instruction::ScopeEnter);
+            self.depth += 1;
+        }
+        for expr in &block.expressions {
+            self.statement(expr);
+        }
+        if scope {
+            self.depth -= 1;
+            self.emit(Instruction::ScopeExit);
+        }
+    }
+
+    /// Compiles an expression where the value is ignored.
+    fn statement(&mut self, expr: &ast::Expression) {
+        if let ast::Expression::Assign(ref assign) = *expr {
+            self.assign(assign);
+        } else {
+            self.expression(expr);
+            self.emit(Instruction::Pop);
+        }
+    }
+
+    /// Compiles an expression that must return nothing.
+    fn nothing(&mut self, expr: &ast::Expression, msg: &'static str) {
+        if let ast::Expression::Assign(ref assign) = *expr {
+            self.assign(assign);
+        } else {
+            self.expression(expr);
+            self.emit(Instruction::ExpectNone(expr.source_range(), msg));
+        }
+    }
+
+    /// Compiles an assignment, which pushes no value.
+    fn assign(&mut self, assign: &ast::Assign) {
+        use ast::Expression as E;
+
+        self.expression(&assign.right);
+        if let E::Item(ref item) = assign.left {
+            match item.slot.get() {
+                Some(slot) if assign.op != ast::AssignOp::Assign &&
+                            item.ids.len() == 0 && !item.try => {
+                    self.emit(Instruction::AssignLocal(assign.op, slot,
+                                                       item.source_range));
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.emit(Instruction::Assign(assign.op, Box::new(assign.left.clone()),
+                                      assign.right.source_range()));
+    }
+
+    /// Compiles an expression where the value is returned from the function.
+    fn tail_expression(&mut self, expr: &ast::Expression) {
+        match *expr {
+            ast::Expression::Call(ref call) => {
+                if let FnIndex::Loaded(_) = call.f_index.get() {
+                    self.call(call, true);
+                    return;
+                }
+            }
+            ast::Expression::If(ref if_expr) => {
+                self.if_expr(if_expr, true);
+                return;
+            }
+            _ => {}
+        }
+        self.expression(expr)
+    }
+
+    fn call(&mut self, call: &ast::Call, tail: bool) {
+        // Arguments of other functions must be something, such that they can be counted.
+        let loaded = match call.f_index.get() {
+            FnIndex::Loaded(_) => true,
+            _ => false,
+        };
+        let strict = !loaded;
+        let ind = self.code.calls.len();
+        self.code.calls.push(call.clone());
+        if loaded {
+            self.emit(Instruction::CallBegin(ind));
+        }
+        for arg in &call.args {
+            self.expression(arg);
+            self.emit(Instruction::PushArg(arg.source_range(), strict));
+        }
+        if tail {
+            self.emit(Instruction::TailCall(ind));
+        } else {
+            self.emit(Instruction::CallEnd(ind));
+        }
+    }
+
+    fn expression(&mut self, expr: &ast::Expression) {
+        use ast::Expression as E;
+
+        match *expr {
+            E::Number(ref num) => {
+                self.emit(Instruction::Push(Constant::F64(num.num)));
+            }
+            E::Bool(ref b) => {
+                self.emit(Instruction::Push(Constant::Bool(b.val)));
+            }
+            E::Text(ref text) => {
+                self.emit(Instruction::Push(Constant::Text(text.text.clone())));
+            }
+            E::Variable(_, Variable::Vec4(v)) => {
+                self.emit(Instruction::Push(Constant::Vec4(v)));
+            }
+            E::Item(ref item) => {
+                match item.slot.get() {
+                    Some(slot) if item.ids.len() == 0 && !item.try => {
+                        self.emit(Instruction::Local(slot));
+                    }
+                    Some(slot) if !item.try && item.try_ids.len() == 0 => {
+                        let mut n = 0;
+                        for id in &item.ids {
+                            if let ast::Id::Expression(ref expr) = *id {
+                                self.expression(expr);
+                                self.emit(Instruction::PushStack(expr.source_range(),
+                                          "Expected something for index"));
+                                n += 1;
+                            }
+                        }
+                        self.emit(Instruction::Index(Box::new(item.clone()), slot, n));
+                    }
+                    _ => self.eval(expr)
+                }
+            }
+            E::Object(ref obj) => {
+                for &(_, ref expr) in &obj.key_values {
+                    self.something(expr, "Expected something");
+                }
+                self.emit(Instruction::Object(obj.key_values.iter()
+                    .map(|&(ref key, ref expr)| (key.clone(), expr.source_range()))
+                    .collect()));
+            }
+            E::Array(ref arr) => {
+                for item in &arr.items {
+                    self.something(item, "Expected something");
+                }
+                self.emit(Instruction::Array(arr.items.len()));
+            }
+            E::ArrayFill(ref array_fill) => {
+                self.something(&array_fill.fill, "Expected something");
+                self.something(&array_fill.n, "Expected something");
+                self.emit(Instruction::ArrayFill(array_fill.n.source_range()));
+            }
+            E::Link(ref link) => {
+                for item in &link.items {
+                    self.something(item, "Expected something");
+                }
+                self.emit(Instruction::Link(link.items.iter()
+                    .map(|item| item.source_range()).collect()));
+            }
+            E::Vec4(ref vec4) if vec4.args.iter().all(|arg| {
+                if let E::Swizzle(_) = *arg { false } else { true }
+            }) => {
+                for arg in &vec4.args {
+                    self.expression(arg);
+                    self.emit(Instruction::PushStack(arg.source_range(),
+                              "Expected something from vec4 argument"));
+                }
+                self.emit(Instruction::Vec4(vec4.args.iter()
+                    .map(|arg| arg.source_range()).collect()));
+            }
+            E::Block(ref block) => self.block(block, false),
+            E::BinOp(ref binop) => {
+                use ast::BinOp::*;
+
+                self.expression(&binop.left);
+                let lazy = match binop.op {
+                    OrElse => Some(self.emit(Instruction::OrElse(0))),
+                    AndAlso => Some(self.emit(Instruction::AndAlso(0))),
+                    _ => None
+                };
+                self.expression(&binop.right);
+                self.emit(Instruction::BinOp(binop.op, binop.source_range));
+                if let Some(ind) = lazy {
+                    let pc = self.pc();
+                    self.patch(ind, pc);
+                }
+            }
+            E::Compare(ref compare) => {
+                self.expression(&compare.left);
+                self.expression(&compare.right);
+                self.emit(Instruction::Compare(compare.op, compare.source_range));
+            }
+            E::UnOp(ref unop) => {
+                self.expression(&unop.expr);
+                self.emit(Instruction::UnOp(unop.op, unop.source_range));
+            }
+            E::Assign(ref assign) => {
+                self.assign(assign);
+                self.emit(Instruction::PushNone);
+            }
+            E::Return(ref item, ref ret) => {
+                self.tail_expression(ret);
+                self.emit(Instruction::Return(item.clone(), ret.source_range()));
+            }
+            E::Break(ref b) => {
+                match self.find_loop(&b.label) {
+                    Some(l) => { self.emit(Instruction::Break(l)); }
+                    None => self.eval(expr)
+                }
+            }
+            E::Continue(ref c) => {
+                match self.find_loop(&c.label) {
+                    Some(l) => { self.emit(Instruction::Continue(l)); }
+                    None => self.eval(expr)
+                }
+            }
+            E::If(ref if_expr) => self.if_expr(if_expr, false),
+            E::For(ref for_expr) => self.for_expr(for_expr),
+            E::ForN(ref for_n_expr) => self.for_n_expr(for_n_expr, None),
+            E::Sum(ref for_n_expr) |
+            E::SumVec4(ref for_n_expr) |
+            E::Prod(ref for_n_expr) |
+            E::Min(ref for_n_expr) |
+            E::Max(ref for_n_expr) |
+            E::Any(ref for_n_expr) |
+            E::All(ref for_n_expr) |
+            E::Sift(ref for_n_expr) if !for_n_expr.par &&
+                                      for_n_expr.indexed_slot().is_none() => {
+                let kind = match *expr {
+                    E::Sum(_) => ShortLoop::Sum,
+                    E::SumVec4(_) => ShortLoop::SumVec4,
+                    E::Prod(_) => ShortLoop::Prod,
+                    E::Min(_) => ShortLoop::Min,
+                    E::Max(_) => ShortLoop::Max,
+                    E::Any(_) => ShortLoop::Any,
+                    E::All(_) => ShortLoop::All,
+                    _ => ShortLoop::Sift,
+                };
+                self.for_n_expr(for_n_expr, Some(kind))
+            }
+            E::Call(ref call) => {
+                match call.f_index.get() {
+                    FnIndex::None => self.eval(expr),
+                    FnIndex::Intrinsic(intrinsics::NONE) => {
+                        self.emit(Instruction::Push(Constant::None));
+                    }
+                    _ => self.call(call, false)
+                }
+            }
+            _ => self.eval(expr)
+        }
+    }
+
+    /// Compiles an expression that must return something.
+    fn something(&mut self, expr: &ast::Expression, msg: &'static str) {
+        self.expression(expr);
+        self.emit(Instruction::ExpectSome(expr.source_range(), msg));
+    }
+
+    fn if_expr(&mut self, if_expr: &ast::If, tail: bool) {
+        let mut end_jumps = vec![];
+        self.expression(&if_expr.cond);
+        let next = self.emit(Instruction::JumpIfFalse(0, if_expr.cond.source_range(),
+                             "Expected bool from if condition"));
+        self.block(&if_expr.true_block, tail);
+        end_jumps.push(self.emit(Instruction::Jump(0)));
+        let pc = self.pc();
+        self.patch(next, pc);
+        for (cond, body) in if_expr.else_if_conds.iter()
+            .zip(if_expr.else_if_blocks.iter()) {
+            self.expression(cond);
+            let next = self.emit(Instruction::JumpIfFalse(0, cond.source_range(),
+                                 "Expected bool from else if condition"));
+            self.block(body, tail);
+            end_jumps.push(self.emit(Instruction::Jump(0)));
+            let pc = self.pc();
+            self.patch(next, pc);
+        }
+        if let Some(ref block) = if_expr.else_block {
+            self.block(block, tail);
+        } else {
+            self.emit(Instruction::PushNone);
+        }
+        let pc = self.pc();
+        for ind in end_jumps {
+            self.patch(ind, pc);
+        }
+    }
+
+    fn begin_loop(&mut self, label: &Option<Arc<String>>) -> usize {
+        let l = self.code.loops.len();
+        let parent = self.innermost_loop();
+        self.code.loops.push(Loop {
+            label: label.clone(),
+            parent: parent,
+            depth: self.depth,
+            break_target: 0,
+            continue_target: 0,
+        });
+        l
+    }
+
+    fn for_expr(&mut self, for_expr: &ast::For) {
+        self.emit(Instruction::ScopeEnter);
+        self.depth += 1;
+        self.nothing(&for_expr.init, "Expected nothing from for init");
+        let l = self.begin_loop(&for_expr.label);
+        let top = self.pc();
+        self.expression(&for_expr.cond);
+        let exit = self.emit(Instruction::JumpIfFalse(0, for_expr.cond.source_range(),
+                             "Expected bool from for condition"));
+        self.emit(Instruction::ConsumeFuel(for_expr.source_range));
+        self.loops.push(l);
+        self.statements(&for_expr.block);
+        self.loops.pop();
+        self.code.loops[l].continue_target = self.pc();
+        self.nothing(&for_expr.step, "Expected nothing from for step");
+        self.emit(Instruction::Jump(top));
+        let pc = self.pc();
+        self.patch(exit, pc);
+        self.code.loops[l].break_target = pc;
+        self.depth -= 1;
+        self.emit(Instruction::ScopeExit);
+        self.emit(Instruction::PushNone);
+    }
+
+    /// Compiles a `for n` loop, or a short loop that leaves its value.
+    fn for_n_expr(&mut self, for_n_expr: &ast::ForN, kind: Option<ShortLoop>) {
+        if let Some(kind) = kind {
+            self.emit(Instruction::ShortLoop(kind));
+        }
+        self.emit(Instruction::ScopeEnter);
+        self.depth += 1;
+        if let Some(ref start) = for_n_expr.start {
+            self.expression(start);
+        } else {
+            self.emit(Instruction::Push(Constant::F64(0.0)));
+        }
+        self.expression(&for_n_expr.end);
+        self.emit(Instruction::ForNStart(for_n_expr.name.clone(),
+                                         for_n_expr.end.source_range()));
+        let l = self.begin_loop(&for_n_expr.label);
+        let exit = self.emit(Instruction::ForNCheck(0, for_n_expr.source_range));
+        let top = self.pc();
+        self.emit(Instruction::ConsumeFuel(for_n_expr.source_range));
+        self.loops.push(l);
+        if let Some(kind) = kind {
+            self.block(&for_n_expr.block, false);
+            self.emit(Instruction::Accumulate(kind, for_n_expr.block.source_range, l));
+        } else {
+            self.statements(&for_n_expr.block);
+        }
+        self.loops.pop();
+        self.code.loops[l].continue_target = self.pc();
+        self.emit(Instruction::ForNIncrement(top, for_n_expr.source_range));
+        let pc = self.pc();
+        self.patch(exit, pc);
+        self.code.loops[l].break_target = pc;
+        self.depth -= 1;
+        self.emit(Instruction::ScopeExit);
+        if kind.is_none() {
+            self.emit(Instruction::PushNone);
+        }
+    }
+}
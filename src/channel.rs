@@ -0,0 +1,118 @@
+/*
+Channels for sending values between threads started with `go`.
+
+A channel is a queue shared by all copies of the channel value.
+Values are deep cloned before they are sent, so they never refer to the stack of the sender.
+A closed channel accepts no more values, but the values in the queue can still be received.
+
+Waiting for a value is done by the runtime, which tells the scheduler that a worker is waiting,
+such that a full worker pool does not wait for a sender that is not started.
+*/
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use Variable;
+
+struct Queue {
+    items: VecDeque<Variable>,
+    closed: bool,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+/// The result of receiving without waiting.
+pub enum TryRecv {
+    /// Received a value.
+    Item(Variable),
+    /// No value is ready, but the channel is open.
+    Empty,
+    /// The channel is closed and has no more values.
+    Closed,
+}
+
+/// Sends values between threads.
+#[derive(Clone)]
+pub struct Channel {
+    inner: Arc<Inner>,
+}
+
+impl Channel {
+    /// Creates a new open channel.
+    pub fn new() -> Channel {
+        Channel {
+            inner: Arc::new(Inner {
+                queue: Mutex::new(Queue {
+                    items: VecDeque::new(),
+                    closed: false,
+                }),
+                changed: Condvar::new(),
+            })
+        }
+    }
+
+    /// Adds a value to the queue.
+    ///
+    /// Returns the value back if the channel is closed.
+    pub fn send(&self, val: Variable) -> Result<(), Variable> {
+        {
+            let mut queue = self.inner.queue.lock().unwrap();
+            if queue.closed { return Err(val); }
+            queue.items.push_back(val);
+        }
+        self.inner.changed.notify_all();
+        Ok(())
+    }
+
+    /// Takes the next value from the queue without waiting.
+    pub fn try_recv(&self) -> TryRecv {
+        let mut queue = self.inner.queue.lock().unwrap();
+        match queue.items.pop_front() {
+            Some(val) => TryRecv::Item(val),
+            None if queue.closed => TryRecv::Closed,
+            None => TryRecv::Empty,
+        }
+    }
+
+    /// Waits until a value is sent or the channel is closed, at most `timeout`.
+    pub fn wait(&self, timeout: Duration) {
+        let queue = self.inner.queue.lock().unwrap();
+        if queue.items.is_empty() && !queue.closed {
+            let _ = self.inner.changed.wait_timeout(queue, timeout).unwrap();
+        }
+    }
+
+    /// Closes the channel.
+    pub fn close(&self) {
+        self.inner.queue.lock().unwrap().closed = true;
+        self.inner.changed.notify_all();
+    }
+
+    /// Returns `true` if the channel is closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.queue.lock().unwrap().closed
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel::new()
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Channel) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel")
+    }
+}
@@ -0,0 +1,234 @@
+/*
+Counts how many times statements and `if` branches are executed.
+
+The runtime records each statement in a block and the branch taken by
+each `if` expression when coverage is attached.
+Counts are stored per file and range, and mapped to lines when writing
+an lcov `.info` file.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use range::Range;
+
+use ast;
+use debugger::Location;
+use Module;
+
+/// Stores coverage data of a runtime.
+pub struct Coverage {
+    /// Execution count per file and statement range.
+    pub statements: HashMap<Arc<String>, HashMap<Range, u64>>,
+    /// Execution count per file and `if` range, for each branch.
+    ///
+    /// The first branch is the `if` block, followed by `else if` blocks.
+    /// The last branch is the `else` block, or no block taken.
+    pub branches: HashMap<Arc<String>, HashMap<Range, Vec<u64>>>,
+}
+
+impl Coverage {
+    /// Creates a new coverage.
+    pub fn new() -> Coverage {
+        Coverage {
+            statements: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Records an executed statement.
+    pub fn statement(&mut self, file: &Arc<String>, range: Range) {
+        let file = self.statements.entry(file.clone()).or_default();
+        *file.entry(range).or_insert(0) += 1;
+    }
+
+    /// Records a branch taken by an `if` expression.
+    pub fn branch(&mut self, file: &Arc<String>, if_expr: &ast::If, taken: usize) {
+        let file = self.branches.entry(file.clone()).or_default();
+        let n = if_expr.else_if_blocks.len() + 2;
+        file.entry(if_expr.source_range).or_insert(vec![0; n])[taken] += 1;
+    }
+
+    /// Writes coverage of the loaded functions in a module,
+    /// in the lcov tracefile format.
+    pub fn write_lcov<W: io::Write>(&self, w: &mut W, module: &Module) -> Result<(), io::Error> {
+        let mut files: Vec<&Arc<String>> = vec![];
+        for f in &module.functions {
+            if !files.contains(&&f.file) { files.push(&f.file); }
+        }
+        let no_statements = HashMap::new();
+        let no_branches = HashMap::new();
+        for file in files {
+            let statements = self.statements.get(file).unwrap_or(&no_statements);
+            let branches = self.branches.get(file).unwrap_or(&no_branches);
+            // Line and execution count.
+            let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+            // Line, range and number of branches.
+            let mut ifs: Vec<(usize, Range, usize)> = vec![];
+            for f in module.functions.iter().filter(|f| &f.file == file) {
+                let mut nodes = Nodes { statements: vec![], ifs: vec![] };
+                nodes.block(&f.block);
+                for range in nodes.statements {
+                    let (line, _) = Location::line_column(&f.source, range);
+                    let count = statements.get(&range).copied().unwrap_or(0);
+                    let entry = lines.entry(line).or_insert(0);
+                    if count > *entry { *entry = count; }
+                }
+                for (range, n) in nodes.ifs {
+                    let (line, _) = Location::line_column(&f.source, range);
+                    ifs.push((line, range, n));
+                }
+            }
+            ifs.sort_by_key(|&(_, range, _)| range.offset);
+
+            writeln!(w, "TN:")?;
+            writeln!(w, "SF:{}", file)?;
+            let mut branches_hit = 0;
+            let mut branches_found = 0;
+            for (block, &(line, range, n)) in ifs.iter().enumerate() {
+                for i in 0..n {
+                    branches_found += 1;
+                    match branches.get(&range) {
+                        None => writeln!(w, "BRDA:{},{},{},-", line, block, i)?,
+                        Some(counts) => {
+                            if counts[i] > 0 { branches_hit += 1; }
+                            writeln!(w, "BRDA:{},{},{},{}", line, block, i, counts[i])?;
+                        }
+                    }
+                }
+            }
+            writeln!(w, "BRF:{}", branches_found)?;
+            writeln!(w, "BRH:{}", branches_hit)?;
+            for (line, count) in &lines {
+                writeln!(w, "DA:{},{}", line, count)?;
+            }
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(w, "LH:{}", lines.values().filter(|&&n| n > 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+/// Collects statement and `if` ranges of a function.
+struct Nodes {
+    statements: Vec<Range>,
+    /// Range and number of branches.
+    ifs: Vec<(Range, usize)>,
+}
+
+impl Nodes {
+    fn block(&mut self, block: &ast::Block) {
+        for expr in &block.expressions {
+            self.statements.push(expr.source_range());
+            self.expr(expr);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[ast::Expression]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn for_n(&mut self, for_n: &ast::ForN) {
+        if let Some(ref start) = for_n.start { self.expr(start); }
+        self.expr(&for_n.end);
+        self.block(&for_n.block);
+    }
+
+    fn expr(&mut self, expr: &ast::Expression) {
+        use ast::Expression as E;
+
+        match *expr {
+            E::Link(ref link) => self.exprs(&link.items),
+            E::Object(ref obj) => {
+                for (_, expr) in &obj.key_values { self.expr(expr); }
+            }
+            E::Array(ref arr) => self.exprs(&arr.items),
+            E::ArrayFill(ref arr_fill) => {
+                self.expr(&arr_fill.fill);
+                self.expr(&arr_fill.n);
+            }
+            E::Return(_, ref expr) => self.expr(expr),
+            E::Block(ref block) => self.block(block),
+            E::Go(ref go) => self.exprs(&go.call.args),
+            E::Call(ref call) => self.exprs(&call.args),
+            E::Item(ref item) => {
+                for id in &item.ids {
+                    if let ast::Id::Expression(ref expr) = *id { self.expr(expr); }
+                }
+            }
+            E::BinOp(ref binop) => {
+                self.expr(&binop.left);
+                self.expr(&binop.right);
+            }
+            E::Assign(ref assign) => {
+                self.expr(&assign.left);
+                self.expr(&assign.right);
+            }
+            E::Vec4(ref vec4) => self.exprs(&vec4.args),
+            E::For(ref for_expr) => {
+                self.expr(&for_expr.init);
+                self.expr(&for_expr.cond);
+                self.expr(&for_expr.step);
+                self.block(&for_expr.block);
+            }
+            E::ForN(ref for_n) |
+            E::Sum(ref for_n) |
+            E::SumVec4(ref for_n) |
+            E::Prod(ref for_n) |
+            E::Min(ref for_n) |
+            E::Max(ref for_n) |
+            E::Sift(ref for_n) |
+            E::Any(ref for_n) |
+            E::All(ref for_n) => self.for_n(for_n),
+            E::If(ref if_expr) => {
+                self.ifs.push((if_expr.source_range, if_expr.else_if_blocks.len() + 2));
+                self.expr(&if_expr.cond);
+                self.block(&if_expr.true_block);
+                for (cond, block) in if_expr.else_if_conds.iter()
+                    .zip(if_expr.else_if_blocks.iter()) {
+                    self.expr(cond);
+                    self.block(block);
+                }
+                if let Some(ref block) = if_expr.else_block { self.block(block); }
+            }
+            E::Match(ref match_expr) => {
+                self.expr(&match_expr.expr);
+                for arm in &match_expr.arms { self.expr(&arm.expr); }
+            }
+            E::Interpolation(ref interpolation) => {
+                for part in &interpolation.parts {
+                    if let ast::InterpolationPart::Expression(ref expr) = *part {
+                        self.expr(expr);
+                    }
+                }
+            }
+            E::Compare(ref compare) => {
+                self.expr(&compare.left);
+                self.expr(&compare.right);
+            }
+            E::UnOp(ref unop) => self.expr(&unop.expr),
+            E::Norm(ref norm) => self.expr(&norm.expr),
+            E::Try(ref expr) => self.expr(expr),
+            E::Swizzle(ref swizzle) => self.expr(&swizzle.expr),
+            E::Closure(ref closure) => self.expr(&closure.expr),
+            E::CallClosure(ref call) => self.exprs(&call.args),
+            E::Grab(ref grab) => self.expr(&grab.expr),
+            E::ReturnVoid(_) |
+            E::Break(_) |
+            E::Continue(_) |
+            E::Text(_) |
+            E::Number(_) |
+            E::Bool(_) |
+            E::Variable(_, _) => {}
+        }
+    }
+}
@@ -0,0 +1,143 @@
+/*
+Pauses a script at breakpoints and steps through statements.
+
+The runtime calls the debugger before evaluating each statement in a block.
+While a debugger is attached, functions are evaluated by walking the AST.
+*/
+
+use std::sync::Arc;
+use range::Range;
+use Runtime;
+
+/// A breakpoint by file and line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// The file as passed to `load`.
+    pub file: Arc<String>,
+    /// Line number, starting at 1.
+    pub line: usize,
+}
+
+/// Where the script is paused.
+#[derive(Clone, Debug)]
+pub struct Location {
+    /// The file of the function.
+    pub file: Option<Arc<String>>,
+    /// The name of the function.
+    pub fn_name: Arc<String>,
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Column number, starting at 1.
+    pub column: usize,
+    /// The number of functions on the call stack.
+    pub depth: usize,
+}
+
+impl Location {
+    /// Computes line and column of a range in the source.
+    pub fn line_column(source: &str, range: Range) -> (usize, usize) {
+        let mut line = 1;
+        let mut column = 1;
+        for c in source.chars().take(range.offset) {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        (line, column)
+    }
+}
+
+/// Tells the runtime how to continue after a pause.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Runs until the next breakpoint.
+    Continue,
+    /// Pauses at the next statement.
+    In,
+    /// Pauses at the next statement in the same function or a caller.
+    Over,
+    /// Pauses at the next statement in a caller.
+    Out,
+}
+
+/// Called when the script is paused.
+pub trait DebugHandler: Send {
+    /// Inspects the runtime and decides how to continue.
+    ///
+    /// Breakpoints can be added or removed while paused.
+    fn pause(
+        &mut self,
+        rt: &Runtime,
+        location: &Location,
+        breakpoints: &mut Vec<Breakpoint>
+    ) -> Step;
+}
+
+/// Stores the debugger state of a runtime.
+pub struct Debugger {
+    /// The breakpoints where the script pauses.
+    pub breakpoints: Vec<Breakpoint>,
+    /// How to continue from the current position.
+    pub step: Step,
+    /// The depth of the call stack when stepping started.
+    depth: usize,
+    /// The last visited line, such that a breakpoint pauses once per line.
+    last_line: Option<(Option<Arc<String>>, usize, usize)>,
+    handler: Box<DebugHandler>,
+}
+
+impl Debugger {
+    /// Creates a new debugger that runs until the first breakpoint.
+    pub fn new(handler: Box<DebugHandler>) -> Debugger {
+        Debugger {
+            breakpoints: vec![],
+            step: Step::Continue,
+            depth: 0,
+            last_line: None,
+            handler: handler,
+        }
+    }
+
+    /// Adds a breakpoint.
+    pub fn add_breakpoint(&mut self, file: Arc<String>, line: usize) {
+        let bp = Breakpoint { file: file, line: line };
+        if !self.breakpoints.contains(&bp) {
+            self.breakpoints.push(bp);
+        }
+    }
+
+    /// Removes a breakpoint, returns `true` if it existed.
+    pub fn remove_breakpoint(&mut self, file: &str, line: usize) -> bool {
+        let n = self.breakpoints.len();
+        self.breakpoints.retain(|bp| !(&**bp.file == file && bp.line == line));
+        n != self.breakpoints.len()
+    }
+
+    /// Returns `true` if the script should pause at a location.
+    pub fn should_pause(&mut self, location: &Location) -> bool {
+        let line = (location.file.clone(), location.line, location.depth);
+        let new_line = self.last_line.as_ref() != Some(&line);
+        self.last_line = Some(line);
+        match self.step {
+            Step::In => return true,
+            Step::Over => if location.depth <= self.depth { return true },
+            Step::Out => if location.depth < self.depth { return true },
+            Step::Continue => {}
+        }
+        if !new_line { return false; }
+        match location.file {
+            None => false,
+            Some(ref file) => self.breakpoints.iter().any(|bp|
+                bp.line == location.line && bp.file == *file),
+        }
+    }
+
+    /// Calls the handler and stores how to continue.
+    pub fn pause(&mut self, rt: &Runtime, location: &Location) {
+        self.step = self.handler.pause(rt, location, &mut self.breakpoints);
+        self.depth = location.depth;
+    }
+}
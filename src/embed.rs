@@ -3,11 +3,12 @@ use std::sync::Arc;
 use Error;
 use Object;
 use Runtime;
+use Symbol;
 use Variable;
 use RustObject;
 
 pub fn obj_field<T: PopVariable>(rt: &Runtime, obj: &Object, name: &str) -> Result<T, String> {
-    let var = try!(obj.get(&Arc::new(name.into()))
+    let var = try!(Symbol::get(name).and_then(|key| obj.get(&key))
         .ok_or_else(|| format!("Object has no key `{}`", name)));
     PopVariable::pop_var(rt, var)
 }
@@ -25,6 +26,18 @@ pub trait PushVariable {
     fn push_var(&self) -> Variable;
 }
 
+/// Implemented by item types of packed arrays.
+///
+/// Use `Arc<Vec<T>>` to pass packed arrays to and from Rust without copying.
+pub trait PackedItem: Sized + Copy {
+    /// Gets the items of a packed array with this item type.
+    fn packed(var: &Variable) -> Option<&Arc<Vec<Self>>>;
+    /// Converts an item of a regular array.
+    fn item(var: &Variable) -> Option<Self>;
+    /// Creates a packed array.
+    fn pack(items: Arc<Vec<Self>>) -> Variable;
+}
+
 /// Implemented by types that can be converted to and from vec4.
 pub trait ConvertVec4: Sized {
     /// Converts vec4 to self.
@@ -230,12 +243,48 @@ impl<T: PopVariable, U: PopVariable, V: PopVariable, W: PopVariable> PopVariable
 
 impl<T: PopVariable> PopVariable for Vec<T> {
     fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+        match *var {
+            Variable::Array(ref arr) => {
+                let mut res = Vec::with_capacity(arr.len());
+                for it in &**arr {
+                    res.push(try!(PopVariable::pop_var(rt, rt.resolve(it))))
+                }
+                Ok(res)
+            }
+            Variable::F64Array(ref arr) => {
+                let mut res = Vec::with_capacity(arr.len());
+                for &it in &**arr {
+                    res.push(try!(PopVariable::pop_var(rt, &Variable::f64(it))))
+                }
+                Ok(res)
+            }
+            Variable::Vec4Array(ref arr) => {
+                let mut res = Vec::with_capacity(arr.len());
+                for &it in &**arr {
+                    res.push(try!(PopVariable::pop_var(rt, &Variable::Vec4(it))))
+                }
+                Ok(res)
+            }
+            _ => Err(rt.expected(var, "array"))
+        }
+    }
+}
+
+impl<T: PackedItem> PopVariable for Arc<Vec<T>> {
+    fn pop_var(rt: &Runtime, var: &Variable) -> Result<Self, String> {
+        if let Some(arr) = T::packed(var) {
+            return Ok(arr.clone());
+        }
         if let &Variable::Array(ref arr) = var {
             let mut res = Vec::with_capacity(arr.len());
             for it in &**arr {
-                res.push(try!(PopVariable::pop_var(rt, rt.resolve(it))))
+                let it = rt.resolve(it);
+                match T::item(it) {
+                    Some(val) => res.push(val),
+                    None => return Err(rt.expected(it, "packed item")),
+                }
             }
-            Ok(res)
+            Ok(Arc::new(res))
         } else {
             Err(rt.expected(var, "array"))
         }
@@ -303,7 +352,7 @@ impl<T: PushVariable> PushVariable for [T; 2] {
         Variable::Array(Arc::new(vec![
             self[0].push_var(),
             self[1].push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -313,7 +362,7 @@ impl<T: PushVariable> PushVariable for [T; 3] {
             self[0].push_var(),
             self[1].push_var(),
             self[2].push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -324,7 +373,7 @@ impl<T: PushVariable> PushVariable for [T; 4] {
             self[1].push_var(),
             self[2].push_var(),
             self[3].push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -333,7 +382,7 @@ impl<T: PushVariable, U: PushVariable> PushVariable for (T, U) {
         Variable::Array(Arc::new(vec![
             self.0.push_var(),
             self.1.push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -343,7 +392,7 @@ impl<T: PushVariable, U: PushVariable, V: PushVariable> PushVariable for (T, U,
             self.0.push_var(),
             self.1.push_var(),
             self.2.push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -355,7 +404,7 @@ PushVariable for (T, U, V, W) {
             self.1.push_var(),
             self.2.push_var(),
             self.3.push_var()
-        ]))
+        ].into()))
     }
 }
 
@@ -365,6 +414,32 @@ impl<T: PushVariable> PushVariable for Vec<T> {
     }
 }
 
+impl<T: PackedItem> PushVariable for Arc<Vec<T>> {
+    fn push_var(&self) -> Variable {
+        T::pack(self.clone())
+    }
+}
+
+impl PackedItem for f64 {
+    fn packed(var: &Variable) -> Option<&Arc<Vec<f64>>> {
+        if let &Variable::F64Array(ref arr) = var { Some(arr) } else { None }
+    }
+    fn item(var: &Variable) -> Option<f64> {
+        if let &Variable::F64(val, _) = var { Some(val) } else { None }
+    }
+    fn pack(items: Arc<Vec<f64>>) -> Variable { Variable::F64Array(items) }
+}
+
+impl PackedItem for [f32; 4] {
+    fn packed(var: &Variable) -> Option<&Arc<Vec<[f32; 4]>>> {
+        if let &Variable::Vec4Array(ref arr) = var { Some(arr) } else { None }
+    }
+    fn item(var: &Variable) -> Option<[f32; 4]> {
+        if let &Variable::Vec4(val) = var { Some(val) } else { None }
+    }
+    fn pack(items: Arc<Vec<[f32; 4]>>) -> Variable { Variable::Vec4Array(items) }
+}
+
 impl ConvertVec4 for [f32; 2] {
     fn from(val: [f32; 4]) -> Self { [val[0], val[1]] }
     fn to(&self) -> [f32; 4] { [self[0], self[1], 0.0, 0.0] }
@@ -0,0 +1,121 @@
+/*
+Fetching data from urls for `load(meta: _, url: _)` and `download(url: _, file: _)`.
+
+A module uses `HttpFetcher` by default.
+Modules created with `load` use the fetcher of the module loading them,
+so a script can be run against local data by setting the fetcher of the main module.
+
+Downloads are written to a sink while they are received, so large files are not kept in memory.
+*/
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Fetches the data at an url.
+pub trait UrlFetcher: Send + Sync {
+    /// Returns the data or an error message.
+    fn fetch(&self, url: &str) -> Result<Vec<u8>, String>;
+
+    /// Writes the data to a sink while it is received.
+    ///
+    /// Returns an error message if fetching or writing fails.
+    /// The default implementation fetches all the data before writing it.
+    fn fetch_to(&self, url: &str, out: &mut dyn Write) -> Result<(), String> {
+        let data = self.fetch(url)?;
+        out.write_all(&data).map_err(|err| err.to_string())
+    }
+}
+
+/// Fetches data over http.
+pub struct HttpFetcher;
+
+impl UrlFetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>, String> {
+        let mut data = vec![];
+        self.fetch_to(url, &mut data)?;
+        Ok(data)
+    }
+
+    fn fetch_to(&self, url: &str, out: &mut dyn Write) -> Result<(), String> {
+        use hyper::client::Client;
+        use hyper::Url;
+        use hyper::status::StatusCode;
+
+        let url_address = Url::parse(url)
+            .map_err(|e| format!("Error parsing url:\n`{}`\n", e))?;
+        let client = Client::new();
+        let request = client.get(url_address);
+        let mut response = request.send()
+            .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
+                                 url, e))?;
+        if response.status == StatusCode::Ok {
+            io::copy(&mut response, out)
+                .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
+                                     url, e))?;
+            Ok(())
+        } else {
+            Err(format!("Error fetching file over http `{}:\n{}\n",
+                        url, response.status))
+        }
+    }
+}
+
+/// Fetches data from memory and local directories, without using the network.
+#[derive(Clone, Default)]
+pub struct LocalFetcher {
+    data: HashMap<String, Vec<u8>>,
+    /// Url prefixes and the directories they are mapped to.
+    dirs: Vec<(String, PathBuf)>,
+}
+
+impl LocalFetcher {
+    /// Creates a new fetcher without any data.
+    pub fn new() -> LocalFetcher {
+        LocalFetcher::default()
+    }
+
+    /// Sets the data of an url.
+    pub fn insert<U: Into<String>, D: Into<Vec<u8>>>(&mut self, url: U, data: D) {
+        self.data.insert(url.into(), data.into());
+    }
+
+    /// Maps urls starting with a prefix to files in a directory.
+    ///
+    /// The rest of the url is used as path relative to the directory.
+    pub fn dir<U: Into<String>, P: Into<PathBuf>>(&mut self, prefix: U, dir: P) {
+        self.dirs.push((prefix.into(), dir.into()));
+    }
+}
+
+impl UrlFetcher for LocalFetcher {
+    fn fetch(&self, url: &str) -> Result<Vec<u8>, String> {
+        let mut data = vec![];
+        self.fetch_to(url, &mut data)?;
+        Ok(data)
+    }
+
+    fn fetch_to(&self, url: &str, out: &mut dyn Write) -> Result<(), String> {
+        if let Some(data) = self.data.get(url) {
+            return out.write_all(data).map_err(|err|
+                format!("Error fetching file `{}`:\n{}\n", url, err));
+        }
+        // Use the longest matching prefix.
+        let dir = self.dirs.iter()
+            .filter(|&(prefix, _)| url.starts_with(&**prefix))
+            .max_by_key(|&(prefix, _)| prefix.len());
+        if let Some((prefix, dir)) = dir {
+            let rel = Path::new(&url[prefix.len()..]);
+            // Do not allow the url to point outside the directory.
+            if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                let path = dir.join(rel);
+                File::open(&path).and_then(|mut f| io::copy(&mut f, out))
+                    .map_err(|err| format!("Error fetching file `{}` from `{}`:\n{}\n",
+                                           url, path.display(), err))?;
+                return Ok(());
+            }
+        }
+        Err(format!("Error fetching file `{}`:\nNo local data for url\n", url))
+    }
+}
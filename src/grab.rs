@@ -178,6 +178,50 @@ pub fn grab_expr(
                 source_range: if_expr.source_range.clone(),
             }))), Flow::Continue))
         },
+        &E::Match(ref match_expr) => {
+            Ok((Grabbed::Expression(E::Match(Box::new(ast::Match {
+                expr: match grab_expr(level, rt, &match_expr.expr, side, module) {
+                    Ok((Grabbed::Expression(x), Flow::Continue)) => x,
+                    x => return x,
+                },
+                arms: {
+                    let mut new_arms = vec![];
+                    for arm in &match_expr.arms {
+                        new_arms.push(ast::MatchArm {
+                            pattern: arm.pattern.clone(),
+                            expr: match grab_expr(level, rt, &arm.expr, side, module) {
+                                Ok((Grabbed::Expression(x), Flow::Continue)) => x,
+                                x => return x,
+                            },
+                            source_range: arm.source_range.clone(),
+                        });
+                    }
+                    new_arms
+                },
+                source_range: match_expr.source_range.clone(),
+            }))), Flow::Continue))
+        },
+        &E::Interpolation(ref interpolation) => {
+            Ok((Grabbed::Expression(E::Interpolation(Box::new(ast::Interpolation {
+                parts: {
+                    let mut new_parts = vec![];
+                    for part in &interpolation.parts {
+                        new_parts.push(match *part {
+                            ast::InterpolationPart::Text(_) => part.clone(),
+                            ast::InterpolationPart::Expression(ref expr) => {
+                                ast::InterpolationPart::Expression(
+                                    match grab_expr(level, rt, expr, side, module) {
+                                        Ok((Grabbed::Expression(x), Flow::Continue)) => x,
+                                        x => return x,
+                                    })
+                            }
+                        });
+                    }
+                    new_parts
+                },
+                source_range: interpolation.source_range.clone(),
+            }))), Flow::Continue))
+        },
         &E::Go(ref go) => {
             let call = &go.call;
             Ok((Grabbed::Expression(E::Go(Box::new(ast::Go {
@@ -453,15 +497,14 @@ fn grab_item(
 ) -> Result<(Grabbed, Flow), String> {
     Ok((Grabbed::Item(ast::Item {
         name: item.name.clone(),
-        stack_id: item.stack_id.clone(),
-        static_stack_id: item.static_stack_id.clone(),
+        slot: item.slot.clone(),
         current: item.current.clone(),
         try: item.try.clone(),
         ids: {
             let mut new_ids = vec![];
             for id in &item.ids {
                 new_ids.push(match id {
-                    &ast::Id::String(_, _) => id.clone(),
+                    &ast::Id::String(_, _, _) => id.clone(),
                     &ast::Id::F64(_, _) => id.clone(),
                     &ast::Id::Expression(ref expr) =>
                         match grab_expr(level, rt, &expr, side, module) {
@@ -505,6 +548,7 @@ fn grab_for_n(
             x => return x,
         },
         label: for_n.label.clone(),
+        par: for_n.par,
         source_range: for_n.source_range.clone()
     }), Flow::Continue))
 }
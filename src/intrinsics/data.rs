@@ -1,5 +1,3 @@
-use std::fs::File;
-use std::io::Read;
 use std::collections::HashSet;
 use std::sync::Arc;
 
@@ -8,15 +6,20 @@ use read_token::{NumberSettings, ReadToken};
 
 use super::io::io_error;
 
+use source_loader::{load_relative, SourceLoader};
+use ObjectMap;
+use Symbol;
 use Variable;
 
 type Strings = HashSet<Arc<String>>;
 
 /// Loads data from a file.
-pub fn load_file(file: &str) -> Result<Variable, String> {
-    let mut data_file = try!(File::open(file).map_err(|err| io_error("open", file, &err)));
-    let mut d = String::new();
-    try!(data_file.read_to_string(&mut d).map_err(|err| io_error("read", file, &err)));
+pub fn load_file(
+    file: &str,
+    loader: &SourceLoader,
+    from: Option<&str>
+) -> Result<Variable, String> {
+    let (_, d) = try!(load_relative(loader, file, from).map_err(|err| io_error("open", file, &err)));
     load_data(&d)
 }
 
@@ -120,9 +123,7 @@ fn object(
     strings: &mut Strings,
     data: &str
 ) -> Result<Variable, String> {
-    use std::collections::HashMap;
-
-    let mut res: HashMap<Arc<String>, Variable> = HashMap::new();
+    let mut res = ObjectMap::new();
     let mut was_comma = false;
     loop {
         opt_w(read);
@@ -137,17 +138,11 @@ fn object(
         }
 
         let (range, _) = read.until_any_or_whitespace(SEPS);
-        let key: Arc<String>;
+        let key: Symbol;
         if range.length == 0 {
             return Err(error(range, "Expected key", data));
         } else {
-            let k = read.raw_string(range.length);
-            // Use reference to existing string to reduce memory.
-            key = if let Some(s) = strings.get(&k) {
-                s.clone()
-            } else {
-                Arc::new(k)
-            };
+            key = Symbol::from(read.raw_string(range.length));
             *read = read.consume(range.length);
         };
 
@@ -192,7 +187,7 @@ fn array(
         res.push(try!(expr(read, strings, data)));
         was_comma = comma(read);
     }
-    Ok(Variable::Array(Arc::new(res)))
+    Ok(Variable::Array(Arc::new(res.into())))
 }
 
 fn link(
@@ -1,36 +1,38 @@
 use std::sync::Arc;
-use std::collections::HashMap;
 
 use super::standard;
 use Lt;
 use Module;
+use ObjectMap;
 use Prelude;
+use Symbol;
 use Variable;
 
 /// Lists all functions available in a module.
 pub fn list_functions(module: &Module) -> Vec<Variable> {
     let mut functions = vec![];
-    let name: Arc<String> = Arc::new("name".into());
-    let arguments: Arc<String> = Arc::new("arguments".into());
-    let returns: Arc<String> = Arc::new("returns".into());
-    let takes: Arc<String> = Arc::new("takes".into());
-    let lifetime: Arc<String> = Arc::new("lifetime".into());
+    let name = Symbol::new("name");
+    let arguments = Symbol::new("arguments");
+    let returns = Symbol::new("returns");
+    let takes = Symbol::new("takes");
+    let lifetime = Symbol::new("lifetime");
     let ret_lifetime: Arc<String> = Arc::new("return".into());
-    let ty: Arc<String> = Arc::new("type".into());
+    let ty = Symbol::new("type");
     let intrinsic: Arc<String> = Arc::new("intrinsic".into());
     let external: Arc<String> = Arc::new("external".into());
     let loaded: Arc<String> = Arc::new("loaded".into());
     let mut intrinsics = Prelude::new();
     standard(&mut intrinsics);
     for (f_name, &f) in &intrinsics.functions {
+        if !module.has_intrinsic(f_name) { continue; }
         let f = &intrinsics.list[f];
-        let mut obj = HashMap::new();
+        let mut obj = ObjectMap::new();
         obj.insert(name.clone(), Variable::Text(f_name.clone()));
         obj.insert(returns.clone(), Variable::Text(Arc::new(f.ret.description())));
         obj.insert(ty.clone(), Variable::Text(intrinsic.clone()));
         let mut args = vec![];
         for (i, lt) in f.lts.iter().enumerate() {
-            let mut obj_arg = HashMap::new();
+            let mut obj_arg = ObjectMap::new();
             obj_arg.insert(name.clone(),
                 Variable::Text(Arc::new(format!("arg{}", i).into())));
             obj_arg.insert(lifetime.clone(), match *lt {
@@ -48,17 +50,17 @@ pub fn list_functions(module: &Module) -> Vec<Variable> {
                 Variable::Text(Arc::new(f.tys[i].description())));
             args.push(Variable::Object(Arc::new(obj_arg)));
         }
-        obj.insert(arguments.clone(), Variable::Array(Arc::new(args)));
+        obj.insert(arguments.clone(), Variable::Array(Arc::new(args.into())));
         functions.push(Variable::Object(Arc::new(obj)));
     }
     for f in &*module.ext_prelude {
-        let mut obj = HashMap::new();
+        let mut obj = ObjectMap::new();
         obj.insert(name.clone(), Variable::Text(f.name.clone()));
         obj.insert(returns.clone(), Variable::Text(Arc::new(f.p.ret.description())));
         obj.insert(ty.clone(), Variable::Text(external.clone()));
         let mut args = vec![];
         for (i, lt) in f.p.lts.iter().enumerate() {
-            let mut obj_arg = HashMap::new();
+            let mut obj_arg = ObjectMap::new();
             obj_arg.insert(name.clone(),
                 Variable::Text(Arc::new(format!("arg{}", i).into())));
             obj_arg.insert(lifetime.clone(), match *lt {
@@ -76,17 +78,17 @@ pub fn list_functions(module: &Module) -> Vec<Variable> {
                 Variable::Text(Arc::new(f.p.tys[i].description())));
             args.push(Variable::Object(Arc::new(obj_arg)));
         }
-        obj.insert(arguments.clone(), Variable::Array(Arc::new(args)));
+        obj.insert(arguments.clone(), Variable::Array(Arc::new(args.into())));
         functions.push(Variable::Object(Arc::new(obj)));
     }
     for f in &module.functions {
-        let mut obj = HashMap::new();
+        let mut obj = ObjectMap::new();
         obj.insert(name.clone(), Variable::Text(f.name.clone()));
         obj.insert(returns.clone(), Variable::Text(Arc::new(f.ret.description())));
         obj.insert(ty.clone(), Variable::Text(loaded.clone()));
         let mut args = vec![];
         for arg in &f.args {
-            let mut obj_arg = HashMap::new();
+            let mut obj_arg = ObjectMap::new();
             obj_arg.insert(name.clone(),
                 Variable::Text(arg.name.clone()));
             obj_arg.insert(lifetime.clone(),
@@ -101,14 +103,14 @@ pub fn list_functions(module: &Module) -> Vec<Variable> {
                 Variable::Text(Arc::new(arg.ty.description())));
             args.push(Variable::Object(Arc::new(obj_arg)));
         }
-        obj.insert(arguments.clone(), Variable::Array(Arc::new(args)));
+        obj.insert(arguments.clone(), Variable::Array(Arc::new(args.into())));
         functions.push(Variable::Object(Arc::new(obj)));
     }
     // Sort by function names.
     functions.sort_by(|a, b|
         match (a, b) {
             (&Variable::Object(ref a), &Variable::Object(ref b)) => {
-                match (&a[&name], &b[&name]) {
+                match (a.get(&name).unwrap(), b.get(&name).unwrap()) {
                     (&Variable::Text(ref a), &Variable::Text(ref b)) => {
                         a.cmp(b)
                     }
@@ -120,3 +122,31 @@ pub fn list_functions(module: &Module) -> Vec<Variable> {
     );
     functions
 }
+
+/// Lists all records declared in a module, sorted by name.
+///
+/// When several records have the same name, the last one is used.
+pub fn list_records(module: &Module) -> Vec<Variable> {
+    let name = Symbol::new("name");
+    let ty = Symbol::new("type");
+    let fields = Symbol::new("fields");
+    let records = module.record_types();
+    let mut list = vec![];
+    for (i, r) in module.records.iter().enumerate() {
+        if module.records[i + 1..].iter().any(|other| other.name == r.name) { continue; }
+        let mut obj = ObjectMap::new();
+        obj.insert(name.clone(), Variable::Text(r.name.clone()));
+        let mut field_list = vec![];
+        for &(ref field, ref field_ty) in &r.fields {
+            let mut obj_field = ObjectMap::new();
+            obj_field.insert(name.clone(), Variable::Text(field.clone()));
+            obj_field.insert(ty.clone(),
+                Variable::Text(Arc::new(field_ty.records(&records).description())));
+            field_list.push(Variable::Object(Arc::new(obj_field)));
+        }
+        obj.insert(fields.clone(), Variable::Array(Arc::new(field_list.into())));
+        list.push((r.name.clone(), Variable::Object(Arc::new(obj))));
+    }
+    list.sort_by(|a, b| a.0.cmp(&b.0));
+    list.into_iter().map(|(_, v)| v).collect()
+}
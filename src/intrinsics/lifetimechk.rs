@@ -67,6 +67,9 @@ fn min_ref(v: &Variable, min: &mut Option<usize>) {
         &Variable::Option(_) => {}
         &Variable::Result(_) => {}
         &Variable::Thread(_) => {}
+        &Variable::Channel(_) => {}
+        &Variable::F64Array(_) => {}
+        &Variable::Vec4Array(_) => {}
         &Variable::Array(ref arr) => {
             for v in arr.iter() {
                 min_ref(v, min);
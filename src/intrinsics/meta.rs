@@ -1,10 +1,13 @@
 use std::sync::Arc;
-use std::io::{self, Read};
+use std::io;
 use std::fs::File;
 use std::error::Error;
 use piston_meta::*;
 use super::io::io_error;
 
+use fetch::UrlFetcher;
+use source_loader::{load_relative, SourceLoader};
+use PersistentVec;
 use Variable;
 
 fn load_metarules_data(meta: &str, s: &str, file: &str, d: &str) -> Result<Vec<Variable>, String> {
@@ -48,87 +51,107 @@ fn load_metarules_data(meta: &str, s: &str, file: &str, d: &str) -> Result<Vec<V
                 data.push(Variable::Text(name.clone()));
             }
         }
-        res.push(Variable::Array(Arc::new(data)));
+        res.push(Variable::Array(Arc::new(data.into())));
     }
     Ok(res)
 }
 
 /// Loads a file using a meta file as syntax.
-pub fn load_meta_file(meta: &str, file: &str) -> Result<Vec<Variable>, String> {
-    let mut syntax_file = try!(File::open(meta).map_err(|err| io_error("open", meta, &err)));
-    let mut s = String::new();
-    try!(syntax_file.read_to_string(&mut s).map_err(|err| io_error("read", meta, &err)));
-    let mut data_file = try!(File::open(file).map_err(|err| io_error("open", file, &err)));
-    let mut d = String::new();
-    try!(data_file.read_to_string(&mut d).map_err(|err| io_error("read", file, &err)));
+pub fn load_meta_file(
+    meta: &str,
+    file: &str,
+    loader: &SourceLoader,
+    from: Option<&str>
+) -> Result<Vec<Variable>, String> {
+    let (_, s) = try!(load_relative(loader, meta, from).map_err(|err| io_error("open", meta, &err)));
+    let (_, d) = try!(load_relative(loader, file, from).map_err(|err| io_error("open", file, &err)));
     load_metarules_data(meta, &s, file, &d)
 }
 
 /// Loads a text file from url.
-fn load_text_file_from_url(url: &str) -> Result<String, String> {
-    use hyper::client::Client;
-    use hyper::{Url};
-    use hyper::status::StatusCode;
-    use std::io::Read;
-
-    let url_address = try!(Url::parse(url)
-        .map_err(|e| format!("Error parsing url:\n`{}`\n", e)));
-    let client = Client::new();
-    let request = client.get(url_address);
-    let mut response = try!(request.send()
+fn load_text_file_from_url(url: &str, fetcher: &UrlFetcher) -> Result<String, String> {
+    let data = try!(fetcher.fetch(url));
+    String::from_utf8(data)
         .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
-                             url, e.to_string())));
-    if response.status == StatusCode::Ok {
-        let mut data = String::new();
-        try!(response.read_to_string(&mut data)
-            .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
-                                 url, e.to_string())));
-        Ok(data)
-    } else {
-        Err(format!("Error fetching file over http `{}:\n{}\n",
-                    url, response.status))
-    }
+                             url, e.to_string()))
 }
 
 /// Loads an url using a meta file as syntax.
-pub fn load_meta_url(meta: &str, url: &str) -> Result<Vec<Variable>, String> {
-    let mut syntax_file = try!(File::open(meta).map_err(|err| io_error("open", meta, &err)));
-    let mut s = String::new();
-    try!(syntax_file.read_to_string(&mut s).map_err(|err| io_error("read", meta, &err)));
-    let d = try!(load_text_file_from_url(url));
+pub fn load_meta_url(
+    meta: &str,
+    url: &str,
+    fetcher: &UrlFetcher,
+    loader: &SourceLoader,
+    from: Option<&str>
+) -> Result<Vec<Variable>, String> {
+    let (_, s) = try!(load_relative(loader, meta, from).map_err(|err| io_error("open", meta, &err)));
+    let d = try!(load_text_file_from_url(url, fetcher));
     load_metarules_data(meta, &s, url, &d)
 }
 
+/// Writes to a file that is created on the first write,
+/// such that a failed download does not replace an existing file.
+///
+/// Keeps the error from writing, to tell it apart from errors from fetching.
+struct FileSink<'a> {
+    path: &'a str,
+    file: Option<File>,
+    error: Option<String>,
+}
+
+impl<'a> FileSink<'a> {
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            self.file = Some(try!(File::create(self.path).map_err(|err| {
+                self.error = Some(format!("Could not create file `{}`:\n{}",
+                                          self.path, err.description()));
+                err
+            })));
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl<'a> io::Write for FileSink<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = try!(self.file()).write(buf);
+        res.map_err(|err| {
+            self.error = Some(io_error("write", self.path, &err));
+            err
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            None => Ok(()),
+            Some(ref mut f) => f.flush(),
+        }
+    }
+}
+
 // Downloads a file from url.
-pub fn download_url_to_file(url: &str, file: &str) -> Result<String, String> {
-    use hyper::client::Client;
-    use hyper::{Url};
-    use hyper::status::StatusCode;
-    use std::io::copy;
-    use std::fs::File;
-
-    let url_address = try!(Url::parse(url)
-        .map_err(|e| format!("Error parsing url:\n`{}`\n", e)));
-    let client = Client::new();
-    let request = client.get(url_address);
-    let mut response = try!(request.send()
-        .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
-                             url, e.to_string())));
-    if response.status == StatusCode::Ok {
-        let mut f = try!(File::create(file).map_err(|err| {
-            format!("Could not create file `{}`:\n{}", file, err.description())
-        }));
-        try!(copy(&mut response, &mut f)
-            .map_err(|e| format!("Error fetching file over http `{}`:\n{}\n",
-                                 url, e.to_string())));
-        Ok(file.into())
-    } else {
-        Err(format!("Error fetching file over http `{}:\n{}\n",
-                    url, response.status))
+pub fn download_url_to_file(url: &str, file: &str, fetcher: &UrlFetcher) -> Result<String, String> {
+    use std::fs::{metadata, remove_file};
+
+    let mut sink = FileSink {path: file, file: None, error: None};
+    let res = fetcher.fetch_to(url, &mut sink);
+    if let Err(err) = res {
+        if sink.file.take().is_some() &&
+           metadata(file).map(|m| m.is_file()).unwrap_or(false) {
+            // Remove the partially downloaded file.
+            let _ = remove_file(file);
+        }
+        return Err(match sink.error {
+            Some(write_err) => write_err,
+            None => err,
+        });
     }
+    // Create the file when there is no data.
+    try!(sink.file().map(|_| ()).map_err(|_| sink.error.take().unwrap()));
+    Ok(file.into())
 }
 
-pub fn json_from_meta_data(data: &Vec<Variable>) -> Result<String, io::Error> {
+pub fn json_from_meta_data(data: &PersistentVec) -> Result<String, io::Error> {
     fn is_start_node(v: &Variable) -> bool {
         if let &Variable::Array(ref arr) = v {
             if let &Variable::Text(ref t) = &arr[2] {
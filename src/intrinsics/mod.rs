@@ -10,7 +10,10 @@ use prelude::{Lt, Prelude, Dfn};
 use FnIndex;
 use Error;
 use Module;
+use Symbol;
 use Variable;
+use Channel;
+use source_loader;
 use Type;
 use TINVOTS;
 
@@ -76,7 +79,7 @@ const LOAD__SOURCE_IMPORTS: usize = 52;
 const CALL: usize = 53;
 const CALL_RET: usize = 54;
 const FUNCTIONS: usize = 55;
-const NONE: usize = 56;
+pub const NONE: usize = 56;
 const SOME: usize = 57;
 const UNWRAP: usize = 58;
 const UNWRAP_ERR: usize = 59;
@@ -107,6 +110,21 @@ const NECK: usize = 83;
 const LOAD_DATA__FILE: usize = 84;
 const FUNCTIONS__MODULE: usize = 85;
 const KEYS: usize = 86;
+const PACK_F64: usize = 87;
+const PACK_VEC4: usize = 88;
+const UNPACK: usize = 89;
+const CHANNEL: usize = 90;
+const SEND: usize = 91;
+const RECV: usize = 92;
+const TRY_RECV: usize = 93;
+const CLOSE: usize = 94;
+const SELECT: usize = 95;
+const IS_DONE: usize = 96;
+const CANCEL: usize = 97;
+const JOIN__THREAD_TIMEOUT: usize = 98;
+const JOIN__THREADS: usize = 99;
+const RECORDS: usize = 100;
+const RECORDS__MODULE: usize = 101;
 
 const TABLE: &'static [(usize, fn(
         &mut Runtime,
@@ -204,6 +222,21 @@ const TABLE: &'static [(usize, fn(
     (LOAD_DATA__FILE, load_data__file),
     (FUNCTIONS__MODULE, functions__module),
     (KEYS, keys),
+    (PACK_F64, pack_f64),
+    (PACK_VEC4, pack_vec4),
+    (UNPACK, unpack),
+    (CHANNEL, channel),
+    (SEND, send),
+    (RECV, recv),
+    (TRY_RECV, try_recv),
+    (CLOSE, close),
+    (SELECT, select),
+    (IS_DONE, is_done),
+    (CANCEL, cancel),
+    (JOIN__THREAD_TIMEOUT, join__thread_timeout),
+    (JOIN__THREADS, join__threads),
+    (RECORDS, records),
+    (RECORDS__MODULE, records__module),
 ];
 
 pub fn standard(f: &mut Prelude) {
@@ -410,6 +443,47 @@ pub fn standard(f: &mut Prelude) {
     sarg(f, "load_data__file", LOAD_DATA__FILE, Type::Text, Type::Result(Box::new(Type::Any)));
     sarg(f, "functions__module", FUNCTIONS__MODULE, Type::Any, Type::Any);
     sarg(f, "keys", KEYS, Type::Object, Type::Array(Box::new(Type::Text)));
+    sarg(f, "pack_f64", PACK_F64, Type::Array(Box::new(Type::F64)),
+         Type::Array(Box::new(Type::F64)));
+    sarg(f, "pack_vec4", PACK_VEC4, Type::Array(Box::new(Type::Vec4)),
+         Type::Array(Box::new(Type::Vec4)));
+    sarg(f, "unpack", UNPACK, Type::array(), Type::array());
+    f.intrinsic(Arc::new("channel".into()), CHANNEL, Dfn {
+        lts: vec![],
+        tys: vec![],
+        ret: Type::channel()
+    });
+    // The type checker checks values of `send` and `recv` against the type of the channel.
+    f.intrinsic(Arc::new("send".into()), SEND, Dfn {
+        lts: vec![Lt::Default; 2],
+        tys: vec![Type::channel(), Type::Any],
+        ret: Type::Void
+    });
+    sarg(f, "recv", RECV, Type::channel(), Type::option());
+    sarg(f, "try_recv", TRY_RECV, Type::channel(), Type::option());
+    sarg(f, "close", CLOSE, Type::channel(), Type::Void);
+    sarg(f, "select", SELECT, Type::Array(Box::new(Type::channel())),
+         Type::Option(Box::new(Type::array())));
+    sarg(f, "is_done", IS_DONE, Type::thread(), Type::Bool);
+    sarg(f, "cancel", CANCEL, Type::thread(), Type::Void);
+    f.intrinsic(Arc::new("join__thread_timeout".into()), JOIN__THREAD_TIMEOUT, Dfn {
+        lts: vec![Lt::Default; 2],
+        tys: vec![Type::thread(), Type::F64],
+        ret: Type::Option(Box::new(Type::Result(Box::new(Type::Any))))
+    });
+    sarg(f, "join__threads", JOIN__THREADS, Type::Array(Box::new(Type::thread())),
+         Type::Array(Box::new(Type::Result(Box::new(Type::Any)))));
+    f.intrinsic(Arc::new("records".into()), RECORDS, Dfn {
+        lts: vec![],
+        tys: vec![],
+        ret: Type::Any
+    });
+    sarg(f, "records__module", RECORDS__MODULE, Type::Any, Type::Any);
+}
+
+/// Returns the file of the calling function, used to resolve relative paths.
+fn caller_file(rt: &Runtime) -> Option<Arc<String>> {
+    rt.call_stack.iter().rev().filter_map(|c| c.file.clone()).next()
 }
 
 pub fn call_standard(
@@ -431,10 +505,29 @@ pub fn call_standard(
                     rt.stack_trace()), rt))
         };
     }
+    let expect = try!(call_standard_pushed(rt, index, call, module, st, lc, cu));
+    Ok((expect, Flow::Continue))
+}
+
+/// Calls an intrinsic when the arguments are already on the stack.
+pub fn call_standard_pushed(
+    rt: &mut Runtime,
+    index: usize,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize,
+) -> Result<Option<Variable>, String> {
     let (ind, f) = TABLE[index];
     debug_assert!(ind == index);
-    let expect = try!((f)(rt, call, module, st, lc, cu));
-    Ok((expect, Flow::Continue))
+    if rt.profiler.is_none() {
+        return (f)(rt, call, module, st, lc, cu);
+    }
+    rt.profiler.as_mut().unwrap().enter(call.name.clone());
+    let res = (f)(rt, call, module, st, lc, cu);
+    rt.profiler.as_mut().unwrap().exit();
+    res
 }
 
 fn x(
@@ -561,7 +654,7 @@ fn why(
         &Variable::Bool(true, Some(ref sec)) => {
             let mut sec = (**sec).clone();
             sec.reverse();
-            sec
+            sec.into()
         }
         &Variable::Bool(true, None) => {
             return Err(module.error(call.args[0].source_range(),
@@ -598,7 +691,7 @@ fn _where(
             } else {
                 let mut sec = (**sec).clone();
                 sec.reverse();
-                sec
+                sec.into()
             }
         }
         &Variable::F64(_, None) => {
@@ -1032,12 +1125,14 @@ fn len(
     };
 
     let v = {
-        let arr = match rt.resolve(&v) {
-            &Variable::Array(ref arr) => arr,
+        let n = match rt.resolve(&v) {
+            &Variable::Array(ref arr) => arr.len(),
+            &Variable::F64Array(ref arr) => arr.len(),
+            &Variable::Vec4Array(ref arr) => arr.len(),
             x => return Err(module.error(call.args[0].source_range(),
                             &rt.expected(x, "array"), rt))
         };
-        Variable::f64(arr.len() as f64)
+        Variable::f64(n as f64)
     };
     rt.pop_fn(call.name.clone());
     Ok(Some(v))
@@ -1056,7 +1151,9 @@ fn push_ref(
     let v = rt.stack.pop().expect(TINVOTS);
 
     if let Variable::Ref(ind) = v {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
+        let ok = if push_packed(&mut rt.stack[ind], &item) {
+            true
+        } else if let Variable::Array(ref mut arr) = rt.stack[ind] {
             Arc::make_mut(arr).push(item);
             true
         } else {
@@ -1076,6 +1173,25 @@ fn push_ref(
     Ok(None)
 }
 
+/// Pushes an item to a packed array when the item has the same type.
+///
+/// Unpacks the array when the item has another type.
+fn push_packed(arr: &mut Variable, item: &Variable) -> bool {
+    match (&mut *arr, item) {
+        (&mut Variable::F64Array(ref mut arr), &Variable::F64(val, None)) => {
+            Arc::make_mut(arr).push(val);
+            return true;
+        }
+        (&mut Variable::Vec4Array(ref mut arr), &Variable::Vec4(val)) => {
+            Arc::make_mut(arr).push(val);
+            return true;
+        }
+        _ => {}
+    }
+    arr.unpack();
+    false
+}
+
 fn push(
     rt: &mut Runtime,
     call: &ast::Call,
@@ -1090,7 +1206,9 @@ fn push(
     let v = rt.stack.pop().expect(TINVOTS);
 
     if let Variable::Ref(ind) = v {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
+        let ok = if push_packed(&mut rt.stack[ind], &item) {
+            true
+        } else if let Variable::Array(ref mut arr) = rt.stack[ind] {
             Arc::make_mut(arr).push(item);
             true
         } else {
@@ -1122,11 +1240,20 @@ fn pop(
     let arr = rt.stack.pop().expect(TINVOTS);
     let mut v: Option<Variable> = None;
     if let Variable::Ref(ind) = arr {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
-            v = Arc::make_mut(arr).pop();
-            true
-        } else {
-            false
+        let ok = match rt.stack[ind] {
+            Variable::Array(ref mut arr) => {
+                v = Arc::make_mut(arr).pop();
+                true
+            }
+            Variable::F64Array(ref mut arr) => {
+                v = Arc::make_mut(arr).pop().map(Variable::f64);
+                true
+            }
+            Variable::Vec4Array(ref mut arr) => {
+                v = Arc::make_mut(arr).pop().map(Variable::Vec4);
+                true
+            }
+            _ => false
         };
         if !ok {
             return Err(module.error(call.args[0].source_range(),
@@ -1159,11 +1286,20 @@ fn reverse(
     rt.push_fn(call.name.clone(), 0, None, st, lc, cu);
     let v = rt.stack.pop().expect(TINVOTS);
     if let Variable::Ref(ind) = v {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
-            Arc::make_mut(arr).reverse();
-            true
-        } else {
-            false
+        let ok = match rt.stack[ind] {
+            Variable::Array(ref mut arr) => {
+                Arc::make_mut(arr).reverse();
+                true
+            }
+            Variable::F64Array(ref mut arr) => {
+                Arc::make_mut(arr).reverse();
+                true
+            }
+            Variable::Vec4Array(ref mut arr) => {
+                Arc::make_mut(arr).reverse();
+                true
+            }
+            _ => false
         };
         if !ok {
             return Err(module.error(call.args[0].source_range(),
@@ -1190,11 +1326,20 @@ fn clear(
     rt.push_fn(call.name.clone(), 0, None, st, lc, cu);
     let v = rt.stack.pop().expect(TINVOTS);
     if let Variable::Ref(ind) = v {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
-            Arc::make_mut(arr).clear();
-            true
-        } else {
-            false
+        let ok = match rt.stack[ind] {
+            Variable::Array(ref mut arr) => {
+                Arc::make_mut(arr).clear();
+                true
+            }
+            Variable::F64Array(ref mut arr) => {
+                Arc::make_mut(arr).clear();
+                true
+            }
+            Variable::Vec4Array(ref mut arr) => {
+                Arc::make_mut(arr).clear();
+                true
+            }
+            _ => false
         };
         if !ok {
             return Err(module.error(call.args[0].source_range(),
@@ -1233,11 +1378,20 @@ fn swap(
     };
     let v = rt.stack.pop().expect(TINVOTS);
     if let Variable::Ref(ind) = v {
-        let ok = if let Variable::Array(ref mut arr) = rt.stack[ind] {
-            Arc::make_mut(arr).swap(i as usize, j as usize);
-            true
-        } else {
-            false
+        let ok = match rt.stack[ind] {
+            Variable::Array(ref mut arr) => {
+                Arc::make_mut(arr).swap(i as usize, j as usize);
+                true
+            }
+            Variable::F64Array(ref mut arr) => {
+                Arc::make_mut(arr).swap(i as usize, j as usize);
+                true
+            }
+            Variable::Vec4Array(ref mut arr) => {
+                Arc::make_mut(arr).swap(i as usize, j as usize);
+                true
+            }
+            _ => false
         };
         if !ok {
             return Err(module.error(call.args[0].source_range(),
@@ -1532,7 +1686,9 @@ fn _typeof(
         &Variable::Return => rt.return_type.clone(),
         &Variable::Bool(_, _) => rt.bool_type.clone(),
         &Variable::Object(_) => rt.object_type.clone(),
-        &Variable::Array(_) => rt.array_type.clone(),
+        &Variable::Array(_) |
+            &Variable::F64Array(_) |
+            &Variable::Vec4Array(_) => rt.array_type.clone(),
         &Variable::Link(_) => rt.link_type.clone(),
         &Variable::Ref(_) => rt.ref_type.clone(),
         &Variable::UnsafeRef(_) => rt.unsafe_ref_type.clone(),
@@ -1540,6 +1696,7 @@ fn _typeof(
         &Variable::Option(_) => rt.option_type.clone(),
         &Variable::Result(_) => rt.result_type.clone(),
         &Variable::Thread(_) => rt.thread_type.clone(),
+        &Variable::Channel(_) => rt.channel_type.clone(),
         &Variable::Closure(_, _) => rt.closure_type.clone(),
     }))
 }
@@ -1578,17 +1735,21 @@ fn load(
     lc: usize,
     cu: usize,
 ) -> Result<Option<Variable>, String> {
-    use load;
+    use load_relative;
 
+    let from = caller_file(rt);
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
     let v = rt.stack.pop().expect(TINVOTS);
     let v = match rt.resolve(&v) {
         &Variable::Text(ref text) => {
             let mut m = Module::new_intrinsics(module.intrinsics.clone());
+            m.capabilities = module.capabilities;
+            m.fetcher = module.fetcher.clone();
+            m.loader = module.loader.clone();
             for f in &module.ext_prelude {
                 m.add(f.name.clone(), f.f, f.p.clone());
             }
-            if let Err(err) = load(text, &mut m) {
+            if let Err(err) = load_relative(text, from.as_ref().map(|f| &***f), &mut m) {
                 Variable::Result(Err(Box::new(Error {
                     message: Variable::Text(Arc::new(
                         format!("{}\n{}\n{}", rt.stack_trace(), err,
@@ -1597,6 +1758,9 @@ fn load(
                     trace: vec![]
                 })))
             } else {
+                if let Some(observer) = rt.observer.clone() {
+                    observer.load(rt, text);
+                }
                 Variable::Result(Ok(Box::new(
                     Variable::RustObject(Arc::new(Mutex::new(Arc::new(m)))))))
             }
@@ -1616,17 +1780,24 @@ fn load__source_imports(
     lc: usize,
     cu: usize,
 ) -> Result<Option<Variable>, String> {
-    use load;
+    use load_relative;
 
+    let from = caller_file(rt);
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
     let modules = rt.stack.pop().expect(TINVOTS);
     let source = rt.stack.pop().expect(TINVOTS);
     let mut new_module = Module::new_intrinsics(module.intrinsics.clone());
+    new_module.capabilities = module.capabilities;
+    new_module.fetcher = module.fetcher.clone();
+    new_module.loader = module.loader.clone();
     for f in &module.ext_prelude {
         new_module.add(f.name.clone(), f.f, f.p.clone());
     }
-    match rt.resolve(&modules) {
-        &Variable::Array(ref array) => {
+    // Packed arrays are unpacked, such that they work like other arrays.
+    let mut modules = rt.resolve(&modules).clone();
+    modules.unpack();
+    match modules {
+        Variable::Array(ref array) => {
             for it in &**array {
                 match rt.resolve(it) {
                     &Variable::RustObject(ref obj) => {
@@ -1644,6 +1815,10 @@ fn load__source_imports(
                                 for f in &m.functions {
                                     new_module.register(f.clone())
                                 }
+                                // Use records from imports.
+                                for r in &m.records {
+                                    new_module.records.push(r.clone())
+                                }
                             }
                             None => return Err(module.error(
                                 call.args[1].source_range(),
@@ -1657,12 +1832,13 @@ fn load__source_imports(
                 }
             }
         }
-        x => return Err(module.error(call.args[1].source_range(),
+        ref x => return Err(module.error(call.args[1].source_range(),
                 &rt.expected(x, "[Module]"), rt))
     }
     let v = match rt.resolve(&source) {
         &Variable::Text(ref text) => {
-            if let Err(err) = load(text, &mut new_module) {
+            if let Err(err) = load_relative(text, from.as_ref().map(|f| &***f),
+                                            &mut new_module) {
                 Variable::Result(Err(Box::new(Error {
                     message: Variable::Text(Arc::new(
                         format!("{}\n{}\n{}", rt.stack_trace(), err,
@@ -1671,6 +1847,9 @@ fn load__source_imports(
                     trace: vec![]
                 })))
             } else {
+                if let Some(observer) = rt.observer.clone() {
+                    observer.load(rt, text);
+                }
                 Variable::Result(Ok(Box::new(
                     Variable::RustObject(Arc::new(
                         Mutex::new(Arc::new(new_module)))))))
@@ -1702,10 +1881,12 @@ fn _call(
         x => return Err(module.error(call.args[1].source_range(),
                         &rt.expected(x, "text"), rt))
     };
-    let args = match rt.resolve(&args) {
-        &Variable::Array(ref arr) => arr.clone(),
-        x => return Err(module.error(call.args[2].source_range(),
-                        &rt.expected(x, "array"), rt))
+    let mut args = rt.resolve(&args).clone();
+    args.unpack();
+    let args = match args {
+        Variable::Array(arr) => arr,
+        ref x => return Err(module.error(call.args[2].source_range(),
+                            &rt.expected(x, "array"), rt))
     };
     let obj = match rt.resolve(&call_module) {
         &Variable::RustObject(ref obj) => obj.clone(),
@@ -1716,7 +1897,6 @@ fn _call(
     match obj.lock().unwrap()
         .downcast_ref::<Arc<Module>>() {
         Some(m) => {
-            use std::cell::Cell;
 
             let f_index = m.find_function(&fn_name, 0);
             match f_index {
@@ -1745,7 +1925,7 @@ fn _call(
             }
             let call = ast::Call {
                 name: fn_name.clone(),
-                f_index: Cell::new(f_index),
+                f_index: ::AtomicFnIndex::new(f_index),
                 args: args.iter().map(|arg|
                     ast::Expression::Variable(
                         call.source_range, arg.clone())).collect(),
@@ -1783,10 +1963,12 @@ fn call_ret(
         x => return Err(module.error(call.args[1].source_range(),
                         &rt.expected(x, "text"), rt))
     };
-    let args = match rt.resolve(&args) {
-        &Variable::Array(ref arr) => arr.clone(),
-        x => return Err(module.error(call.args[2].source_range(),
-                        &rt.expected(x, "array"), rt))
+    let mut args = rt.resolve(&args).clone();
+    args.unpack();
+    let args = match args {
+        Variable::Array(arr) => arr,
+        ref x => return Err(module.error(call.args[2].source_range(),
+                            &rt.expected(x, "array"), rt))
     };
     let obj = match rt.resolve(&call_module) {
         &Variable::RustObject(ref obj) => obj.clone(),
@@ -1797,7 +1979,6 @@ fn call_ret(
     let v = match obj.lock().unwrap()
         .downcast_ref::<Arc<Module>>() {
         Some(m) => {
-            use std::cell::Cell;
 
             let f_index = m.find_function(&fn_name, 0);
             match f_index {
@@ -1826,7 +2007,7 @@ fn call_ret(
             }
             let call = ast::Call {
                 name: fn_name.clone(),
-                f_index: Cell::new(f_index),
+                f_index: ::AtomicFnIndex::new(f_index),
                 args: args.iter().map(|arg|
                     ast::Expression::Variable(
                         call.source_range, arg.clone())).collect(),
@@ -1854,7 +2035,7 @@ fn functions(
 ) -> Result<Option<Variable>, String> {
     // List available functions in scope.
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
-    let v = Variable::Array(Arc::new(functions::list_functions(module)));
+    let v = Variable::Array(Arc::new(functions::list_functions(module).into()));
     rt.pop_fn(call.name.clone());
     Ok(Some(v))
 }
@@ -1883,7 +2064,51 @@ fn functions__module(
             &format!("{}\nExpected `Module`", rt.stack_trace()), rt))
     };
 
-    let v = Variable::Array(Arc::new(functions));
+    let v = Variable::Array(Arc::new(functions.into()));
+    rt.pop_fn(call.name.clone());
+    Ok(Some(v))
+}
+
+fn records(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize,
+) -> Result<Option<Variable>, String> {
+    // List records declared in the module.
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let v = Variable::Array(Arc::new(functions::list_records(module).into()));
+    rt.pop_fn(call.name.clone());
+    Ok(Some(v))
+}
+
+fn records__module(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize,
+) -> Result<Option<Variable>, String> {
+    // List records declared in the module.
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let m = rt.stack.pop().expect(TINVOTS);
+    let m = match rt.resolve(&m) {
+        &Variable::RustObject(ref obj) => obj.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "Module"), rt))
+    };
+
+    let records = match m.lock().unwrap()
+        .downcast_ref::<Arc<Module>>() {
+        Some(m) => functions::list_records(m),
+        None => return Err(module.error(call.args[0].source_range(),
+            &format!("{}\nExpected `Module`", rt.stack_trace()), rt))
+    };
+
+    let v = Variable::Array(Arc::new(records.into()));
     rt.pop_fn(call.name.clone());
     Ok(Some(v))
 }
@@ -2001,6 +2226,13 @@ fn min(
             }
             min
         }
+        &Variable::F64Array(ref arr) => {
+            let mut min: f64 = ::std::f64::NAN;
+            for &val in &**arr {
+                if val < min || min.is_nan() { min = val }
+            }
+            min
+        }
         x => {
             return Err(module.error(call.args[0].source_range(),
                 &rt.expected(x, "array"), rt));
@@ -2030,6 +2262,13 @@ fn max(
             }
             max
         }
+        &Variable::F64Array(ref arr) => {
+            let mut max: f64 = ::std::f64::NAN;
+            for &val in &**arr {
+                if val > max || max.is_nan() { max = val }
+            }
+            max
+        }
         x => {
             return Err(module.error(call.args[0].source_range(),
                 &rt.expected(x, "array"), rt));
@@ -2170,10 +2409,11 @@ fn load__meta_file(
         x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "str"), rt))
     };
-    let res = meta::load_meta_file(&**meta, &**file);
+    let from = caller_file(rt);
+    let res = meta::load_meta_file(&**meta, &**file, &*module.loader, from.as_ref().map(|f| &***f));
     rt.pop_fn(call.name.clone());
     Ok(Some(Variable::Result(match res {
-        Ok(res) => Ok(Box::new(Variable::Array(Arc::new(res)))),
+        Ok(res) => Ok(Box::new(Variable::Array(Arc::new(res.into())))),
         Err(err) => Err(Box::new(Error {
             message: Variable::Text(Arc::new(err)),
             trace: vec![]
@@ -2202,10 +2442,12 @@ fn load__meta_url(
         x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "str"), rt))
     };
-    let res = meta::load_meta_url(&**meta, &**url);
+    let from = caller_file(rt);
+    let res = meta::load_meta_url(&**meta, &**url, &*module.fetcher,
+                                  &*module.loader, from.as_ref().map(|f| &***f));
     rt.pop_fn(call.name.clone());
     Ok(Some(Variable::Result(match res {
-        Ok(res) => Ok(Box::new(Variable::Array(Arc::new(res)))),
+        Ok(res) => Ok(Box::new(Variable::Array(Arc::new(res.into())))),
         Err(err) => Err(Box::new(Error {
             message: Variable::Text(Arc::new(err)),
             trace: vec![]
@@ -2235,7 +2477,10 @@ fn download__url_file(
                         &rt.expected(x, "str"), rt))
     };
 
-    let res = meta::download_url_to_file(&**url, &**file);
+    let from = caller_file(rt);
+    let path = source_loader::write_path(&file, from.as_ref().map(|f| &***f));
+    let res = meta::download_url_to_file(&**url, &path, &*module.fetcher)
+        .map(|_| (*file).clone());
     rt.pop_fn(call.name.clone());
     Ok(Some(Variable::Result(match res {
         Ok(res) => Ok(Box::new(Variable::Text(Arc::new(res)))),
@@ -2272,8 +2517,10 @@ fn save__string_file(
                         &rt.expected(x, "str"), rt))
     };
 
+    let from = caller_file(rt);
+    let path = source_loader::write_path(&file, from.as_ref().map(|f| &***f));
     rt.pop_fn(call.name.clone());
-    Ok(Some(Variable::Result(match File::create(&**file) {
+    Ok(Some(Variable::Result(match File::create(&path) {
         Ok(mut f) => {
             match f.write_all(text.as_bytes()) {
                 Ok(_) => Ok(Box::new(Variable::Text(file))),
@@ -2298,8 +2545,6 @@ fn load_string__file(
     lc: usize,
     cu: usize,
 ) -> Result<Option<Variable>, String> {
-    use std::fs::File;
-    use std::io::Read;
     use std::error::Error as StdError;
 
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
@@ -2310,22 +2555,11 @@ fn load_string__file(
                         &rt.expected(x, "str"), rt))
     };
 
+    let from = caller_file(rt);
     rt.pop_fn(call.name.clone());
-    Ok(Some(Variable::Result(match File::open(&**file) {
-        Ok(mut f) => {
-            let mut s = String::new();
-            match f.read_to_string(&mut s) {
-                Ok(_) => {
-                    Ok(Box::new(Variable::Text(Arc::new(s))))
-                }
-                Err(err) => {
-                    Err(Box::new(Error {
-                        message: Variable::Text(Arc::new(err.description().into())),
-                        trace: vec![]
-                    }))
-                }
-            }
-        }
+    let res = source_loader::load_relative(&*module.loader, &file, from.as_ref().map(|f| &***f));
+    Ok(Some(Variable::Result(match res {
+        Ok((_, s)) => Ok(Box::new(Variable::Text(Arc::new(s)))),
         Err(err) => Err(Box::new(Error {
             message: Variable::Text(Arc::new(err.description().into())),
             trace: vec![]
@@ -2346,34 +2580,185 @@ fn join__thread(
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
     let thread = rt.stack.pop().expect(TINVOTS);
     let handle_res = Thread::invalidate_handle(rt, thread);
-    let v = Variable::Result({
-        match handle_res {
-            Ok(handle) => {
-                match handle.join() {
-                    Ok(res) => match res {
-                        Ok(res) => Ok(Box::new(res)),
-                        Err(err) => Err(Box::new(Error {
-                            message: Variable::Text(Arc::new(err)),
-                            trace: vec![]
-                        }))
-                    },
-                    Err(_err) => Err(Box::new(Error {
-                        message: Variable::Text(Arc::new(
-                            "Thread did not exit successfully".into())),
-                        trace: vec![]
-                    }))
+    let v = match handle_res {
+        Ok(handle) => thread_result(rt.join_thread(&handle)),
+        Err(err) => {
+            Variable::Result(Err(Box::new(Error {
+                message: Variable::Text(Arc::new(err)),
+                trace: vec![]
+            })))
+        }
+    };
+    if let Some(observer) = rt.observer.clone() {
+        observer.join(rt, &v);
+    }
+    rt.pop_fn(call.name.clone());
+    Ok(Some(v))
+}
+
+/// Converts the result of joining a thread into a Dyon result.
+fn thread_result(res: ::std::thread::Result<Result<Variable, String>>) -> Variable {
+    Variable::Result(match res {
+        Ok(Ok(res)) => Ok(Box::new(res)),
+        Ok(Err(err)) => Err(Box::new(Error {
+            message: Variable::Text(Arc::new(err)),
+            trace: vec![]
+        })),
+        Err(_err) => Err(Box::new(Error {
+            message: Variable::Text(Arc::new(
+                "Thread did not exit successfully".into())),
+            trace: vec![]
+        }))
+    })
+}
+
+/// Returns an error result for a thread that has been joined.
+fn invalidated_thread() -> Variable {
+    Variable::Result(Err(Box::new(Error {
+        message: Variable::Text(Arc::new("The Thread has already been invalidated".into())),
+        trace: vec![]
+    })))
+}
+
+fn join__thread_timeout(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize,
+) -> Result<Option<Variable>, String> {
+    use std::time::Duration;
+
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let timeout = rt.stack.pop().expect(TINVOTS);
+    let timeout = match rt.resolve(&timeout) {
+        // Fails for NaN.
+        &Variable::F64(v, _) if v >= 0.0 => v,
+        &Variable::F64(v, _) => return Err(module.error(call.args[1].source_range(),
+            &format!("{}\nExpected a non-negative timeout, found `{}`",
+                rt.stack_trace(), v), rt)),
+        x => return Err(module.error(call.args[1].source_range(),
+                        &rt.expected(x, "number"), rt))
+    };
+    let timeout = if timeout >= ::std::u64::MAX as f64 {
+        // Too large to represent, so the join waits without a deadline.
+        Duration::new(::std::u64::MAX, 0)
+    } else {
+        Duration::new(timeout as u64, (timeout.fract() * 1.0e9) as u32)
+    };
+    let thread = rt.stack.pop().expect(TINVOTS);
+    let handle = match rt.resolve(&thread) {
+        &Variable::Thread(ref thread) => thread.handle.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "thread"), rt))
+    };
+    let v = match handle {
+        None => Some(invalidated_thread()),
+        Some(handle) => try!(rt.join_thread_timeout(&handle.lock().unwrap(), timeout)
+            .map_err(|err| module.error(call.source_range,
+                &format!("{}\n{}", rt.stack_trace(), err), rt)))
+            .map(thread_result),
+    };
+    if let Some(ref v) = v {
+        if let Some(observer) = rt.observer.clone() {
+            observer.join(rt, v);
+        }
+    }
+    rt.pop_fn(call.name.clone());
+    Ok(Some(Variable::Option(v.map(Box::new))))
+}
+
+fn join__threads(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize,
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let var = rt.stack.pop().expect(TINVOTS);
+    let mut arr = rt.resolve(&var).clone();
+    arr.unpack();
+    let mut handles = vec![];
+    match arr {
+        Variable::Array(ref arr) => {
+            for it in arr.iter() {
+                match rt.resolve(it) {
+                    &Variable::Thread(ref thread) => handles.push(thread.handle.clone()),
+                    x => return Err(module.error(call.args[0].source_range(),
+                                    &rt.expected(x, "thread"), rt))
                 }
             }
-            Err(err) => {
-                Err(Box::new(Error {
-                    message: Variable::Text(Arc::new(err)),
-                    trace: vec![]
-                }))
+        }
+        ref x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "array"), rt))
+    }
+    let mut res = Vec::with_capacity(handles.len());
+    for handle in &handles {
+        let v = match *handle {
+            None => invalidated_thread(),
+            Some(ref handle) => thread_result(rt.join_thread(&handle.lock().unwrap())),
+        };
+        if let Some(observer) = rt.observer.clone() {
+            observer.join(rt, &v);
+        }
+        res.push(v);
+    }
+    // Invalidate the handles of the joined threads, like `join(thread: _)`.
+    if let Variable::Ref(ind) = var {
+        if let Variable::Array(ref mut arr) = rt.stack[ind] {
+            let arr = Arc::make_mut(arr);
+            for i in 0..arr.len() {
+                if let Some(&mut Variable::Thread(ref mut thread)) = arr.get_mut(i) {
+                    thread.handle = None;
+                }
             }
         }
-    });
+    }
     rt.pop_fn(call.name.clone());
-    Ok(Some(v))
+    Ok(Some(Variable::Array(Arc::new(res.into()))))
+}
+
+fn is_done(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize,
+) -> Result<Option<Variable>, String> {
+    let thread = rt.stack.pop().expect(TINVOTS);
+    let v = match rt.resolve(&thread) {
+        &Variable::Thread(ref thread) => match thread.handle {
+            // The thread has been joined.
+            None => true,
+            Some(ref handle) => try!(rt.is_thread_done(&handle.lock().unwrap())
+                .map_err(|err| module.error(call.source_range,
+                    &format!("{}\n{}", rt.stack_trace(), err), rt))),
+        },
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "thread"), rt))
+    };
+    Ok(Some(Variable::bool(v)))
+}
+
+fn cancel(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize,
+) -> Result<Option<Variable>, String> {
+    let thread = rt.stack.pop().expect(TINVOTS);
+    match rt.resolve(&thread) {
+        &Variable::Thread(ref thread) => thread.interrupt.interrupt(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "thread"), rt))
+    }
+    Ok(None)
 }
 
 fn load_data__file(
@@ -2391,7 +2776,8 @@ fn load_data__file(
         x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "string"), rt))
     };
-    let res = match data::load_file(&file) {
+    let from = caller_file(rt);
+    let res = match data::load_file(&file, &*module.loader, from.as_ref().map(|f| &***f)) {
         Ok(data) => Ok(Box::new(data)),
         Err(err) => Err(Box::new(super::Error {
             message: Variable::Text(Arc::new(format!(
@@ -2426,7 +2812,9 @@ fn save__data_file(
     };
     let data = rt.stack.pop().expect(TINVOTS);
 
-    let mut f = match File::create(&**file) {
+    let from = caller_file(rt);
+    let path = source_loader::write_path(&file, from.as_ref().map(|f| &***f));
+    let mut f = match File::create(&path) {
         Ok(f) => BufWriter::new(f),
         Err(err) => {
             return Err(module.error(call.args[0].source_range(),
@@ -2461,15 +2849,17 @@ fn json_from_meta_data(
 
     rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
     let meta_data = rt.stack.pop().expect(TINVOTS);
-    let json = match rt.resolve(&meta_data) {
-        &Variable::Array(ref arr) => {
+    let mut meta_data = rt.resolve(&meta_data).clone();
+    meta_data.unpack();
+    let json = match meta_data {
+        Variable::Array(ref arr) => {
             try!(meta::json_from_meta_data(arr).map_err(|err| {
                 format!("{}\nError when generating JSON:\n{}",
                         rt.stack_trace(),
                         err.description())
             }))
         }
-        x => return Err(module.error(call.args[0].source_range(),
+        ref x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "array"), rt))
     };
     rt.pop_fn(call.name.clone());
@@ -2493,7 +2883,7 @@ fn has(
     };
     let obj = rt.stack.pop().expect(TINVOTS);
     let res = match rt.resolve(&obj) {
-        &Variable::Object(ref obj) => obj.contains_key(&key),
+        &Variable::Object(ref obj) => Symbol::get(&key).map_or(false, |key| obj.contains_key(&key)),
         x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "object"), rt))
     };
@@ -2513,7 +2903,7 @@ fn keys(
     let obj = rt.stack.pop().expect(TINVOTS);
     let res = Variable::Array(Arc::new(match rt.resolve(&obj) {
         &Variable::Object(ref obj) => {
-            obj.keys().map(|k| Variable::Text(k.clone())).collect()
+            obj.keys().map(|k| Variable::Text(k.text().clone())).collect()
         }
         x => return Err(module.error(call.args[0].source_range(),
                         &rt.expected(x, "object"), rt))
@@ -2522,6 +2912,224 @@ fn keys(
     Ok(Some(res))
 }
 
+fn pack_f64(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let arr = rt.stack.pop().expect(TINVOTS);
+    let res = match rt.resolve(&arr) {
+        &Variable::Array(ref arr) => {
+            let mut items = Vec::with_capacity(arr.len());
+            for it in &**arr {
+                match rt.resolve(it) {
+                    &Variable::F64(val, _) => items.push(val),
+                    x => return Err(module.error(call.args[0].source_range(),
+                                    &rt.expected(x, "number"), rt))
+                }
+            }
+            Variable::F64Array(Arc::new(items))
+        }
+        x @ &Variable::F64Array(_) => x.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "array"), rt))
+    };
+    rt.pop_fn(call.name.clone());
+    Ok(Some(res))
+}
+
+fn pack_vec4(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let arr = rt.stack.pop().expect(TINVOTS);
+    let res = match rt.resolve(&arr) {
+        &Variable::Array(ref arr) => {
+            let mut items = Vec::with_capacity(arr.len());
+            for it in &**arr {
+                match rt.resolve(it) {
+                    &Variable::Vec4(val) => items.push(val),
+                    x => return Err(module.error(call.args[0].source_range(),
+                                    &rt.expected(x, "vec4"), rt))
+                }
+            }
+            Variable::Vec4Array(Arc::new(items))
+        }
+        x @ &Variable::Vec4Array(_) => x.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "array"), rt))
+    };
+    rt.pop_fn(call.name.clone());
+    Ok(Some(res))
+}
+
+fn unpack(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let arr = rt.stack.pop().expect(TINVOTS);
+    let mut res = match rt.resolve(&arr) {
+        x @ &Variable::Array(_) |
+        x @ &Variable::F64Array(_) |
+        x @ &Variable::Vec4Array(_) => x.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "array"), rt))
+    };
+    res.unpack();
+    rt.pop_fn(call.name.clone());
+    Ok(Some(res))
+}
+
+fn channel(
+    _rt: &mut Runtime,
+    _call: &ast::Call,
+    _module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize
+) -> Result<Option<Variable>, String> {
+    Ok(Some(Variable::Channel(Channel::new())))
+}
+
+fn send(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize
+) -> Result<Option<Variable>, String> {
+    let val = rt.stack.pop().expect(TINVOTS);
+    // Deep clone, since the value might be received by another thread.
+    let val = rt.resolve(&val).deep_clone(&rt.stack);
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let ch = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    if ch.send(val).is_err() {
+        return Err(module.error(call.source_range,
+            &format!("{}\nCan not send to a closed channel", rt.stack_trace()), rt));
+    }
+    Ok(None)
+}
+
+fn recv(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let ch = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.clone(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    let v = match rt.recv_channel(&[ch]) {
+        Ok(Some((_, val))) => Variable::Option(Some(Box::new(val))),
+        Ok(None) => Variable::Option(None),
+        Err(err) => return Err(module.error(call.source_range,
+                        &format!("{}\n{}", rt.stack_trace(), err), rt))
+    };
+    rt.pop_fn(call.name.clone());
+    Ok(Some(v))
+}
+
+fn try_recv(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize
+) -> Result<Option<Variable>, String> {
+    use channel::TryRecv;
+
+    let ch = rt.stack.pop().expect(TINVOTS);
+    let v = match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => match ch.try_recv() {
+            TryRecv::Item(val) => Variable::Option(Some(Box::new(val))),
+            TryRecv::Empty | TryRecv::Closed => Variable::Option(None),
+        },
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    };
+    Ok(Some(v))
+}
+
+fn close(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    _st: usize,
+    _lc: usize,
+    _cu: usize
+) -> Result<Option<Variable>, String> {
+    let ch = rt.stack.pop().expect(TINVOTS);
+    match rt.resolve(&ch) {
+        &Variable::Channel(ref ch) => ch.close(),
+        x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "channel"), rt))
+    }
+    Ok(None)
+}
+
+fn select(
+    rt: &mut Runtime,
+    call: &ast::Call,
+    module: &Arc<Module>,
+    st: usize,
+    lc: usize,
+    cu: usize
+) -> Result<Option<Variable>, String> {
+    rt.push_fn(call.name.clone(), 0, None, st + 1, lc, cu);
+    let arr = rt.stack.pop().expect(TINVOTS);
+    let mut arr = rt.resolve(&arr).clone();
+    arr.unpack();
+    let mut channels = vec![];
+    match arr {
+        Variable::Array(ref arr) => {
+            for it in arr.iter() {
+                match rt.resolve(it) {
+                    &Variable::Channel(ref ch) => channels.push(ch.clone()),
+                    x => return Err(module.error(call.args[0].source_range(),
+                                    &rt.expected(x, "channel"), rt))
+                }
+            }
+        }
+        ref x => return Err(module.error(call.args[0].source_range(),
+                        &rt.expected(x, "array"), rt))
+    }
+    let v = match rt.recv_channel(&channels) {
+        Ok(Some((i, val))) => Variable::Option(Some(Box::new(Variable::Array(Arc::new(
+            vec![Variable::f64(i as f64), val].into()))))),
+        Ok(None) => Variable::Option(None),
+        Err(err) => return Err(module.error(call.source_range,
+                        &format!("{}\n{}", rt.stack_trace(), err), rt))
+    };
+    rt.pop_fn(call.name.clone());
+    Ok(Some(v))
+}
+
 fn chars(
     rt: &mut Runtime,
     call: &ast::Call,
@@ -2545,7 +3153,7 @@ fn chars(
         })
         .collect::<Vec<_>>();
     rt.pop_fn(call.name.clone());
-    Ok(Some(Variable::Array(Arc::new(res))))
+    Ok(Some(Variable::Array(Arc::new(res.into()))))
 }
 
 fn now(
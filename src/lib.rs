@@ -10,8 +10,8 @@ extern crate lazy_static;
 
 use std::any::Any;
 use std::fmt;
-use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::HashMap;
 use range::Range;
 
@@ -26,20 +26,40 @@ pub mod link;
 pub mod macros;
 pub mod vec4;
 pub mod write;
+pub mod bytecode;
+pub mod debugger;
+pub mod profiler;
+pub mod coverage;
+pub mod observer;
+pub mod reload;
+pub mod snapshot;
+pub mod symbol;
+pub mod object;
+pub mod array;
+pub mod scheduler;
+pub mod channel;
+pub mod fetch;
+pub mod source_loader;
 
 mod grab;
 
-pub use runtime::Runtime;
-pub use prelude::{Lt, Prelude, Dfn};
+pub use runtime::{InterruptHandle, Runtime};
+pub use prelude::{Capabilities, Lt, Prelude, Dfn};
 pub use ty::Type;
 pub use link::Link;
 pub use vec4::Vec4;
+pub use symbol::Symbol;
+pub use object::ObjectMap;
+pub use array::PersistentVec;
+pub use channel::Channel;
+pub use fetch::{HttpFetcher, LocalFetcher, UrlFetcher};
+pub use source_loader::{FileLoader, MemoryLoader, SourceLoader};
 
 /// A common error message when there is no value on the stack.
 pub const TINVOTS: &'static str = "There is no value on the stack";
 
-pub type Array = Arc<Vec<Variable>>;
-pub type Object = Arc<HashMap<Arc<String>, Variable>>;
+pub type Array = Arc<PersistentVec>;
+pub type Object = Arc<ObjectMap>;
 pub type RustObject = Arc<Mutex<Any>>;
 
 #[derive(Debug, Clone)]
@@ -52,13 +72,16 @@ pub struct Error {
 
 #[derive(Clone)]
 pub struct Thread {
-    pub handle: Option<Arc<Mutex<JoinHandle<Result<Variable, String>>>>>,
+    pub handle: Option<Arc<Mutex<scheduler::JoinHandle>>>,
+    /// Cancels the thread.
+    pub interrupt: InterruptHandle,
 }
 
 impl Thread {
-    pub fn new(handle: JoinHandle<Result<Variable, String>>) -> Thread {
+    pub fn new(handle: scheduler::JoinHandle, interrupt: InterruptHandle) -> Thread {
         Thread {
-            handle: Some(Arc::new(Mutex::new(handle)))
+            handle: Some(Arc::new(Mutex::new(handle))),
+            interrupt: interrupt,
         }
     }
 
@@ -67,14 +90,15 @@ impl Thread {
     pub fn invalidate_handle(
         rt: &mut Runtime,
         var: Variable
-    ) -> Result<JoinHandle<Result<Variable, String>>, String> {
+    ) -> Result<scheduler::JoinHandle, String> {
         use std::error::Error;
 
         let thread = match var {
             Variable::Ref(ind) => {
                 use std::mem::replace;
 
-                match replace(&mut rt.stack[ind], Variable::Thread(Thread { handle: None })) {
+                let invalid = Thread { handle: None, interrupt: InterruptHandle::new() };
+                match replace(&mut rt.stack[ind], Variable::Thread(invalid)) {
                     Variable::Thread(th) => th,
                     x => return Err(rt.expected(&x, "Thread"))
                 }
@@ -125,6 +149,10 @@ pub enum Variable {
     Vec4([f32; 4]),
     Text(Arc<String>),
     Array(Array),
+    /// Array with only `f64` items, stored without the enum.
+    F64Array(Arc<Vec<f64>>),
+    /// Array with only `vec4` items, stored without the enum.
+    Vec4Array(Arc<Vec<[f32; 4]>>),
     Object(Object),
     Link(Box<Link>),
     UnsafeRef(UnsafeRef),
@@ -132,6 +160,8 @@ pub enum Variable {
     Option(Option<Box<Variable>>),
     Result(Result<Box<Variable>, Box<Error>>),
     Thread(Thread),
+    /// Sends values between threads.
+    Channel(Channel),
     // Stores closure AST, relative function index.
     Closure(Arc<ast::Closure>, Box<ClosureEnvironment>),
 }
@@ -150,6 +180,18 @@ impl Variable {
         Variable::Bool(val, None)
     }
 
+    /// Converts a packed array into a regular array.
+    ///
+    /// Does nothing if the variable is not a packed array.
+    pub fn unpack(&mut self) {
+        let arr = match *self {
+            Variable::F64Array(ref arr) => arr.iter().map(|&x| Variable::f64(x)).collect(),
+            Variable::Vec4Array(ref arr) => arr.iter().map(|&x| Variable::Vec4(x)).collect(),
+            _ => return,
+        };
+        *self = Variable::Array(Arc::new(arr));
+    }
+
     fn deep_clone(&self, stack: &Vec<Variable>) -> Variable {
         use Variable::*;
 
@@ -161,18 +203,16 @@ impl Variable {
             Text(_) => self.clone(),
             Object(ref obj) => {
                 let mut res = obj.clone();
-                for (_, val) in Arc::make_mut(&mut res) {
+                for val in Arc::make_mut(&mut res).values_mut() {
                     *val = val.deep_clone(stack);
                 }
                 Object(res)
             }
             Array(ref arr) => {
-                let mut res = arr.clone();
-                for it in Arc::make_mut(&mut res) {
-                    *it = it.deep_clone(stack);
-                }
-                Array(res)
+                Array(Arc::new(arr.iter().map(|it| it.deep_clone(stack)).collect()))
             }
+            F64Array(_) => self.clone(),
+            Vec4Array(_) => self.clone(),
             Link(_) => self.clone(),
             Ref(ind) => {
                 stack[ind].deep_clone(stack)
@@ -187,6 +227,7 @@ impl Variable {
             // `err(x)` always uses deep clone, so it does not contain references.
             Result(Err(ref err)) => Result(Err(err.clone())),
             Thread(_) => self.clone(),
+            Channel(_) => self.clone(),
             Closure(_, _) => self.clone(),
         }
     }
@@ -201,6 +242,23 @@ impl PartialEq for Variable {
             (&Variable::Text(ref a), &Variable::Text(ref b)) => a == b,
             (&Variable::Object(ref a), &Variable::Object(ref b)) => a == b,
             (&Variable::Array(ref a), &Variable::Array(ref b)) => a == b,
+            (&Variable::F64Array(ref a), &Variable::F64Array(ref b)) => a == b,
+            (&Variable::Vec4Array(ref a), &Variable::Vec4Array(ref b)) => a == b,
+            (&Variable::Array(ref a), &Variable::F64Array(ref b)) |
+            (&Variable::F64Array(ref b), &Variable::Array(ref a)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, &b)| match *a {
+                    Variable::F64(a, _) => a == b,
+                    _ => false,
+                })
+            }
+            (&Variable::Array(ref a), &Variable::Vec4Array(ref b)) |
+            (&Variable::Vec4Array(ref b), &Variable::Array(ref a)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, &b)| match *a {
+                    Variable::Vec4(a) => a == b,
+                    _ => false,
+                })
+            }
+            (&Variable::Channel(ref a), &Variable::Channel(ref b)) => a == b,
             (&Variable::Ref(_), _) => false,
             (&Variable::UnsafeRef(_), _) => false,
             (&Variable::RustObject(_), _) => false,
@@ -235,6 +293,87 @@ impl fmt::Debug for FnExternalRef {
     }
 }
 
+/// Stores the function index of a call, which is set when loading.
+///
+/// Uses atomics, because threads share the AST.
+/// External functions are stored in their own field,
+/// such that a function pointer is never read from an index.
+pub struct AtomicFnIndex {
+    kind: AtomicUsize,
+    index: AtomicUsize,
+    external: AtomicUsize,
+}
+
+const FN_INDEX_NONE: usize = 0;
+const FN_INDEX_INTRINSIC: usize = 1;
+const FN_INDEX_LOADED: usize = 2;
+const FN_INDEX_EXTERNAL_VOID: usize = 3;
+const FN_INDEX_EXTERNAL_RETURN: usize = 4;
+
+impl AtomicFnIndex {
+    pub fn new(f_index: FnIndex) -> AtomicFnIndex {
+        let res = AtomicFnIndex {
+            kind: AtomicUsize::new(FN_INDEX_NONE),
+            index: AtomicUsize::new(0),
+            external: AtomicUsize::new(0),
+        };
+        res.set(f_index);
+        res
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> FnIndex {
+        match self.kind.load(Ordering::Acquire) {
+            FN_INDEX_INTRINSIC => FnIndex::Intrinsic(self.index.load(Ordering::Relaxed)),
+            FN_INDEX_LOADED => FnIndex::Loaded(self.index.load(Ordering::Relaxed) as isize),
+            FN_INDEX_EXTERNAL_VOID => FnIndex::ExternalVoid(self.external()),
+            FN_INDEX_EXTERNAL_RETURN => FnIndex::ExternalReturn(self.external()),
+            _ => FnIndex::None,
+        }
+    }
+
+    fn external(&self) -> FnExternalRef {
+        let f = self.external.load(Ordering::Relaxed);
+        // The field only holds function pointers, stored before the kind.
+        FnExternalRef(unsafe { ::std::mem::transmute(f) })
+    }
+
+    pub fn set(&self, f_index: FnIndex) {
+        let kind = match f_index {
+            FnIndex::None => FN_INDEX_NONE,
+            FnIndex::Intrinsic(i) => {
+                self.index.store(i, Ordering::Relaxed);
+                FN_INDEX_INTRINSIC
+            }
+            FnIndex::Loaded(i) => {
+                self.index.store(i as usize, Ordering::Relaxed);
+                FN_INDEX_LOADED
+            }
+            FnIndex::ExternalVoid(f) => {
+                self.external.store(f.0 as usize, Ordering::Relaxed);
+                FN_INDEX_EXTERNAL_VOID
+            }
+            FnIndex::ExternalReturn(f) => {
+                self.external.store(f.0 as usize, Ordering::Relaxed);
+                FN_INDEX_EXTERNAL_RETURN
+            }
+        };
+        self.kind.store(kind, Ordering::Release);
+    }
+}
+
+impl Clone for AtomicFnIndex {
+    fn clone(&self) -> AtomicFnIndex {
+        AtomicFnIndex::new(self.get())
+    }
+}
+
+impl fmt::Debug for AtomicFnIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
 pub struct FnExternal {
     pub name: Arc<String>,
     pub f: fn(&mut Runtime) -> Result<(), String>,
@@ -256,6 +395,22 @@ pub struct Module {
     pub functions: Vec<ast::Function>,
     pub ext_prelude: Vec<FnExternal>,
     pub intrinsics: Arc<HashMap<Arc<String>, usize>>,
+    /// Groups of intrinsics that are allowed, inherited by modules created with `load`.
+    ///
+    /// Checked when resolving intrinsics, so it can be changed after creating the module.
+    pub capabilities: Capabilities,
+    /// Fetches urls for `load(meta: _, url: _)` and `download(url: _, file: _)`,
+    /// inherited by modules created with `load`.
+    pub fetcher: Arc<UrlFetcher>,
+    /// Reads files for `load` and the file intrinsics,
+    /// inherited by modules created with `load`.
+    pub loader: Arc<SourceLoader>,
+    /// Warnings from loading sources, for example non-exhaustive `match` expressions.
+    pub warnings: Vec<String>,
+    /// Records declared in loaded sources.
+    ///
+    /// Listed by the `records` intrinsic, separately from `functions`.
+    pub records: Vec<ast::Record>,
 }
 
 impl Module {
@@ -268,13 +423,41 @@ impl Module {
             functions: vec![],
             ext_prelude: vec![],
             intrinsics: intrinsics,
+            capabilities: Capabilities::all(),
+            fetcher: Arc::new(HttpFetcher),
+            loader: Arc::new(FileLoader),
+            warnings: vec![],
+            records: vec![],
         }
     }
 
+    /// Creates a new module with the intrinsics allowed by capabilities.
+    pub fn new_capabilities(capabilities: Capabilities) -> Module {
+        let mut module = Module::new();
+        module.capabilities = capabilities;
+        module
+    }
+
+    /// Returns `true` if the intrinsic is registered and allowed by the capabilities.
+    pub fn has_intrinsic(&self, name: &Arc<String>) -> bool {
+        self.intrinsics.contains_key(name) && self.capabilities.allows(name)
+    }
+
     pub fn register(&mut self, function: ast::Function) {
         self.functions.push(function);
     }
 
+    /// Returns the types of records by name.
+    ///
+    /// When several records have the same name, the last one is used.
+    pub fn record_types(&self) -> HashMap<Arc<String>, Type> {
+        let mut records = HashMap::new();
+        for record in &self.records {
+            records.insert(record.name.clone(), record.ty());
+        }
+        records
+    }
+
     /// Find function relative another function index.
     pub fn find_function(&self, name: &Arc<String>, relative: usize) -> FnIndex {
         for (i, f) in self.functions.iter().enumerate().rev() {
@@ -292,8 +475,8 @@ impl Module {
             }
         }
         match self.intrinsics.get(name) {
-            None => FnIndex::None,
-            Some(&ind) => FnIndex::Intrinsic(ind)
+            Some(&ind) if self.capabilities.allows(name) => FnIndex::Intrinsic(ind),
+            _ => FnIndex::None,
         }
     }
 
@@ -349,16 +532,16 @@ pub fn run_str(source: &str, d: Arc<String>) -> Result<(), String> {
     Ok(())
 }
 
-/// Loads source from file.
+/// Loads source from file, using the loader of the module.
 pub fn load(source: &str, module: &mut Module) -> Result<(), String> {
-    use std::fs::File;
-    use std::io::Read;
-
-    let mut data_file = try!(File::open(source).map_err(|err|
-        format!("Could not open `{}`, {}", source, err)));
-    let mut data = Arc::new(String::new());
-    data_file.read_to_string(Arc::make_mut(&mut data)).unwrap();
-    load_str(source, data, module)
+    load_relative(source, None, module)
+}
+
+/// Loads source from file, looking next to the file `from` first.
+fn load_relative(source: &str, from: Option<&str>, module: &mut Module) -> Result<(), String> {
+    let (path, data) = try!(source_loader::load_relative(&*module.loader, source, from)
+        .map_err(|err| format!("Could not open `{}`, {}", source, err)));
+    load_str(&path, Arc::new(data), module)
 }
 
 /// Loads a source from string.
@@ -391,7 +574,9 @@ pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(),
     // Do lifetime checking in parallel directly on meta data.
     let handle = thread::spawn(move || {
         let check_data = check_data;
-        lifetime::check(&check_data, &prelude2)
+        let mut warnings = vec![];
+        let res = lifetime::check_with_warnings(&check_data, &prelude2, &mut warnings);
+        (res, warnings)
     });
 
     // Convert to AST.
@@ -399,7 +584,21 @@ pub fn load_str(source: &str, d: Arc<String>, module: &mut Module) -> Result<(),
     let conv_res = ast::convert(Arc::new(source.into()), d.clone(), &data, &mut ignored, module);
 
     // Check that lifetime checking succeeded.
-    match handle.join().unwrap() {
+    let (res, warnings) = handle.join().unwrap();
+    for warning in warnings {
+        use std::io::Write;
+        use piston_meta::ParseErrorHandler;
+
+        let (range, msg) = warning.decouple();
+
+        let mut buf: Vec<u8> = vec![];
+        writeln!(&mut buf, "In `{}`:\n", source).unwrap();
+        ParseErrorHandler::new(&d)
+            .write_msg(&mut buf, range, &msg)
+            .unwrap();
+        module.warnings.push(String::from_utf8(buf).unwrap());
+    }
+    match res {
         Ok(refined_rets) => {
             for (name, ty) in &refined_rets {
                 if let FnIndex::Loaded(f_index) = module.find_function(name, 0) {
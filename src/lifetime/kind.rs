@@ -80,6 +80,16 @@ pub enum Kind {
     ClArg,
     ClRet,
     Grab,
+    Match,
+    Arm,
+    ArmSome,
+    ArmNone,
+    ArmOk,
+    ArmErr,
+    Pattern,
+    Interpolation,
+    Record,
+    Field,
 }
 
 impl Kind {
@@ -166,6 +176,12 @@ impl Kind {
             "cl_arg" => Kind::ClArg,
             "cl_ret" => Kind::ClRet,
             "grab" => Kind::Grab,
+            "match" => Kind::Match,
+            "arm" => Kind::Arm,
+            "pattern" => Kind::Pattern,
+            "interpolation" => Kind::Interpolation,
+            "record" => Kind::Record,
+            "field" => Kind::Field,
             _ => return None
         })
     }
@@ -189,6 +205,26 @@ impl Kind {
         }
     }
 
+    /// A match arm can declare a variable bound to the matched value.
+    pub fn is_decl_arm(&self) -> bool {
+        use self::Kind::*;
+
+        match *self {
+            ArmSome | ArmOk | ArmErr => true,
+            _ => false
+        }
+    }
+
+    /// The kind of an arm depends on its pattern.
+    pub fn is_arm(&self) -> bool {
+        use self::Kind::*;
+
+        match *self {
+            Arm | ArmSome | ArmNone | ArmOk | ArmErr => true,
+            _ => false
+        }
+    }
+
     pub fn is_block(&self) -> bool {
         use self::Kind::*;
 
@@ -24,10 +24,28 @@ mod typecheck;
 pub fn check(
     data: &[Range<MetaData>],
     prelude: &Prelude
+) -> Result<HashMap<Arc<String>, Type>, Range<String>> {
+    check_with_warnings(data, prelude, &mut vec![])
+}
+
+/// Checks lifetime constraints and does type checking,
+/// pushing warnings that do not stop the program from running.
+/// Returns refined return types of functions to put in AST.
+pub fn check_with_warnings(
+    data: &[Range<MetaData>],
+    prelude: &Prelude,
+    warnings: &mut Vec<Range<String>>
 ) -> Result<HashMap<Arc<String>, Type>, Range<String>> {
     let mut nodes: Vec<Node> = vec![];
     try!(convert_meta_data(&mut nodes, data));
 
+    if !prelude.capabilities.threads {
+        if let Some(node) = nodes.iter().find(|n| n.kind == Kind::Go) {
+            return Err(node.source.wrap(
+                format!("Can not use `go` because threads are not allowed")));
+        }
+    }
+
     // Add mutability information to function names.
     for i in 0..nodes.len() {
         match nodes[i].kind {
@@ -149,10 +167,11 @@ pub fn check(
         .collect();
 
     // Collect indices to references that are not declared.
+    // Inserting into an object refers to the object, which is used to check fields of records.
     let items: Vec<usize> = nodes.iter().enumerate()
         .filter(|&(i, n)| {
             n.kind == Kind::Item &&
-            locals.binary_search_by(|&(_, it)| it.cmp(&i)).is_err()
+            (n.item_ids() || locals.binary_search_by(|&(_, it)| it.cmp(&i)).is_err())
         })
         .map(|(i, _)| i)
         .collect();
@@ -182,7 +201,8 @@ pub fn check(
 
         'search: loop {
             if nodes[parent].kind.is_decl_loop() ||
-               nodes[parent].kind.is_decl_un_loop() {
+               nodes[parent].kind.is_decl_un_loop() ||
+               nodes[parent].kind.is_decl_arm() {
                 let my_name = nodes[i].name().unwrap();
                 for name in &nodes[parent].names {
                     if name == my_name {
@@ -717,7 +737,72 @@ pub fn check(
         }
     }
 
-    try!(typecheck::run(&mut nodes, prelude));
+    // Check that parallel loops do not mutate variables declared outside the loop,
+    // since each part of the loop changes its own copy.
+    let mut mutated: Vec<usize> = mutated_locals.iter().map(|&(_, i)| i).collect();
+    for &c in &calls {
+        for &arg in nodes[c].children.iter()
+            .filter(|&&n| nodes[n].kind == Kind::CallArg && nodes[n].mutable)
+        {
+            // Item is 2 levels down inside call_arg/item
+            let mut n = arg;
+            for _ in 0..2 {
+                if nodes[n].kind == Kind::Item {
+                    mutated.push(n);
+                    break;
+                }
+                if nodes[n].children.len() == 0 { break; }
+                n = nodes[n].children[0];
+            }
+        }
+    }
+    for &i in &mutated {
+        let decl = match nodes[i].declaration {
+            Some(decl) => decl,
+            None => continue
+        };
+        let mut parent = nodes[i].parent;
+        while let Some(p) = parent {
+            if nodes[p].par && !nodes[p].contains(&nodes[decl]) {
+                return Err(nodes[i].source.wrap(
+                    format!("Can not mutate `{}` in a parallel loop, \
+                    because it is declared outside the loop",
+                    nodes[i].name().expect("Expected name"))));
+            }
+            parent = nodes[p].parent;
+        }
+    }
+
+    // Check for duplicate records and fields, and build name to type map.
+    let mut records: HashMap<Arc<String>, Type> = prelude.records.clone();
+    let mut record_names: HashSet<Arc<String>> = HashSet::new();
+    for i in 0..nodes.len() {
+        if nodes[i].kind != Kind::Record { continue; }
+        let name = nodes[i].name().expect("Expected name").clone();
+        if record_names.contains(&name) {
+            return Err(nodes[i].source.wrap(
+                format!("Duplicate record `{}`", name)));
+        }
+        let mut fields: Vec<(Arc<String>, Type)> = vec![];
+        for &j in &nodes[i].children {
+            let field = nodes[j].name().expect("Expected name");
+            if fields.iter().any(|&(ref f, _)| f == field) {
+                return Err(nodes[j].source.wrap(
+                    format!("Duplicate field `{}` in record `{}`", field, name)));
+            }
+            fields.push((field.clone(), nodes[j].ty.clone().unwrap_or(Type::Any)));
+        }
+        record_names.insert(name.clone());
+        records.insert(name.clone(), Type::Record(name, Arc::new(fields)));
+    }
+
+    // Use declared records in types.
+    for node in &mut nodes {
+        let ty = node.ty.as_ref().map(|ty| ty.records(&records));
+        node.ty = ty;
+    }
+
+    try!(typecheck::run(&mut nodes, prelude, &records, warnings));
 
     // Copy refined return types to use in AST.
     let mut refined_rets: HashMap<Arc<String>, Type> = HashMap::new();
@@ -47,6 +47,8 @@ pub struct Node {
     /// The argument lifetime constraints, one for each argument to a function.
     /// Just using an empty vector for nodes that are not functions.
     pub lts: Vec<Lt>,
+    /// Whether a short loop runs in parallel.
+    pub par: bool,
 }
 
 impl Node {
@@ -73,11 +75,23 @@ impl Node {
         return None
     }
 
+    /// Returns `true` if the other node is this node or inside it.
+    pub fn contains(&self, other: &Node) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+
     pub fn item_ids(&self) -> bool {
         if self.kind == Kind::Item && self.children.len() > 0 { true }
         else { false }
     }
 
+    /// Returns `true` if the node is an item with ids and no type.
+    ///
+    /// Only fields of records get a type.
+    pub fn untyped_ids(&self) -> bool {
+        self.item_ids() && self.ty.is_none()
+    }
+
     pub fn inner_type(&self, ty: &Type) -> Type {
         if self.try {
             match ty {
@@ -97,7 +111,7 @@ impl Node {
             Pow | Sum | Prod | SumVec4 | Min | Max | Any | All |
             Vec4 | Vec4UnLoop | Swizzle |
             Assign | For | ForN | Link |
-            Closure | CallClosure | Grab | Norm => false,
+            Closure | CallClosure | Grab | Norm | Interpolation => false,
             Add | Mul | Compare => self.children.len() == 1,
             _ => true
         }
@@ -116,6 +130,9 @@ impl Node {
                     return arg_lifetime(declaration, &arg, nodes, arg_names);
                 } else if arg.kind == Kind::Current {
                     return Some(Lifetime::Current(declaration));
+                } else if arg.kind.is_decl_arm() {
+                    // The bound value is a copy of the value inside the matched value.
+                    return None;
                 } else {
                     return Some(Lifetime::Local(declaration));
                 }
@@ -209,6 +226,19 @@ impl Node {
                 }
                 (_, Kind::Left) => {}
                 (_, Kind::Right) => {}
+                (Kind::Match, Kind::Expr) => {
+                    // Values bound by arms are copied,
+                    // so the result does not depend on the matched value.
+                    continue
+                }
+                (_, Kind::Match) => {}
+                (_, Kind::Arm) => {}
+                (_, Kind::ArmSome) => {}
+                (_, Kind::ArmNone) => {}
+                (_, Kind::ArmOk) => {}
+                (_, Kind::ArmErr) => {}
+                (_, Kind::Pattern) => { continue }
+                (_, Kind::Interpolation) => {}
                 (_, Kind::Expr) => {}
                 (_, Kind::Return) => {}
                 (_, Kind::Array) => {}
@@ -310,6 +340,7 @@ pub fn convert_meta_data(
                     Kind::Norm => Some(Type::F64),
                     Kind::Swizzle => Some(Type::F64),
                     Kind::Link => Some(Type::Link),
+                    Kind::Interpolation => Some(Type::Text),
                     Kind::Any | Kind::All => Some(Type::Secret(Box::new(Type::Bool))),
                     Kind::Min | Kind::Max => Some(Type::Secret(Box::new(Type::F64))),
                     Kind::For | Kind::ForN => Some(Type::Void),
@@ -335,7 +366,8 @@ pub fn convert_meta_data(
                     op: None,
                     binops: vec![],
                     ids: 0,
-                    lts: vec![]
+                    lts: vec![],
+                    par: false,
                 });
             }
             MetaData::EndNode(_) => {
@@ -378,8 +410,14 @@ pub fn convert_meta_data(
                         nodes[i].lifetime = Some(val.clone());
                     }
                     "id" => {
+                        // Put names of ids together to look up fields of records.
                         let i = *parents.last().unwrap();
                         nodes[i].ids += 1;
+                        nodes[i].names.push(val.clone());
+                    }
+                    "key" => {
+                        let i = *parents.last().unwrap();
+                        nodes[i].names.push(val.clone());
                     }
                     "text" => {
                         let i = *parents.last().unwrap();
@@ -389,6 +427,16 @@ pub fn convert_meta_data(
                         let i = *parents.last().unwrap();
                         nodes[i].ty = Some(Type::Vec4);
                     }
+                    "some" | "ok" | "err" => {
+                        // There is no sub node for the pattern, so we change kind of arm.
+                        let i = *parents.last().unwrap();
+                        nodes[i].kind = match &***n {
+                            "some" => Kind::ArmSome,
+                            "ok" => Kind::ArmOk,
+                            _ => Kind::ArmErr
+                        };
+                        nodes[i].names.push(val.clone());
+                    }
                     _ => {}
                 }
             }
@@ -430,7 +478,11 @@ pub fn convert_meta_data(
                         let i = *parents.last().unwrap();
                         nodes[i].mutable = _val;
                     }
-                    "try" | "try_item" => {
+                    "par" => {
+                        let i = *parents.last().unwrap();
+                        nodes[i].par = _val;
+                    }
+                    "try" | "try_item" | "try_id" => {
                         let i = *parents.last().unwrap();
                         nodes[i].try = _val;
                     }
@@ -448,6 +500,11 @@ pub fn convert_meta_data(
                             nodes[i].ty = Some(Type::Void);
                         }
                     }
+                    "none" => {
+                        // There is no sub node for the pattern, so we change kind of arm.
+                        let i = *parents.last().unwrap();
+                        nodes[i].kind = Kind::ArmNone;
+                    }
                     "return_void" => {
                         // There is no sub node, so we need change kind of parent.
                         // This should always be an expression.
@@ -487,6 +544,10 @@ pub fn convert_meta_data(
                         let i = *parents.last().unwrap();
                         nodes[i].ty = Some(Type::F64);
                     }
+                    "id" => {
+                        let i = *parents.last().unwrap();
+                        nodes[i].ids += 1;
+                    }
                     "grab_level" => {
                         if val < 1.0 {
                             return Err(d.range()
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use range::Range;
 use super::node::Node;
 use super::kind::Kind;
@@ -44,7 +47,15 @@ use Type;
 /// The type propagation step uses this assumption without checking the whole `if` expression.
 /// After type propagation, all blocks in the `if` expression should have some type information,
 /// but no further propagation is necessary, so it only need to check for consistency.
-pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>> {
+///
+/// Warnings, for example about non-exhaustive `match` expressions,
+/// are pushed to `warnings` without stopping the type checking.
+pub fn run(
+    nodes: &mut Vec<Node>,
+    prelude: &Prelude,
+    records: &HashMap<Arc<String>, Type>,
+    warnings: &mut Vec<Range<String>>
+) -> Result<(), Range<String>> {
     // Type propagation.
     let mut changed;
     loop {
@@ -75,7 +86,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                     }
                 }
                 Kind::CallArg => {
-                    if nodes[i].children.len() == 0 || nodes[i].item_ids() {
+                    if nodes[i].children.len() == 0 || nodes[i].untyped_ids() {
                         continue 'node;
                     }
                     let ch = nodes[i].children[0];
@@ -165,12 +176,19 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                             this_ty = Some(ty.clone());
                         }
                     } else if let Some(&f) = prelude.functions.get(nodes[i].name().unwrap()) {
-                        this_ty = Some(prelude.list[f].ret.clone());
+                        match &***nodes[i].name().unwrap() {
+                            // Values received from a channel have the type of the channel.
+                            "recv" | "try_recv" => match channel_element(i, nodes) {
+                                None => continue 'node,
+                                Some(ty) => this_ty = Some(Type::Option(Box::new(ty))),
+                            },
+                            _ => this_ty = Some(prelude.list[f].ret.clone()),
+                        }
                     }
                 }
                 Kind::CallClosure => {
                     if let Some(item) = nodes[i].find_child_by_kind(nodes, Kind::Item) {
-                        if nodes[item].item_ids() { continue 'node; }
+                        if nodes[item].untyped_ids() { continue 'node; }
                         if let Some(decl) = nodes[item].declaration {
                             if let Some(ref ty) = nodes[decl].ty {
                                 match ty {
@@ -199,7 +217,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                         None => continue,
                         Some(x) => x
                     };
-                    if nodes[right].item_ids() { continue 'node; }
+                    if nodes[right].untyped_ids() { continue 'node; }
                     nodes[left].ty = match (&nodes[left].ty, &nodes[right].ty) {
                         (&None, &Some(ref right_ty)) => {
                             // Make assign return void since there is no more need for checking.
@@ -211,8 +229,10 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                     changed = true;
                 }
                 Kind::Item => {
-                    if nodes[i].item_ids() { continue 'node; }
-                    if let Some(decl) = nodes[i].declaration {
+                    if nodes[i].item_ids() {
+                        // Look up the type of record fields.
+                        this_ty = try!(field_type(i, nodes, records));
+                    } else if let Some(decl) = nodes[i].declaration {
                         match nodes[decl].kind {
                             Kind::Sum | Kind::Min | Kind::Max |
                             Kind::Any | Kind::All | Kind::Sift |
@@ -230,6 +250,21 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                                 this_ty = Some(nodes[i].inner_type(nodes[decl].ty.as_ref()
                                     .unwrap_or(&Type::Any)));
                             }
+                            Kind::ArmSome | Kind::ArmOk | Kind::ArmErr => {
+                                // Infer type from the matched value.
+                                let m = nodes[decl].parent.expect("Expected parent");
+                                let expr = match nodes[m].find_child_by_kind(nodes, Kind::Expr) {
+                                    None => continue 'node,
+                                    Some(expr) => expr
+                                };
+                                let ty = match (nodes[decl].kind, &nodes[expr].ty) {
+                                    (_, &None) => continue 'node,
+                                    (Kind::ArmSome, &Some(Type::Option(ref ty))) |
+                                    (Kind::ArmOk, &Some(Type::Result(ref ty))) => (**ty).clone(),
+                                    _ => Type::Any
+                                };
+                                this_ty = Some(nodes[i].inner_type(&ty));
+                            }
                             _ => {
                                 if let Some(ref ty) = nodes[decl].ty {
                                     this_ty = Some(nodes[i].inner_type(ty));
@@ -261,7 +296,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                      // TODO: Report error for expected unary operator.
                     if nodes[i].children.len() == 0 { continue 'node; }
                     let ch = nodes[i].children[0];
-                    if nodes[ch].item_ids() { continue 'node; }
+                    if nodes[ch].untyped_ids() { continue 'node; }
                     let ty = match nodes[ch].ty {
                         None => continue 'node,
                         Some(ref ty) => ty.clone()
@@ -306,7 +341,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                     // Require type to be inferred from all children.
                     let mut it_ty: Option<Type> = None;
                     for &ch in &nodes[i].children {
-                        if nodes[ch].item_ids() { continue 'node; }
+                        if nodes[ch].untyped_ids() { continue 'node; }
                         if let Some(ref ty) = nodes[ch].ty {
                             it_ty = if let Some(ref it) = it_ty {
                                 match it.add(ty) {
@@ -337,7 +372,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                     let mut bin_ind = 0;
                     let mut it_ty: Option<Type> = None;
                     for &ch in &nodes[i].children {
-                        if nodes[ch].item_ids() { continue 'node; }
+                        if nodes[ch].untyped_ids() { continue 'node; }
                         if let Some(ref ty) = nodes[ch].ty {
                             it_ty = if let Some(ref it) = it_ty {
                                 match it.mul(ty, nodes[i].binops[bin_ind]) {
@@ -368,7 +403,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                         None => continue 'node,
                         Some(x) => x
                     };
-                    if nodes[base].item_ids() || nodes[exp].item_ids() {
+                    if nodes[base].untyped_ids() || nodes[exp].untyped_ids() {
                         continue 'node;
                     }
                     match (&nodes[base].ty, &nodes[exp].ty) {
@@ -391,7 +426,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                         None => continue 'node,
                         Some(x) => x
                     };
-                    if nodes[left].item_ids() {
+                    if nodes[left].untyped_ids() {
                         continue 'node;
                     }
                     match &nodes[left].ty {
@@ -412,7 +447,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                         this_ty = Some(Type::Void);
                     }
                     for &ch in nodes[i].children.last() {
-                        if nodes[ch].item_ids() { continue 'node; }
+                        if nodes[ch].untyped_ids() { continue 'node; }
                         if let Some(ref ty) = nodes[ch].ty {
                             this_ty = Some(nodes[i].inner_type(ty));
                             break;
@@ -433,7 +468,7 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                 Kind::X | Kind::Y | Kind::Z | Kind::W => {
                     if nodes[i].children.len() == 0 { continue 'node; }
                     let ch = nodes[i].children[0];
-                    if nodes[ch].item_ids() { continue 'node; }
+                    if nodes[ch].untyped_ids() { continue 'node; }
 
                     let expr_type = nodes[ch].ty.as_ref().map(|ty| nodes[i].inner_type(&ty));
                     if let Some(ref ty) = expr_type {
@@ -458,6 +493,35 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
 
                     this_ty = Some(true_type);
                 }
+                Kind::Match => {
+                    // The type is inferred from the first arm that does not return.
+                    let mut unreachable = true;
+                    for &ch in &nodes[i].children {
+                        if !nodes[ch].kind.is_arm() { continue; }
+                        match nodes[ch].ty {
+                            None => {
+                                unreachable = false;
+                                break;
+                            }
+                            Some(Type::Unreachable) => {}
+                            Some(ref ty) => {
+                                this_ty = Some(nodes[i].inner_type(ty));
+                                break;
+                            }
+                        }
+                    }
+                    if this_ty.is_none() && unreachable {
+                        this_ty = Some(Type::Unreachable);
+                    }
+                }
+                Kind::Arm | Kind::ArmSome | Kind::ArmNone | Kind::ArmOk | Kind::ArmErr => {
+                    let ch = match nodes[i].find_child_by_kind(nodes, Kind::Expr) {
+                        None => continue 'node,
+                        Some(ch) => ch
+                    };
+                    if nodes[ch].untyped_ids() { continue 'node; }
+                    this_ty = nodes[ch].ty.clone();
+                }
                 Kind::Arg => {
                     this_ty = Some(Type::Any);
                 }
@@ -555,12 +619,34 @@ pub fn run(nodes: &mut Vec<Node>, prelude: &Prelude) -> Result<(), Range<String>
                     }
                 }
             }
+            Kind::Call => {
+                try!(check_send(i, nodes))
+            }
             Kind::If => {
                 try!(check_if(i, nodes))
             }
+            Kind::Match => {
+                try!(check_match(i, nodes, warnings))
+            }
+            Kind::Object => {
+                if let Some(ty) = expected_type(i, nodes, prelude) {
+                    try!(check_object(i, &ty, nodes, records));
+                }
+            }
+            Kind::Interpolation => {
+                for &ch in &nodes[i].children {
+                    if nodes[ch].kind != Kind::Expr { continue; }
+                    if let Some(Type::Void) = nodes[ch].ty {
+                        return Err(nodes[ch].source.wrap(
+                            format!("Type mismatch (#2000):\n\
+                                Expected something, found `void`")));
+                    }
+                }
+            }
             Kind::Assign => {
                 use ast::AssignOp;
 
+                try!(check_field_assign(i, nodes));
                 match nodes[i].op {
                     Some(AssignOp::Add) | Some(AssignOp::Sub) => {
                         let left = nodes[i].find_child_by_kind(nodes, Kind::Left).unwrap();
@@ -720,3 +806,314 @@ fn check_if(n: usize, nodes: &Vec<Node>) -> Result<(), Range<String>> {
 
     Ok(())
 }
+
+fn check_match(
+    n: usize,
+    nodes: &Vec<Node>,
+    warnings: &mut Vec<Range<String>>
+) -> Result<(), Range<String>> {
+    let expr_type = nodes[n].find_child_by_kind(nodes, Kind::Expr)
+        .and_then(|expr| nodes[expr].ty.as_ref());
+
+    let mut opt = false;
+    let mut res = false;
+    if let Some(&Type::Option(_)) = expr_type { opt = true; }
+    if let Some(&Type::Result(_)) = expr_type { res = true; }
+
+    let mut found = vec![];
+    let mut wildcard = false;
+    for &ch in &nodes[n].children {
+        let kind = nodes[ch].kind;
+        if !kind.is_arm() { continue; }
+        found.push(kind);
+
+        let pattern_type = match kind {
+            Kind::ArmSome | Kind::ArmNone => {
+                opt = true;
+                Some(Type::option())
+            }
+            Kind::ArmOk | Kind::ArmErr => {
+                res = true;
+                Some(Type::result())
+            }
+            _ => match nodes[ch].find_child_by_kind(nodes, Kind::Pattern) {
+                Some(pat) => nodes[pat].ty.clone(),
+                None => {
+                    wildcard = true;
+                    None
+                }
+            }
+        };
+        if let (Some(ref pattern_type), Some(expr_type)) = (pattern_type, expr_type) {
+            if !pattern_type.goes_with(expr_type) {
+                return Err(nodes[ch].source.wrap(
+                    format!("Type mismatch (#1800):\nExpected `{}`, found `{}`",
+                        pattern_type.description(), expr_type.description())));
+            }
+        }
+
+        // The type of matches are inferred from the arms.
+        if let (&Some(ref match_type), &Some(ref arm_type)) = (&nodes[n].ty, &nodes[ch].ty) {
+            if !arm_type.goes_with(match_type) {
+                return Err(nodes[ch].source.wrap(
+                    format!("Type mismatch (#1900):\nExpected `{}`, found `{}`",
+                        match_type.description(), arm_type.description())));
+            }
+        }
+    }
+
+    if wildcard { return Ok(()); }
+    let missing = if opt && !found.contains(&Kind::ArmSome) {
+        Some("some(_)")
+    } else if opt && !found.contains(&Kind::ArmNone) {
+        Some("none()")
+    } else if res && !found.contains(&Kind::ArmOk) {
+        Some("ok(_)")
+    } else if res && !found.contains(&Kind::ArmErr) {
+        Some("err(_)")
+    } else {
+        None
+    };
+    if let Some(missing) = missing {
+        warnings.push(nodes[n].source.wrap(
+            format!("Non-exhaustive match:\nMissing `{}`, use `_` to match the rest", missing)));
+    }
+
+    Ok(())
+}
+
+/// Looks up the type of a field of a record, for example `p.name`.
+///
+/// Returns `None` when the type of the object is unknown or not a record.
+fn field_type(
+    i: usize,
+    nodes: &[Node],
+    records: &HashMap<Arc<String>, Type>
+) -> Result<Option<Type>, Range<String>> {
+    let decl = match nodes[i].declaration {
+        None => return Ok(None),
+        Some(decl) => decl
+    };
+    match nodes[decl].kind {
+        Kind::Arg | Kind::Current | Kind::Item => {}
+        _ => return Ok(None)
+    }
+    let mut ty = match nodes[decl].ty {
+        None => return Ok(None),
+        Some(ref ty) => nodes[i].inner_type(ty)
+    };
+    let extra = match nodes[i].find_child_by_kind(nodes, Kind::ItemExtra) {
+        None => return Ok(None),
+        Some(extra) => extra
+    };
+    // Fields can only be looked up when all ids are names.
+    if nodes[extra].try || nodes[extra].children.len() > 0 ||
+       nodes[extra].names.len() as u32 != nodes[extra].ids {
+        return Ok(None);
+    }
+    for name in &nodes[extra].names {
+        ty = match ty {
+            Type::Record(ref record, ref fields) => {
+                match fields.iter().find(|&&(ref field, _)| field == name) {
+                    Some(&(_, ref field_ty)) => field_ty.records(records),
+                    None => return Err(nodes[i].source.wrap(
+                        format!("Type mismatch (#2100):\n`{}` has no field `{}`",
+                            record, name)))
+                }
+            }
+            _ => return Ok(None)
+        };
+    }
+    Ok(Some(ty))
+}
+
+/// Finds the type an object literal is expected to have,
+/// from a declared argument, return type or assigned field.
+fn expected_type(n: usize, nodes: &[Node], prelude: &Prelude) -> Option<Type> {
+    // Skip expressions that only contain the object.
+    let mut parent = match nodes[n].parent {
+        None => return None,
+        Some(parent) => parent
+    };
+    loop {
+        match nodes[parent].kind {
+            Kind::Val | Kind::Expr | Kind::Add | Kind::Mul
+            if nodes[parent].children.len() == 1 => {}
+            _ => break
+        }
+        parent = match nodes[parent].parent {
+            None => return None,
+            Some(parent) => parent
+        };
+    }
+    match nodes[parent].kind {
+        Kind::Fn => nodes[parent].ty.clone(),
+        Kind::Return => {
+            // Find the function returning the object.
+            let mut p = parent;
+            loop {
+                p = match nodes[p].parent {
+                    None => return None,
+                    Some(p) => p
+                };
+                match nodes[p].kind {
+                    Kind::Fn => return nodes[p].ty.clone(),
+                    Kind::Closure => return None,
+                    _ => {}
+                }
+            }
+        }
+        Kind::CallArg => {
+            let call = match nodes[parent].parent {
+                None => return None,
+                Some(call) => call
+            };
+            if nodes[call].kind != Kind::Call { return None; }
+            let j = match nodes[call].children.iter()
+                .filter(|&&arg| nodes[arg].kind == Kind::CallArg)
+                .position(|&arg| arg == parent) {
+                None => return None,
+                Some(j) => j
+            };
+            if let Some(decl) = nodes[call].declaration {
+                nodes[decl].children.get(j).and_then(|&arg| nodes[arg].ty.clone())
+            } else if let Some(&f) = prelude.functions.get(nodes[call].name().unwrap()) {
+                prelude.list[f].tys.get(j).cloned()
+            } else {
+                None
+            }
+        }
+        Kind::Right => {
+            let assign = match nodes[parent].parent {
+                None => return None,
+                Some(assign) => assign
+            };
+            let left = match nodes[assign].find_child_by_kind(nodes, Kind::Left) {
+                None => return None,
+                Some(left) => left
+            };
+            nodes[left].children.get(0).and_then(|&item| nodes[item].ty.clone())
+        }
+        _ => None
+    }
+}
+
+/// Checks the fields of an object literal against a record.
+fn check_object(
+    n: usize,
+    ty: &Type,
+    nodes: &[Node],
+    records: &HashMap<Arc<String>, Type>
+) -> Result<(), Range<String>> {
+    let (record, fields) = match *ty {
+        Type::Record(ref record, ref fields) => (record, fields),
+        _ => return Ok(())
+    };
+    for &kv in &nodes[n].children {
+        if nodes[kv].kind != Kind::KeyValue { continue; }
+        let key = nodes[kv].name().expect("Expected key");
+        let field_ty = match fields.iter().find(|&&(ref field, _)| field == key) {
+            Some(&(_, ref field_ty)) => field_ty.records(records),
+            None => return Err(nodes[kv].source.wrap(
+                format!("Type mismatch (#2150):\n`{}` has no field `{}`", record, key)))
+        };
+        let val = match nodes[kv].find_child_by_kind(nodes, Kind::Val) {
+            None => continue,
+            Some(val) => val
+        };
+        if let Some(ref val_ty) = nodes[val].ty {
+            if !field_ty.goes_with(val_ty) {
+                return Err(nodes[val].source.wrap(
+                    format!("Type mismatch (#2250):\nExpected `{}`, found `{}`",
+                        field_ty.description(), val_ty.description())));
+            }
+        }
+        // Check objects inside the object.
+        let mut ch = val;
+        while nodes[ch].children.len() == 1 {
+            ch = nodes[ch].children[0];
+            if nodes[ch].kind == Kind::Object {
+                try!(check_object(ch, &field_ty, nodes, records));
+                break;
+            }
+        }
+    }
+    for &(ref field, _) in fields.iter() {
+        if !nodes[n].children.iter().any(|&kv| nodes[kv].name() == Some(field)) {
+            return Err(nodes[n].source.wrap(
+                format!("Type mismatch (#2300):\n`{}` is missing field `{}`", record, field)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the type of a value assigned to a field of a record.
+fn check_field_assign(n: usize, nodes: &[Node]) -> Result<(), Range<String>> {
+    use ast::AssignOp;
+
+    match nodes[n].op {
+        Some(AssignOp::Assign) | Some(AssignOp::Set) => {}
+        _ => return Ok(())
+    }
+    let left = match nodes[n].find_child_by_kind(nodes, Kind::Left) {
+        None => return Ok(()),
+        Some(left) => left
+    };
+    let right = match nodes[n].find_child_by_kind(nodes, Kind::Right) {
+        None => return Ok(()),
+        Some(right) => right
+    };
+    let item = match nodes[left].children.get(0) {
+        None => return Ok(()),
+        Some(&item) => item
+    };
+    if !nodes[item].item_ids() { return Ok(()); }
+    if let (&Some(ref field_ty), &Some(ref right_ty)) = (&nodes[item].ty, &nodes[right].ty) {
+        if !field_ty.goes_with(right_ty) {
+            return Err(nodes[right].source.wrap(
+                format!("Type mismatch (#2200):\nExpected `{}`, found `{}`",
+                    field_ty.description(), right_ty.description())));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the type of values in the channel passed as first argument to a call.
+///
+/// Returns `None` if the type of the channel is not known.
+fn channel_element(call: usize, nodes: &[Node]) -> Option<Type> {
+    let arg = match nodes[call].find_child_by_kind(nodes, Kind::CallArg) {
+        None => return None,
+        Some(arg) => arg
+    };
+    match nodes[arg].ty {
+        None => None,
+        Some(Type::Channel(ref ty)) => Some((**ty).clone()),
+        Some(_) => Some(Type::Any),
+    }
+}
+
+/// Checks the type of a value sent to a channel.
+fn check_send(n: usize, nodes: &[Node]) -> Result<(), Range<String>> {
+    if nodes[n].declaration.is_some() ||
+       nodes[n].name().map(|name| &***name != "send").unwrap_or(true) {
+        return Ok(());
+    }
+    let ty = match channel_element(n, nodes) {
+        None => return Ok(()),
+        Some(ty) => ty
+    };
+    let val = match nodes[n].children.iter()
+        .filter(|&&arg| nodes[arg].kind == Kind::CallArg).nth(1) {
+        None => return Ok(()),
+        Some(&val) => val
+    };
+    if let Some(ref val_ty) = nodes[val].ty {
+        if !ty.goes_with(val_ty) {
+            return Err(nodes[val].source.wrap(
+                format!("Type mismatch (#2400):\nExpected `{}`, found `{}`",
+                    ty.description(), val_ty.description())));
+        }
+    }
+    Ok(())
+}
@@ -90,11 +90,10 @@ macro_rules! dyon_obj {
             impl $crate::embed::PushVariable for $t {
                 fn push_var(&self) -> $crate::Variable {
                     use std::sync::Arc;
-                    use std::collections::HashMap;
 
-                    let mut obj: HashMap<_, $crate::Variable> = HashMap::new();
+                    let mut obj = $crate::ObjectMap::new();
                     $(
-                        obj.insert(Arc::new(stringify!($f).into()), self.$f.push_var())
+                        obj.insert($crate::Symbol::new(stringify!($f)), self.$f.push_var())
                     ;)*
                     $crate::Variable::Object(Arc::new(obj))
                 }
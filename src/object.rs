@@ -0,0 +1,219 @@
+/*
+Storage of object keys and values.
+
+Keys are kept in insertion order, such that each key has a slot.
+A property lookup in the source remembers the slot where it found the key,
+and checks that slot first the next time, before searching the object.
+The remembered slot is atomic, because threads share the AST.
+Small objects are searched linearly, comparing symbols by pointer.
+Larger objects build a hash map from keys to slots.
+
+Objects are compared without looking at the order of keys.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasherDefault;
+use std::iter::FromIterator;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use symbol::{Symbol, SymbolHasher};
+use Variable;
+
+/// Number of keys searched linearly before building an index.
+const LINEAR: usize = 8;
+
+/// Stores the keys and values of an object.
+#[derive(Clone, Default)]
+pub struct ObjectMap {
+    keys: Vec<Symbol>,
+    values: Vec<Variable>,
+    index: Option<HashMap<Symbol, usize, BuildHasherDefault<SymbolHasher>>>,
+}
+
+impl ObjectMap {
+    /// Creates a new empty object.
+    pub fn new() -> ObjectMap {
+        ObjectMap::default()
+    }
+
+    /// Creates a new empty object with room for `n` keys.
+    pub fn with_capacity(n: usize) -> ObjectMap {
+        ObjectMap {
+            keys: Vec::with_capacity(n),
+            values: Vec::with_capacity(n),
+            index: None,
+        }
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns `true` if the object has no keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the slot of a key.
+    pub fn slot(&self, key: &Symbol) -> Option<usize> {
+        match self.index {
+            Some(ref index) => index.get(key).map(|&i| i),
+            None => self.keys.iter().position(|k| k == key),
+        }
+    }
+
+    /// Returns the slot of a key, checking the cached slot first.
+    ///
+    /// Updates the cache when the key is found in another slot.
+    pub fn cached_slot(&self, key: &Symbol, cache: &SlotCache) -> Option<usize> {
+        let i = cache.get();
+        if self.keys.get(i) == Some(key) { return Some(i); }
+        let res = self.slot(key);
+        if let Some(i) = res { cache.set(i); }
+        res
+    }
+
+    /// Returns `true` if the object has a key.
+    pub fn contains_key(&self, key: &Symbol) -> bool {
+        self.slot(key).is_some()
+    }
+
+    /// Gets the value of a key.
+    pub fn get(&self, key: &Symbol) -> Option<&Variable> {
+        self.slot(key).map(move |i| &self.values[i])
+    }
+
+    /// Gets the mutable value of a key.
+    pub fn get_mut(&mut self, key: &Symbol) -> Option<&mut Variable> {
+        match self.slot(key) {
+            None => None,
+            Some(i) => Some(&mut self.values[i]),
+        }
+    }
+
+    /// Gets the mutable value in a slot.
+    pub fn slot_mut(&mut self, i: usize) -> &mut Variable {
+        &mut self.values[i]
+    }
+
+    /// Inserts a value, returning the old value of the key.
+    ///
+    /// New keys are added to the last slot.
+    pub fn insert(&mut self, key: Symbol, val: Variable) -> Option<Variable> {
+        use std::mem::replace;
+
+        if let Some(i) = self.slot(&key) {
+            return Some(replace(&mut self.values[i], val));
+        }
+        let i = self.keys.len();
+        if let Some(ref mut index) = self.index {
+            index.insert(key.clone(), i);
+        } else if i == LINEAR {
+            self.index = Some(self.keys.iter().cloned().zip(0..).collect());
+            self.index.as_mut().unwrap().insert(key.clone(), i);
+        }
+        self.keys.push(key);
+        self.values.push(val);
+        None
+    }
+
+    /// Iterates over keys and values in insertion order.
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { keys: self.keys.iter(), values: self.values.iter() }
+    }
+
+    /// Iterates over keys in insertion order.
+    pub fn keys<'a>(&'a self) -> slice::Iter<'a, Symbol> {
+        self.keys.iter()
+    }
+
+    /// Iterates over values in insertion order.
+    pub fn values<'a>(&'a self) -> slice::Iter<'a, Variable> {
+        self.values.iter()
+    }
+
+    /// Iterates over mutable values in insertion order.
+    pub fn values_mut<'a>(&'a mut self) -> slice::IterMut<'a, Variable> {
+        self.values.iter_mut()
+    }
+}
+
+impl PartialEq for ObjectMap {
+    fn eq(&self, other: &ObjectMap) -> bool {
+        self.len() == other.len() &&
+        self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl fmt::Debug for ObjectMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl FromIterator<(Symbol, Variable)> for ObjectMap {
+    fn from_iter<I: IntoIterator<Item = (Symbol, Variable)>>(iter: I) -> ObjectMap {
+        let mut obj = ObjectMap::new();
+        for (key, val) in iter { obj.insert(key, val); }
+        obj
+    }
+}
+
+impl<'a> IntoIterator for &'a ObjectMap {
+    type Item = (&'a Symbol, &'a Variable);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// Remembers the slot where a property lookup found its key the last time.
+///
+/// Threads running the same code share the cache,
+/// so it only needs to be correct as a hint and uses relaxed ordering.
+#[derive(Debug, Default)]
+pub struct SlotCache(AtomicUsize);
+
+impl SlotCache {
+    /// Creates a new cache pointing at the first slot.
+    pub fn new() -> SlotCache {
+        SlotCache::default()
+    }
+
+    /// Returns the cached slot.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sets the cached slot.
+    pub fn set(&self, i: usize) {
+        self.0.store(i, Ordering::Relaxed)
+    }
+}
+
+impl Clone for SlotCache {
+    fn clone(&self) -> SlotCache {
+        SlotCache(AtomicUsize::new(self.get()))
+    }
+}
+
+/// Iterates over keys and values of an object.
+pub struct Iter<'a> {
+    keys: slice::Iter<'a, Symbol>,
+    values: slice::Iter<'a, Variable>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Symbol, &'a Variable);
+
+    fn next(&mut self) -> Option<(&'a Symbol, &'a Variable)> {
+        match (self.keys.next(), self.values.next()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,31 @@
+/*
+Callbacks for the host application to trace a running script.
+
+Threads started with `go` share the observer of the runtime starting them.
+Variables might be references to the stack, use `Runtime::resolve` to
+look them up.
+*/
+
+use std::sync::Arc;
+
+use Error;
+use Runtime;
+use Variable;
+
+/// Receives events from a runtime.
+///
+/// All methods do nothing by default.
+pub trait RuntimeObserver: Send + Sync {
+    /// Called when entering a function or closure.
+    fn enter(&self, _rt: &Runtime, _name: &Arc<String>, _args: &[Variable]) {}
+    /// Called when returning from a function or closure.
+    fn exit(&self, _rt: &Runtime, _name: &Arc<String>, _ret: Option<&Variable>) {}
+    /// Called when `?` propagates an error to the caller.
+    fn propagate_error(&self, _rt: &Runtime, _err: &Error) {}
+    /// Called when `go` starts a new thread.
+    fn spawn(&self, _rt: &Runtime, _name: &Arc<String>) {}
+    /// Called when `join` returns the result of a thread.
+    fn join(&self, _rt: &Runtime, _res: &Variable) {}
+    /// Called when `load` loads a new module from a file.
+    fn load(&self, _rt: &Runtime, _file: &str) {}
+}
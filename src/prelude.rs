@@ -14,6 +14,77 @@ pub enum Lt {
     Default,
 }
 
+/// Groups of intrinsics that a module can use.
+///
+/// Intrinsics that are not allowed are not resolved by the module,
+/// so calling them gives an error when checking the source.
+/// Modules created with `load` inherit the capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Reading files.
+    pub read_file: bool,
+    /// Writing files.
+    pub write_file: bool,
+    /// Downloading from URLs.
+    pub network: bool,
+    /// Reading from standard input.
+    pub stdin: bool,
+    /// Starting threads with `go` and using channels.
+    /// Without threads, `par` loops run on the current thread.
+    pub threads: bool,
+    /// Loading modules with `load`.
+    pub load: bool,
+}
+
+impl Capabilities {
+    /// Allows everything.
+    pub fn all() -> Capabilities {
+        Capabilities {
+            read_file: true,
+            write_file: true,
+            network: true,
+            stdin: true,
+            threads: true,
+            load: true,
+        }
+    }
+
+    /// Allows only intrinsics without side effects outside the runtime.
+    pub fn none() -> Capabilities {
+        Capabilities {
+            read_file: false,
+            write_file: false,
+            network: false,
+            stdin: false,
+            threads: false,
+            load: false,
+        }
+    }
+
+    /// Returns `true` if an intrinsic is allowed.
+    pub fn allows(&self, intrinsic: &str) -> bool {
+        match intrinsic {
+            "load_string__file" | "load_data__file" | "load__meta_file" => self.read_file,
+            "save__string_file" | "save__data_file" => self.write_file,
+            "load__meta_url" => self.network && self.read_file,
+            "download__url_file" => self.network && self.write_file,
+            "read_line" | "read_number" => self.stdin,
+            "join__thread" | "join__thread_timeout" | "join__threads" |
+            "is_done" | "cancel" |
+            "channel" | "send" | "recv" | "try_recv" | "close" | "select" => self.threads,
+            // Loading a module reads the file.
+            "load" | "load__source_imports" => self.load && self.read_file,
+            _ => true,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::all()
+    }
+}
+
 /// Stores preloaded function constraints.
 /// These are already checked.
 #[derive(Clone, PartialEq, Debug)]
@@ -58,6 +129,9 @@ impl Dfn {
 pub struct Prelude {
     pub functions: HashMap<Arc<String>, usize>,
     pub list: Vec<Dfn>,
+    pub capabilities: Capabilities,
+    /// Types of records declared in loaded sources.
+    pub records: HashMap<Arc<String>, Type>,
 }
 
 impl Prelude {
@@ -77,7 +151,9 @@ impl Prelude {
     pub fn new() -> Prelude {
         Prelude {
             functions: HashMap::new(),
-            list: vec![]
+            list: vec![],
+            capabilities: Capabilities::all(),
+            records: HashMap::new(),
         }
     }
 
@@ -87,15 +163,26 @@ impl Prelude {
         prelude
     }
 
+    /// Creates a prelude with the intrinsics allowed by capabilities.
+    pub fn new_capabilities(capabilities: Capabilities) -> Prelude {
+        let mut prelude = Prelude::new_intrinsics();
+        prelude.functions.retain(|name, _| capabilities.allows(name));
+        prelude.capabilities = capabilities;
+        prelude
+    }
+
     pub fn from_module(module: &Module) -> Prelude {
         let mut prelude = Prelude::new();
         intrinsics::standard(&mut prelude);
+        prelude.functions.retain(|name, _| module.has_intrinsic(name));
+        prelude.capabilities = module.capabilities;
         for f in &*module.ext_prelude {
             prelude.insert(f.name.clone(), f.p.clone());
         }
         for f in &module.functions {
             prelude.insert(f.name.clone(), Dfn::new(f));
         }
+        prelude.records = module.record_types();
         prelude
     }
 }
@@ -0,0 +1,116 @@
+/*
+Records call counts and time per function.
+
+The runtime calls `enter` and `exit` around loaded functions, closures,
+intrinsics and external functions when a profiler is attached.
+Time is recorded per call stack, which can be written in the folded stack
+format used by flamegraph tools.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Statistics for a function.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionProfile {
+    /// Number of calls.
+    pub calls: u64,
+    /// Time including called functions.
+    pub inclusive: Duration,
+    /// Time excluding called functions.
+    pub exclusive: Duration,
+}
+
+struct Frame {
+    name: Arc<String>,
+    start: Instant,
+    /// Time spent in called functions.
+    children: Duration,
+}
+
+/// Stores profiling data of a runtime.
+pub struct Profiler {
+    /// Statistics per function name.
+    pub functions: HashMap<Arc<String>, FunctionProfile>,
+    /// Exclusive time per call stack, with names separated by `;`.
+    pub stacks: HashMap<String, Duration>,
+    frames: Vec<Frame>,
+}
+
+impl Profiler {
+    /// Creates a new profiler.
+    pub fn new() -> Profiler {
+        Profiler {
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: vec![],
+        }
+    }
+
+    /// Called when entering a function.
+    pub fn enter(&mut self, name: Arc<String>) {
+        self.frames.push(Frame {
+            name: name,
+            start: Instant::now(),
+            children: Duration::new(0, 0),
+        });
+    }
+
+    /// Called when exiting the last entered function.
+    pub fn exit(&mut self) {
+        let frame = match self.frames.pop() {
+            None => return,
+            Some(x) => x,
+        };
+        let inclusive = frame.start.elapsed();
+        let exclusive = if inclusive > frame.children {
+                inclusive - frame.children
+            } else {
+                Duration::new(0, 0)
+            };
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += inclusive;
+        }
+        // Do not count time twice for recursive calls.
+        let recursive = self.frames.iter().any(|f| f.name == frame.name);
+
+        let mut stack = String::new();
+        for f in &self.frames {
+            stack.push_str(&f.name);
+            stack.push(';');
+        }
+        stack.push_str(&frame.name);
+        *self.stacks.entry(stack).or_insert(Duration::new(0, 0)) += exclusive;
+
+        let profile = self.functions.entry(frame.name).or_insert(Default::default());
+        profile.calls += 1;
+        profile.exclusive += exclusive;
+        if !recursive {
+            profile.inclusive += inclusive;
+        }
+    }
+
+    /// Returns the number of entered functions.
+    pub fn depth(&self) -> usize { self.frames.len() }
+
+    /// Exits functions until reaching a depth, used when unwinding errors.
+    pub fn unwind(&mut self, depth: usize) {
+        while self.frames.len() > depth {
+            self.exit();
+        }
+    }
+
+    /// Writes exclusive time in microseconds per call stack,
+    /// in the folded stack format.
+    pub fn write_folded<W: io::Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        let mut stacks: Vec<(&String, &Duration)> = self.stacks.iter().collect();
+        stacks.sort();
+        for (stack, time) in stacks {
+            let micros = time.as_secs() * 1_000_000 + (time.subsec_nanos() / 1_000) as u64;
+            try!(writeln!(w, "{} {}", stack, micros));
+        }
+        Ok(())
+    }
+}
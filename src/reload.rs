@@ -0,0 +1,120 @@
+/*
+Replaces the functions of a loaded file with a changed version of the file.
+
+The new source is parsed, converted and checked like `load_str`,
+against the functions of the other files in the module.
+When it succeeds, functions with the same name are replaced in place,
+such that function indices of other files stay valid.
+New functions are added to the end, and removed functions are kept.
+
+Variables are not stored in the module, so current objects and variables
+held by the host survive a reload.
+Closures and threads keep the module they were created with.
+A module object returned by `load` stores `Arc<Module>` in a mutex,
+which can be reloaded while the script calling into it is running.
+*/
+
+use std::sync::Arc;
+
+use ast;
+use bytecode;
+use load_str;
+use Module;
+
+/// Error when reloading a file, the module is left unchanged.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// Could not read the file.
+    Read(String),
+    /// The new source failed to parse, convert or pass the type and lifetime check.
+    Load(String),
+    /// The arguments or return type of a function changed.
+    ///
+    /// Other files were checked against the old signature,
+    /// so the module must be loaded again.
+    Signature(Arc<String>),
+}
+
+/// Reloads a file, using the loader of the module.
+pub fn reload(source: &str, module: &mut Arc<Module>) -> Result<(), ReloadError> {
+    let data = try!(module.loader.load_text(source).map_err(|err|
+        ReloadError::Read(format!("Could not open `{}`, {}", source, err))));
+    reload_str(source, Arc::new(data), module)
+}
+
+/// Reloads a file from string.
+///
+/// - source - The name of source file, as passed to `load`
+/// - d - The new data of source file
+/// - module - The module to update
+pub fn reload_str(
+    source: &str,
+    d: Arc<String>,
+    module: &mut Arc<Module>
+) -> Result<(), ReloadError> {
+    // Check the new source against the functions of other files.
+    let mut new_module = Module::new_intrinsics(module.intrinsics.clone());
+    new_module.capabilities = module.capabilities;
+    new_module.fetcher = module.fetcher.clone();
+    new_module.loader = module.loader.clone();
+    new_module.ext_prelude = module.ext_prelude.clone();
+    for f in module.functions.iter().filter(|f| &**f.file != source) {
+        new_module.register(f.clone());
+    }
+    for r in module.records.iter().filter(|r| &**r.file != source) {
+        new_module.records.push(r.clone());
+    }
+    let start = new_module.functions.len();
+    try!(load_str(source, d, &mut new_module).map_err(ReloadError::Load));
+
+    let mut functions = module.functions.clone();
+    let mut changed = vec![];
+    for f in new_module.functions.drain(start..) {
+        let old = functions.iter().position(|old| &**old.file == source &&
+                                                  old.name == f.name);
+        match old {
+            Some(i) => {
+                if !same_signature(&functions[i], &f) {
+                    return Err(ReloadError::Signature(f.name.clone()));
+                }
+                functions[i] = f;
+                changed.push(i);
+            }
+            None => {
+                changed.push(functions.len());
+                functions.push(f);
+            }
+        }
+    }
+
+    // Resolve the new functions relative to their final position.
+    let mut new_module = Module {
+        functions: functions,
+        ext_prelude: new_module.ext_prelude,
+        intrinsics: new_module.intrinsics,
+        capabilities: new_module.capabilities,
+        fetcher: new_module.fetcher,
+        loader: new_module.loader,
+        warnings: new_module.warnings,
+        records: new_module.records,
+    };
+    for &i in &changed {
+        new_module.functions[i].resolved.set(false);
+    }
+    for &i in &changed {
+        new_module.functions[i].resolve_locals(i, &new_module);
+    }
+    for &i in &changed {
+        let code = bytecode::compile(&new_module.functions[i]);
+        new_module.functions[i].code = Some(Arc::new(code));
+    }
+    *module = Arc::new(new_module);
+    Ok(())
+}
+
+fn same_signature(a: &ast::Function, b: &ast::Function) -> bool {
+    a.ret == b.ret &&
+    a.args.len() == b.args.len() &&
+    a.args.iter().zip(b.args.iter()).all(|(a, b)|
+        a.ty == b.ty && a.lifetime == b.lifetime && a.mutable == b.mutable)
+}
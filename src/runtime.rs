@@ -1,14 +1,25 @@
 use std::sync::Arc;
-use std::collections::HashMap;
-use rand;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use range::Range;
 
 use ast;
+use bytecode;
+use debugger::{Debugger, Location};
+use profiler::Profiler;
+use coverage::Coverage;
+use observer::RuntimeObserver;
+use snapshot;
+use scheduler::{JoinHandle, Scheduler};
+use channel::Channel;
 use intrinsics;
 use embed;
 
+use AtomicFnIndex;
 use FnIndex;
 use Module;
+use ObjectMap;
+use Symbol;
 use Variable;
 use UnsafeRef;
 use TINVOTS;
@@ -42,11 +53,85 @@ pub struct Call {
     pub file: Option<Arc<String>>,
     // was .1
     pub stack_len: usize,
+    /// Stack index of the first slot in the frame.
+    pub frame_start: usize,
     // was .2
     pub local_len: usize,
     pub current_len: usize,
 }
 
+/// Stops a running script from another thread.
+///
+/// The flag is checked at loop iterations and function calls.
+/// A thread started with `go` gets a child handle,
+/// which is interrupted together with the handle of the runtime starting it.
+#[derive(Clone, Debug)]
+pub struct InterruptHandle {
+    inner: Arc<Interrupt>,
+}
+
+#[derive(Debug)]
+struct Interrupt {
+    flag: AtomicBool,
+    parent: Option<InterruptHandle>,
+}
+
+impl InterruptHandle {
+    /// Creates a new interrupt handle.
+    pub fn new() -> InterruptHandle {
+        InterruptHandle {
+            inner: Arc::new(Interrupt {
+                flag: AtomicBool::new(false),
+                parent: None,
+            })
+        }
+    }
+
+    /// Creates a handle that is interrupted by this handle,
+    /// but can also be interrupted alone.
+    pub fn child(&self) -> InterruptHandle {
+        InterruptHandle {
+            inner: Arc::new(Interrupt {
+                flag: AtomicBool::new(false),
+                parent: Some(self.clone()),
+            })
+        }
+    }
+
+    /// Requests the script to stop.
+    pub fn interrupt(&self) {
+        self.inner.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the script is requested to stop.
+    pub fn is_interrupted(&self) -> bool {
+        let mut handle = self;
+        loop {
+            if handle.inner.flag.load(Ordering::Relaxed) { return true; }
+            match handle.inner.parent {
+                Some(ref parent) => handle = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Clears the request, such that the runtime can be used again.
+    pub fn reset(&self) {
+        self.inner.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Fuel shared by a runtime and the runtimes of its threads.
+#[derive(Debug)]
+struct Fuel {
+    /// Whether the fuel is limited.
+    limited: AtomicBool,
+    /// Remaining fuel when limited.
+    remaining: AtomicU64,
+    /// Fuel consumed since the runtime was created.
+    consumed: AtomicU64,
+}
+
 pub struct Runtime {
     pub stack: Vec<Variable>,
     /// name, file, stack_len, local_len.
@@ -54,7 +139,11 @@ pub struct Runtime {
     pub local_stack: Vec<(Arc<String>, usize)>,
     pub current_stack: Vec<(Arc<String>, usize)>,
     pub ret: Arc<String>,
-    pub rng: rand::StdRng,
+    /// The random number generator used by `random`.
+    ///
+    /// Breaking change: this was `rand::StdRng`, which can not be saved in a snapshot.
+    /// It still implements `rand::Rng`, so code using the trait methods works as before.
+    pub rng: snapshot::Rng,
     pub text_type: Variable,
     pub f64_type: Variable,
     pub vec4_type: Variable,
@@ -69,9 +158,71 @@ pub struct Runtime {
     pub option_type: Variable,
     pub result_type: Variable,
     pub thread_type: Variable,
+    pub channel_type: Variable,
     pub closure_type: Variable,
+    /// Fuel budget, shared with the runtimes of threads.
+    fuel: Arc<Fuel>,
+    /// Time when the script is stopped, `None` for no limit.
+    pub deadline: Option<Instant>,
+    interrupt: InterruptHandle,
+    /// Pauses the script at breakpoints and steps.
+    pub debugger: Option<Debugger>,
+    /// Records call counts and time per function.
+    pub profiler: Option<Profiler>,
+    /// Counts executed statements and branches.
+    pub coverage: Option<Coverage>,
+    /// Receives events about calls, errors, threads and loaded modules.
+    pub observer: Option<Arc<RuntimeObserver>>,
+    /// Maximum number of calls on the call stack, `None` for no limit.
+    pub max_call_depth: Option<usize>,
+    /// Approximate maximum native stack in bytes used by nested calls, `None` for no limit.
+    ///
+    /// The stack is checked when calling a function,
+    /// so nested expressions and intrinsics between calls might use a little more.
+    /// Must be less than the stack size of the thread calling `run` or `call_str`.
+    /// Threads started with `go` and `par` get twice this size.
+    /// When `None`, they get `THREAD_STACK_SIZE` and use most of it.
+    pub max_stack_size: Option<usize>,
+    /// Number of worker threads running the threads started with `go`.
+    ///
+    /// Read when the first thread is started.
+    /// Defaults to the number of CPUs.
+    pub go_threads: usize,
+    /// Runs threads started with `go`, shared with the runtimes of the threads.
+    scheduler: Option<Arc<Scheduler>>,
+    /// Number of threads used by `par` loops.
+    ///
+    /// Read when the first `par` loop runs.
+    /// Defaults to the number of CPUs.
+    pub par_threads: usize,
+    /// Runs the parts of `par` loops, shared with the runtimes of threads.
+    par_scheduler: Option<Arc<Scheduler>>,
+    /// Native stack address when called from the host, `0` when not known.
+    stack_base: usize,
+    /// Native stack available to the current thread, `0` when not known.
+    stack_limit: usize,
+    /// Fuel consumed by this runtime that is not yet added to the shared fuel.
+    unflushed_fuel: u64,
+    /// Buffers used by `execute`, reused between calls.
+    execute_buffers: Vec<ExecuteBuffers>,
 }
 
+/// The number of fuel units consumed before they are added to the shared fuel.
+const FUEL_FLUSH: u64 = 1024;
+
+/// The scopes, calls and operands of a running function body.
+#[derive(Default)]
+struct ExecuteBuffers {
+    /// Stack, local stack, current stack and operand lengths.
+    scopes: Vec<(usize, usize, usize, usize)>,
+    /// Function index, stack, local stack and current stack lengths.
+    calls: Vec<(usize, usize, usize, usize)>,
+    operands: Vec<Option<Variable>>,
+}
+
+/// The stack size of threads running `go` and `par`, when `max_stack_size` is `None`.
+pub const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
 #[inline(always)]
 fn resolve<'a>(stack: &'a Vec<Variable>, var: &'a Variable) -> &'a Variable {
     match *var {
@@ -80,6 +231,53 @@ fn resolve<'a>(stack: &'a Vec<Variable>, var: &'a Variable) -> &'a Variable {
     }
 }
 
+// Returns the two numbers on top of the operand stack,
+// or `None` when they are not numbers or the left one has a secret.
+#[inline(always)]
+fn f64_operands(stack: &Vec<Variable>, operands: &[Option<Variable>]) -> Option<(f64, f64)> {
+    let n = operands.len();
+    if n < 2 {return None};
+    match (&operands[n - 2], &operands[n - 1]) {
+        (&Some(ref a), &Some(ref b)) => match (resolve(stack, a), resolve(stack, b)) {
+            (&Variable::F64(a, None), &Variable::F64(b, _)) => Some((a, b)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/// The number of items to check in arguments of a call in tail position.
+const TAIL_CALL_SCAN: usize = 64;
+
+// Returns `true` if the variable refers to the stack from `frame_start`,
+// or `None` when more than `budget` items must be checked.
+fn refers_to_frame(var: &Variable, frame_start: usize, budget: &mut usize) -> Option<bool> {
+    if *budget == 0 { return None; }
+    *budget -= 1;
+    match *var {
+        Variable::Ref(id) => Some(id >= frame_start),
+        Variable::Object(ref obj) => {
+            for v in obj.values() {
+                match refers_to_frame(v, frame_start, budget) {
+                    Some(false) => {}
+                    x => return x,
+                }
+            }
+            Some(false)
+        }
+        Variable::Array(ref arr) => {
+            for v in arr.iter() {
+                match refers_to_frame(v, frame_start, budget) {
+                    Some(false) => {}
+                    x => return x,
+                }
+            }
+            Some(false)
+        }
+        _ => Some(false)
+    }
+}
+
 // Looks up an item from a variable property.
 fn item_lookup(
     module: &Module,
@@ -91,15 +289,16 @@ fn item_lookup(
     expr_j: &mut usize,
     insert: bool, // Whether to insert key in object.
     last: bool,   // Whether it is the last property.
+    left: bool,   // Whether the item is on the left side of assignment.
+    scratch: &mut Variable, // Stores items read from packed arrays.
 ) -> Result<*mut Variable, String> {
     use ast::Id;
-    use std::collections::hash_map::Entry;
 
     unsafe {
         match *var {
             Variable::Object(ref mut obj) => {
-                let id = match prop {
-                    &Id::String(_, ref id) => id.clone(),
+                let (id, cache) = match prop {
+                    &Id::String(_, ref id, ref cache) => (id.clone(), Some(cache)),
                     &Id::Expression(_) => {
                         let id = start_stack_len + *expr_j;
                         // Resolve reference of computed expression.
@@ -111,7 +310,15 @@ fn item_lookup(
                         match &mut stack[id] {
                             &mut Variable::Text(ref id) => {
                                 *expr_j += 1;
-                                id.clone()
+                                // Only intern the key when inserting it.
+                                match Symbol::get(id) {
+                                    Some(id) => (id, None),
+                                    None if insert && last => (Symbol::from(id.clone()), None),
+                                    None => return Err(module.error_fnindex(prop.source_range(),
+                                        &format!("{}\nObject has no key `{}`",
+                                            stack_trace(call_stack), id),
+                                            call_stack.last().unwrap().index))
+                                }
                             }
                             _ => return Err(module.error_fnindex(prop.source_range(),
                                 &format!("{}\nExpected string",
@@ -124,11 +331,19 @@ fn item_lookup(
                             stack_trace(call_stack)),
                             call_stack.last().unwrap().index))
                 };
-                let v = match Arc::make_mut(obj).entry(id.clone()) {
-                    Entry::Vacant(vac) => {
+                let obj = Arc::make_mut(obj);
+                let slot = match cache {
+                    Some(cache) => obj.cached_slot(&id, cache),
+                    None => obj.slot(&id),
+                };
+                let v = match slot {
+                    Some(i) => obj.slot_mut(i),
+                    None => {
                         if insert && last {
                             // Insert a key to overwrite with new value.
-                            vac.insert(Variable::Return)
+                            obj.insert(id, Variable::Return);
+                            let i = obj.len() - 1;
+                            obj.slot_mut(i)
                         } else {
                             return Err(module.error_fnindex(prop.source_range(),
                                 &format!("{}\nObject has no key `{}`",
@@ -136,7 +351,6 @@ fn item_lookup(
                                     call_stack.last().unwrap().index));
                         }
                     }
-                    Entry::Occupied(v) => v.into_mut()
                 };
                 // Resolve reference.
                 if let &mut Variable::Ref(id) = v {
@@ -151,33 +365,36 @@ fn item_lookup(
                     Ok(v)
                 }
             }
-            Variable::Array(ref mut arr) => {
-                let id = match prop {
-                    &Id::F64(_, id) => id,
-                    &Id::Expression(_) => {
-                        let id = start_stack_len + *expr_j;
-                        // Resolve reference of computed expression.
-                        let id = if let &Variable::Ref(ref_id) = &stack[id] {
-                                ref_id
-                            } else {
-                                id
-                            };
-                        match &mut stack[id] {
-                            &mut Variable::F64(id, _) => {
-                                *expr_j += 1;
-                                id
-                            }
-                            _ => return Err(module.error_fnindex(prop.source_range(),
-                                            &format!("{}\nExpected number",
-                                                stack_trace(call_stack)),
-                                                call_stack.last().unwrap().index))
-                        }
-                    }
-                    &Id::String(range, _) => return Err(module.error_fnindex(range,
-                        &format!("{}\nExpected number",
-                            stack_trace(call_stack)),
-                            call_stack.last().unwrap().index))
+            Variable::F64Array(_) | Variable::Vec4Array(_) if left => {
+                // Packed items can not be referenced, so unpack before changing.
+                (*var).unpack();
+                item_lookup(module, var, stack, call_stack, prop, start_stack_len,
+                            expr_j, insert, last, left, scratch)
+            }
+            Variable::F64Array(_) | Variable::Vec4Array(_) => {
+                let id = try!(index_id(module, stack, call_stack, prop,
+                                       start_stack_len, expr_j));
+                let item = match *var {
+                    Variable::F64Array(ref arr) =>
+                        arr.get(id as usize).map(|&x| Variable::f64(x)),
+                    Variable::Vec4Array(ref arr) =>
+                        arr.get(id as usize).map(|&x| Variable::Vec4(x)),
+                    _ => unreachable!(),
                 };
+                match item {
+                    None => return Err(module.error_fnindex(prop.source_range(),
+                                       &format!("{}\nOut of bounds `{}`",
+                                                stack_trace(call_stack), id),
+                                                call_stack.last().unwrap().index)),
+                    Some(x) => {
+                        *scratch = x;
+                        Ok(scratch)
+                    }
+                }
+            }
+            Variable::Array(ref mut arr) => {
+                let id = try!(index_id(module, stack, call_stack, prop,
+                                       start_stack_len, expr_j));
                 let v = match Arc::make_mut(arr).get_mut(id as usize) {
                     None => return Err(module.error_fnindex(prop.source_range(),
                                        &format!("{}\nOut of bounds `{}`",
@@ -206,6 +423,45 @@ fn item_lookup(
     }
 }
 
+/// Gets the index of an array item.
+fn index_id(
+    module: &Module,
+    stack: &mut [Variable],
+    call_stack: &[Call],
+    prop: &ast::Id,
+    start_stack_len: usize,
+    expr_j: &mut usize,
+) -> Result<f64, String> {
+    use ast::Id;
+
+    Ok(match prop {
+        &Id::F64(_, id) => id,
+        &Id::Expression(_) => {
+            let id = start_stack_len + *expr_j;
+            // Resolve reference of computed expression.
+            let id = if let &Variable::Ref(ref_id) = &stack[id] {
+                    ref_id
+                } else {
+                    id
+                };
+            match &mut stack[id] {
+                &mut Variable::F64(id, _) => {
+                    *expr_j += 1;
+                    id
+                }
+                _ => return Err(module.error_fnindex(prop.source_range(),
+                                &format!("{}\nExpected number",
+                                    stack_trace(call_stack)),
+                                    call_stack.last().unwrap().index))
+            }
+        }
+        &Id::String(range, _, _) => return Err(module.error_fnindex(range,
+            &format!("{}\nExpected number",
+                stack_trace(call_stack)),
+                call_stack.last().unwrap().index))
+    })
+}
+
 impl Runtime {
     pub fn new() -> Runtime {
         Runtime {
@@ -214,7 +470,7 @@ impl Runtime {
             local_stack: vec![],
             current_stack: vec![],
             ret: Arc::new("return".into()),
-            rng: rand::StdRng::new().unwrap(),
+            rng: snapshot::Rng::new(),
             text_type: Variable::Text(Arc::new("string".into())),
             f64_type: Variable::Text(Arc::new("number".into())),
             vec4_type: Variable::Text(Arc::new("vec4".into())),
@@ -229,10 +485,71 @@ impl Runtime {
             option_type: Variable::Text(Arc::new("option".into())),
             result_type: Variable::Text(Arc::new("result".into())),
             thread_type: Variable::Text(Arc::new("thread".into())),
+            channel_type: Variable::Text(Arc::new("channel".into())),
             closure_type: Variable::Text(Arc::new("closure".into())),
+            fuel: Arc::new(Fuel {
+                limited: AtomicBool::new(false),
+                remaining: AtomicU64::new(0),
+                consumed: AtomicU64::new(0),
+            }),
+            deadline: None,
+            interrupt: InterruptHandle::new(),
+            debugger: None,
+            profiler: None,
+            coverage: None,
+            observer: None,
+            max_call_depth: None,
+            max_stack_size: None,
+            go_threads: ::std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            scheduler: None,
+            par_scheduler: None,
+            par_threads: ::std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            stack_base: 0,
+            stack_limit: 0,
+            unflushed_fuel: 0,
+            execute_buffers: vec![],
+        }
+    }
+
+    /// Returns a handle for stopping the script from another thread.
+    ///
+    /// Interrupting also stops threads started with `go`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Sets the remaining fuel, `None` for no limit.
+    ///
+    /// One unit of fuel is consumed per loop iteration and function call.
+    /// Threads started with `go` and `par` use the same fuel.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel.remaining.store(fuel.unwrap_or(0), Ordering::SeqCst);
+        self.fuel.limited.store(fuel.is_some(), Ordering::SeqCst);
+    }
+
+    /// Returns the remaining fuel, `None` for no limit.
+    pub fn fuel(&self) -> Option<u64> {
+        if self.fuel.limited.load(Ordering::SeqCst) {
+            Some(self.fuel.remaining.load(Ordering::SeqCst))
+        } else {
+            None
         }
     }
 
+    /// Returns the fuel consumed since the runtime was created,
+    /// including the fuel consumed by threads.
+    ///
+    /// Threads that are still running add their fuel in batches.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel.consumed.load(Ordering::SeqCst) + self.unflushed_fuel
+    }
+
+    /// Adds the fuel consumed by this runtime to the shared fuel.
+    fn flush_fuel(&mut self) {
+        self.fuel.consumed.fetch_add(self.unflushed_fuel, Ordering::Relaxed);
+        self.unflushed_fuel = 0;
+    }
+
     pub fn pop<T: embed::PopVariable>(&mut self) -> Result<T, String> {
         let v = self.stack.pop().unwrap_or_else(|| panic!(TINVOTS));
         T::pop_var(self, self.resolve(&v))
@@ -291,6 +608,21 @@ impl Runtime {
         }))
     }
 
+    fn observe_exit(&self, name: &Arc<String>, ret: Option<&Variable>) {
+        if let Some(ref observer) = self.observer {
+            observer.exit(self, name, ret);
+        }
+    }
+
+    /// Notifies the observer when `?` propagates an error.
+    fn observe_try(&self, res: &Result<(Option<Variable>, Flow), String>) {
+        if let Some(ref observer) = self.observer {
+            if let Ok((Some(Variable::Result(Err(ref err))), Flow::Return)) = *res {
+                observer.propagate_error(self, err);
+            }
+        }
+    }
+
     #[inline(always)]
     pub fn push_fn(
         &mut self,
@@ -306,6 +638,7 @@ impl Runtime {
             index: index,
             file: file,
             stack_len: st,
+            frame_start: st,
             local_len: lc,
             current_len: cu,
         });
@@ -359,6 +692,16 @@ impl Runtime {
             Bool(ref b) => Ok((Some(::Variable::bool(b.val)), Flow::Continue)),
             For(ref for_expr) => self.for_expr(for_expr, module),
             ForN(ref for_n_expr) => self.for_n_expr(for_n_expr, module),
+            Sum(ref for_n_expr) |
+            SumVec4(ref for_n_expr) |
+            Prod(ref for_n_expr) |
+            Min(ref for_n_expr) |
+            Max(ref for_n_expr) |
+            Sift(ref for_n_expr) |
+            Any(ref for_n_expr) |
+            All(ref for_n_expr) if for_n_expr.par && module.capabilities.threads => {
+                self.par_n_expr(expr, for_n_expr, module)
+            }
             Sum(ref for_n_expr) => self.sum_n_expr(for_n_expr, module),
             SumVec4(ref for_n_expr) => self.sum_vec4_n_expr(for_n_expr, module),
             Prod(ref for_n_expr) => self.prod_n_expr(for_n_expr, module),
@@ -368,6 +711,8 @@ impl Runtime {
             Any(ref for_n_expr) => self.any_n_expr(for_n_expr, module),
             All(ref for_n_expr) => self.all_n_expr(for_n_expr, module),
             If(ref if_expr) => self.if_expr(if_expr, module),
+            Match(ref match_expr) => self.match_expr(match_expr, module),
+            Interpolation(ref interpolation) => self.interpolation(interpolation, module),
             Compare(ref compare) => self.compare(compare, module),
             Variable(_, ref var) => Ok((Some(var.clone()), Flow::Continue)),
             Try(ref expr) => self.try(expr, side, module),
@@ -530,31 +875,34 @@ impl Runtime {
                 err.trace.push(module.error(expr.source_range(),
                     &format!("In function `{}`{}",
                     &call.fn_name, file), self));
+                if let Some(observer) = self.observer.clone() {
+                    observer.propagate_error(self, &err);
+                }
                 Ok((Some(Variable::Result(Err(err))), Flow::Return))
             }
         }
     }
 
     pub fn run(&mut self, module: &Arc<Module>) -> Result<(), String> {
-        use std::cell::Cell;
 
         let name: Arc<String> = Arc::new("main".into());
+        // Function indices are relative to the last call, e.g. after restoring a snapshot.
+        let relative = self.call_stack.last().map(|c| c.index).unwrap_or(0);
         let call = ast::Call {
             name: name.clone(),
-            f_index: Cell::new(module.find_function(&name, 0)),
+            f_index: AtomicFnIndex::new(module.find_function(&name, relative)),
             args: vec![],
             custom_source: None,
             source_range: Range::empty(0),
         };
         match call.f_index.get() {
             FnIndex::Loaded(f_index) => {
-                let f = &module.functions[f_index as usize];
+                let f = &module.functions[(f_index + relative as isize) as usize];
                 if f.args.len() != 0 {
                     return Err(module.error(f.args[0].source_range,
                                "`main` should not have arguments", self))
                 }
-                try!(self.call(&call, &module));
-                Ok(())
+                self.call_from_host(&call, &module)
             }
             _ => return Err(module.error(call.source_range,
                                "Could not find function `main`", self))
@@ -571,6 +919,13 @@ impl Runtime {
         let lc = self.local_stack.len();
         let cu = self.current_stack.len();
         for e in &block.expressions {
+            if self.debugger.is_some() {
+                self.debug_statement(e.source_range(), module);
+            }
+            if self.coverage.is_some() {
+                let file = &module.functions[self.call_stack.last().unwrap().index].file;
+                self.coverage.as_mut().unwrap().statement(file, e.source_range());
+            }
             expect = match try!(self.expression(e, Side::Right, module)) {
                 (x, Flow::Continue) => x,
                 x => {
@@ -588,168 +943,1121 @@ impl Runtime {
         Ok((expect, Flow::Continue))
     }
 
-    pub fn go(&mut self, go: &ast::Go, module: &Arc<Module>) -> Result<(Option<Variable>, Flow), String> {
-        use std::thread::{self, JoinHandle};
-        use std::cell::Cell;
-        use Thread;
-
-        let n = go.call.args.len();
-        let mut stack = vec![];
-        let relative = self.call_stack.last().map(|c| c.index).unwrap();
-        let mut fake_call = ast::Call {
-            name: go.call.name.clone(),
-            f_index: Cell::new(module.find_function(&go.call.name, relative)),
-            args: Vec::with_capacity(n),
-            custom_source: None,
-            source_range: go.call.source_range,
-        };
-        // Evaluate the arguments and put a deep clone on the new stack.
-        // This prevents the arguments from containing any reference to other variables.
-        for (i, arg) in go.call.args.iter().enumerate() {
-            let v = match try!(self.expression(arg, Side::Right, module)) {
-                (Some(x), Flow::Continue) => x,
-                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-                _ => return Err(module.error(arg.source_range(),
-                                &format!("{}\nExpected something. \
-                                Expression did not return a value.",
-                                self.stack_trace()), self))
-            };
-            stack.push(v.deep_clone(&self.stack));
-            fake_call.args.push(ast::Expression::Variable(
-                go.call.args[i].source_range(), Variable::Ref(n-i-1)));
-        }
-        stack.reverse();
-
-        let last_call = self.call_stack.last().unwrap();
-        let new_rt = Runtime {
-            stack: stack,
-            local_stack: vec![],
-            current_stack: vec![],
-            // Add last call because of loaded functions
-            // use relative index to the function it is calling from.
-            call_stack: vec![Call {
-                fn_name: last_call.fn_name.clone(),
-                index: last_call.index,
-                file: last_call.file.clone(),
-                stack_len: 0,
-                local_len: 0,
-                current_len: 0,
-            }],
-            rng: self.rng.clone(),
-            ret: self.ret.clone(),
-            ref_type: self.ref_type.clone(),
-            option_type: self.option_type.clone(),
-            array_type: self.array_type.clone(),
-            link_type: self.link_type.clone(),
-            bool_type: self.bool_type.clone(),
-            object_type: self.object_type.clone(),
-            text_type: self.text_type.clone(),
-            f64_type: self.f64_type.clone(),
-            thread_type: self.thread_type.clone(),
-            unsafe_ref_type: self.unsafe_ref_type.clone(),
-            return_type: self.return_type.clone(),
-            rust_object_type: self.rust_object_type.clone(),
-            vec4_type: self.vec4_type.clone(),
-            result_type: self.result_type.clone(),
-            closure_type: self.closure_type.clone(),
-        };
-        let new_module: Module = (**module).clone();
-        let handle: JoinHandle<Result<Variable, String>> = thread::spawn(move || {
-            let mut new_rt = new_rt;
-            let new_module = Arc::new(new_module);
-            let fake_call = fake_call;
-            Ok(match new_rt.call(&fake_call, &new_module) {
-                Err(err) => return Err(err),
-                Ok((None, _)) => {
-                    new_rt.stack.pop().expect(TINVOTS)
-                }
-                Ok((Some(x), _)) => x,
-            }.deep_clone(&new_rt.stack))
-        });
-        Ok((Some(Variable::Thread(Thread::new(handle))), Flow::Continue))
+    /// Executes the compiled body of a function.
+    fn execute(
+        &mut self,
+        code: &bytecode::Code,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        let mut buffers = self.execute_buffers.pop().unwrap_or_default();
+        let res = self.execute_with(code, &mut buffers, module);
+        buffers.scopes.clear();
+        buffers.calls.clear();
+        buffers.operands.clear();
+        self.execute_buffers.push(buffers);
+        res
     }
 
-    pub fn call_closure(
+    fn execute_with(
         &mut self,
-        call: &ast::CallClosure,
+        code: &bytecode::Code,
+        buffers: &mut ExecuteBuffers,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
-        // Find item.
-        let item = match try!(self.item(&call.item, Side::Right, module)) {
-            (Some(x), Flow::Continue) => x,
-            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-            _ => return Err(module.error(call.item.source_range,
-                            &format!("{}\nExpected something. \
-                            Check that item returns a value.",
-                            self.stack_trace()), self))
-        };
+        use bytecode::Instruction::*;
+        use FnExternalRef;
 
-        let (f, env) = match self.resolve(&item) {
-            &Variable::Closure(ref f, ref env) => (f.clone(), env.clone()),
-            x => return Err(module.error(call.source_range,
-                    &self.expected(x, "closure"), self))
-        };
+        let scopes = &mut buffers.scopes;
+        let calls = &mut buffers.calls;
+        let operands = &mut buffers.operands;
+        // Replaced by tail calls.
+        let mut code = code;
 
-        if call.arg_len() != f.args.len() {
-            return Err(module.error(call.source_range,
-                &format!("{}\nExpected {} arguments but found {}",
-                self.stack_trace(),
-                f.args.len(),
-                call.arg_len()), self));
-        }
-        // Arguments must be computed.
-        if f.returns() {
-            // Add return value before arguments on the stack.
-            // The stack value should remain, but the local should not.
-            self.stack.push(Variable::Return);
-        }
-        let st = self.stack.len();
-        let lc = self.local_stack.len();
-        let cu = self.current_stack.len();
-        for arg in &call.args {
-            match try!(self.expression(arg, Side::Right, module)) {
-                (Some(x), Flow::Continue) => self.stack.push(x),
-                (None, Flow::Continue) => {}
-                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
-                _ => return Err(module.error(arg.source_range(),
-                                &format!("{}\nExpected something. \
-                                Check that expression returns a value.",
-                                self.stack_trace()), self))
-            };
+        fn unwind(
+            rt: &mut Runtime,
+            scopes: &mut Vec<(usize, usize, usize, usize)>,
+            operands: &mut Vec<Option<Variable>>,
+            depth: usize
+        ) {
+            while scopes.len() > depth {
+                let (st, lc, cu, op) = scopes.pop().unwrap();
+                rt.stack.truncate(st);
+                rt.local_stack.truncate(lc);
+                rt.current_stack.truncate(cu);
+                operands.truncate(op);
+            }
         }
 
-        // Look for variable in current stack.
-        if f.currents.len() > 0 {
-            for current in &f.currents {
-                let mut res = None;
-                for &(ref cname, ind) in self.current_stack.iter().rev() {
-                    if cname == &current.name {
-                        res = Some(ind);
-                        break;
-                    }
+        let mut pc = 0;
+        while pc < code.instructions.len() {
+            let (res, innermost) = match code.instructions[pc] {
+                Push(ref v) => {
+                    operands.push(Some(v.to_variable()));
+                    pc += 1;
+                    continue;
                 }
-                if let Some(ind) = res {
-                    self.local_stack.push((current.name.clone(), self.stack.len()));
-                    self.stack.push(Variable::Ref(ind));
-                } else {
-                    return Err(module.error(call.source_range, &format!(
-                        "{}\nCould not find urrent variable `{}`",
-                            self.stack_trace(), current.name), self));
+                PushNone => {
+                    operands.push(None);
+                    pc += 1;
+                    continue;
                 }
-            }
-        }
-
-        self.push_fn(call.item.name.clone(), env.relative, Some(f.file.clone()), st, lc, cu);
-        if f.returns() {
-            self.local_stack.push((self.ret.clone(), st - 1));
-        }
-        for (i, arg) in f.args.iter().enumerate() {
-            // Do not resolve locals to keep fixed length from end of stack.
-            self.local_stack.push((arg.name.clone(), st + i));
-        }
-        let (x, flow) = try!(self.expression(&f.expr, Side::Right, &env.module));
-        match flow {
-            Flow::Break(None) =>
+                Pop => {
+                    operands.pop();
+                    pc += 1;
+                    continue;
+                }
+                Local(slot) => {
+                    let id = self.call_stack.last().unwrap().frame_start + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                            ref_id
+                        } else {
+                            id
+                        };
+                    operands.push(Some(Variable::Ref(id)));
+                    pc += 1;
+                    continue;
+                }
+                ScopeEnter => {
+                    scopes.push((self.stack.len(), self.local_stack.len(),
+                                 self.current_stack.len(), operands.len()));
+                    pc += 1;
+                    continue;
+                }
+                ScopeExit => {
+                    let depth = scopes.len() - 1;
+                    unwind(self, scopes, operands, depth);
+                    pc += 1;
+                    continue;
+                }
+                BlockExit => {
+                    let v = operands.pop().expect(TINVOTS);
+                    let depth = scopes.len() - 1;
+                    unwind(self, scopes, operands, depth);
+                    operands.push(v);
+                    pc += 1;
+                    continue;
+                }
+                Jump(target) => {
+                    pc = target;
+                    continue;
+                }
+                JumpIfFalse(target, range, msg) => {
+                    let val = match operands.pop().expect(TINVOTS) {
+                        Some(x) => match *self.resolve(&x) {
+                            Variable::Bool(val, _) => Some(val),
+                            _ => None
+                        },
+                        None => None
+                    };
+                    match val {
+                        Some(true) => pc += 1,
+                        Some(false) => pc = target,
+                        None => return Err(self.instruction_error(range, msg, module))
+                    }
+                    continue;
+                }
+                OrElse(target) | AndAlso(target) => {
+                    let stop = if let OrElse(_) = code.instructions[pc] { true } else { false };
+                    let v = match operands.last() {
+                        Some(&Some(ref x)) => match *self.resolve(x) {
+                            Variable::Bool(val, ref sec) if val == stop =>
+                                Some(Variable::Bool(val, sec.clone())),
+                            _ => None
+                        },
+                        _ => None
+                    };
+                    if let Some(v) = v {
+                        *operands.last_mut().unwrap() = Some(v);
+                        pc = target;
+                    } else {
+                        pc += 1;
+                    }
+                    continue;
+                }
+                BinOp(op, range) => {
+                    if let Some((a, b)) = f64_operands(&self.stack, operands) {
+                        use ast::BinOp::*;
+
+                        let v = match op {
+                            Add => Some(a + b),
+                            Sub => Some(a - b),
+                            Mul => Some(a * b),
+                            Div => Some(a / b),
+                            Rem => Some(a % b),
+                            Pow => Some(a.powf(b)),
+                            _ => None
+                        };
+                        if let Some(v) = v {
+                            operands.pop();
+                            *operands.last_mut().unwrap() = Some(Variable::f64(v));
+                            pc += 1;
+                            continue;
+                        }
+                    }
+                    let right = operands.pop().expect(TINVOTS);
+                    let left = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from left argument", module))
+                    };
+                    let right = match right {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from right argument", module))
+                    };
+                    let v = try!(self.binop_values(op, range, &left, &right, module));
+                    operands.push(Some(v));
+                    pc += 1;
+                    continue;
+                }
+                Compare(op, range) => {
+                    if let Some((a, b)) = f64_operands(&self.stack, operands) {
+                        use ast::CompareOp::*;
+
+                        let v = match op {
+                            Less => a < b,
+                            LessOrEqual => a <= b,
+                            Greater => a > b,
+                            GreaterOrEqual => a >= b,
+                            Equal => a == b,
+                            NotEqual => a != b
+                        };
+                        operands.pop();
+                        *operands.last_mut().unwrap() = Some(Variable::bool(v));
+                        pc += 1;
+                        continue;
+                    }
+                    let right = operands.pop().expect(TINVOTS);
+                    let left = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from the left argument", module))
+                    };
+                    let right = match right {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from the right argument", module))
+                    };
+                    let v = try!(self.compare_values(op, range, &left, &right, module));
+                    operands.push(Some(v));
+                    pc += 1;
+                    continue;
+                }
+                UnOp(op, range) => {
+                    let v = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from unary argument", module))
+                    };
+                    let v = try!(self.unop_value(op, range, &v, module));
+                    operands.push(Some(v));
+                    pc += 1;
+                    continue;
+                }
+                ExpectNone(range, msg) => {
+                    if operands.pop().expect(TINVOTS).is_some() {
+                        return Err(self.instruction_error(range, msg, module));
+                    }
+                    pc += 1;
+                    continue;
+                }
+                ExpectSome(range, msg) => {
+                    if let Some(&None) = operands.last() {
+                        return Err(self.instruction_error(range, msg, module));
+                    }
+                    pc += 1;
+                    continue;
+                }
+                PushStack(range, msg) => {
+                    match operands.pop().expect(TINVOTS) {
+                        Some(x) => self.stack.push(x),
+                        None => return Err(self.instruction_error(range, msg, module))
+                    }
+                    pc += 1;
+                    continue;
+                }
+                Array(_) | ArrayFill(_) | Object(_) | Link(_) | Vec4(_) | Index(..) => {
+                    (try!(self.construct(&code.instructions[pc], operands, module)), None)
+                }
+                ConsumeFuel(range) => {
+                    try!(self.consume_fuel(range, module));
+                    pc += 1;
+                    continue;
+                }
+                Break(l) => {
+                    let l = &code.loops[l];
+                    unwind(self, scopes, operands, l.depth);
+                    pc = l.break_target;
+                    continue;
+                }
+                Continue(l) => {
+                    let l = &code.loops[l];
+                    unwind(self, scopes, operands, l.depth);
+                    pc = l.continue_target;
+                    continue;
+                }
+                ForNStart(ref name, range) => {
+                    let end = match operands.pop().expect(TINVOTS) {
+                        Some(x) => match *self.resolve(&x) {
+                            Variable::F64(val, _) => val,
+                            ref x => return Err(module.error(range,
+                                &self.expected(x, "number"), self))
+                        },
+                        None => return Err(self.instruction_error(range,
+                            "Expected number from for end", module))
+                    };
+                    let start = match operands.pop().expect(TINVOTS) {
+                        Some(x) => match *self.resolve(&x) {
+                            Variable::F64(val, _) => val,
+                            ref x => return Err(module.error(range,
+                                &self.expected(x, "number"), self))
+                        },
+                        None => return Err(self.instruction_error(range,
+                            "Expected number from for start", module))
+                    };
+                    // Initialize counter.
+                    self.local_stack.push((name.clone(), self.stack.len()));
+                    self.stack.push(Variable::f64(start));
+                    operands.push(Some(Variable::f64(end)));
+                    pc += 1;
+                    continue;
+                }
+                ForNCheck(target, range) => {
+                    let st = scopes.last().unwrap().0;
+                    let end = match operands.last() {
+                        Some(&Some(Variable::F64(end, _))) => end,
+                        _ => panic!(TINVOTS)
+                    };
+                    match self.stack[st] {
+                        Variable::F64(val, _) => {
+                            if val < end { pc += 1 } else { pc = target }
+                        }
+                        ref x => return Err(module.error(range,
+                                            &self.expected(x, "number"), self))
+                    }
+                    continue;
+                }
+                ShortLoop(kind) => {
+                    use bytecode::ShortLoop as S;
+
+                    operands.push(Some(match kind {
+                        S::Sum => Variable::f64(0.0),
+                        S::SumVec4 => Variable::Vec4([0.0; 4]),
+                        S::Prod => Variable::f64(1.0),
+                        S::Min | S::Max => Variable::f64(::std::f64::NAN),
+                        S::Any => Variable::bool(false),
+                        S::All => Variable::bool(true),
+                        S::Sift => Variable::Array(Arc::new(::array::PersistentVec::new())),
+                    }));
+                    pc += 1;
+                    continue;
+                }
+                Accumulate(kind, range, l) => {
+                    let x = operands.pop().expect(TINVOTS);
+                    let (st, _, _, op) = *scopes.last().unwrap();
+                    let ind = match self.stack[st] {
+                        Variable::F64(ind, _) => ind,
+                        _ => panic!(TINVOTS)
+                    };
+                    let acc = operands[op - 1].as_mut().expect(TINVOTS);
+                    if try!(self.accumulate(kind, range, x, acc, ind, module)) {
+                        let l = &code.loops[l];
+                        unwind(self, scopes, operands, l.depth);
+                        pc = l.break_target;
+                    } else {
+                        pc += 1;
+                    }
+                    continue;
+                }
+                ForNIncrement(top, range) => {
+                    let st = scopes.last().unwrap().0;
+                    let end = match operands.last() {
+                        Some(&Some(Variable::F64(end, _))) => end,
+                        _ => panic!(TINVOTS)
+                    };
+                    let val = if let Variable::F64(ref mut val, _) = self.stack[st] {
+                        *val += 1.0;
+                        Some(*val)
+                    } else { None };
+                    match val {
+                        Some(val) => if val < end { pc = top } else { pc += 1 },
+                        None => return Err(module.error(range,
+                            &self.expected(&self.stack[st], "number"), self))
+                    }
+                    continue;
+                }
+                CallBegin(ind) => {
+                    let call = &code.calls[ind];
+                    let lc = self.local_stack.len();
+                    let cu = self.current_stack.len();
+                    let new_index = if let FnIndex::Loaded(f_index) = call.f_index.get() {
+                        let relative = self.call_stack.last().map(|c| c.index).unwrap_or(0);
+                        let new_index = (f_index + relative as isize) as usize;
+                        let f = &module.functions[new_index];
+                        if call.arg_len() != f.args.len() {
+                            return Err(module.error(call.source_range,
+                                &format!("{}\nExpected {} arguments but found {}",
+                                self.stack_trace(),
+                                f.args.len(),
+                                call.arg_len()), self));
+                        }
+                        if f.returns() {
+                            // Add return value before arguments on the stack.
+                            self.stack.push(Variable::Return);
+                        }
+                        new_index
+                    } else { 0 };
+                    calls.push((new_index, self.stack.len(), lc, cu));
+                    pc += 1;
+                    continue;
+                }
+                PushArg(range, strict) => {
+                    match operands.pop().expect(TINVOTS) {
+                        Some(x) => self.stack.push(x),
+                        None => if strict {
+                            return Err(self.instruction_error(range,
+                                "Expected something. \
+                                Expression did not return a value.", module))
+                        }
+                    }
+                    pc += 1;
+                    continue;
+                }
+                TailCall(ind) => {
+                    let call = &code.calls[ind];
+                    let (new_index, st, _, _) = *calls.last().unwrap();
+                    if try!(self.tail_call(call, new_index, st, module)) {
+                        code = module.functions[new_index].code.as_ref().unwrap();
+                        scopes.clear();
+                        calls.clear();
+                        operands.clear();
+                        pc = 0;
+                        continue;
+                    }
+                    let (new_index, st, lc, cu) = calls.pop().unwrap();
+                    (try!(self.call_loaded(call, new_index, st, lc, cu, module)), None)
+                }
+                CallEnd(ind) => {
+                    let call = &code.calls[ind];
+                    (match call.f_index.get() {
+                        FnIndex::Loaded(_) => {
+                            let (new_index, st, lc, cu) = calls.pop().unwrap();
+                            try!(self.call_loaded(call, new_index, st, lc, cu, module))
+                        }
+                        FnIndex::Intrinsic(index) => {
+                            // Every argument is on the stack.
+                            let st = self.stack.len() - call.args.len();
+                            let lc = self.local_stack.len();
+                            let cu = self.current_stack.len();
+                            (try!(intrinsics::call_standard_pushed(
+                                self, index, call, module, st, lc, cu)), Flow::Continue)
+                        }
+                        FnIndex::ExternalVoid(FnExternalRef(f)) => {
+                            try!(self.call_external(call, f, module));
+                            (None, Flow::Continue)
+                        }
+                        FnIndex::ExternalReturn(FnExternalRef(f)) => {
+                            try!(self.call_external(call, f, module));
+                            (Some(self.stack.pop().expect(TINVOTS)), Flow::Continue)
+                        }
+                        FnIndex::None => {
+                            return Err(module.error(call.source_range,
+                                &format!("{}\nUnknown function `{}`",
+                                    self.stack_trace(), call.name), self))
+                        }
+                    }, None)
+                }
+                Assign(op, ref left, range) => {
+                    let b = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from the right side", module))
+                    };
+                    match try!(self.assign_value(op, left, b, module)) {
+                        (_, Flow::Continue) => {
+                            pc += 1;
+                            continue;
+                        }
+                        x => (x, None)
+                    }
+                }
+                AssignLocal(op, slot, range) => {
+                    let b = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from the right side", module))
+                    };
+                    let id = self.call_stack.last().unwrap().frame_start + slot;
+                    let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                            ref_id
+                        } else {
+                            id
+                        };
+                    try!(self.assign_to(op, Variable::Ref(id), b, range, module));
+                    pc += 1;
+                    continue;
+                }
+                Return(ref item, range) => {
+                    let b = match operands.pop().expect(TINVOTS) {
+                        Some(x) => x,
+                        None => return Err(self.instruction_error(range,
+                            "Expected something from the right side", module))
+                    };
+                    let (x, _) = try!(self.assign_value(ast::AssignOp::Set, item, b, module));
+                    ((x, Flow::Return), None)
+                }
+                Eval(ref expr, innermost) => {
+                    (try!(self.expression(expr, Side::Right, module)), innermost)
+                }
+            };
+            match res {
+                (x, Flow::Continue) => operands.push(x),
+                (x, Flow::Return) => {
+                    unwind(self, scopes, operands, 0);
+                    return Ok((x, Flow::Return));
+                }
+                (x, flow) => {
+                    // Find the loop to break or continue.
+                    let (label, is_break) = match flow {
+                        Flow::Break(ref label) => (label.clone(), true),
+                        Flow::ContinueLoop(ref label) => (label.clone(), false),
+                        _ => unreachable!()
+                    };
+                    let mut found = innermost;
+                    if let Some(ref label) = label {
+                        while let Some(l) = found {
+                            if code.loops[l].label.as_ref() == Some(label) { break; }
+                            found = code.loops[l].parent;
+                        }
+                    }
+                    match found {
+                        Some(l) => {
+                            let l = &code.loops[l];
+                            unwind(self, scopes, operands, l.depth);
+                            pc = if is_break { l.break_target } else { l.continue_target };
+                            continue;
+                        }
+                        None => {
+                            unwind(self, scopes, operands, 0);
+                            return Ok((x, flow));
+                        }
+                    }
+                }
+            }
+            pc += 1;
+        }
+        Ok((operands.pop().unwrap_or(None), Flow::Continue))
+    }
+
+    /// Formats the error of an instruction in `execute`.
+    ///
+    /// Kept out of `execute` such that its native stack frame stays small.
+    #[inline(never)]
+    fn instruction_error(&self, range: Range, msg: &str, module: &Arc<Module>) -> String {
+        module.error(range, &format!("{}\n{}", self.stack_trace(), msg), self)
+    }
+
+    /// Creates a value from operands of `execute`.
+    ///
+    /// Kept out of `execute` such that its native stack frame stays small.
+    #[inline(never)]
+    fn construct(
+        &mut self,
+        instruction: &bytecode::Instruction,
+        operands: &mut Vec<Option<Variable>>,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use bytecode::Instruction::*;
+
+        match *instruction {
+            Array(n) => {
+                let first = operands.len() - n;
+                let array: Vec<Variable> = operands.drain(first..)
+                    .map(|x| x.expect(TINVOTS)).collect();
+                Ok((Some(Variable::Array(Arc::new(array.into()))), Flow::Continue))
+            }
+            ArrayFill(range) => {
+                let n = operands.pop().expect(TINVOTS).expect(TINVOTS);
+                let fill = operands.pop().expect(TINVOTS).expect(TINVOTS);
+                let v = match (self.resolve(&fill), self.resolve(&n)) {
+                    (x, &Variable::F64(n, _)) => {
+                        Variable::Array(Arc::new(vec![x.clone(); n as usize].into()))
+                    }
+                    _ => return Err(module.error(range,
+                        &format!("{}\nExpected number for length in `[value; length]`",
+                            self.stack_trace()), self))
+                };
+                Ok((Some(v), Flow::Continue))
+            }
+            Object(ref keys) => {
+                let first = operands.len() - keys.len();
+                let mut object = ObjectMap::with_capacity(keys.len());
+                for (&(ref key, range), x) in keys.iter().zip(operands.drain(first..)) {
+                    if object.insert(key.clone(), x.expect(TINVOTS)).is_some() {
+                        return Err(module.error(range,
+                            &format!("{}\nDuplicate key in object `{}`",
+                                self.stack_trace(), key), self));
+                    }
+                }
+                Ok((Some(Variable::Object(Arc::new(object))), Flow::Continue))
+            }
+            Link(ref ranges) => {
+                let first = operands.len() - ranges.len();
+                let mut new_link = ::Link::new();
+                for (&range, x) in ranges.iter().zip(operands.drain(first..)) {
+                    let x = x.expect(TINVOTS);
+                    if let Err(err) = new_link.push(resolve(&self.stack, &x)) {
+                        return Err(module.error(range,
+                            &format!("{}\n{}", self.stack_trace(), err), self));
+                    }
+                }
+                Ok((Some(Variable::Link(Box::new(new_link))), Flow::Continue))
+            }
+            Vec4(ref ranges) => {
+                let st = self.stack.len() - ranges.len();
+                let mut v = [0.0; 4];
+                for (i, &range) in ranges.iter().enumerate() {
+                    match *resolve(&self.stack, &self.stack[st + i]) {
+                        Variable::F64(val, _) => v[i] = val as f32,
+                        ref x => return Err(module.error(range,
+                            &self.expected(x, "number"), self))
+                    }
+                }
+                self.stack.truncate(st);
+                Ok((Some(Variable::Vec4(v)), Flow::Continue))
+            }
+            Index(ref item, slot, n) => {
+                let start_stack_len = self.stack.len() - n;
+                let id = self.call_stack.last().unwrap().frame_start + slot;
+                let id = if let Variable::Ref(ref_id) = self.stack[id] {
+                        ref_id
+                    } else {
+                        id
+                    };
+                self.item_ids(item, Side::Right, id, start_stack_len, module)
+            }
+            _ => panic!("Expected instruction that creates a value")
+        }
+    }
+
+    /// Combines the value of the body of a short loop with the value of the loop.
+    ///
+    /// Returns `true` when the value of the loop is known.
+    /// Kept out of `execute` such that its native stack frame stays small.
+    #[inline(never)]
+    fn accumulate(
+        &self,
+        kind: bytecode::ShortLoop,
+        range: Range,
+        x: Option<Variable>,
+        acc: &mut Variable,
+        ind: f64,
+        module: &Arc<Module>
+    ) -> Result<bool, String> {
+        use bytecode::ShortLoop as S;
+
+        // Adds the index to the secret of the value.
+        fn with_index(sec: &Option<Box<Vec<Variable>>>, ind: f64) -> Option<Box<Vec<Variable>>> {
+            match *sec {
+                None => Some(Box::new(vec![Variable::f64(ind)])),
+                Some(ref arr) => {
+                    let mut arr = arr.clone();
+                    arr.push(Variable::f64(ind));
+                    Some(arr)
+                }
+            }
+        }
+
+        let x = match x {
+            Some(x) => x,
+            None => return Err(module.error(range, match kind {
+                S::Sum | S::Prod => "Expected `number`",
+                S::SumVec4 => "Expected `vec4`",
+                S::Min | S::Max => "Expected `number or option`",
+                S::Any | S::All => "Expected `boolean`",
+                S::Sift => "Expected variable",
+            }, self))
+        };
+        match (kind, &mut *acc, self.resolve(&x)) {
+            (S::Sum, &mut Variable::F64(ref mut sum, _), &Variable::F64(val, _)) => *sum += val,
+            (S::Prod, &mut Variable::F64(ref mut prod, _), &Variable::F64(val, _)) => *prod *= val,
+            (S::SumVec4, &mut Variable::Vec4(ref mut sum), &Variable::Vec4(val)) => {
+                for i in 0..4 {
+                    sum[i] += val[i]
+                }
+            }
+            (S::Min, &mut Variable::F64(ref mut min, ref mut sec),
+             &Variable::F64(val, ref val_sec)) => {
+                if min.is_nan() || *min > val {
+                    *min = val;
+                    *sec = with_index(val_sec, ind);
+                }
+            }
+            (S::Max, &mut Variable::F64(ref mut max, ref mut sec),
+             &Variable::F64(val, ref val_sec)) => {
+                if max.is_nan() || *max < val {
+                    *max = val;
+                    *sec = with_index(val_sec, ind);
+                }
+            }
+            (S::Any, &mut Variable::Bool(ref mut any, ref mut sec),
+             &Variable::Bool(val, ref val_sec)) => {
+                if val {
+                    *any = true;
+                    *sec = with_index(val_sec, ind);
+                    return Ok(true);
+                }
+            }
+            (S::All, &mut Variable::Bool(ref mut all, ref mut sec),
+             &Variable::Bool(val, ref val_sec)) => {
+                if !val {
+                    *all = false;
+                    *sec = with_index(val_sec, ind);
+                    return Ok(true);
+                }
+            }
+            (S::Sift, &mut Variable::Array(ref mut arr), _) => Arc::make_mut(arr).push(x.clone()),
+            (_, _, x) => return Err(module.error(range, &self.expected(x, match kind {
+                S::SumVec4 => "vec4",
+                S::Any | S::All => "boolean",
+                _ => "number",
+            }), self))
+        }
+        Ok(false)
+    }
+
+    /// Reuses the current frame for a call in tail position.
+    ///
+    /// Returns `false` when the call must be done as usual.
+    fn tail_call(
+        &mut self,
+        call: &ast::Call,
+        new_index: usize,
+        st: usize,
+        module: &Arc<Module>
+    ) -> Result<bool, String> {
+        // Report every call to the profiler and the observer.
+        if self.profiler.is_some() || self.observer.is_some() { return Ok(false); }
+        let f = &module.functions[new_index];
+        let (frame_start, stack_len, local_len, current_len) = {
+            let caller = self.call_stack.last().unwrap();
+            if f.code.is_none() ||
+               f.returns() != module.functions[caller.index].returns() ||
+               // Current objects of the caller are needed by the called function.
+               self.current_stack.len() != caller.current_len {
+                return Ok(false);
+            }
+            (caller.frame_start, caller.stack_len, caller.local_len, caller.current_len)
+        };
+
+        // Arguments that refer to the frame must be copied, since the frame is replaced.
+        // This includes references inside objects and arrays.
+        let mut budget = TAIL_CALL_SCAN;
+        let mut deep = vec![];
+        for i in st..self.stack.len() {
+            let mut v = &self.stack[i];
+            while let Variable::Ref(id) = *v {
+                if id < frame_start { break; }
+                v = &self.stack[id];
+            }
+            match refers_to_frame(v, frame_start, &mut budget) {
+                Some(false) => {}
+                Some(true) => deep.push(i),
+                // Too large to check, so use a new frame.
+                None => return Ok(false),
+            }
+        }
+        for i in st..self.stack.len() {
+            while let Variable::Ref(id) = self.stack[i] {
+                if id < frame_start { break; }
+                self.stack[i] = self.stack[id].clone();
+            }
+        }
+        for i in deep {
+            self.stack[i] = self.stack[i].deep_clone(&self.stack);
+        }
+        let args = self.stack.split_off(st);
+        self.stack.truncate(stack_len);
+        self.stack.extend(args);
+        if f.returns() {
+            self.stack[frame_start] = Variable::Return;
+        }
+        self.local_stack.truncate(local_len);
+        self.current_stack.truncate(current_len);
+
+        // Look for variable in current stack.
+        for current in &f.currents {
+            let mut res = None;
+            for &(ref cname, ind) in self.current_stack.iter().rev() {
+                if cname == &current.name {
+                    res = Some(ind);
+                    break;
+                }
+            }
+            if let Some(ind) = res {
+                self.local_stack.push((current.name.clone(), self.stack.len()));
+                self.stack.push(Variable::Ref(ind));
+            } else {
+                return Err(module.error(call.source_range, &format!(
+                    "{}\nCould not find current variable `{}`",
+                        self.stack_trace(), current.name), self));
+            }
+        }
+
+        {
+            let frame = self.call_stack.last_mut().unwrap();
+            frame.fn_name = call.name.clone();
+            frame.index = new_index;
+            frame.file = Some(f.file.clone());
+        }
+        try!(self.check_call_depth(f.source_range, module));
+        try!(self.consume_fuel(f.source_range, module));
+        if f.returns() {
+            self.local_stack.push((self.ret.clone(), frame_start));
+        }
+        for (i, arg) in f.args.iter().enumerate() {
+            self.local_stack.push((arg.name.clone(), stack_len + i));
+        }
+        Ok(true)
+    }
+
+    pub fn go(&mut self, go: &ast::Go, module: &Arc<Module>) -> Result<(Option<Variable>, Flow), String> {
+        use Thread;
+
+        let n = go.call.args.len();
+        let mut stack = vec![];
+        let relative = self.call_stack.last().map(|c| c.index).unwrap();
+        let mut fake_call = ast::Call {
+            name: go.call.name.clone(),
+            f_index: AtomicFnIndex::new(module.find_function(&go.call.name, relative)),
+            args: Vec::with_capacity(n),
+            custom_source: None,
+            source_range: go.call.source_range,
+        };
+        // Evaluate the arguments and put a deep clone on the new stack.
+        // This prevents the arguments from containing any reference to other variables.
+        for (i, arg) in go.call.args.iter().enumerate() {
+            let v = match try!(self.expression(arg, Side::Right, module)) {
+                (Some(x), Flow::Continue) => x,
+                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                _ => return Err(module.error(arg.source_range(),
+                                &format!("{}\nExpected something. \
+                                Expression did not return a value.",
+                                self.stack_trace()), self))
+            };
+            stack.push(v.deep_clone(&self.stack));
+            fake_call.args.push(ast::Expression::Variable(
+                go.call.args[i].source_range(), Variable::Ref(n-i-1)));
+        }
+        stack.reverse();
+
+        // Start the scheduler first, such that the thread can start new threads on it.
+        let scheduler = try!(self.scheduler().map_err(|err| module.error(go.source_range,
+            &format!("{}\n{}", self.stack_trace(), err), self)));
+        let mut new_rt = {
+            let last_call = self.call_stack.last().unwrap();
+            // Add last call because of loaded functions
+            // use relative index to the function it is calling from.
+            self.thread_runtime(stack, vec![Call {
+                fn_name: last_call.fn_name.clone(),
+                index: last_call.index,
+                file: last_call.file.clone(),
+                stack_len: 0,
+                frame_start: 0,
+                local_len: 0,
+                current_len: 0,
+            }])
+        };
+        // The thread can be cancelled without interrupting this runtime.
+        let interrupt = self.interrupt.child();
+        new_rt.interrupt = interrupt.clone();
+        if let Some(observer) = self.observer.clone() {
+            observer.spawn(self, &go.call.name);
+        }
+        let new_module = module.clone();
+        let stack_limit = self.stack_limit;
+        let handle = scheduler.spawn(Box::new(move |stack_base: Option<usize>| {
+            let mut new_rt = new_rt;
+            new_rt.set_stack(stack_base, stack_limit);
+            let fake_call = fake_call;
+            let res = match new_rt.call(&fake_call, &new_module) {
+                Err(err) => Err(err),
+                Ok((None, _)) => {
+                    let x = new_rt.stack.pop().expect(TINVOTS);
+                    Ok(x.deep_clone(&new_rt.stack))
+                }
+                Ok((Some(x), _)) => Ok(x.deep_clone(&new_rt.stack)),
+            };
+            new_rt.flush_fuel();
+            res
+        }));
+        Ok((Some(Variable::Thread(Thread::new(handle, interrupt))), Flow::Continue))
+    }
+
+    /// Returns the stack size of threads running `go` and `par`.
+    fn thread_stack_size(&self) -> usize {
+        self.max_stack_size.map(|n| 2 * n).unwrap_or(THREAD_STACK_SIZE)
+    }
+
+    /// Sets the native stack of a thread runtime.
+    ///
+    /// The stack base is `None` when running on a worker thread,
+    /// otherwise the thread runs on the stack of the joining runtime.
+    fn set_stack(&mut self, stack_base: Option<usize>, stack_limit: usize) {
+        match stack_base {
+            Some(stack_base) => {
+                self.stack_base = stack_base;
+                self.stack_limit = stack_limit;
+            }
+            None => {
+                self.stack_base = stack_address();
+                // Leave room for nested expressions and intrinsics between calls.
+                self.stack_limit = self.thread_stack_size() / 4 * 3;
+            }
+        }
+    }
+
+    /// Returns the scheduler for threads, starting it if needed.
+    fn scheduler(&mut self) -> Result<Arc<Scheduler>, String> {
+        if let Some(ref scheduler) = self.scheduler {
+            return Ok(scheduler.clone());
+        }
+        let scheduler = Arc::new(try!(Scheduler::new(self.go_threads, self.thread_stack_size())));
+        self.scheduler = Some(scheduler.clone());
+        Ok(scheduler)
+    }
+
+    /// Returns the scheduler for `par` loops, starting it if needed.
+    fn par_scheduler(&mut self) -> Result<Arc<Scheduler>, String> {
+        if let Some(ref scheduler) = self.par_scheduler {
+            return Ok(scheduler.clone());
+        }
+        let scheduler = Arc::new(try!(Scheduler::new(self.par_threads, self.thread_stack_size())));
+        self.par_scheduler = Some(scheduler.clone());
+        Ok(scheduler)
+    }
+
+    /// Waits for a thread started with `go` and returns the result.
+    ///
+    /// Runs the thread on the current native stack if no worker has started it.
+    pub fn join_thread(
+        &self,
+        handle: &JoinHandle
+    ) -> ::std::thread::Result<Result<Variable, String>> {
+        handle.join(self.stack_base)
+    }
+
+    /// Waits at most `timeout` for a thread started with `go` and returns the result.
+    ///
+    /// Returns `None` if the thread is not done in time.
+    /// A worker thread does not count as a worker of the scheduler while waiting,
+    /// since the thread might still be in the queue.
+    pub fn join_thread_timeout(
+        &self,
+        handle: &JoinHandle,
+        timeout: Duration
+    ) -> Result<Option<::std::thread::Result<Result<Variable, String>>>, String> {
+        let _blocked = match self.scheduler {
+            Some(ref scheduler) if !handle.is_done() => Some(try!(scheduler.block())),
+            _ => None
+        };
+        Ok(handle.join_timeout(timeout))
+    }
+
+    /// Returns `true` if a thread started with `go` is done.
+    ///
+    /// Makes sure the thread can start when polled from a worker thread.
+    pub fn is_thread_done(&self, handle: &JoinHandle) -> Result<bool, String> {
+        if handle.is_done() { return Ok(true); }
+        if let Some(ref scheduler) = self.scheduler {
+            try!(scheduler.poll());
+        }
+        Ok(false)
+    }
+
+    /// Waits for a value from one of the channels.
+    ///
+    /// Returns the index of the channel and the value,
+    /// or `None` when all channels are closed and have no more values.
+    /// A worker thread does not count as a worker of the scheduler while waiting,
+    /// since the thread sending the value might still be in the queue.
+    pub fn recv_channel(
+        &self,
+        channels: &[Channel]
+    ) -> Result<Option<(usize, Variable)>, String> {
+        use channel::TryRecv;
+
+        let mut blocked = None;
+        loop {
+            let mut open = None;
+            for (i, channel) in channels.iter().enumerate() {
+                match channel.try_recv() {
+                    TryRecv::Item(val) => return Ok(Some((i, val))),
+                    TryRecv::Empty => if open.is_none() { open = Some(i) },
+                    TryRecv::Closed => {}
+                }
+            }
+            let open = match open {
+                None => return Ok(None),
+                Some(i) => i,
+            };
+            if self.interrupt.is_interrupted() {
+                return Err("Interrupted".into());
+            }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Err("Deadline exceeded".into());
+                }
+            }
+            if blocked.is_none() {
+                if let Some(ref scheduler) = self.scheduler {
+                    blocked = Some(try!(scheduler.block()));
+                }
+            }
+            // Only one channel notifies when a value is sent,
+            // so check the others regularly.
+            let timeout = if channels.len() == 1 { 10 } else { 1 };
+            channels[open].wait(Duration::from_millis(timeout));
+        }
+    }
+
+    /// Creates a runtime for a new thread, with the limits of this runtime.
+    fn thread_runtime(&self, stack: Vec<Variable>, call_stack: Vec<Call>) -> Runtime {
+        Runtime {
+            stack: stack,
+            local_stack: vec![],
+            current_stack: vec![],
+            call_stack: call_stack,
+            rng: self.rng.clone(),
+            ret: self.ret.clone(),
+            ref_type: self.ref_type.clone(),
+            option_type: self.option_type.clone(),
+            array_type: self.array_type.clone(),
+            link_type: self.link_type.clone(),
+            bool_type: self.bool_type.clone(),
+            object_type: self.object_type.clone(),
+            text_type: self.text_type.clone(),
+            f64_type: self.f64_type.clone(),
+            thread_type: self.thread_type.clone(),
+            channel_type: self.channel_type.clone(),
+            unsafe_ref_type: self.unsafe_ref_type.clone(),
+            return_type: self.return_type.clone(),
+            rust_object_type: self.rust_object_type.clone(),
+            vec4_type: self.vec4_type.clone(),
+            result_type: self.result_type.clone(),
+            closure_type: self.closure_type.clone(),
+            // Threads share the fuel and inherit the deadline.
+            fuel: self.fuel.clone(),
+            deadline: self.deadline,
+            interrupt: self.interrupt.clone(),
+            debugger: None,
+            profiler: None,
+            coverage: None,
+            observer: self.observer.clone(),
+            max_call_depth: self.max_call_depth,
+            max_stack_size: self.max_stack_size,
+            go_threads: self.go_threads,
+            scheduler: self.scheduler.clone(),
+            par_scheduler: self.par_scheduler.clone(),
+            par_threads: self.par_threads,
+            stack_base: 0,
+            stack_limit: 0,
+            unflushed_fuel: 0,
+            execute_buffers: vec![],
+        }
+    }
+
+    pub fn call_closure(
+        &mut self,
+        call: &ast::CallClosure,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        // Find item.
+        let item = match try!(self.item(&call.item, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+            _ => return Err(module.error(call.item.source_range,
+                            &format!("{}\nExpected something. \
+                            Check that item returns a value.",
+                            self.stack_trace()), self))
+        };
+
+        let (f, env) = match self.resolve(&item) {
+            &Variable::Closure(ref f, ref env) => (f.clone(), env.clone()),
+            x => return Err(module.error(call.source_range,
+                    &self.expected(x, "closure"), self))
+        };
+
+        if call.arg_len() != f.args.len() {
+            return Err(module.error(call.source_range,
+                &format!("{}\nExpected {} arguments but found {}",
+                self.stack_trace(),
+                f.args.len(),
+                call.arg_len()), self));
+        }
+        // Arguments must be computed.
+        if f.returns() {
+            // Add return value before arguments on the stack.
+            // The stack value should remain, but the local should not.
+            self.stack.push(Variable::Return);
+        }
+        let st = self.stack.len();
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        for arg in &call.args {
+            match try!(self.expression(arg, Side::Right, module)) {
+                (Some(x), Flow::Continue) => self.stack.push(x),
+                (None, Flow::Continue) => {}
+                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                _ => return Err(module.error(arg.source_range(),
+                                &format!("{}\nExpected something. \
+                                Check that expression returns a value.",
+                                self.stack_trace()), self))
+            };
+        }
+
+        // Look for variable in current stack.
+        if f.currents.len() > 0 {
+            for current in &f.currents {
+                let mut res = None;
+                for &(ref cname, ind) in self.current_stack.iter().rev() {
+                    if cname == &current.name {
+                        res = Some(ind);
+                        break;
+                    }
+                }
+                if let Some(ind) = res {
+                    self.local_stack.push((current.name.clone(), self.stack.len()));
+                    self.stack.push(Variable::Ref(ind));
+                } else {
+                    return Err(module.error(call.source_range, &format!(
+                        "{}\nCould not find urrent variable `{}`",
+                            self.stack_trace(), current.name), self));
+                }
+            }
+        }
+
+        self.push_fn(call.item.name.clone(), env.relative, Some(f.file.clone()), st, lc, cu);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(call.item.name.clone());
+        }
+        if let Some(observer) = self.observer.clone() {
+            observer.enter(self, &call.item.name, &self.stack[st..st + f.args.len()]);
+        }
+        try!(self.check_call_depth(f.source_range, module));
+        try!(self.consume_fuel(f.source_range, module));
+        if f.returns() {
+            // The return value is the first slot in the frame.
+            self.call_stack.last_mut().unwrap().frame_start = st - 1;
+            self.local_stack.push((self.ret.clone(), st - 1));
+        }
+        for (i, arg) in f.args.iter().enumerate() {
+            // Do not resolve locals to keep fixed length from end of stack.
+            self.local_stack.push((arg.name.clone(), st + i));
+        }
+        let (x, flow) = try!(self.expression(&f.expr, Side::Right, &env.module));
+        match flow {
+            Flow::Break(None) =>
                 return Err(module.error(call.source_range,
                            &format!("{}\nCan not break from function",
                                 self.stack_trace()), self)),
@@ -768,6 +2076,9 @@ impl Runtime {
             _ => {}
         }
         self.pop_fn(call.item.name.clone());
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.exit();
+        }
         match (f.returns(), x) {
             (true, None) => {
                 match self.stack.pop().expect(TINVOTS) {
@@ -781,6 +2092,7 @@ impl Runtime {
                     x => {
                         // This happens when return is only
                         // assigned to `return = x`.
+                        self.observe_exit(&call.item.name, Some(&x));
                         return Ok((Some(x), Flow::Continue))
                     }
                 };
@@ -804,6 +2116,7 @@ impl Runtime {
             }
             (returns, b) => {
                 if returns { self.stack.pop(); }
+                self.observe_exit(&call.item.name, b.as_ref());
                 return Ok((b, Flow::Continue))
             }
         }
@@ -831,8 +2144,7 @@ impl Runtime {
                                         self.stack_trace()), self))
                     };
                 }
-                try!((f)(self).map_err(|err|
-                    module.error(call.source_range, &err, self)));
+                try!(self.call_external(call, f, module));
                 return Ok((None, Flow::Continue));
             }
             FnIndex::ExternalReturn(FnExternalRef(f)) => {
@@ -846,8 +2158,7 @@ impl Runtime {
                                         self.stack_trace()), self))
                     };
                 }
-                try!((f)(self).map_err(|err|
-                    module.error(call.source_range, &err, self)));
+                try!(self.call_external(call, f, module));
                 return Ok((Some(self.stack.pop().expect(TINVOTS)), Flow::Continue));
             }
             FnIndex::Loaded(f_index) => {
@@ -881,111 +2192,170 @@ impl Runtime {
                                         self.stack_trace()), self))
                     };
                 }
+                self.call_loaded(call, new_index, st, lc, cu, module)
+            }
+            FnIndex::None => {
+                return Err(module.error(call.source_range,
+                    &format!("{}\nUnknown function `{}`", self.stack_trace(), call.name), self))
+            }
+        }
+    }
 
-                // Look for variable in current stack.
-                if f.currents.len() > 0 {
-                    for current in &f.currents {
-                        let mut res = None;
-                        for &(ref cname, ind) in self.current_stack.iter().rev() {
-                            if cname == &current.name {
-                                res = Some(ind);
-                                break;
-                            }
-                        }
-                        if let Some(ind) = res {
-                            self.local_stack.push((current.name.clone(), self.stack.len()));
-                            self.stack.push(Variable::Ref(ind));
-                        } else {
-                            return Err(module.error(call.source_range, &format!(
-                                "{}\nCould not find current variable `{}`",
-                                    self.stack_trace(), current.name), self));
-                        }
+    /// Calls an external function when the arguments are on the stack.
+    fn call_external(
+        &mut self,
+        call: &ast::Call,
+        f: fn(&mut Runtime) -> Result<(), String>,
+        module: &Arc<Module>
+    ) -> Result<(), String> {
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(call.name.clone());
+        }
+        let res = (f)(self);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.exit();
+        }
+        res.map_err(|err| module.error(call.source_range, &err, self))
+    }
+
+    /// Calls a loaded function when the arguments are on the stack.
+    ///
+    /// - new_index - The absolute index of the function in the module
+    /// - st - The stack length before the arguments were pushed
+    /// - lc - The local stack length before the call
+    /// - cu - The current stack length before the call
+    pub fn call_loaded(
+        &mut self,
+        call: &ast::Call,
+        new_index: usize,
+        st: usize,
+        lc: usize,
+        cu: usize,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        let f = &module.functions[new_index];
+        // Look for variable in current stack.
+        if f.currents.len() > 0 {
+            for current in &f.currents {
+                let mut res = None;
+                for &(ref cname, ind) in self.current_stack.iter().rev() {
+                    if cname == &current.name {
+                        res = Some(ind);
+                        break;
                     }
                 }
+                if let Some(ind) = res {
+                    self.local_stack.push((current.name.clone(), self.stack.len()));
+                    self.stack.push(Variable::Ref(ind));
+                } else {
+                    return Err(module.error(call.source_range, &format!(
+                        "{}\nCould not find current variable `{}`",
+                            self.stack_trace(), current.name), self));
+                }
+            }
+        }
 
-                self.push_fn(call.name.clone(), new_index, Some(f.file.clone()), st, lc, cu);
-                if f.returns() {
-                    self.local_stack.push((self.ret.clone(), st - 1));
-                }
-                for (i, arg) in f.args.iter().enumerate() {
-                    // Do not resolve locals to keep fixed length from end of stack.
-                    self.local_stack.push((arg.name.clone(), st + i));
-                }
-                let (x, flow) = try!(self.block(&f.block, module));
-                match flow {
-                    Flow::Break(None) =>
-                        return Err(module.error(call.source_range,
-                                   &format!("{}\nCan not break from function",
-                                        self.stack_trace()), self)),
-                    Flow::ContinueLoop(None) =>
-                        return Err(module.error(call.source_range,
-                                   &format!("{}\nCan not continue from function",
-                                        self.stack_trace()), self)),
-                    Flow::Break(Some(ref label)) =>
-                        return Err(module.error(call.source_range,
-                            &format!("{}\nThere is no loop labeled `{}`",
-                                     self.stack_trace(), label), self)),
-                    Flow::ContinueLoop(Some(ref label)) =>
-                        return Err(module.error(call.source_range,
-                            &format!("{}\nThere is no loop labeled `{}`",
-                                    self.stack_trace(), label), self)),
-                    _ => {}
-                }
-                self.pop_fn(call.name.clone());
-                match (f.returns(), x) {
-                    (true, None) => {
-                        match self.stack.pop().expect(TINVOTS) {
-                            Variable::Return => {
-                                let source = call.custom_source.as_ref().unwrap_or(
-                                    &module.functions[
-                                        self.call_stack.last().unwrap().index
-                                    ].source
-                                );
-                                return Err(module.error_source(
-                                call.source_range, &format!(
-                                "{}\nFunction `{}` did not return a value",
-                                self.stack_trace(),
-                                f.name), source))
-                            }
-                            x => {
-                                // This happens when return is only
-                                // assigned to `return = x`.
-                                return Ok((Some(x), Flow::Continue))
-                            }
-                        };
-                    }
-                    (false, Some(_)) => {
-                        let source = call.custom_source.as_ref().unwrap_or(
-                            &module.functions[self.call_stack.last().unwrap().index].source
-                        );
-                        return Err(module.error_source(call.source_range,
-                            &format!(
-                                "{}\nFunction `{}` should not return a value",
-                                self.stack_trace(),
-                                f.name), source))
-                    }
-                    (true, Some(Variable::Return)) => {
-                        // TODO: Could return the last value on the stack.
-                        //       Requires .pop_fn delayed after.
+        self.push_fn(call.name.clone(), new_index, Some(f.file.clone()), st, lc, cu);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.enter(f.name.clone());
+        }
+        if let Some(observer) = self.observer.clone() {
+            observer.enter(self, &f.name, &self.stack[st..st + f.args.len()]);
+        }
+        try!(self.check_call_depth(f.source_range, module));
+        try!(self.consume_fuel(f.source_range, module));
+        if f.returns() {
+            // The return value is the first slot in the frame.
+            self.call_stack.last_mut().unwrap().frame_start = st - 1;
+            self.local_stack.push((self.ret.clone(), st - 1));
+        }
+        for (i, arg) in f.args.iter().enumerate() {
+            // Do not resolve locals to keep fixed length from end of stack.
+            self.local_stack.push((arg.name.clone(), st + i));
+        }
+        let (x, flow) = match f.code {
+            // The debugger and coverage record statements when walking the AST.
+            Some(ref code) if self.debugger.is_none() && self.coverage.is_none() =>
+                try!(self.execute(code, module)),
+            _ => try!(self.block(&f.block, module)),
+        };
+        match flow {
+            Flow::Break(None) =>
+                return Err(module.error(call.source_range,
+                           &format!("{}\nCan not break from function",
+                                self.stack_trace()), self)),
+            Flow::ContinueLoop(None) =>
+                return Err(module.error(call.source_range,
+                           &format!("{}\nCan not continue from function",
+                                self.stack_trace()), self)),
+            Flow::Break(Some(ref label)) =>
+                return Err(module.error(call.source_range,
+                    &format!("{}\nThere is no loop labeled `{}`",
+                             self.stack_trace(), label), self)),
+            Flow::ContinueLoop(Some(ref label)) =>
+                return Err(module.error(call.source_range,
+                    &format!("{}\nThere is no loop labeled `{}`",
+                            self.stack_trace(), label), self)),
+            _ => {}
+        }
+        // The frame might have been reused by tail calls.
+        let Call { stack_len, local_len, current_len, .. } = self.call_stack.pop().unwrap();
+        self.stack.truncate(stack_len);
+        self.local_stack.truncate(local_len);
+        self.current_stack.truncate(current_len);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.exit();
+        }
+        match (f.returns(), x) {
+            (true, None) => {
+                match self.stack.pop().expect(TINVOTS) {
+                    Variable::Return => {
                         let source = call.custom_source.as_ref().unwrap_or(
-                            &module.functions[self.call_stack.last().unwrap().index].source
+                            &module.functions[
+                                self.call_stack.last().unwrap().index
+                            ].source
                         );
-                        return Err(module.error_source(call.source_range,
-                            &format!(
-                            "{}\nFunction `{}` did not return a value. \
-                            Did you forget a `return`?",
-                                self.stack_trace(),
-                                f.name), source))
+                        return Err(module.error_source(
+                        call.source_range, &format!(
+                        "{}\nFunction `{}` did not return a value",
+                        self.stack_trace(),
+                        f.name), source))
                     }
-                    (returns, b) => {
-                        if returns { self.stack.pop(); }
-                        return Ok((b, Flow::Continue))
+                    x => {
+                        // This happens when return is only
+                        // assigned to `return = x`.
+                        self.observe_exit(&f.name, Some(&x));
+                        return Ok((Some(x), Flow::Continue))
                     }
-                }
+                };
             }
-            FnIndex::None => {
-                return Err(module.error(call.source_range,
-                    &format!("{}\nUnknown function `{}`", self.stack_trace(), call.name), self))
+            (false, Some(_)) => {
+                let source = call.custom_source.as_ref().unwrap_or(
+                    &module.functions[self.call_stack.last().unwrap().index].source
+                );
+                return Err(module.error_source(call.source_range,
+                    &format!(
+                        "{}\nFunction `{}` should not return a value",
+                        self.stack_trace(),
+                        f.name), source))
+            }
+            (true, Some(Variable::Return)) => {
+                // TODO: Could return the last value on the stack.
+                //       Requires .pop_fn delayed after.
+                let source = call.custom_source.as_ref().unwrap_or(
+                    &module.functions[self.call_stack.last().unwrap().index].source
+                );
+                return Err(module.error_source(call.source_range,
+                    &format!(
+                    "{}\nFunction `{}` did not return a value. \
+                    Did you forget a `return`?",
+                        self.stack_trace(),
+                        f.name), source))
+            }
+            (returns, b) => {
+                if returns { self.stack.pop(); }
+                self.observe_exit(&f.name, b.as_ref());
+                return Ok((b, Flow::Continue))
             }
         }
     }
@@ -996,24 +2366,53 @@ impl Runtime {
         args: &[Variable],
         module: &Arc<Module>
     ) -> Result<(), String> {
-        use std::cell::Cell;
 
         let name: Arc<String> = Arc::new(function.into());
-        match module.find_function(&name, 0) {
+        let relative = self.call_stack.last().map(|c| c.index).unwrap_or(0);
+        match module.find_function(&name, relative) {
             FnIndex::Loaded(f_index) => {
                 let call = ast::Call {
                     name: name.clone(),
-                    f_index: Cell::new(FnIndex::Loaded(f_index)),
+                    f_index: AtomicFnIndex::new(FnIndex::Loaded(f_index)),
                     args: args.iter()
                             .map(|arg| ast::Expression::Variable(Range::empty(0), arg.clone()))
                             .collect(),
                     custom_source: None,
                     source_range: Range::empty(0),
                 };
-                try!(self.call(&call, &module));
-                Ok(())
+                self.call_from_host(&call, &module)
+            }
+            _ => return Err(format!("Could not find function `{}`",function))
+        }
+    }
+
+    /// Calls a function from the host.
+    ///
+    /// Unwinds the stacks on error, such that the runtime can be reused.
+    fn call_from_host(&mut self, call: &ast::Call, module: &Arc<Module>) -> Result<(), String> {
+        let st = self.stack.len();
+        let lc = self.local_stack.len();
+        let cu = self.current_stack.len();
+        let cs = self.call_stack.len();
+        let depth = self.profiler.as_ref().map(|p| p.depth()).unwrap_or(0);
+        let stack_base = self.stack_base;
+        if stack_base == 0 {
+            self.stack_base = stack_address();
+        }
+        let res = self.call(call, module);
+        self.stack_base = stack_base;
+        match res {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                self.stack.truncate(st);
+                self.local_stack.truncate(lc);
+                self.current_stack.truncate(cu);
+                self.call_stack.truncate(cs);
+                if let Some(ref mut profiler) = self.profiler {
+                    profiler.unwind(depth);
+                }
+                Err(err)
             }
-            _ => return Err(format!("Could not find function `{}`",function))
         }
     }
 
@@ -1078,7 +2477,7 @@ impl Runtime {
         obj: &ast::Object,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
-        let mut object: HashMap<_, _> = HashMap::new();
+        let mut object = ObjectMap::with_capacity(obj.key_values.len());
         for &(ref key, ref expr) in &obj.key_values {
             let x = match try!(self.expression(expr, Side::Right, module)) {
                 (Some(x), Flow::Continue) => x,
@@ -1112,7 +2511,7 @@ impl Runtime {
                         self.stack_trace()), self))
             });
         }
-        Ok((Some(Variable::Array(Arc::new(array))), Flow::Continue))
+        Ok((Some(Variable::Array(Arc::new(array.into()))), Flow::Continue))
     }
 
     fn array_fill(
@@ -1136,7 +2535,7 @@ impl Runtime {
         };
         let v = match (self.resolve(&fill), self.resolve(&n)) {
             (x, &Variable::F64(n, _)) => {
-                Variable::Array(Arc::new(vec![x.clone(); n as usize]))
+                Variable::Array(Arc::new(vec![x.clone(); n as usize].into()))
             }
             _ => return Err(module.error(array_fill.n.source_range(),
                 &format!("{}\nExpected number for length in `[value; length]`",
@@ -1151,21 +2550,31 @@ impl Runtime {
         left: &ast::Expression,
         right: &ast::Expression,
         module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        // Evaluate right side before left because the left leaves
+        // an raw pointer on the stack which might point to wrong place
+        // if there are side effects of the right side affecting it.
+        let b = match try!(self.expression(right, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => return Ok((x, Flow::Return)),
+            _ => return Err(module.error(right.source_range(),
+                    &format!("{}\nExpected something from the right side",
+                        self.stack_trace()), self))
+        };
+        self.assign_value(op, left, b, module)
+    }
+    /// Assigns a value that is already evaluated to the left expression.
+    fn assign_value(
+        &mut self,
+        op: ast::AssignOp,
+        left: &ast::Expression,
+        b: Variable,
+        module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
         use ast::AssignOp::*;
         use ast::Expression;
 
         if op != Assign {
-            // Evaluate right side before left because the left leaves
-            // an raw pointer on the stack which might point to wrong place
-            // if there are side effects of the right side affecting it.
-            let b = match try!(self.expression(right, Side::Right, module)) {
-                (Some(x), Flow::Continue) => x,
-                (x, Flow::Return) => return Ok((x, Flow::Return)),
-                _ => return Err(module.error(right.source_range(),
-                        &format!("{}\nExpected something from the right side",
-                            self.stack_trace()), self))
-            };
             let a = match try!(self.expression(left, Side::LeftInsert(false), module)) {
                 (Some(x), Flow::Continue) => x,
                 (x, Flow::Return) => return Ok((x, Flow::Return)),
@@ -1173,465 +2582,528 @@ impl Runtime {
                         &format!("{}\nExpected something from the left side",
                             self.stack_trace()), self))
             };
-            let mut r = match a {
-                Variable::UnsafeRef(mut r) => {
-                    // If reference, use a shallow clone to type check,
-                    // without affecting the original object.
-                    unsafe {
-                        if let Variable::Ref(ind) = *r.0 {
-                            *r.0 = self.stack[ind].clone()
+            self.assign_to(op, a, b, left.source_range(), module)
+        } else {
+            return match *left {
+                Expression::Item(ref item) => {
+                    let v = match b {
+                        // Use a shallow clone of a reference.
+                        Variable::Ref(ind) => self.stack[ind].clone(),
+                        x => x
+                    };
+                    if item.ids.len() != 0 {
+                        let x = match try!(self.expression(left, Side::LeftInsert(true),
+                                                   module)) {
+                            (Some(x), Flow::Continue) => x,
+                            (x, Flow::Return) => return Ok((x, Flow::Return)),
+                            _ => return Err(module.error(left.source_range(),
+                                    &format!("{}\nExpected something from the left side",
+                                        self.stack_trace()), self))
+                        };
+                        match x {
+                            Variable::UnsafeRef(mut r) => {
+                                unsafe { *r.0 = v }
+                            }
+                            _ => panic!("Expected unsafe reference")
                         }
+                    } else {
+                        self.local_stack.push((item.name.clone(), self.stack.len()));
+                        if item.current {
+                            self.current_stack.push((item.name.clone(), self.stack.len()));
+                        }
+                        self.stack.push(v);
                     }
-                    r
+                    Ok((None, Flow::Continue))
                 }
-                Variable::Ref(ind) => {
-                    UnsafeRef(&mut self.stack[ind] as *mut Variable)
+                _ => return Err(module.error(left.source_range(),
+                                &format!("{}\nExpected item",
+                                    self.stack_trace()), self))
+            }
+        }
+    }
+    /// Assigns a value to a reference, using an assignment operator.
+    fn assign_to(
+        &mut self,
+        op: ast::AssignOp,
+        a: Variable,
+        b: Variable,
+        left_range: Range,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use ast::AssignOp::*;
+
+        let mut r = match a {
+            Variable::UnsafeRef(mut r) => {
+                // If reference, use a shallow clone to type check,
+                // without affecting the original object.
+                unsafe {
+                    if let Variable::Ref(ind) = *r.0 {
+                        *r.0 = self.stack[ind].clone()
+                    }
                 }
-                x => panic!("Expected reference, found `{}`", self.typeof_var(&x))
-            };
+                r
+            }
+            Variable::Ref(ind) => {
+                UnsafeRef(&mut self.stack[ind] as *mut Variable)
+            }
+            x => panic!("Expected reference, found `{}`", self.typeof_var(&x))
+        };
 
-            match *self.resolve(&b) {
-                Variable::F64(b, ref sec) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::F64(ref mut n, ref mut n_sec) => {
-                                match op {
-                                    Set => *n = b,
-                                    Add => *n += b,
-                                    Sub => *n -= b,
-                                    Mul => *n *= b,
-                                    Div => *n /= b,
-                                    Rem => *n %= b,
-                                    Pow => *n = n.powf(b),
-                                    Assign => {}
-                                };
-                                *n_sec = sec.clone()
+        match *self.resolve(&b) {
+            Variable::F64(b, ref sec) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::F64(ref mut n, ref mut n_sec) => {
+                            match op {
+                                Set => *n = b,
+                                Add => *n += b,
+                                Sub => *n -= b,
+                                Mul => *n *= b,
+                                Div => *n /= b,
+                                Rem => *n %= b,
+                                Pow => *n = n.powf(b),
+                                Assign => {}
+                            };
+                            *n_sec = sec.clone()
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::F64(b, sec.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::F64(b, sec.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::f64(b)));
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `number`",
+                                        self.stack_trace()), self));
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::f64(b)));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `number`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        _ => return Err(module.error(
+                                left_range,
+                                &format!("{}\nExpected assigning to a number",
+                                    self.stack_trace()), self))
+                    };
+                }
+            }
+            Variable::Vec4(b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Vec4(ref mut n) => {
+                            match op {
+                                Set => *n = b,
+                                Add => *n = [n[0] + b[0], n[1] + b[1],
+                                             n[2] + b[2], n[3] + b[3]],
+                                Sub => *n = [n[0] - b[0], n[1] - b[1],
+                                             n[2] - b[2], n[3] - b[3]],
+                                Mul => *n = [n[0] * b[0], n[1] * b[1],
+                                             n[2] * b[2], n[3] * b[3]],
+                                Div => *n = [n[0] / b[0], n[1] / b[1],
+                                             n[2] / b[2], n[3] / b[3]],
+                                Rem => *n = [n[0] % b[0], n[1] % b[1],
+                                             n[2] % b[2], n[3] % b[3]],
+                                Pow => *n = [n[0].powf(b[0]), n[1].powf(b[1]),
+                                             n[2].powf(b[2]), n[3].powf(b[3])],
+                                Assign => {}
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a number",
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Vec4(b)
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
                                         self.stack_trace()), self))
-                        };
-                    }
+                            }
+                        }
+                        _ => return Err(module.error(
+                                left_range,
+                                &format!("{}\nExpected assigning to a vec4",
+                                    self.stack_trace()), self))
+                    };
                 }
-                Variable::Vec4(b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Vec4(ref mut n) => {
-                                match op {
-                                    Set => *n = b,
-                                    Add => *n = [n[0] + b[0], n[1] + b[1],
-                                                 n[2] + b[2], n[3] + b[3]],
-                                    Sub => *n = [n[0] - b[0], n[1] - b[1],
-                                                 n[2] - b[2], n[3] - b[3]],
-                                    Mul => *n = [n[0] * b[0], n[1] * b[1],
-                                                 n[2] * b[2], n[3] * b[3]],
-                                    Div => *n = [n[0] / b[0], n[1] / b[1],
-                                                 n[2] / b[2], n[3] / b[3]],
-                                    Rem => *n = [n[0] % b[0], n[1] % b[1],
-                                                 n[2] % b[2], n[3] % b[3]],
-                                    Pow => *n = [n[0].powf(b[0]), n[1].powf(b[1]),
-                                                 n[2].powf(b[2]), n[3].powf(b[3])],
-                                    Assign => {}
-                                }
+            }
+            Variable::Bool(b, ref sec) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Bool(ref mut n, ref mut n_sec) => {
+                            match op {
+                                Set => *n = b,
+                                _ => unimplemented!()
+                            };
+                            *n_sec = sec.clone();
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Bool(b, sec.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Vec4(b)
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::bool(b)));
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `bool`",
+                                        self.stack_trace()), self));
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a vec4",
-                                        self.stack_trace()), self))
-                        };
-                    }
+                        }
+                        _ => return Err(module.error(
+                                left_range,
+                                &format!("{}\nExpected assigning to a bool",
+                                    self.stack_trace()), self))
+                    };
                 }
-                Variable::Bool(b, ref sec) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Bool(ref mut n, ref mut n_sec) => {
-                                match op {
-                                    Set => *n = b,
-                                    _ => unimplemented!()
-                                };
-                                *n_sec = sec.clone();
+            }
+            Variable::Text(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Text(ref mut n) => {
+                            match op {
+                                Set => *n = b.clone(),
+                                Add => Arc::make_mut(n).push_str(b),
+                                _ => unimplemented!()
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Bool(b, sec.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Text(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::bool(b)));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `bool`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        Variable::Link(ref mut n) => {
+                            if let Add = op {
+                                try!(n.push(&Variable::Text(b.clone())));
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nCan not use this assignment \
+                                    operator with `link` and `text`",
+                                        self.stack_trace()), self));
                             }
-                            _ => return Err(module.error(
-                                    left.source_range(),
-                                    &format!("{}\nExpected assigning to a bool",
-                                        self.stack_trace()), self))
-                        };
+                        }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to text",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Text(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Text(ref mut n) => {
-                                match op {
-                                    Set => *n = b.clone(),
-                                    Add => Arc::make_mut(n).push_str(b),
-                                    _ => unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Text(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
+            }
+            Variable::Object(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Object(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
+                                    *r.0 = Variable::Object(b.clone())
                                 }
+                                // *n = obj.clone()
+                            } else {
+                                unimplemented!()
                             }
-                            Variable::Link(ref mut n) => {
-                                if let Add = op {
-                                    try!(n.push(&Variable::Text(b.clone())));
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nCan not use this assignment \
-                                        operator with `link` and `text`",
-                                            self.stack_trace()), self));
-                                }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Object(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to text",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to object",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Object(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Object(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Object(b.clone())
-                                    }
-                                    // *n = obj.clone()
-                                } else {
-                                    unimplemented!()
+            }
+            Variable::Array(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Array(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
+                                    *r.0 = Variable::Array(b.clone())
                                 }
+                                // *n = arr.clone();
+                            } else {
+                                unimplemented!()
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Object(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::F64Array(_) | Variable::Vec4Array(_) => {
+                            if let Set = op {
+                                *r.0 = Variable::Array(b.clone())
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Array(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to object",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to array",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Array(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Array(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Array(b.clone())
-                                    }
-                                    // *n = arr.clone();
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Array(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+            }
+            Variable::F64Array(_) | Variable::Vec4Array(_) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Array(_) |
+                        Variable::F64Array(_) |
+                        Variable::Vec4Array(_) |
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = b.clone()
+                            } else if let Variable::Return = *r.0 {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
+                            } else {
+                                unimplemented!()
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to array",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to array",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Link(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Link(ref mut n) => {
-                                match op {
-                                    Set => *n = b.clone(),
-                                    Add => **n = n.add(b),
-                                    Sub => **n = b.add(n),
-                                    _ => unimplemented!()
-                                }
+            }
+            Variable::Link(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Link(ref mut n) => {
+                            match op {
+                                Set => *n = b.clone(),
+                                Add => **n = n.add(b),
+                                Sub => **n = b.add(n),
+                                _ => unimplemented!()
                             }
-                            Variable::Return => {
-                                if let Set = op {
-                                    *r.0 = Variable::Link(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
-                                }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Link(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to link",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to link",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Option(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Option(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Option(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Option(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Option(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Option(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Option(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to option",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to option",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Result(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Result(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Result(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Result(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Result(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Result(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Result(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!("{}\nExpected assigning to result",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!("{}\nExpected assigning to result",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::RustObject(ref b) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::RustObject(ref mut n) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::RustObject(b.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::RustObject(ref b) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::RustObject(ref mut n) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::RustObject(b.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
+                            }
+                        }
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::RustObject(b.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
+                                        self.stack_trace()), self))
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!(
-                                    "{}\nExpected assigning to rust_object",
-                                    self.stack_trace()), self))
                         }
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!(
+                                "{}\nExpected assigning to rust_object",
+                                self.stack_trace()), self))
                     }
                 }
-                Variable::Closure(ref b, ref env) => {
-                    unsafe {
-                        match *r.0 {
-                            Variable::Closure(ref mut n, _) => {
-                                if let Set = op {
-                                    // Check address to avoid unsafe
-                                    // reading and writing to same memory.
-                                    let n_addr = n as *const _ as usize;
-                                    let b_addr = b as *const _ as usize;
-                                    if n_addr != b_addr {
-                                        *r.0 = Variable::Closure(b.clone(), env.clone())
-                                    }
-                                } else {
-                                    unimplemented!()
-                                }
-                            }
-                            Variable::Return => {
-                                if let Set = op {
+            }
+            Variable::Closure(ref b, ref env) => {
+                unsafe {
+                    match *r.0 {
+                        Variable::Closure(ref mut n, _) => {
+                            if let Set = op {
+                                // Check address to avoid unsafe
+                                // reading and writing to same memory.
+                                let n_addr = n as *const _ as usize;
+                                let b_addr = b as *const _ as usize;
+                                if n_addr != b_addr {
                                     *r.0 = Variable::Closure(b.clone(), env.clone())
-                                } else {
-                                    return Err(module.error(
-                                        left.source_range(),
-                                        &format!("{}\nReturn has no value",
-                                            self.stack_trace()), self))
                                 }
+                            } else {
+                                unimplemented!()
                             }
-                            _ => return Err(module.error(
-                                left.source_range(),
-                                &format!(
-                                    "{}\nExpected assigning to closure",
-                                    self.stack_trace()), self))
                         }
-                    }
-                }
-                ref x => {
-                    return Err(module.error(
-                        left.source_range(),
-                        &format!("{}\nCan not use this assignment operator with `{}`",
-                            self.stack_trace(), self.typeof_var(x)), self));
-                }
-            };
-            Ok((None, Flow::Continue))
-        } else {
-            return match *left {
-                Expression::Item(ref item) => {
-                    let x = match try!(self.expression(right, Side::Right, module)) {
-                        (x, Flow::Return) => return Ok((x, Flow::Return)),
-                        (Some(x), Flow::Continue) => x,
-                        _ => return Err(module.error(right.source_range(),
-                                    &format!("{}\nExpected something from the right side",
-                                        self.stack_trace()), self))
-                    };
-                    let v = match x {
-                        // Use a shallow clone of a reference.
-                        Variable::Ref(ind) => self.stack[ind].clone(),
-                        x => x
-                    };
-                    if item.ids.len() != 0 {
-                        let x = match try!(self.expression(left, Side::LeftInsert(true),
-                                                   module)) {
-                            (Some(x), Flow::Continue) => x,
-                            (x, Flow::Return) => return Ok((x, Flow::Return)),
-                            _ => return Err(module.error(left.source_range(),
-                                    &format!("{}\nExpected something from the left side",
+                        Variable::Return => {
+                            if let Set = op {
+                                *r.0 = Variable::Closure(b.clone(), env.clone())
+                            } else {
+                                return Err(module.error(
+                                    left_range,
+                                    &format!("{}\nReturn has no value",
                                         self.stack_trace()), self))
-                        };
-                        match x {
-                            Variable::UnsafeRef(mut r) => {
-                                unsafe { *r.0 = v }
                             }
-                            _ => panic!("Expected unsafe reference")
-                        }
-                    } else {
-                        self.local_stack.push((item.name.clone(), self.stack.len()));
-                        if item.current {
-                            self.current_stack.push((item.name.clone(), self.stack.len()));
                         }
-                        self.stack.push(v);
+                        _ => return Err(module.error(
+                            left_range,
+                            &format!(
+                                "{}\nExpected assigning to closure",
+                                self.stack_trace()), self))
                     }
-                    Ok((None, Flow::Continue))
                 }
-                _ => return Err(module.error(left.source_range(),
-                                &format!("{}\nExpected item",
-                                    self.stack_trace()), self))
+            }
+            ref x => {
+                return Err(module.error(
+                    left_range,
+                    &format!("{}\nCan not use this assignment operator with `{}`",
+                        self.stack_trace(), self.typeof_var(x)), self));
+            }
+        };
+        Ok((None, Flow::Continue))
+    }
+
+    // Checks the slot resolved when loading against looking up the name.
+    // This scans the local stack, so it is only enabled with `debug_resolve`.
+    fn debug_resolve(
+        &self,
+        item: &ast::Item,
+        stack_id: usize,
+        locals: usize,
+        module: &Arc<Module>
+    ) -> Result<(), String> {
+        for &(ref n, id) in self.local_stack.iter().rev().take(locals) {
+            if &**n == &**item.name {
+                if stack_id != id {
+                    return Err(module.error(item.source_range,
+                        &format!("DEBUG: Not same for {} stack_id `{:?}` vs id `{:?}`",
+                            item.name,
+                            stack_id,
+                            id), self));
+                }
+                break;
             }
         }
+        Ok(())
     }
+
     // `insert` is true for `:=` and false for `=`.
     // This works only on objects, but does not have to check since it is
     // ignored for arrays.
@@ -1682,66 +3154,39 @@ impl Runtime {
         use ast::Id;
 
         let locals = self.local_stack.len() - self.call_stack.last().unwrap().local_len;
-        let stack_id = {
-            if cfg!(not(feature = "debug_resolve")) {
-                self.stack.len() - item.static_stack_id.get().unwrap()
-            } else {
-                match item.stack_id.get() {
-                    Some(val) => self.stack.len() - val,
-                    None => {
-                        let name: &str = &**item.name;
-                        let mut found = false;
-                        for &(ref n, id) in self.local_stack.iter().rev().take(locals) {
-                            if &**n == name {
-                                let new_val = Some(self.stack.len() - id);
-                                item.stack_id.set(new_val);
-
-                                let static_stack_id = item.static_stack_id.get();
-                                if new_val != static_stack_id {
-                                    return Err(module.error(item.source_range,
-                                        &format!(
-                                            "DEBUG: resolved not same for {} `{:?}` vs static `{:?}`",
-                                            name,
-                                            new_val,
-                                            static_stack_id
-                                        ), self));
-                                }
-
-                                found = true;
-                                break;
-                            }
-                        }
-                        if found {
-                            self.stack.len() - item.stack_id.get().unwrap()
-                        } else if name == "return" {
-                            return Err(module.error(item.source_range, &format!(
-                                "{}\nRequires `->` on function `{}`",
-                                self.stack_trace(),
-                                &self.call_stack.last().unwrap().fn_name), self));
-                        } else {
-                            return Err(module.error(item.source_range, &format!(
-                                "{}\nCould not find local or current variable `{}`",
-                                    self.stack_trace(), name), self));
-                        }
-                    }
+        let stack_id = match item.slot.get() {
+            Some(slot) => {
+                let stack_id = self.call_stack.last().unwrap().frame_start + slot;
+                if cfg!(feature = "debug_resolve") {
+                    try!(self.debug_resolve(item, stack_id, locals, module));
                 }
+                stack_id
             }
-        };
-
-        if cfg!(feature = "debug_resolve") {
-            for &(ref n, id) in self.local_stack.iter().rev().take(locals) {
-                if &**n == &**item.name {
-                    if stack_id != id {
-                        return Err(module.error(item.source_range,
-                            &format!("DEBUG: Not same for {} stack_id `{:?}` vs id `{:?}`",
-                                item.name,
-                                stack_id,
-                                id), self));
+            None => {
+                let name: &str = &**item.name;
+                let mut found = None;
+                for &(ref n, id) in self.local_stack.iter().rev().take(locals) {
+                    if &**n == name {
+                        found = Some(id);
+                        break;
+                    }
+                }
+                match found {
+                    Some(id) => id,
+                    None if name == "return" => {
+                        return Err(module.error(item.source_range, &format!(
+                            "{}\nRequires `->` on function `{}`",
+                            self.stack_trace(),
+                            &self.call_stack.last().unwrap().fn_name), self));
+                    }
+                    None => {
+                        return Err(module.error(item.source_range, &format!(
+                            "{}\nCould not find local or current variable `{}`",
+                                self.stack_trace(), name), self));
                     }
-                    break;
                 }
             }
-        }
+        };
 
         let stack_id = if let &Variable::Ref(ref_id) = &self.stack[stack_id] {
                 ref_id
@@ -1759,8 +3204,10 @@ impl Runtime {
                                 self.stack_trace()), self));
                     }
                 };
-                return try(&mut self.stack, &self.call_stack, v, locals,
-                           item.source_range, module);
+                let res = try(&mut self.stack, &self.call_stack, v, locals,
+                              item.source_range, module);
+                self.observe_try(&res);
+                return res;
             } else {
                 return Ok((Some(Variable::Ref(stack_id)), Flow::Continue));
             }
@@ -1779,6 +3226,41 @@ impl Runtime {
                 };
             }
         }
+        let res = self.item_ids(item, side, stack_id, start_stack_len, module);
+        self.observe_try(&res);
+        res
+    }
+
+    /// Looks up the ids of an item after evaluating index expressions.
+    fn item_ids(
+        &mut self,
+        item: &ast::Item,
+        side: Side,
+        stack_id: usize,
+        start_stack_len: usize,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        if side == Side::Right && item.ids.len() == 1 && item.try_ids.len() == 0 {
+            // Read an item of an array without copying the shared nodes.
+            let v = if let Variable::Array(ref arr) = self.stack[stack_id] {
+                let ind = match item.ids[0] {
+                    ast::Id::F64(_, ind) => Some(ind),
+                    ast::Id::Expression(_) => {
+                        match *resolve(&self.stack, &self.stack[start_stack_len]) {
+                            Variable::F64(ind, _) => Some(ind),
+                            _ => None
+                        }
+                    }
+                    ast::Id::String(..) => None
+                };
+                ind.and_then(|ind| arr.get(ind as usize).cloned())
+            } else { None };
+            if let Some(v) = v {
+                self.stack.truncate(start_stack_len);
+                return Ok((Some(v), Flow::Continue));
+            }
+        }
+
         let &mut Runtime {
             ref mut stack,
             ref mut local_stack,
@@ -1791,6 +3273,8 @@ impl Runtime {
             Side::Right => false,
             Side::LeftInsert(insert) => insert,
         };
+        let left = side != Side::Right;
+        let mut scratch = Variable::Return;
 
         let v = {
             let item_len = item.ids.len();
@@ -1804,7 +3288,9 @@ impl Runtime {
                 start_stack_len,
                 &mut expr_j,
                 insert,
-                item_len == 1
+                item_len == 1,
+                left,
+                &mut scratch
             ));
             let mut try_id_ind = 0;
             if item.try_ids.len() > 0 && item.try_ids[try_id_ind] == 0 {
@@ -1862,7 +3348,9 @@ impl Runtime {
                     &mut expr_j,
                     insert,
                     // `i` skips first index.
-                    i + 2 == item_len
+                    i + 2 == item_len,
+                    left,
+                    &mut scratch
                 ));
 
                 if item.try_ids.len() > try_id_ind &&
@@ -1928,7 +3416,9 @@ impl Runtime {
             &Variable::Return => self.return_type.clone(),
             &Variable::Bool(_, _) => self.bool_type.clone(),
             &Variable::Object(_) => self.object_type.clone(),
-            &Variable::Array(_) => self.array_type.clone(),
+            &Variable::Array(_) |
+            &Variable::F64Array(_) |
+            &Variable::Vec4Array(_) => self.array_type.clone(),
             &Variable::Link(_) => self.link_type.clone(),
             &Variable::Ref(_) => self.ref_type.clone(),
             &Variable::UnsafeRef(_) => self.unsafe_ref_type.clone(),
@@ -1936,6 +3426,7 @@ impl Runtime {
             &Variable::Option(_) => self.option_type.clone(),
             &Variable::Result(_) => self.result_type.clone(),
             &Variable::Thread(_) => self.thread_type.clone(),
+            &Variable::Channel(_) => self.channel_type.clone(),
             &Variable::Closure(_, _) => self.closure_type.clone(),
         };
         match v {
@@ -1949,120 +3440,6 @@ impl Runtime {
         compare: &ast::Compare,
         module: &Arc<Module>
     ) -> Result<(Option<Variable>, Flow), String> {
-        fn sub_compare(
-            rt: &Runtime,
-            compare: &ast::Compare,
-            module: &Module,
-            a: &Variable,
-            b: &Variable
-        ) -> Result<Variable, String> {
-            use ast::CompareOp::*;
-
-            match (rt.resolve(&b), rt.resolve(&a)) {
-                (&Variable::F64(b, _), &Variable::F64(a, ref sec)) => {
-                    Ok(Variable::Bool(match compare.op {
-                        Less => a < b,
-                        LessOrEqual => a <= b,
-                        Greater => a > b,
-                        GreaterOrEqual => a >= b,
-                        Equal => a == b,
-                        NotEqual => a != b
-                    }, sec.clone()))
-                }
-                (&Variable::Text(ref b), &Variable::Text(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Less => a < b,
-                        LessOrEqual => a <= b,
-                        Greater => a > b,
-                        GreaterOrEqual => a >= b,
-                        Equal => a == b,
-                        NotEqual => a != b
-                    }))
-                }
-                (&Variable::Bool(b, _), &Variable::Bool(a, ref sec)) => {
-                    Ok(Variable::Bool(match compare.op {
-                        Equal => a == b,
-                        NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with bools",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }, sec.clone()))
-                }
-                (&Variable::Vec4(ref b), &Variable::Vec4(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => a == b,
-                        NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with vec4s",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Object(ref b), &Variable::Object(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => a == b,
-                        NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with objects",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Array(ref b), &Variable::Array(ref a)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => a == b,
-                        NotEqual => a != b,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with arrays",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Option(None), &Variable::Option(None)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => true,
-                        NotEqual => false,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with options",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Option(None), &Variable::Option(_)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => false,
-                        NotEqual => true,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with options",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Option(_), &Variable::Option(None)) => {
-                    Ok(Variable::bool(match compare.op {
-                        Equal => false,
-                        NotEqual => true,
-                        x => return Err(module.error(compare.source_range,
-                            &format!("{}\n`{}` can not be used with options",
-                                rt.stack_trace(),
-                                x.symbol()), rt))
-                    }))
-                }
-                (&Variable::Option(Some(ref b)),
-                 &Variable::Option(Some(ref a))) => {
-                    sub_compare(rt, compare, module, a, b)
-                }
-                (b, a) => return Err(module.error(compare.source_range,
-                    &format!(
-                    "{}\n`{}` can not be used with `{}` and `{}`",
-                    rt.stack_trace(),
-                    compare.op.symbol(),
-                    rt.typeof_var(a),
-                    rt.typeof_var(b)), rt))
-            }
-        }
-
         let left = match try!(self.expression(&compare.left, Side::Right, module)) {
             (Some(x), Flow::Continue) => x,
             (x, Flow::Return) => { return Ok((x, Flow::Return)); }
@@ -2077,7 +3454,127 @@ impl Runtime {
                 &format!("{}\nExpected something from the right argument",
                     self.stack_trace()), self))
         };
-        Ok((Some(try!(sub_compare(self, compare, module, &left, &right))), Flow::Continue))
+        Ok((Some(try!(self.compare_values(compare.op, compare.source_range,
+            &left, &right, module))), Flow::Continue))
+    }
+    fn compare_values(
+        &self,
+        op: ast::CompareOp,
+        source_range: Range,
+        a: &Variable,
+        b: &Variable,
+        module: &Module
+    ) -> Result<Variable, String> {
+        use ast::CompareOp::*;
+
+        match (self.resolve(b), self.resolve(a)) {
+            (&Variable::F64(b, _), &Variable::F64(a, ref sec)) => {
+                Ok(Variable::Bool(match op {
+                    Less => a < b,
+                    LessOrEqual => a <= b,
+                    Greater => a > b,
+                    GreaterOrEqual => a >= b,
+                    Equal => a == b,
+                    NotEqual => a != b
+                }, sec.clone()))
+            }
+            (&Variable::Text(ref b), &Variable::Text(ref a)) => {
+                Ok(Variable::bool(match op {
+                    Less => a < b,
+                    LessOrEqual => a <= b,
+                    Greater => a > b,
+                    GreaterOrEqual => a >= b,
+                    Equal => a == b,
+                    NotEqual => a != b
+                }))
+            }
+            (&Variable::Bool(b, _), &Variable::Bool(a, ref sec)) => {
+                Ok(Variable::Bool(match op {
+                    Equal => a == b,
+                    NotEqual => a != b,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with bools",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }, sec.clone()))
+            }
+            (&Variable::Vec4(ref b), &Variable::Vec4(ref a)) => {
+                Ok(Variable::bool(match op {
+                    Equal => a == b,
+                    NotEqual => a != b,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with vec4s",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (&Variable::Object(ref b), &Variable::Object(ref a)) => {
+                Ok(Variable::bool(match op {
+                    Equal => a == b,
+                    NotEqual => a != b,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with objects",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (b @ &Variable::Array(_), a) |
+            (b @ &Variable::F64Array(_), a) |
+            (b @ &Variable::Vec4Array(_), a) if match *a {
+                Variable::Array(_) | Variable::F64Array(_) | Variable::Vec4Array(_) => true,
+                _ => false
+            } => {
+                Ok(Variable::bool(match op {
+                    Equal => a == b,
+                    NotEqual => a != b,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with arrays",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (&Variable::Option(None), &Variable::Option(None)) => {
+                Ok(Variable::bool(match op {
+                    Equal => true,
+                    NotEqual => false,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with options",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (&Variable::Option(None), &Variable::Option(_)) => {
+                Ok(Variable::bool(match op {
+                    Equal => false,
+                    NotEqual => true,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with options",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (&Variable::Option(_), &Variable::Option(None)) => {
+                Ok(Variable::bool(match op {
+                    Equal => false,
+                    NotEqual => true,
+                    x => return Err(module.error(source_range,
+                        &format!("{}\n`{}` can not be used with options",
+                            self.stack_trace(),
+                            x.symbol()), self))
+                }))
+            }
+            (&Variable::Option(Some(ref b)),
+             &Variable::Option(Some(ref a))) => {
+                self.compare_values(op, source_range, a, b, module)
+            }
+            (b, a) => return Err(module.error(source_range,
+                &format!(
+                "{}\n`{}` can not be used with `{}` and `{}`",
+                self.stack_trace(),
+                op.symbol(),
+                self.typeof_var(a),
+                self.typeof_var(b)), self))
+        }
     }
     fn if_expr(
         &mut self,
@@ -2098,10 +3595,11 @@ impl Runtime {
                     self.stack_trace()), self))
         };
         if val {
+            self.cover_branch(if_expr, 0, module);
             return self.block(&if_expr.true_block, module);
         }
-        for (cond, body) in if_expr.else_if_conds.iter()
-            .zip(if_expr.else_if_blocks.iter()) {
+        for (i, (cond, body)) in if_expr.else_if_conds.iter()
+            .zip(if_expr.else_if_blocks.iter()).enumerate() {
             let else_if_cond = match try!(self.expression(cond, Side::Right, module)) {
                 (Some(x), Flow::Continue) => x,
                 (x, Flow::Return) => {
@@ -2114,6 +3612,7 @@ impl Runtime {
             match self.resolve(&else_if_cond) {
                 &Variable::Bool(false, _) => {}
                 &Variable::Bool(true, _) => {
+                    self.cover_branch(if_expr, i + 1, module);
                     return self.block(body, module);
                 }
                 _ => return Err(module.error(cond.source_range(),
@@ -2121,12 +3620,101 @@ impl Runtime {
                         self.stack_trace()), self))
             }
         }
+        self.cover_branch(if_expr, if_expr.else_if_blocks.len() + 1, module);
         if let Some(ref block) = if_expr.else_block {
             self.block(block, module)
         } else {
             Ok((None, Flow::Continue))
         }
     }
+
+    /// Records the branch taken by an `if` expression for coverage.
+    fn cover_branch(&mut self, if_expr: &ast::If, taken: usize, module: &Module) {
+        if let Some(ref mut coverage) = self.coverage {
+            let file = &module.functions[self.call_stack.last().unwrap().index].file;
+            coverage.branch(file, if_expr, taken);
+        }
+    }
+    fn match_expr(
+        &mut self,
+        match_expr: &ast::Match,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use ast::Pattern;
+
+        let val = match try!(self.expression(&match_expr.expr, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+            _ => return Err(module.error(match_expr.expr.source_range(),
+                &format!("{}\nExpected something to match",
+                    self.stack_trace()), self))
+        };
+        let val = self.resolve(&val).clone();
+        for arm in &match_expr.arms {
+            // Stores the value to bind when the arm matches.
+            let bound = match (&arm.pattern, &val) {
+                (&Pattern::Some(_), &Variable::Option(Some(ref x))) |
+                (&Pattern::Ok(_), &Variable::Result(Ok(ref x))) => Some(Some((**x).clone())),
+                (&Pattern::Err(_), &Variable::Result(Err(ref err))) =>
+                    Some(Some(err.message.clone())),
+                (&Pattern::None, &Variable::Option(None)) |
+                (&Pattern::Wildcard, _) => Some(None),
+                (&Pattern::Value(ref x), v) if x == v => Some(None),
+                _ => None
+            };
+            let bound = match bound {
+                None => continue,
+                Some(bound) => bound
+            };
+            let st = self.stack.len();
+            let lc = self.local_stack.len();
+            if let (Some(name), Some(x)) = (arm.pattern.binding(), bound) {
+                self.local_stack.push((name.clone(), st));
+                self.stack.push(x);
+            }
+            let res = try!(self.expression(&arm.expr, Side::Right, module));
+            // The binding is removed from the stack, so return a copy of it.
+            let res = match res {
+                (Some(Variable::Ref(ind)), flow) if ind >= st =>
+                    (Some(self.stack[ind].clone()), flow),
+                x => x
+            };
+            self.stack.truncate(st);
+            self.local_stack.truncate(lc);
+            return Ok(res);
+        }
+        Err(module.error(match_expr.source_range,
+            &format!("{}\nNo arm matches `{}`",
+                self.stack_trace(), self.typeof_var(&val)), self))
+    }
+    fn interpolation(
+        &mut self,
+        interpolation: &ast::Interpolation,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use ast::InterpolationPart;
+        use write::{write_variable, EscapeString};
+
+        let mut buf: Vec<u8> = vec![];
+        for part in &interpolation.parts {
+            match *part {
+                InterpolationPart::Text(ref text) => buf.extend_from_slice(text.as_bytes()),
+                InterpolationPart::Expression(ref expr) => {
+                    let v = match try!(self.expression(expr, Side::Right, module)) {
+                        (Some(x), Flow::Continue) => x,
+                        (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                        _ => return Err(module.error(expr.source_range(),
+                            &format!("{}\nExpected something from interpolation",
+                                self.stack_trace()), self))
+                    };
+                    write_variable(&mut buf, self, self.resolve(&v), EscapeString::None, 0)
+                        .unwrap();
+                }
+            }
+        }
+        let text = String::from_utf8(buf).unwrap();
+        Ok((Some(Variable::Text(Arc::new(text))), Flow::Continue))
+    }
     fn for_expr(
         &mut self,
         for_expr: &ast::For,
@@ -2159,6 +3747,7 @@ impl Runtime {
                     &format!("{}\nExpected bool", self.stack_trace()), self))
             };
             if !val { break }
+            try!(self.consume_fuel(for_expr.source_range, module));
             match try!(self.block(&for_expr.block, module)) {
                 (x, Flow::Return) => return Ok((x, Flow::Return)),
                 (_, Flow::Continue) => {}
@@ -2272,6 +3861,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (x, Flow::Return) => { return Ok((x, Flow::Return)); }
                 (_, Flow::Continue) => {}
@@ -2321,6 +3911,220 @@ impl Runtime {
         self.local_stack.truncate(prev_lc);
         Ok((None, flow))
     }
+    /// Runs a short loop in parallel, splitting the range across the `par` worker pool.
+    ///
+    /// The stack is deep cloned once and shared by the parts of the range.
+    /// Variables outside the loop can not be changed, which is checked when loading.
+    /// Each part gets its own random number generator, by jumping ahead.
+    /// Parts that are not started by a worker run on the current thread when joined.
+    /// The results are combined in the order of the range.
+    fn par_n_expr(
+        &mut self,
+        expr: &ast::Expression,
+        for_n_expr: &ast::ForN,
+        module: &Arc<Module>
+    ) -> Result<(Option<Variable>, Flow), String> {
+        use ast::Expression as E;
+
+        let start = if let Some(ref start) = for_n_expr.start {
+            let start = match try!(self.expression(start, Side::Right, module)) {
+                (Some(x), Flow::Continue) => x,
+                (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+                _ => return Err(module.error(for_n_expr.end.source_range(),
+                    &format!("{}\nExpected number from for start",
+                        self.stack_trace()), self))
+            };
+            let start = match self.resolve(&start) {
+                &Variable::F64(val, _) => val,
+                x => return Err(module.error(for_n_expr.end.source_range(),
+                                &self.expected(x, "number"), self))
+            };
+            start
+        } else { 0.0 };
+
+        let end = match try!(self.expression(&for_n_expr.end, Side::Right, module)) {
+            (Some(x), Flow::Continue) => x,
+            (x, Flow::Return) => { return Ok((x, Flow::Return)); }
+            _ => return Err(module.error(for_n_expr.end.source_range(),
+                &format!("{}\nExpected number from for end",
+                    self.stack_trace()), self))
+        };
+        let end = match self.resolve(&end) {
+            &Variable::F64(val, _) => val,
+            x => return Err(module.error(for_n_expr.end.source_range(),
+                            &self.expected(x, "number"), self))
+        };
+
+        let n = if end > start { (end - start).ceil() as usize } else { 0 };
+        let threads = ::std::cmp::max(1, ::std::cmp::min(self.par_threads, n));
+
+        let scheduler = try!(self.par_scheduler().map_err(|err| module.error(
+            for_n_expr.source_range, &format!("{}\n{}", self.stack_trace(), err), self)));
+        // Deep clone the stack once, such that locals are found at the same place
+        // and the parts share values until they change them.
+        let stack: Vec<Variable> = self.stack.iter().map(|v| v.deep_clone(&self.stack)).collect();
+        let mut handles = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let range = for_n_expr.source_range;
+            let part_start = start + (n * i / threads) as f64;
+            let part_end = if i + 1 == threads { end } else {
+                start + (n * (i + 1) / threads) as f64
+            };
+            let part = Box::new(ast::ForN {
+                start: Some(E::Variable(range, Variable::f64(part_start))),
+                end: E::Variable(range, Variable::f64(part_end)),
+                par: false,
+                ..for_n_expr.clone()
+            });
+            let part = match *expr {
+                E::Sum(_) => E::Sum(part),
+                E::SumVec4(_) => E::SumVec4(part),
+                E::Prod(_) => E::Prod(part),
+                E::Min(_) => E::Min(part),
+                E::Max(_) => E::Max(part),
+                E::Sift(_) => E::Sift(part),
+                E::Any(_) => E::Any(part),
+                E::All(_) => E::All(part),
+                _ => panic!("Expected short loop")
+            };
+
+            let call_stack = self.call_stack.iter().map(|c| Call {
+                fn_name: c.fn_name.clone(),
+                index: c.index,
+                file: c.file.clone(),
+                stack_len: c.stack_len,
+                frame_start: c.frame_start,
+                local_len: c.local_len,
+                current_len: c.current_len,
+            }).collect();
+            let mut new_rt = self.thread_runtime(stack.clone(), call_stack);
+            // Give each part its own sequence of random numbers.
+            self.rng.jump();
+            new_rt.local_stack = self.local_stack.clone();
+            new_rt.current_stack = self.current_stack.clone();
+            let new_module = module.clone();
+            let stack_limit = self.stack_limit;
+            handles.push(scheduler.spawn(Box::new(move |stack_base: Option<usize>| {
+                let mut new_rt = new_rt;
+                new_rt.set_stack(stack_base, stack_limit);
+                let res = match new_rt.expression(&part, Side::Right, &new_module) {
+                    Err(err) => Err(err),
+                    Ok((Some(x), Flow::Continue)) => Ok(x.deep_clone(&new_rt.stack)),
+                    Ok((None, Flow::Continue)) => Ok(Variable::Return),
+                    Ok(_) => Err(new_module.error(range,
+                        &format!("{}\nCan not return or jump out of a parallel loop",
+                            new_rt.stack_trace()), &new_rt)),
+                };
+                new_rt.flush_fuel();
+                res
+            })));
+        }
+
+        // Wait for all parts before reporting errors.
+        let results: Vec<_> = handles.iter().map(|h| h.join(self.stack_base)).collect();
+        let mut parts = Vec::with_capacity(results.len());
+        for res in results {
+            match res {
+                Ok(Ok(x)) => parts.push(x),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Err(module.error(for_n_expr.source_range,
+                    &format!("{}\nThread in parallel loop panicked",
+                        self.stack_trace()), self)),
+            }
+        }
+
+        let res = match *expr {
+            E::Sum(_) => {
+                let mut sum = 0.0;
+                for x in &parts {
+                    if let &Variable::F64(val, _) = x { sum += val; }
+                }
+                Variable::f64(sum)
+            }
+            E::SumVec4(_) => {
+                let mut sum: [f32; 4] = [0.0; 4];
+                for x in &parts {
+                    if let &Variable::Vec4(val) = x {
+                        for k in 0..4 { sum[k] += val[k]; }
+                    }
+                }
+                Variable::Vec4(sum)
+            }
+            E::Prod(_) => {
+                let mut prod = 1.0;
+                for x in &parts {
+                    if let &Variable::F64(val, _) = x { prod *= val; }
+                }
+                Variable::f64(prod)
+            }
+            E::Min(_) | E::Max(_) => {
+                let is_min = if let E::Min(_) = *expr { true } else { false };
+                let mut res = Variable::f64(::std::f64::NAN);
+                for x in parts {
+                    let better = match (&res, &x) {
+                        (&Variable::F64(a, _), &Variable::F64(b, _)) => {
+                            a.is_nan() || if is_min { a > b } else { a < b }
+                        }
+                        _ => false
+                    };
+                    if better { res = x; }
+                }
+                res
+            }
+            E::Any(_) | E::All(_) => {
+                // Stop at the first part that decides the result.
+                let any = if let E::Any(_) = *expr { true } else { false };
+                let mut res = Variable::bool(!any);
+                for x in parts {
+                    if let Variable::Bool(val, _) = x {
+                        if val == any {
+                            res = x;
+                            break;
+                        }
+                    }
+                }
+                res
+            }
+            E::Sift(_) => {
+                let mut res = vec![];
+                for x in &parts {
+                    if let &Variable::Array(ref arr) = x {
+                        res.extend(arr.iter().cloned());
+                    }
+                }
+                Variable::Array(Arc::new(res.into()))
+            }
+            _ => panic!("Expected short loop")
+        };
+        Ok((Some(res), Flow::Continue))
+    }
+    /// Returns a packed array and the range of indices,
+    /// when the body of a short loop only indexes the array by the counter,
+    /// e.g. `sum i { a[i] }`.
+    ///
+    /// Consumes the fuel of all iterations when the fast path is taken.
+    fn packed_f64_loop(
+        &mut self,
+        for_n_expr: &ast::ForN,
+        start: f64,
+        end: f64
+    ) -> Option<(Arc<Vec<f64>>, usize, usize)> {
+        let slot = match for_n_expr.indexed_slot() {
+            Some(slot) => slot,
+            None => return None
+        };
+        let id = self.call_stack.last().unwrap().frame_start + slot;
+        let arr = match *resolve(&self.stack, &self.stack[id]) {
+            Variable::F64Array(ref arr) => arr.clone(),
+            _ => return None
+        };
+        if start < 0.0 || start.fract() != 0.0 || end > arr.len() as f64 { return None; }
+        let a = start as usize;
+        let b = if end > start { end.ceil() as usize } else { a };
+        if !self.consume_fuel_n((b - a) as u64) { return None; }
+        Some((arr, a, b))
+    }
+
     fn sum_n_expr(
         &mut self,
         for_n_expr: &ast::ForN,
@@ -2360,6 +4164,12 @@ impl Runtime {
             x => return Err(module.error(for_n_expr.end.source_range(),
                             &self.expected(x, "number"), self))
         };
+        if let Some((arr, a, b)) = self.packed_f64_loop(for_n_expr, start, end) {
+            let sum = arr[a..b].iter().fold(0.0, |sum, &x| sum + x);
+            self.stack.truncate(prev_st);
+            self.local_stack.truncate(prev_lc);
+            return Ok((Some(Variable::f64(sum)), Flow::Continue));
+        }
 
         // Initialize counter.
         self.local_stack.push((for_n_expr.name.clone(), self.stack.len()));
@@ -2377,6 +4187,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -2492,6 +4303,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -2611,6 +4423,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -2709,6 +4522,21 @@ impl Runtime {
                             &self.expected(x, "number"), self))
         };
 
+        if let Some((arr, a, b)) = self.packed_f64_loop(for_n_expr, start, end) {
+            let mut min = ::std::f64::NAN;
+            let mut ind = None;
+            for i in a..b {
+                if min.is_nan() || min > arr[i] {
+                    min = arr[i];
+                    ind = Some(i);
+                }
+            }
+            let sec = ind.map(|i| Box::new(vec![Variable::f64(i as f64)]));
+            self.stack.truncate(prev_st);
+            self.local_stack.truncate(prev_lc);
+            return Ok((Some(Variable::F64(min, sec)), Flow::Continue));
+        }
+
         let mut min = ::std::f64::NAN;
         let mut sec = None;
         // Initialize counter.
@@ -2727,6 +4555,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -2839,6 +4668,21 @@ impl Runtime {
                             &self.expected(x, "number"), self))
         };
 
+        if let Some((arr, a, b)) = self.packed_f64_loop(for_n_expr, start, end) {
+            let mut max = ::std::f64::NAN;
+            let mut ind = None;
+            for i in a..b {
+                if max.is_nan() || max < arr[i] {
+                    max = arr[i];
+                    ind = Some(i);
+                }
+            }
+            let sec = ind.map(|i| Box::new(vec![Variable::f64(i as f64)]));
+            self.stack.truncate(prev_st);
+            self.local_stack.truncate(prev_lc);
+            return Ok((Some(Variable::F64(max, sec)), Flow::Continue));
+        }
+
         let mut max = ::std::f64::NAN;
         let mut sec = None;
         // Initialize counter.
@@ -2858,6 +4702,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -2988,6 +4833,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -3119,6 +4965,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => {
                     match self.resolve(&x) {
@@ -3249,6 +5096,7 @@ impl Runtime {
                 x => return Err(module.error(for_n_expr.source_range,
                                 &self.expected(x, "number"), self))
             };
+            try!(self.consume_fuel(for_n_expr.source_range, module));
             match try!(self.block(&for_n_expr.block, module)) {
                 (Some(x), Flow::Continue) => res.push(x),
                 (x, Flow::Return) => { return Ok((x, Flow::Return)); }
@@ -3300,7 +5148,7 @@ impl Runtime {
         };
         self.stack.truncate(prev_st);
         self.local_stack.truncate(prev_lc);
-        Ok((Some(Variable::Array(Arc::new(res))), flow))
+        Ok((Some(Variable::Array(Arc::new(res.into()))), flow))
     }
     fn vec4(
         &mut self,
@@ -3382,27 +5230,36 @@ impl Runtime {
                 &format!("{}\nExpected something from unary argument",
                     self.stack_trace()), self))
         };
-        let v = match self.resolve(&val) {
+        Ok((Some(try!(self.unop_value(unop.op, unop.source_range, &val, module))),
+            Flow::Continue))
+    }
+    fn unop_value(
+        &self,
+        op: ast::UnOp,
+        source_range: Range,
+        val: &Variable,
+        module: &Module
+    ) -> Result<Variable, String> {
+        Ok(match self.resolve(val) {
             &Variable::Bool(b, ref sec) => {
-                Variable::Bool(match unop.op {
+                Variable::Bool(match op {
                     ast::UnOp::Not => !b,
-                    _ => return Err(module.error(unop.source_range,
+                    _ => return Err(module.error(source_range,
                                     &format!("{}\nUnknown boolean unary operator",
                                              self.stack_trace()), self))
                 }, sec.clone())
             }
             &Variable::F64(v, ref sec) => {
-                Variable::F64(match unop.op {
+                Variable::F64(match op {
                     ast::UnOp::Neg => -v,
-                    _ => return Err(module.error(unop.source_range,
+                    _ => return Err(module.error(source_range,
                                     &format!("{}\nUnknown number unary operator",
                                              self.stack_trace()), self))
                 }, sec.clone())
             }
-            _ => return Err(module.error(unop.source_range,
+            _ => return Err(module.error(source_range,
                 &format!("{}\nInvalid type, expected bool", self.stack_trace()), self))
-        };
-        Ok((Some(v), Flow::Continue))
+        })
     }
     fn binop(
         &mut self,
@@ -3442,23 +5299,36 @@ impl Runtime {
                 &format!("{}\nExpected something from right argument",
                     self.stack_trace()), self))
         };
-        let v = match (self.resolve(&left), self.resolve(&right)) {
+        Ok((Some(try!(self.binop_values(binop.op, binop.source_range,
+            &left, &right, module))), Flow::Continue))
+    }
+    fn binop_values(
+        &self,
+        op: ast::BinOp,
+        source_range: Range,
+        left: &Variable,
+        right: &Variable,
+        module: &Module
+    ) -> Result<Variable, String> {
+        use ast::BinOp::*;
+
+        Ok(match (self.resolve(left), self.resolve(right)) {
             (&Variable::F64(a, ref sec), &Variable::F64(b, _)) => {
-                Variable::F64(match binop.op {
+                Variable::F64(match op {
                     Add => a + b,
                     Sub => a - b,
                     Mul => a * b,
                     Div => a / b,
                     Rem => a % b,
                     Pow => a.powf(b),
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nUnknown number operator `{:?}`",
                             self.stack_trace(),
-                            binop.op.symbol()), self))
+                            op.symbol()), self))
                 }, sec.clone())
             }
             (&Variable::Vec4(a), &Variable::Vec4(b)) => {
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]]),
                     Sub => Variable::Vec4([a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]]),
                     Mul => Variable::Vec4([a[0] * b[0], a[1] * b[1], a[2] * b[2], a[3] * b[3]]),
@@ -3471,36 +5341,36 @@ impl Runtime {
                     Rem => Variable::Vec4([a[0] % b[0], a[1] % b[1], a[2] % b[2], a[3] % b[3]]),
                     Pow => Variable::Vec4([a[0].powf(b[0]), a[1].powf(b[1]),
                                            a[2].powf(b[2]), a[3].powf(b[3])]),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `vec4` and `vec4`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::Vec4(a), &Variable::F64(b, _)) => {
                 let b = b as f32;
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a[0] + b, a[1] + b, a[2] + b, a[3] + b]),
                     Sub => Variable::Vec4([a[0] - b, a[1] - b, a[2] - b, a[3] - b]),
                     Mul => Variable::Vec4([a[0] * b, a[1] * b, a[2] * b, a[3] * b]),
                     Dot => Variable::f64((a[0] * b + a[1] * b +
                                           a[2] * b + a[3] * b) as f64),
-                    Cross => return Err(module.error(binop.source_range,
+                    Cross => return Err(module.error(source_range,
                         &format!("{}\nExpected two vec4 for `{:?}`",
-                            self.stack_trace(), binop.op.symbol()), self)),
+                            self.stack_trace(), op.symbol()), self)),
                     Div => Variable::Vec4([a[0] / b, a[1] / b, a[2] / b, a[3] / b]),
                     Rem => Variable::Vec4([a[0] % b, a[1] % b, a[2] % b, a[3] % b]),
                     Pow => Variable::Vec4([a[0].powf(b), a[1].powf(b),
                                            a[2].powf(b), a[3].powf(b)]),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `vec4` and `f64`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::F64(a, _), &Variable::Vec4(b)) => {
                 let a = a as f32;
-                match binop.op {
+                match op {
                     Add => Variable::Vec4([a + b[0], a + b[1], a + b[2], a + b[3]]),
                     Sub => Variable::Vec4([a - b[0], a - b[1], a - b[2], a - b[3]]),
                     Mul => Variable::Vec4([a * b[0], a * b[1], a * b[2], a * b[3]]),
@@ -3510,72 +5380,207 @@ impl Runtime {
                     Rem => Variable::Vec4([a % b[0], a % b[1], a % b[2], a % b[3]]),
                     Pow => Variable::Vec4([a.powf(b[0]), a.powf(b[1]),
                                            a.powf(b[2]), a.powf(b[3])]),
-                    Cross => return Err(module.error(binop.source_range,
+                    Cross => return Err(module.error(source_range,
                         &format!("{}\nExpected two vec4 for `{:?}`",
-                            self.stack_trace(), binop.op.symbol()), self)),
-                    AndAlso | OrElse => return Err(module.error(binop.source_range,
+                            self.stack_trace(), op.symbol()), self)),
+                    AndAlso | OrElse => return Err(module.error(source_range,
                         &format!("{}\nUnknown operator `{:?}` for `f64` and `vec4`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self)),
+                            op.symbol_bool()), self)),
                 }
             }
             (&Variable::Bool(a, ref sec), &Variable::Bool(b, _)) => {
-                Variable::Bool(match binop.op {
+                Variable::Bool(match op {
                     Add | OrElse => a || b,
                     // Boolean subtraction with lazy precedence.
                     Sub => a && !b,
                     Mul | AndAlso => a && b,
                     Pow => a ^ b,
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nUnknown boolean operator `{:?}`",
                             self.stack_trace(),
-                            binop.op.symbol_bool()), self))
+                            op.symbol_bool()), self))
                 }, sec.clone())
             }
             (&Variable::Text(ref a), &Variable::Text(ref b)) => {
-                match binop.op {
+                match op {
                     Add => {
                         let mut res = String::with_capacity(a.len() + b.len());
                         res.push_str(a);
                         res.push_str(b);
                         Variable::Text(Arc::new(res))
                     }
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nThis operation can not be used with strings",
                             self.stack_trace()), self))
                 }
             }
             (&Variable::Text(_), _) =>
-                return Err(module.error(binop.source_range,
+                return Err(module.error(source_range,
                 &format!("{}\nThe right argument must be a string. \
                 Try the `str` function", self.stack_trace()), self)),
             (&Variable::Link(ref a), &Variable::Link(ref b)) => {
-                match binop.op {
+                match op {
                     Add => {
                         Variable::Link(Box::new(a.add(b)))
                     }
-                    _ => return Err(module.error(binop.source_range,
+                    _ => return Err(module.error(source_range,
                         &format!("{}\nThis operation can not be used with links",
                             self.stack_trace()), self))
                 }
             }
-            _ => return Err(module.error(binop.source_range, &format!(
+            _ => return Err(module.error(source_range, &format!(
                 "{}\nInvalid type for binary operator `{:?}`, \
                 expected numbers, vec4s, bools or strings",
                 self.stack_trace(),
-                binop.op.symbol()), self))
-        };
-
-        Ok((Some(v), Flow::Continue))
+                op.symbol()), self))
+        })
     }
     pub fn stack_trace(&self) -> String {
         stack_trace(&self.call_stack)
     }
+
+    /// Returns the locals of the current function, formatted as Dyon values.
+    pub fn debug_locals(&self) -> Vec<(Arc<String>, String)> {
+        let lc = self.call_stack.last().map(|c| c.local_len).unwrap_or(0);
+        self.debug_variables(&self.local_stack[lc..])
+    }
+
+    /// Returns the current objects, formatted as Dyon values.
+    pub fn debug_currents(&self) -> Vec<(Arc<String>, String)> {
+        self.debug_variables(&self.current_stack)
+    }
+
+    fn debug_variables(&self, vars: &[(Arc<String>, usize)]) -> Vec<(Arc<String>, String)> {
+        use write::{write_variable, EscapeString};
+
+        vars.iter().map(|&(ref name, id)| {
+            let mut w: Vec<u8> = vec![];
+            write_variable(&mut w, self, self.resolve(&self.stack[id]),
+                           EscapeString::Json, 0).unwrap();
+            (name.clone(), String::from_utf8(w).unwrap())
+        }).collect()
+    }
+
+    /// Pauses in the debugger if there is a breakpoint or a step ends.
+    fn debug_statement(&mut self, source_range: Range, module: &Module) {
+        let location = {
+            let call = self.call_stack.last().unwrap();
+            let source = &module.functions[call.index].source;
+            let (line, column) = Location::line_column(source, source_range);
+            Location {
+                file: call.file.clone(),
+                fn_name: call.fn_name.clone(),
+                line: line,
+                column: column,
+                depth: self.call_stack.len(),
+            }
+        };
+        let mut debugger = self.debugger.take().unwrap();
+        if debugger.should_pause(&location) {
+            debugger.pause(self, &location);
+        }
+        self.debugger = Some(debugger);
+    }
+
+    /// Consumes one unit of fuel and checks the deadline and interrupt.
+    pub fn consume_fuel(
+        &mut self,
+        source_range: Range,
+        module: &Module
+    ) -> Result<(), String> {
+        if self.interrupt.is_interrupted() {
+            return Err(module.error(source_range,
+                &format!("{}\nInterrupted", self.stack_trace()), self));
+        }
+        if self.fuel.limited.load(Ordering::Relaxed) {
+            // Never goes below zero, such that threads running out of fuel agree.
+            if self.fuel.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                |fuel| fuel.checked_sub(1)).is_err() {
+                return Err(module.error(source_range,
+                    &format!("{}\nOut of fuel", self.stack_trace()), self));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(module.error(source_range,
+                    &format!("{}\nDeadline exceeded", self.stack_trace()), self));
+            }
+        }
+        self.unflushed_fuel += 1;
+        if self.unflushed_fuel >= FUEL_FLUSH { self.flush_fuel(); }
+        Ok(())
+    }
+
+    /// Consumes fuel for `n` loop iterations at once.
+    ///
+    /// Returns `false` without consuming fuel when there is not enough,
+    /// or when the script is stopped, such that the loop can run one iteration at a time
+    /// and report the error at the right place.
+    fn consume_fuel_n(&mut self, n: u64) -> bool {
+        if n == 0 { return true; }
+        if self.interrupt.is_interrupted() { return false; }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline { return false; }
+        }
+        if self.fuel.limited.load(Ordering::Relaxed) {
+            if self.fuel.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                |fuel| fuel.checked_sub(n)).is_err() {
+                return false;
+            }
+        }
+        self.unflushed_fuel += n;
+        if self.unflushed_fuel >= FUEL_FLUSH { self.flush_fuel(); }
+        true
+    }
+
+    /// Checks the depth of the call stack and the native stack.
+    fn check_call_depth(&self, source_range: Range, module: &Module) -> Result<(), String> {
+        if let Some(max_call_depth) = self.max_call_depth {
+            if self.call_stack.len() > max_call_depth {
+                return Err(module.error(source_range,
+                    &format!("{}\nExceeded maximum call depth of {}",
+                        self.stack_trace(), max_call_depth), self));
+            }
+        }
+        let max_stack_size = self.max_stack_size.unwrap_or(self.stack_limit);
+        if self.stack_base != 0 && max_stack_size != 0 {
+            let address = stack_address();
+            let used = if self.stack_base > address {
+                    self.stack_base - address
+                } else {
+                    address - self.stack_base
+                };
+            if used > max_stack_size {
+                return Err(module.error(source_range,
+                    &format!("{}\nExceeded maximum stack size of {} bytes",
+                        self.stack_trace(), max_stack_size), self));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns an address on the native stack.
+#[inline(always)]
+fn stack_address() -> usize {
+    let x = 0u8;
+    &x as *const u8 as usize
 }
 
+/// The number of calls shown at each end of a long stack trace.
+const STACK_TRACE_ENDS: usize = 10;
+
 fn stack_trace(call_stack: &[Call]) -> String {
     let mut s = String::new();
-    for call in call_stack.iter() {
+    let n = call_stack.len();
+    for (i, call) in call_stack.iter().enumerate() {
+        if n > 2 * STACK_TRACE_ENDS && i >= STACK_TRACE_ENDS && i < n - STACK_TRACE_ENDS {
+            if i == STACK_TRACE_ENDS {
+                s.push_str(&format!("... {} calls omitted\n", n - 2 * STACK_TRACE_ENDS));
+            }
+            continue;
+        }
         s.push_str(&call.fn_name);
         if let Some(ref file) = call.file {
             s.push_str(" (");
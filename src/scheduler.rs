@@ -0,0 +1,361 @@
+/*
+Worker pool running the threads started with `go`.
+
+A fixed number of worker threads take tasks from a shared queue.
+Runtimes of tasks share the module and the scheduler of the runtime starting them,
+so starting a thread does not clone the module or create an OS thread.
+
+When a task is joined before a worker has started it,
+the joining thread runs the task itself instead of waiting.
+This keeps a full pool from blocking on tasks that are still in the queue.
+
+A worker waiting on a channel, or joining a thread with a timeout,
+does not count as a worker while it waits.
+If no other worker is free to take tasks from the queue, a temporary worker is started,
+which stops when it is idle and the pool has enough workers again.
+Tasks are never run on a thread waiting on a channel,
+because the task might wait for a value that the waiting thread sends later.
+
+A worker polling a thread with `is_done` is still counted as a worker,
+so a temporary worker is started when nobody else takes tasks from the queue.
+*/
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use Variable;
+
+/// The function of a task.
+///
+/// Takes the native stack address to use as stack base when the task runs
+/// on the stack of a joining thread, `None` when it runs on a worker.
+pub type TaskFn = Box<FnOnce(Option<usize>) -> Result<Variable, String> + Send>;
+
+enum State {
+    Pending(TaskFn),
+    Running,
+    Done(thread::Result<Result<Variable, String>>),
+    Joined,
+}
+
+/// A task started with `go`.
+struct Task {
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+impl Task {
+    /// Runs the task if it is not started yet.
+    fn run(&self, stack_base: Option<usize>) {
+        use std::mem::replace;
+
+        let f = {
+            let mut state = self.state.lock().unwrap();
+            match replace(&mut *state, State::Running) {
+                State::Pending(f) => f,
+                x => {
+                    *state = x;
+                    return;
+                }
+            }
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(move || f(stack_base)));
+        *self.state.lock().unwrap() = State::Done(res);
+        self.done.notify_all();
+    }
+}
+
+/// Waits for the result of a task.
+pub struct JoinHandle {
+    task: Arc<Task>,
+}
+
+impl JoinHandle {
+    /// Waits for the task to finish and returns the result.
+    ///
+    /// Runs the task on the current thread if no worker has started it,
+    /// using `stack_base` as the base of the native stack.
+    pub fn join(&self, stack_base: usize) -> thread::Result<Result<Variable, String>> {
+        self.task.run(Some(stack_base));
+        self.wait(None).unwrap()
+    }
+
+    /// Waits at most `timeout` for the task to finish and returns the result.
+    ///
+    /// Returns `None` if the task is not done in time.
+    /// Waits without a deadline when the timeout is too large to represent.
+    /// The task is not run on the current thread, since it might take longer.
+    /// A worker calling this should be counted as blocked with `Scheduler::block`,
+    /// such that another worker can start the task.
+    pub fn join_timeout(
+        &self,
+        timeout: Duration
+    ) -> Option<thread::Result<Result<Variable, String>>> {
+        self.wait(Instant::now().checked_add(timeout))
+    }
+
+    /// Returns `true` if the task is done.
+    pub fn is_done(&self) -> bool {
+        match *self.task.state.lock().unwrap() {
+            State::Done(_) | State::Joined => true,
+            State::Pending(_) | State::Running => false,
+        }
+    }
+
+    fn wait(
+        &self,
+        deadline: Option<Instant>
+    ) -> Option<thread::Result<Result<Variable, String>>> {
+        use std::mem::replace;
+
+        let mut state = self.task.state.lock().unwrap();
+        loop {
+            match replace(&mut *state, State::Joined) {
+                State::Done(res) => return Some(res),
+                State::Joined => {
+                    return Some(Ok(Err("The Thread has already been joined".into())));
+                }
+                x => *state = x,
+            }
+            state = match deadline {
+                None => self.task.done.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline { return None; }
+                    self.task.done.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+thread_local! {
+    /// The address of the shared state of the scheduler this thread is a worker of.
+    static WORKER_OF: Cell<usize> = Cell::new(0);
+}
+
+struct Queue {
+    tasks: VecDeque<Arc<Task>>,
+    shutdown: bool,
+    /// The number of live worker threads.
+    live: usize,
+    /// The number of workers waiting for a task.
+    idle: usize,
+    /// The number of workers waiting on a channel.
+    blocked: usize,
+    /// Used to name worker threads.
+    started: usize,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    /// The number of workers taking tasks, not counting blocked workers.
+    workers: usize,
+    stack_size: usize,
+}
+
+impl Shared {
+    /// Returns `true` if a worker should be started to take tasks from the queue.
+    fn needs_worker(&self, queue: &Queue) -> bool {
+        !queue.shutdown && queue.idle == 0 && !queue.tasks.is_empty() &&
+        queue.live - queue.blocked < self.workers
+    }
+
+    /// Returns `true` if a worker is not needed when the queue is empty.
+    fn too_many_workers(&self, queue: &Queue) -> bool {
+        queue.live - queue.blocked > self.workers
+    }
+}
+
+/// Starts a worker thread.
+///
+/// The worker must be counted as live before it is started.
+fn start_worker(shared: &Arc<Shared>, id: usize) -> Result<(), String> {
+    let worker_shared = shared.clone();
+    let res = thread::Builder::new()
+        .name(format!("dyon-worker-{}", id))
+        .stack_size(shared.stack_size)
+        .spawn(move || work(&worker_shared));
+    match res {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            shared.queue.lock().unwrap().live -= 1;
+            Err(format!("Could not start worker thread:\n{}", err))
+        }
+    }
+}
+
+/// Counts a worker as blocked until it is dropped.
+///
+/// Created by `Scheduler::block`.
+pub struct Blocked {
+    shared: Option<Arc<Shared>>,
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        if let Some(ref shared) = self.shared {
+            shared.queue.lock().unwrap().blocked -= 1;
+            // Let idle workers check whether they are still needed.
+            shared.available.notify_all();
+        }
+    }
+}
+
+/// Runs tasks on a fixed number of worker threads.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: usize,
+}
+
+impl Scheduler {
+    /// Starts a new scheduler with `workers` threads of `stack_size` bytes.
+    pub fn new(workers: usize, stack_size: usize) -> Result<Scheduler, String> {
+        let workers = if workers == 0 { 1 } else { workers };
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                tasks: VecDeque::new(),
+                shutdown: false,
+                live: workers,
+                idle: 0,
+                blocked: 0,
+                started: workers,
+            }),
+            available: Condvar::new(),
+            workers: workers,
+            stack_size: stack_size,
+        });
+        for i in 0..workers {
+            if let Err(err) = start_worker(&shared, i) {
+                // Stop the workers that are started.
+                shared.queue.lock().unwrap().shutdown = true;
+                shared.available.notify_all();
+                return Err(err);
+            }
+        }
+        Ok(Scheduler {
+            shared: shared,
+            workers: workers,
+        })
+    }
+
+    /// Returns the number of worker threads.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Adds a task to the queue.
+    pub fn spawn(&self, f: TaskFn) -> JoinHandle {
+        let task = Arc::new(Task {
+            state: Mutex::new(State::Pending(f)),
+            done: Condvar::new(),
+        });
+        let id = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.tasks.push_back(task.clone());
+            self.extra_worker(&mut queue)
+        };
+        self.shared.available.notify_one();
+        if let Some(id) = id {
+            // The task stays in the queue and is run when joined.
+            let _ = start_worker(&self.shared, id);
+        }
+        JoinHandle {task: task}
+    }
+
+    /// Counts the current thread as blocked until the returned value is dropped,
+    /// if it is a worker of this scheduler.
+    ///
+    /// Starts a temporary worker if tasks in the queue would have no free worker.
+    pub fn block(&self) -> Result<Blocked, String> {
+        let address = &*self.shared as *const Shared as usize;
+        if WORKER_OF.with(|w| w.get()) != address {
+            return Ok(Blocked {shared: None});
+        }
+        let id = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.blocked += 1;
+            self.extra_worker(&mut queue)
+        };
+        let blocked = Blocked {shared: Some(self.shared.clone())};
+        if let Some(id) = id {
+            try!(start_worker(&self.shared, id));
+        }
+        Ok(blocked)
+    }
+
+    /// Starts a temporary worker if the current thread is a worker of this scheduler,
+    /// and no other worker takes tasks from the queue.
+    ///
+    /// Used when the current thread polls for a task that might still be in the queue.
+    pub fn poll(&self) -> Result<(), String> {
+        let address = &*self.shared as *const Shared as usize;
+        if WORKER_OF.with(|w| w.get()) != address {
+            return Ok(());
+        }
+        let id = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            // Counts the current thread as busy,
+            // but starts at most one worker more than the pool.
+            if !queue.shutdown && queue.idle == 0 && !queue.tasks.is_empty() &&
+               queue.live - queue.blocked <= self.shared.workers {
+                queue.live += 1;
+                queue.started += 1;
+                Some(queue.started - 1)
+            } else {
+                None
+            }
+        };
+        if let Some(id) = id {
+            try!(start_worker(&self.shared, id));
+        }
+        Ok(())
+    }
+
+    /// Counts a new worker as live if one is needed and returns its id.
+    fn extra_worker(&self, queue: &mut Queue) -> Option<usize> {
+        if self.shared.needs_worker(queue) {
+            queue.live += 1;
+            queue.started += 1;
+            Some(queue.started - 1)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+    }
+}
+
+/// Runs tasks until the scheduler is dropped and the queue is empty.
+///
+/// Stops earlier when the queue is empty and there are more workers than needed.
+fn work(shared: &Shared) {
+    WORKER_OF.with(|w| w.set(shared as *const Shared as usize));
+    loop {
+        let task = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if queue.tasks.is_empty() &&
+                   (shared.too_many_workers(&queue) || queue.shutdown) {
+                    queue.live -= 1;
+                    return;
+                }
+                if let Some(task) = queue.tasks.pop_front() { break task; }
+                queue.idle += 1;
+                queue = shared.available.wait(queue).unwrap();
+                queue.idle -= 1;
+            }
+        };
+        task.run(None);
+    }
+}
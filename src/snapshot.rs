@@ -0,0 +1,579 @@
+/*
+Saves the state of a runtime and restores it later, e.g. in a new process.
+
+A snapshot stores the stack, call frames, local and current variables,
+and the state of the random number generator.
+The code is not stored, so a snapshot must be restored with the same module.
+Call frames refer to functions by index, which is checked against the module.
+
+Evaluation of a function uses the native stack of the host,
+so a snapshot does not continue in the middle of a function.
+The host calls into the module again after restoring,
+where current objects declared by the saved call frames are available.
+A typical use is to save from an external function or a debugger handler,
+and resume by calling a function that uses the same current objects.
+
+Resuming a runtime that is paused in the middle of a function,
+e.g. by a debugger or an interrupt, is out of scope.
+
+Rust objects, threads, closures and unsafe references can not be saved,
+and produce an error naming the variable that holds them.
+
+Restoring checks every index in the snapshot and limits how deep variables are nested,
+such that a truncated or invalid snapshot gives an error instead of a panic.
+*/
+
+use std::io;
+use std::sync::Arc;
+use rand;
+
+use link::Link;
+use runtime::Call;
+use Error;
+use Module;
+use ObjectMap;
+use PersistentVec;
+use Runtime;
+use Symbol;
+use Variable;
+
+const MAGIC: &[u8] = b"dyon-snapshot-1\n";
+
+/// The maximum depth of variables inside other variables when restoring.
+const MAX_DEPTH: usize = 256;
+
+/// Random number generator with a small state, such that it can be saved.
+///
+/// Uses the xoshiro256** algorithm.
+#[derive(Clone)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    /// Creates a new random number generator seeded by the operating system.
+    pub fn new() -> Rng {
+        use rand::Rng as R;
+
+        let mut os = rand::OsRng::new().unwrap();
+        Rng::from_seed(os.next_u64())
+    }
+
+    /// Creates a new random number generator from a seed.
+    pub fn from_seed(seed: u64) -> Rng {
+        // Expand the seed with SplitMix64, which never gives a state of zeros.
+        let mut x = seed;
+        let mut state = [0; 4];
+        for s in &mut state {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *s = z ^ (z >> 31);
+        }
+        Rng { state }
+    }
+
+    /// Advances the generator as much as 2^128 calls to `next_u64`.
+    ///
+    /// Used to give threads their own sequence of numbers,
+    /// by cloning the generator before jumping.
+    pub fn jump(&mut self) {
+        use rand::Rng as R;
+
+        const JUMP: [u64; 4] = [
+            0x180ec6d33cfd0aba, 0xd5a61266f0c9392c,
+            0xa9582618e03fc9aa, 0x39abdc4529b1661c
+        ];
+
+        let mut state = [0; 4];
+        for &jump in &JUMP {
+            for bit in 0..64 {
+                if jump & (1 << bit) != 0 {
+                    for (s, x) in state.iter_mut().zip(self.state.iter()) {
+                        *s ^= *x;
+                    }
+                }
+                self.next_u64();
+            }
+        }
+        self.state = state;
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng::new()
+    }
+}
+
+impl rand::Rng for Rng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let res = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        res
+    }
+}
+
+/// Writes a snapshot of a runtime.
+pub fn save<W: io::Write>(rt: &Runtime, w: &mut W) -> Result<(), String> {
+    let mut s = Saver { rt, data: vec![] };
+    s.runtime()?;
+    w.write_all(&s.data).map_err(|err| format!("Could not write snapshot, {}", err))
+}
+
+/// Restores a snapshot into a runtime.
+///
+/// The module must be the same as the one running when the snapshot was saved.
+/// The runtime is left unchanged when an error occurs.
+pub fn restore<R: io::Read>(rt: &mut Runtime, r: &mut R, module: &Module) -> Result<(), String> {
+    let mut data = vec![];
+    r.read_to_end(&mut data).map_err(|err| format!("Could not read snapshot, {}", err))?;
+    let mut l = Loader { data: &data, pos: 0, stack_len: 0, depth: 0 };
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err("Not a snapshot, or saved by another version".into());
+    }
+    l.pos = MAGIC.len();
+
+    let mut rng = Rng { state: [0; 4] };
+    for s in &mut rng.state { *s = l.u64()?; }
+    if rng.state == [0; 4] {
+        return Err("Invalid state of random number generator in snapshot".into());
+    }
+
+    let n = l.len()?;
+    let mut call_stack = Vec::with_capacity(n);
+    for _ in 0..n {
+        let fn_name = l.text()?;
+        let index = l.u64()? as usize;
+        let file = if l.bool()? { Some(l.text()?) } else { None };
+        match (module.functions.get(index), &file) {
+            (Some(f), Some(file)) if f.name == fn_name && &f.file == file => {}
+            // Intrinsics and calls from the host have no file.
+            (Some(_), None) => {}
+            (None, None) if index == 0 => {}
+            (_, Some(file)) => {
+                return Err(format!("Function `{}` in `{}` is not found at index {}, \
+                                    the snapshot was saved with another module",
+                                   fn_name, file, index));
+            }
+            (_, None) => {
+                return Err(format!("Function index {} of `{}` is outside the module, \
+                                    the snapshot was saved with another module",
+                                   index, fn_name));
+            }
+        }
+        call_stack.push(Call {
+            fn_name,
+            index,
+            file,
+            stack_len: l.u64()? as usize,
+            frame_start: l.u64()? as usize,
+            local_len: l.u64()? as usize,
+            current_len: l.u64()? as usize,
+        });
+    }
+    let local_stack = l.names()?;
+    let current_stack = l.names()?;
+
+    let n = l.len()?;
+    // References are checked against the length of the stack when read.
+    l.stack_len = n;
+    let mut stack = Vec::with_capacity(n);
+    for _ in 0..n { stack.push(l.variable()?); }
+    if l.pos != data.len() {
+        return Err("Unexpected data at end of snapshot".into());
+    }
+
+    // Check that indices point inside the stacks.
+    let len = stack.len();
+    let out_of_stack = |&(_, id): &(Arc<String>, usize)| id >= len;
+    if local_stack.iter().any(&out_of_stack) ||
+       current_stack.iter().any(&out_of_stack) ||
+       call_stack.iter().any(|c| c.stack_len > len || c.frame_start > len ||
+                                 c.local_len > local_stack.len() ||
+                                 c.current_len > current_stack.len()) {
+        return Err("Snapshot refers outside the stack".into());
+    }
+
+    rt.stack = stack;
+    rt.call_stack = call_stack;
+    rt.local_stack = local_stack;
+    rt.current_stack = current_stack;
+    rt.rng = rng;
+    Ok(())
+}
+
+struct Saver<'a> {
+    rt: &'a Runtime,
+    data: Vec<u8>,
+}
+
+impl<'a> Saver<'a> {
+    fn runtime(&mut self) -> Result<(), String> {
+        let rt = self.rt;
+        self.data.extend_from_slice(MAGIC);
+
+        for &s in &rt.rng.state { self.u64(s); }
+
+        self.u64(rt.call_stack.len() as u64);
+        for call in &rt.call_stack {
+            self.text(&call.fn_name);
+            self.u64(call.index as u64);
+            match call.file {
+                None => self.bool(false),
+                Some(ref file) => {
+                    self.bool(true);
+                    self.text(file);
+                }
+            }
+            self.u64(call.stack_len as u64);
+            self.u64(call.frame_start as u64);
+            self.u64(call.local_len as u64);
+            self.u64(call.current_len as u64);
+        }
+        self.names(&rt.local_stack);
+        self.names(&rt.current_stack);
+
+        self.u64(rt.stack.len() as u64);
+        for (i, v) in rt.stack.iter().enumerate() {
+            if let Err(ty) = self.variable(v) {
+                return Err(format!("Can not save `{}` in snapshot, held by {}",
+                                   ty, self.holder(i)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the variable holding a stack slot.
+    fn holder(&self, ind: usize) -> String {
+        let rt = self.rt;
+        if let Some((name, _)) = rt.local_stack.iter().rev().find(|&&(_, id)| id == ind) {
+            return format!("variable `{}`", name);
+        }
+        if let Some((name, _)) = rt.current_stack.iter().rev().find(|&&(_, id)| id == ind) {
+            return format!("current object `~ {}`", name);
+        }
+        match rt.call_stack.iter().rev().find(|c| c.frame_start <= ind) {
+            Some(call) => format!("stack index {} in `{}`", ind, call.fn_name),
+            None => format!("stack index {}", ind),
+        }
+    }
+
+    fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    fn u64(&mut self, val: u64) {
+        for i in 0..8 {
+            self.data.push((val >> (i * 8)) as u8);
+        }
+    }
+
+    fn text(&mut self, val: &str) {
+        self.u64(val.len() as u64);
+        self.data.extend_from_slice(val.as_bytes());
+    }
+
+    fn names(&mut self, names: &[(Arc<String>, usize)]) {
+        self.u64(names.len() as u64);
+        for &(ref name, id) in names {
+            self.text(name);
+            self.u64(id as u64);
+        }
+    }
+
+    fn secret(&mut self, sec: Option<&[Variable]>) -> Result<(), Arc<String>> {
+        match sec {
+            None => self.bool(false),
+            Some(sec) => {
+                self.bool(true);
+                self.u64(sec.len() as u64);
+                for v in sec.iter() { self.variable(v)?; }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the type of a variable that can not be saved.
+    fn variable(&mut self, v: &Variable) -> Result<(), Arc<String>> {
+        match *v {
+            Variable::Ref(ind) => {
+                self.u8(REF);
+                self.u64(ind as u64);
+            }
+            Variable::Return => self.u8(RETURN),
+            Variable::Bool(val, ref sec) => {
+                self.u8(BOOL);
+                self.bool(val);
+                self.secret(sec.as_ref().map(|sec| &sec[..]))?;
+            }
+            Variable::F64(val, ref sec) => {
+                self.u8(F64);
+                self.u64(val.to_bits());
+                self.secret(sec.as_ref().map(|sec| &sec[..]))?;
+            }
+            Variable::Vec4(val) => {
+                self.u8(VEC4);
+                for &x in &val { self.u64(x.to_bits() as u64); }
+            }
+            Variable::Text(ref val) => {
+                self.u8(TEXT);
+                self.text(val);
+            }
+            Variable::Array(ref arr) => {
+                self.u8(ARRAY);
+                self.u64(arr.len() as u64);
+                for v in arr.iter() { self.variable(v)?; }
+            }
+            Variable::F64Array(ref arr) => {
+                self.u8(F64_ARRAY);
+                self.u64(arr.len() as u64);
+                for &x in arr.iter() { self.u64(x.to_bits()); }
+            }
+            Variable::Vec4Array(ref arr) => {
+                self.u8(VEC4_ARRAY);
+                self.u64(arr.len() as u64);
+                for val in arr.iter() {
+                    for &x in val { self.u64(x.to_bits() as u64); }
+                }
+            }
+            Variable::Object(ref obj) => {
+                self.u8(OBJECT);
+                self.u64(obj.len() as u64);
+                for (key, v) in obj.iter() {
+                    self.text(key);
+                    self.variable(v)?;
+                }
+            }
+            Variable::Link(ref link) => {
+                self.u8(LINK);
+                let n: usize = link.slices.iter().map(|s| (s.end - s.start) as usize).sum();
+                self.u64(n as u64);
+                for slice in &link.slices {
+                    for i in slice.start..slice.end {
+                        self.variable(&slice.block.var(i))?;
+                    }
+                }
+            }
+            Variable::Option(ref opt) => {
+                self.u8(OPTION);
+                match *opt {
+                    None => self.bool(false),
+                    Some(ref v) => {
+                        self.bool(true);
+                        self.variable(v)?;
+                    }
+                }
+            }
+            Variable::Result(Ok(ref v)) => {
+                self.u8(OK);
+                self.variable(v)?;
+            }
+            Variable::Result(Err(ref err)) => {
+                self.u8(ERR);
+                self.variable(&err.message)?;
+                self.u64(err.trace.len() as u64);
+                for t in &err.trace { self.text(t); }
+            }
+            Variable::UnsafeRef(_) |
+            Variable::RustObject(_) |
+            Variable::Thread(_) |
+            Variable::Channel(_) |
+            Variable::Closure(_, _) => return Err(self.rt.typeof_var(v)),
+        }
+        Ok(())
+    }
+}
+
+const REF: u8 = 0;
+const RETURN: u8 = 1;
+const BOOL: u8 = 2;
+const F64: u8 = 3;
+const VEC4: u8 = 4;
+const TEXT: u8 = 5;
+const ARRAY: u8 = 6;
+const OBJECT: u8 = 7;
+const LINK: u8 = 8;
+const OPTION: u8 = 9;
+const OK: u8 = 10;
+const ERR: u8 = 11;
+const F64_ARRAY: u8 = 12;
+const VEC4_ARRAY: u8 = 13;
+
+struct Loader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// The number of variables on the stack, which references must be less than.
+    stack_len: usize,
+    /// The depth of the variable being read.
+    depth: usize,
+}
+
+impl<'a> Loader<'a> {
+    fn u8(&mut self) -> Result<u8, String> {
+        match self.data.get(self.pos) {
+            None => Err("Unexpected end of snapshot".into()),
+            Some(&val) => {
+                self.pos += 1;
+                Ok(val)
+            }
+        }
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut val = 0;
+        for i in 0..8 {
+            val |= (self.u8()? as u64) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    /// Reads a length, checking that it fits in the remaining data.
+    fn len(&mut self) -> Result<usize, String> {
+        let n = self.u64()?;
+        if n > (self.data.len() - self.pos) as u64 {
+            return Err("Unexpected end of snapshot".into());
+        }
+        Ok(n as usize)
+    }
+
+    fn text(&mut self) -> Result<Arc<String>, String> {
+        let n = self.len()?;
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(text) => Ok(Arc::new(text)),
+            Err(_) => Err("Invalid text in snapshot".into()),
+        }
+    }
+
+    fn names(&mut self) -> Result<Vec<(Arc<String>, usize)>, String> {
+        let n = self.len()?;
+        let mut names = Vec::with_capacity(n);
+        for _ in 0..n {
+            let name = self.text()?;
+            names.push((name, self.u64()? as usize));
+        }
+        Ok(names)
+    }
+
+    fn secret(&mut self) -> Result<Option<Vec<Variable>>, String> {
+        if !self.bool()? { return Ok(None); }
+        let n = self.len()?;
+        let mut sec = Vec::with_capacity(n);
+        for _ in 0..n { sec.push(self.variable()?); }
+        Ok(Some(sec))
+    }
+
+    fn variable(&mut self) -> Result<Variable, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("Snapshot has variables nested too deeply".into());
+        }
+        self.depth += 1;
+        let res = self.variable_kind();
+        self.depth -= 1;
+        res
+    }
+
+    // Arrays and objects are shared between threads like other variables.
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn variable_kind(&mut self) -> Result<Variable, String> {
+        Ok(match self.u8()? {
+            REF => {
+                let ind = self.u64()?;
+                if ind >= self.stack_len as u64 {
+                    return Err("Snapshot refers outside the stack".into());
+                }
+                Variable::Ref(ind as usize)
+            }
+            RETURN => Variable::Return,
+            BOOL => {
+                let val = self.bool()?;
+                Variable::Bool(val, self.secret()?.map(Box::new))
+            }
+            F64 => {
+                let val = f64::from_bits(self.u64()?);
+                Variable::F64(val, self.secret()?.map(Box::new))
+            }
+            VEC4 => {
+                let mut val = [0.0; 4];
+                for x in &mut val { *x = f32::from_bits(self.u64()? as u32); }
+                Variable::Vec4(val)
+            }
+            TEXT => Variable::Text(self.text()?),
+            ARRAY => {
+                let n = self.len()?;
+                let mut arr = PersistentVec::new();
+                for _ in 0..n { arr.push(self.variable()?); }
+                Variable::Array(Arc::new(arr))
+            }
+            F64_ARRAY => {
+                let n = self.len()?;
+                let mut arr = Vec::with_capacity(n);
+                for _ in 0..n { arr.push(f64::from_bits(self.u64()?)); }
+                Variable::F64Array(Arc::new(arr))
+            }
+            VEC4_ARRAY => {
+                let n = self.len()?;
+                let mut arr = Vec::with_capacity(n);
+                for _ in 0..n {
+                    let mut val = [0.0; 4];
+                    for x in &mut val { *x = f32::from_bits(self.u64()? as u32); }
+                    arr.push(val);
+                }
+                Variable::Vec4Array(Arc::new(arr))
+            }
+            OBJECT => {
+                let n = self.len()?;
+                let mut obj = ObjectMap::with_capacity(n);
+                for _ in 0..n {
+                    let key = Symbol::from(self.text()?);
+                    obj.insert(key, self.variable()?);
+                }
+                Variable::Object(Arc::new(obj))
+            }
+            LINK => {
+                let n = self.len()?;
+                let mut link = Link::new();
+                for _ in 0..n { link.push(&self.variable()?)?; }
+                Variable::Link(Box::new(link))
+            }
+            OPTION => {
+                if self.bool()? {
+                    Variable::Option(Some(Box::new(self.variable()?)))
+                } else {
+                    Variable::Option(None)
+                }
+            }
+            OK => Variable::Result(Ok(Box::new(self.variable()?))),
+            ERR => {
+                let message = self.variable()?;
+                let n = self.len()?;
+                let mut trace = Vec::with_capacity(n);
+                for _ in 0..n { trace.push((*self.text()?).clone()); }
+                Variable::Result(Err(Box::new(Error { message, trace })))
+            }
+            _ => return Err("Invalid variable in snapshot".into()),
+        })
+    }
+}
@@ -0,0 +1,127 @@
+/*
+Reading source and data files for `load` and the file intrinsics,
+and resolving the paths of files written by scripts.
+
+A module reads files through its loader, which is `FileLoader` by default.
+Modules created with `load` use the loader of the module loading them,
+so scripts can be shipped in memory or inside an archive by setting the loader of the main module.
+
+A relative path is first looked up next to the file of the function using it,
+then as given, such that paths relative to the working directory keep working.
+A relative path is written next to the file of the function when its directory exists there,
+so a file that is saved and then loaded again resolves to the same path.
+*/
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Reads files by path.
+pub trait SourceLoader: Send + Sync {
+    /// Reads the text of a file.
+    fn load_text(&self, path: &str) -> io::Result<String>;
+}
+
+/// Reads files from the file system.
+pub struct FileLoader;
+
+impl SourceLoader for FileLoader {
+    fn load_text(&self, path: &str) -> io::Result<String> {
+        let mut data = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut data));
+        Ok(data)
+    }
+}
+
+/// Reads files from memory, for example scripts embedded with `include_str!`.
+#[derive(Clone, Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryLoader {
+    /// Creates a new loader without files.
+    pub fn new() -> MemoryLoader {
+        MemoryLoader::default()
+    }
+
+    /// Sets the text of a file.
+    pub fn insert<P: Into<String>, T: Into<String>>(&mut self, path: P, text: T) {
+        self.files.insert(normalize(Path::new(&path.into())), text.into());
+    }
+}
+
+impl SourceLoader for MemoryLoader {
+    fn load_text(&self, path: &str) -> io::Result<String> {
+        match self.files.get(&normalize(Path::new(path))) {
+            Some(text) => Ok(text.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "File not found in memory")),
+        }
+    }
+}
+
+/// Reads a file relative to the file `from`.
+///
+/// Returns the path that was found and the text.
+pub fn load_relative(
+    loader: &SourceLoader,
+    path: &str,
+    from: Option<&str>
+) -> io::Result<(String, String)> {
+    if let Some(rel) = relative_to(path, from) {
+        let rel = rel.to_string_lossy().into_owned();
+        if let Ok(text) = loader.load_text(&rel) {
+            return Ok((rel, text));
+        }
+    }
+    loader.load_text(path).map(|text| (path.into(), text))
+}
+
+/// Returns the path to write a file to, resolved like reading it with `load_relative`.
+///
+/// A relative path is written next to the file `from` when that directory exists,
+/// such that reading the path afterwards finds the written file.
+pub fn write_path(path: &str, from: Option<&str>) -> String {
+    match relative_to(path, from) {
+        Some(ref rel) if rel.parent().map(|dir| dir.is_dir()).unwrap_or(false) =>
+            rel.to_string_lossy().into_owned(),
+        _ => path.into(),
+    }
+}
+
+/// Joins a relative path with the directory of another file.
+///
+/// Returns `None` when the path is used as given,
+/// because it is absolute or the file is in the working directory.
+fn relative_to(path: &str, from: Option<&str>) -> Option<PathBuf> {
+    let from = match from {
+        Some(from) => from,
+        None => return None,
+    };
+    if Path::new(path).is_absolute() { return None; }
+    match Path::new(from).parent() {
+        Some(dir) if dir != Path::new("") => Some(normalize(&dir.join(path))),
+        // The file is in the working directory.
+        _ => None,
+    }
+}
+
+/// Removes `.` and `..` from a path where possible.
+fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {}
+            Component::ParentDir if is_normal(res.components().next_back()) => {
+                res.pop();
+            }
+            _ => res.push(part.as_os_str()),
+        }
+    }
+    res
+}
+
+fn is_normal(part: Option<Component>) -> bool {
+    if let Some(Component::Normal(_)) = part { true } else { false }
+}
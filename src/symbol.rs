@@ -0,0 +1,170 @@
+/*
+Interned strings used as object keys.
+
+All symbols with the same text share one allocation in a global table,
+so comparing and hashing a symbol only looks at the pointer.
+Keys written in the source are interned when the AST is converted.
+Looking up a key computed at runtime only takes a read lock on the table,
+and such keys are interned only when they are inserted into an object.
+When the table grows, strings that are no longer used by any symbol are removed,
+so keys computed at runtime do not keep memory for the life of the process.
+*/
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    static ref SYMBOLS: RwLock<Table> = RwLock::new(Table {
+        entries: HashSet::new(),
+        purge_len: MIN_PURGE_LEN,
+    });
+}
+
+/// The smallest size of the table where unused strings are removed.
+const MIN_PURGE_LEN: usize = 1024;
+
+/// The global table of interned strings.
+struct Table {
+    entries: HashSet<Entry>,
+    /// Removes unused strings when the table reaches this size.
+    purge_len: usize,
+}
+
+impl Table {
+    fn get(&self, name: &str) -> Option<Symbol> {
+        self.entries.get(name).map(|s| Symbol(s.0.clone()))
+    }
+
+    fn insert(&mut self, name: Arc<String>) -> Symbol {
+        if self.entries.len() >= self.purge_len {
+            // A string only referenced by the table has no symbols,
+            // and can not get new ones while the table is locked for writing.
+            self.entries.retain(|s| Arc::strong_count(&s.0) > 1);
+            self.purge_len = ::std::cmp::max(MIN_PURGE_LEN, 2 * self.entries.len());
+        }
+        self.entries.insert(Entry(name.clone()));
+        Symbol(name)
+    }
+}
+
+/// Looks up entries in the global table by text.
+#[derive(PartialEq, Eq, Hash)]
+struct Entry(Arc<String>);
+
+impl Borrow<str> for Entry {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An interned string.
+#[derive(Clone)]
+pub struct Symbol(Arc<String>);
+
+impl Symbol {
+    /// Interns a string.
+    pub fn new(name: &str) -> Symbol {
+        if let Some(s) = Symbol::get(name) { return s; }
+        let mut symbols = SYMBOLS.write().unwrap();
+        // Another thread might have interned the string in the meantime.
+        match symbols.get(name) {
+            Some(s) => s,
+            None => symbols.insert(Arc::new(String::from(name))),
+        }
+    }
+
+    /// Returns the symbol of an interned string, without interning it.
+    ///
+    /// When this returns `None`, no object has the string as key.
+    pub fn get(name: &str) -> Option<Symbol> {
+        SYMBOLS.read().unwrap().get(name)
+    }
+
+    /// Returns the text of symbol.
+    pub fn text(&self) -> &Arc<String> {
+        &self.0
+    }
+}
+
+impl From<Arc<String>> for Symbol {
+    fn from(name: Arc<String>) -> Symbol {
+        if let Some(s) = Symbol::get(&name) { return s; }
+        let mut symbols = SYMBOLS.write().unwrap();
+        match symbols.get(&name) {
+            Some(s) => s,
+            None => symbols.insert(name),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Symbol {
+    fn from(name: &'a str) -> Symbol {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::from(Arc::new(name))
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(&*self.0 as *const String as usize)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self.0, f)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self.0, f)
+    }
+}
+
+/// Hashes the pointer of a symbol.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_usize(&mut self, val: usize) {
+        // Pointers are aligned, so mix the high bits into the low bits.
+        let h = (val as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        self.0 = h ^ (h >> 32);
+    }
+}
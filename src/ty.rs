@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use piston_meta::bootstrap::Convert;
@@ -17,13 +18,22 @@ pub enum Type {
     Text,
     Link,
     Array(Box<Type>),
-    // Object(HashMap<Arc<String>, Type>),
     Object,
+    /// An object with named fields, declared with `record`.
+    ///
+    /// Fields refer to records by name, such that a record can contain itself.
+    /// Use `Type::records` to look up the record of a field type.
+    ///
+    /// A record goes with `{}` both ways, so an object from an untyped expression
+    /// is accepted as a record. Fields are only checked in object literals
+    /// and in fields of variables with a declared record type.
+    Record(Arc<String>, Arc<Vec<(Arc<String>, Type)>>),
     // Rust(Arc<String>),
     Option(Box<Type>),
     Result(Box<Type>),
     Secret(Box<Type>),
     Thread(Box<Type>),
+    Channel(Box<Type>),
     AdHoc(Arc<String>, Box<Type>),
     Closure(Box<Dfn>),
 }
@@ -52,6 +62,7 @@ impl Type {
                 }
             }
             &Object => "{}".into(),
+            &Record(ref name, _) => (**name).clone(),
             &Option(ref ty) => {
                 if let Any = **ty {
                     "opt".into()
@@ -89,6 +100,16 @@ impl Type {
                     res
                 }
             }
+            &Channel(ref ty) => {
+                if let Any = **ty {
+                    "chan".into()
+                } else {
+                    let mut res = String::from("chan[");
+                    res.push_str(&ty.description());
+                    res.push(']');
+                    res
+                }
+            }
             &AdHoc(ref ad, ref ty) => {
                 (&**ad).clone() + " " + &ty.description()
             }
@@ -128,12 +149,18 @@ impl Type {
         Type::Thread(Box::new(Type::Any))
     }
 
+    pub fn channel() -> Type {
+        Type::Channel(Box::new(Type::Any))
+    }
+
     /// Returns `true` if a type goes with another type (directional check).
     ///
     /// - `bool` (argument) goes with `sec[bool]` (value)
     /// - `f64` (argument) goes with `sec[f64]` (value)
     ///
     /// The opposite is not true, since `sec` contains extra information.
+    ///
+    /// A record goes with `{}` both ways, so only the fields of object literals are checked.
     pub fn goes_with(&self, other: &Type) -> bool {
         use self::Type::*;
 
@@ -170,6 +197,20 @@ impl Type {
             &Object => {
                 if let &Object = other {
                     true
+                } else if let &Record(_, _) = other {
+                    true
+                } else if let &Any = other {
+                    true
+                } else {
+                    false
+                }
+            }
+            &Record(ref name, _) => {
+                if let &Record(ref other_name, _) = other {
+                    name == other_name
+                } else if let &Object = other {
+                    // Fields of object literals are checked against the record.
+                    true
                 } else if let &Any = other {
                     true
                 } else {
@@ -203,6 +244,15 @@ impl Type {
                     false
                 }
             }
+            &Channel(ref chan) => {
+                if let &Channel(ref other_chan) = other {
+                    chan.goes_with(other_chan)
+                } else if let &Any = other {
+                    true
+                } else {
+                    false
+                }
+            }
             &Closure(ref cl) => {
                 if let &Closure(ref other_cl) = other {
                     if cl.tys.len() != other_cl.tys.len() { return false; }
@@ -345,6 +395,32 @@ impl Type {
         }
     }
 
+    /// Replaces ad-hoc object types with the declared record of the same name.
+    pub fn records(&self, records: &HashMap<Arc<String>, Type>) -> Type {
+        use self::Type::*;
+
+        match self {
+            &AdHoc(ref name, ref ty) if **ty == Object => {
+                match records.get(name) {
+                    Some(record) => record.clone(),
+                    None => self.clone()
+                }
+            }
+            &AdHoc(ref name, ref ty) => AdHoc(name.clone(), Box::new(ty.records(records))),
+            &Array(ref ty) => Array(Box::new(ty.records(records))),
+            &Option(ref ty) => Option(Box::new(ty.records(records))),
+            &Result(ref ty) => Result(Box::new(ty.records(records))),
+            &Thread(ref ty) => Thread(Box::new(ty.records(records))),
+            &Channel(ref ty) => Channel(Box::new(ty.records(records))),
+            &Closure(ref dfn) => Closure(Box::new(Dfn {
+                lts: dfn.lts.clone(),
+                tys: dfn.tys.iter().map(|ty| ty.records(records)).collect(),
+                ret: dfn.ret.records(records),
+            })),
+            x => x.clone()
+        }
+    }
+
     pub fn from_meta_data(node: &str, mut convert: Convert, ignored: &mut Vec<Range>)
     -> Result<(Range, Type), ()> {
         let start = convert.clone();
@@ -395,6 +471,9 @@ impl Type {
             } else if let Ok((range, _)) = convert.meta_bool("thr_any") {
                 convert.update(range);
                 ty = Some(Type::Thread(Box::new(Type::Any)));
+            } else if let Ok((range, _)) = convert.meta_bool("chan_any") {
+                convert.update(range);
+                ty = Some(Type::Channel(Box::new(Type::Any)));
             } else if let Ok((range, val)) = Type::from_meta_data(
                     "opt", convert, ignored) {
                 convert.update(range);
@@ -411,6 +490,10 @@ impl Type {
                     "thr", convert, ignored) {
                 convert.update(range);
                 ty = Some(Type::Thread(Box::new(val)));
+            } else if let Ok((range, val)) = Type::from_meta_data(
+                    "chan", convert, ignored) {
+                convert.update(range);
+                ty = Some(Type::Channel(Box::new(val)));
             } else if let Ok((range, val)) = convert.meta_string("ad_hoc") {
                 convert.update(range);
                 let inner_ty = if let Ok((range, val)) = Type::from_meta_data(
@@ -99,6 +99,26 @@ pub fn write_variable<W>(
             }
             try!(write!(w, "]"));
         }
+        Variable::F64Array(ref arr) => {
+            try!(write!(w, "["));
+            for (i, &v) in arr.iter().enumerate() {
+                try!(write_variable(w, rt, &Variable::f64(v), EscapeString::Json, tabs));
+                if i + 1 < arr.len() {
+                    try!(write!(w, ", "));
+                }
+            }
+            try!(write!(w, "]"));
+        }
+        Variable::Vec4Array(ref arr) => {
+            try!(write!(w, "["));
+            for (i, &v) in arr.iter().enumerate() {
+                try!(write_variable(w, rt, &Variable::Vec4(v), EscapeString::Json, tabs));
+                if i + 1 < arr.len() {
+                    try!(write!(w, ", "));
+                }
+            }
+            try!(write!(w, "]"));
+        }
         Variable::Option(ref opt) => {
             match opt {
                 &None => {
@@ -127,6 +147,7 @@ pub fn write_variable<W>(
             }
         }
         Variable::Thread(_) => try!(write!(w, "_thread")),
+        Variable::Channel(_) => try!(write!(w, "_channel")),
         Variable::Return => try!(write!(w, "_return")),
         Variable::UnsafeRef(_) => try!(write!(w, "_unsafe_ref")),
         Variable::RustObject(_) => try!(write!(w, "_rust_object")),
@@ -185,6 +206,8 @@ pub fn write_expr<W: io::Write>(
         &E::Item(ref item) => try!(write_item(w, rt, item, tabs)),
         &E::Number(ref number) => try!(write!(w, "{}", number.num)),
         &E::Text(ref text) => try!(json::write_string(w, &text.text)),
+        &E::Interpolation(ref interpolation) =>
+            try!(write_interpolation(w, rt, interpolation, tabs)),
         &E::Bool(ref b) => try!(write!(w, "{}", b.val)),
         &E::Variable(_, ref var) => try!(write_variable(w, rt, var, EscapeString::Json, tabs)),
         &E::Link(ref link) => try!(write_link(w, rt, link, tabs)),
@@ -258,6 +281,7 @@ pub fn write_expr<W: io::Write>(
             try!(write_for_n(w, rt, for_n, tabs));
         }
         &E::If(ref if_expr) => try!(write_if(w, rt, if_expr, tabs)),
+        &E::Match(ref match_expr) => try!(write_match(w, rt, match_expr, tabs)),
         &E::Norm(ref norm) => try!(write_norm(w, rt, norm, tabs)),
         &E::UnOp(ref unop) => try!(write_unop(w, rt, unop, tabs)),
         &E::Try(ref expr) => {
@@ -385,7 +409,7 @@ pub fn write_item<W: io::Write>(
     try!(write!(w, "{}", item.name));
     for (i, id) in item.ids.iter().enumerate() {
         match id {
-            &Id::String(_, ref prop) => try!(write!(w, ".{}", prop)),
+            &Id::String(_, ref prop, _) => try!(write!(w, ".{}", prop)),
             &Id::F64(_, ind) => try!(write!(w, "[{}]", ind)),
             &Id::Expression(ref expr) => {
                 try!(write!(w, "["));
@@ -684,6 +708,72 @@ pub fn write_if<W: io::Write>(
     Ok(())
 }
 
+pub fn write_match<W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    match_expr: &ast::Match,
+    tabs: u32,
+) -> Result<(), io::Error> {
+    use ast::Pattern;
+
+    try!(write!(w, "match "));
+    try!(write_expr(w, rt, &match_expr.expr, tabs));
+    try!(writeln!(w, " {{"));
+    for arm in &match_expr.arms {
+        try!(write_tabs(w, tabs + 1));
+        match arm.pattern {
+            Pattern::Some(ref name) => try!(write!(w, "some({})", name)),
+            Pattern::None => try!(write!(w, "none()")),
+            Pattern::Ok(ref name) => try!(write!(w, "ok({})", name)),
+            Pattern::Err(ref name) => try!(write!(w, "err({})", name)),
+            Pattern::Value(ref val) => try!(write_variable(w, rt, val, EscapeString::Json, tabs)),
+            Pattern::Wildcard => try!(write!(w, "_")),
+        }
+        try!(write!(w, " => "));
+        try!(write_expr(w, rt, &arm.expr, tabs + 1));
+        try!(writeln!(w, ","));
+    }
+    try!(write_tabs(w, tabs));
+    try!(write!(w, "}}"));
+    Ok(())
+}
+
+pub fn write_interpolation<W: io::Write>(
+    w: &mut W,
+    rt: &Runtime,
+    interpolation: &ast::Interpolation,
+    tabs: u32,
+) -> Result<(), io::Error> {
+    use ast::InterpolationPart;
+
+    try!(write!(w, "$\""));
+    for part in &interpolation.parts {
+        match *part {
+            InterpolationPart::Text(ref text) => {
+                for c in text.chars() {
+                    match c {
+                        '"' => try!(write!(w, "\\\"")),
+                        '\\' => try!(write!(w, "\\\\")),
+                        '\n' => try!(write!(w, "\\n")),
+                        '\t' => try!(write!(w, "\\t")),
+                        '\r' => try!(write!(w, "\\r")),
+                        '{' => try!(write!(w, "\\{{")),
+                        '}' => try!(write!(w, "\\}}")),
+                        c => try!(write!(w, "{}", c)),
+                    }
+                }
+            }
+            InterpolationPart::Expression(ref expr) => {
+                try!(write!(w, "{{"));
+                try!(write_expr(w, rt, expr, tabs));
+                try!(write!(w, "}}"));
+            }
+        }
+    }
+    try!(write!(w, "\""));
+    Ok(())
+}
+
 pub fn write_grab<W: io::Write>(
     w: &mut W,
     rt: &Runtime,
@@ -30,6 +30,14 @@ pub fn debug_src(source: &str) {
     panic!("{:?}", module.functions);
 }
 
+pub fn load_module(source: &str) -> ::std::sync::Arc<Module> {
+    let mut module = Module::new();
+    load(source, &mut module).unwrap_or_else(|err| {
+        panic!("In `{}`:\n{}", source, err);
+    });
+    ::std::sync::Arc::new(module)
+}
+
 #[test]
 fn test_syntax() {
     test_src("source/syntax/main.dyon");
@@ -109,6 +117,7 @@ fn test_syntax() {
     test_fail_src("source/syntax/closure_4.dyon");
     test_src("source/syntax/closure_5.dyon");
     test_src("source/syntax/closure_6.dyon");
+    test_src("source/syntax/closure_7.dyon");
     test_src("source/syntax/or.dyon");
 }
 
@@ -151,6 +160,16 @@ fn test_typechk() {
     test_fail_src("source/typechk/if_4.dyon");
     test_fail_src("source/typechk/if_5.dyon");
     test_fail_src("source/typechk/if_6.dyon");
+    test_fail_src("source/typechk/match.dyon");
+    test_fail_src("source/typechk/match_2.dyon");
+    test_src("source/typechk/match_3.dyon");
+    test_fail_src("source/typechk/interpolation.dyon");
+    test_fail_src("source/typechk/record.dyon");
+    test_fail_src("source/typechk/record_2.dyon");
+    test_fail_src("source/typechk/record_3.dyon");
+    test_fail_src("source/typechk/record_4.dyon");
+    test_fail_src("source/typechk/record_5.dyon");
+    test_fail_src("source/typechk/record_6.dyon");
     test_src("source/typechk/ad_hoc.dyon");
     test_fail_src("source/typechk/add_ad_hoc.dyon");
     test_src("source/typechk/add_ad_hoc_2.dyon");
@@ -183,6 +202,9 @@ fn test_typechk() {
     test_src("source/typechk/secret_9.dyon");
     test_fail_src("source/typechk/secret_10.dyon");
     test_src("source/typechk/dot.dyon");
+    test_fail_src("source/typechk/channel.dyon");
+    test_fail_src("source/typechk/channel_2.dyon");
+    test_src("source/typechk/channel_3.dyon");
 }
 
 #[test]
@@ -200,3 +222,648 @@ fn test_error() {
     test_src("source/error/unwrap_err.dyon");
     test_src("source/error/option.dyon");
 }
+
+#[test]
+fn test_fuel() {
+    use std::time::Instant;
+
+    let module = load_module("source/runtime/infinite_loop.dyon");
+    let mut rt = Runtime::new();
+    rt.set_fuel(Some(1000));
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Out of fuel"), "{}", err);
+    // One unit for calling `main`, the rest for loop iterations.
+    assert_eq!(rt.fuel_consumed(), 1000);
+    assert_eq!(rt.fuel(), Some(0));
+
+    let mut rt = Runtime::new();
+    rt.deadline = Some(Instant::now());
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Deadline exceeded"), "{}", err);
+
+    let module = load_module("source/runtime/recursion.dyon");
+    let mut rt = Runtime::new();
+    rt.set_fuel(Some(100));
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Out of fuel"), "{}", err);
+    assert_eq!(rt.fuel_consumed(), 100);
+
+    // Threads started with `go` and `par` share the fuel.
+    let module = load_module("source/runtime/fuel_threads.dyon");
+    let mut rt = Runtime::new();
+    rt.par_threads = 4;
+    rt.run(&module).unwrap();
+    assert_eq!(rt.fuel_consumed(), 303);
+    let mut rt = Runtime::new();
+    rt.par_threads = 4;
+    rt.set_fuel(Some(250));
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Out of fuel"), "{}", err);
+    assert_eq!(rt.fuel_consumed(), 250);
+}
+
+#[test]
+fn test_interrupt() {
+    use std::thread;
+    use std::time::Duration;
+
+    let module = load_module("source/runtime/go_loop.dyon");
+    let mut rt = Runtime::new();
+    let handle = rt.interrupt_handle();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Interrupted"), "{}", err);
+    assert_eq!(rt.stack.len(), 0);
+    assert_eq!(rt.call_stack.len(), 0);
+}
+
+#[test]
+fn test_debugger() {
+    use std::sync::{Arc, Mutex};
+    use dyon::debugger::{Breakpoint, DebugHandler, Debugger, Location, Step};
+
+    struct Record(Arc<Mutex<Vec<(usize, Vec<(Arc<String>, String)>)>>>, Step);
+
+    impl DebugHandler for Record {
+        fn pause(
+            &mut self,
+            rt: &Runtime,
+            location: &Location,
+            _: &mut Vec<Breakpoint>
+        ) -> Step {
+            self.0.lock().unwrap().push((location.line, rt.debug_locals()));
+            self.1
+        }
+    }
+
+    let file = "source/runtime/debug.dyon";
+    let module = load_module(file);
+
+    // Pause at a breakpoint.
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut debugger = Debugger::new(Box::new(Record(log.clone(), Step::Continue)));
+    debugger.add_breakpoint(Arc::new(file.into()), 3);
+    let mut rt = Runtime::new();
+    rt.debugger = Some(debugger);
+    rt.run(&module).unwrap();
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(log[1].0, 3);
+    assert_eq!(log[1].1[1..], [
+        (Arc::new("a".into()), "1".into()),
+        (Arc::new("b".into()), "1".into()),
+        (Arc::new("c".into()), "2".into()),
+    ]);
+
+    // Step over every statement in `main`.
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut debugger = Debugger::new(Box::new(Record(log.clone(), Step::Over)));
+    debugger.add_breakpoint(Arc::new(file.into()), 7);
+    let mut rt = Runtime::new();
+    rt.debugger = Some(debugger);
+    rt.run(&module).unwrap();
+    let lines: Vec<usize> = log.lock().unwrap().iter().map(|x| x.0).collect();
+    assert_eq!(lines, [7, 8, 9, 9]);
+}
+
+#[test]
+fn test_profiler() {
+    use std::sync::Arc;
+    use dyon::profiler::Profiler;
+
+    let module = load_module("source/runtime/profile.dyon");
+    let mut rt = Runtime::new();
+    rt.profiler = Some(Profiler::new());
+    rt.run(&module).unwrap();
+    let profiler = rt.profiler.unwrap();
+    assert_eq!(profiler.functions[&Arc::new("main".into())].calls, 1);
+    assert_eq!(profiler.functions[&Arc::new("fib".into())].calls, 177);
+    assert_eq!(profiler.functions[&Arc::new("println".into())].calls, 1);
+    let main = &profiler.functions[&Arc::new("main".into())];
+    let fib = &profiler.functions[&Arc::new("fib".into())];
+    assert!(main.inclusive >= fib.inclusive);
+
+    let mut w: Vec<u8> = vec![];
+    profiler.write_folded(&mut w).unwrap();
+    let folded = String::from_utf8(w).unwrap();
+    assert!(folded.lines().any(|line| line.starts_with("main;println ")));
+    assert!(folded.lines().any(|line| line.starts_with("main;fib;fib;fib ")));
+}
+
+#[test]
+fn test_coverage() {
+    use dyon::coverage::Coverage;
+
+    let module = load_module("source/runtime/coverage.dyon");
+    let mut rt = Runtime::new();
+    rt.coverage = Some(Coverage::new());
+    rt.run(&module).unwrap();
+    let mut w: Vec<u8> = vec![];
+    rt.coverage.unwrap().write_lcov(&mut w, &module).unwrap();
+    assert_eq!(String::from_utf8(w).unwrap(), "\
+TN:
+SF:source/runtime/coverage.dyon
+BRDA:2,0,0,1
+BRDA:2,0,1,1
+BRDA:2,0,2,0
+BRF:3
+BRH:2
+DA:2,2
+DA:3,1
+DA:5,1
+DA:7,0
+DA:11,0
+DA:15,1
+DA:16,1
+LF:7
+LH:5
+end_of_record
+");
+}
+
+#[test]
+fn test_observer() {
+    use std::sync::{Arc, Mutex};
+    use dyon::{Error, Variable};
+    use dyon::observer::RuntimeObserver;
+
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl RuntimeObserver for Recorder {
+        fn enter(&self, rt: &Runtime, name: &Arc<String>, args: &[Variable]) {
+            let args: Vec<String> = args.iter().map(|arg| match *rt.resolve(arg) {
+                Variable::F64(x, _) => format!("{}", x),
+                _ => "_".into(),
+            }).collect();
+            self.0.lock().unwrap().push(format!("enter {}({})", name, args.join(", ")));
+        }
+        fn exit(&self, _rt: &Runtime, name: &Arc<String>, ret: Option<&Variable>) {
+            let ret = if ret.is_some() { "value" } else { "none" };
+            self.0.lock().unwrap().push(format!("exit {} {}", name, ret));
+        }
+        fn propagate_error(&self, _rt: &Runtime, err: &Error) {
+            self.0.lock().unwrap().push(format!("error {:?}", err.message));
+        }
+        fn spawn(&self, _rt: &Runtime, name: &Arc<String>) {
+            self.0.lock().unwrap().push(format!("spawn {}", name));
+        }
+        fn join(&self, _rt: &Runtime, res: &Variable) {
+            let ok = if let Variable::Result(Ok(_)) = *res { "ok" } else { "err" };
+            self.0.lock().unwrap().push(format!("join {}", ok));
+        }
+        fn load(&self, _rt: &Runtime, file: &str) {
+            self.0.lock().unwrap().push(format!("load {}", file));
+        }
+    }
+
+    let recorder = Arc::new(Recorder(Mutex::new(vec![])));
+    let module = load_module("source/runtime/observe.dyon");
+    let mut rt = Runtime::new();
+    rt.observer = Some(recorder.clone());
+    rt.run(&module).unwrap();
+    assert_eq!(*recorder.0.lock().unwrap(), vec![
+        "enter main()",
+        "enter twice(2)",
+        "enter check(2)",
+        "exit check value",
+        "exit twice value",
+        "enter twice(-1)",
+        "enter check(-1)",
+        "exit check value",
+        "error Text(\"negative\")",
+        "exit twice value",
+        "spawn id",
+        "enter id(3)",
+        "exit id value",
+        "join ok",
+        "load source/runtime/profile.dyon",
+        "exit main none",
+    ]);
+}
+
+#[test]
+fn test_reload() {
+    use std::sync::Arc;
+    use dyon::reload::{reload_str, ReloadError};
+
+    let file = "source/runtime/reload.dyon";
+    let mut module = load_module(file);
+    let mut rt = Runtime::new();
+    rt.run(&module).unwrap();
+
+    reload_str(file, Arc::new(r#"
+        fn check() -> res[f64] { return err("reloaded") }
+        fn main() { x := unwrap(check()) }
+    "#.into()), &mut module).unwrap();
+    assert!(rt.run(&module).unwrap_err().contains("reloaded"));
+
+    // The old code is kept when the new source fails to load.
+    match reload_str(file, Arc::new("fn main() { x := }".into()), &mut module) {
+        Err(ReloadError::Load(_)) => {}
+        x => panic!("Expected load error, found {:?}", x),
+    }
+    match reload_str(file, Arc::new("fn check() {}\nfn main() {}".into()), &mut module) {
+        Err(ReloadError::Signature(name)) => assert_eq!(&**name, "check"),
+        x => panic!("Expected signature error, found {:?}", x),
+    }
+    assert!(rt.run(&module).unwrap_err().contains("reloaded"));
+}
+
+#[test]
+fn test_call_depth() {
+    let module = load_module("source/runtime/deep_recursion.dyon");
+    let mut rt = Runtime::new();
+    rt.max_call_depth = Some(100);
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Exceeded maximum call depth of 100"), "{}", err);
+    assert!(err.contains("calls omitted"), "{}", err);
+
+    let module = load_module("source/runtime/nested.dyon");
+    let mut rt = Runtime::new();
+    rt.run(&module).unwrap();
+    rt.max_stack_size = Some(512);
+    let err = rt.run(&module).unwrap_err();
+    assert!(err.contains("Exceeded maximum stack size"), "{}", err);
+
+    // Threads started with `go` use most of their stack by default.
+    let module = load_module("source/runtime/go_recursion.dyon");
+    let err = Runtime::new().run(&module).unwrap_err();
+    assert!(err.contains("Exceeded maximum stack size"), "{}", err);
+}
+
+#[test]
+fn test_tail_call() {
+    let module = load_module("source/runtime/tail_call.dyon");
+    let mut rt = Runtime::new();
+    // Calls in tail position reuse the frame.
+    rt.max_call_depth = Some(10);
+    rt.run(&module).unwrap();
+}
+
+#[test]
+fn test_snapshot() {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use dyon::snapshot;
+
+    thread_local! {
+        static SNAPSHOT: RefCell<Vec<u8>> = RefCell::new(vec![]);
+        static RECORDS: RefCell<Vec<(f64, f64)>> = RefCell::new(vec![]);
+    }
+
+    fn checkpoint(rt: &mut Runtime) -> Result<(), String> {
+        let mut data = vec![];
+        try!(snapshot::save(rt, &mut data));
+        SNAPSHOT.with(|s| *s.borrow_mut() = data);
+        Ok(())
+    }
+
+    fn record(rt: &mut Runtime) -> Result<(), String> {
+        let r: f64 = try!(rt.pop());
+        let steps: f64 = try!(rt.pop());
+        RECORDS.with(|records| records.borrow_mut().push((steps, r)));
+        Ok(())
+    }
+
+    fn snapshot_module() -> Arc<Module> {
+        let mut module = Module::new();
+        module.add(Arc::new("checkpoint".into()), checkpoint, Dfn {
+            lts: vec![],
+            tys: vec![],
+            ret: Type::Void
+        });
+        module.add(Arc::new("record".into()), record, Dfn {
+            lts: vec![Lt::Default; 2],
+            tys: vec![Type::F64; 2],
+            ret: Type::Void
+        });
+        load("source/runtime/snapshot.dyon", &mut module).unwrap();
+        Arc::new(module)
+    }
+
+    let module = snapshot_module();
+    let mut rt = Runtime::new();
+    rt.run(&module).unwrap();
+
+    // Resume from the checkpoint with a new runtime and module.
+    let module = snapshot_module();
+    let mut rt = Runtime::new();
+    SNAPSHOT.with(|s| snapshot::restore(&mut rt, &mut &s.borrow()[..], &module)).unwrap();
+    rt.call_str("resume", &[], &module).unwrap();
+    RECORDS.with(|records| {
+        let records = records.borrow();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, 4.0);
+        assert_eq!(records[0], records[1]);
+    });
+
+    let err = rt.call_str("with_thread", &[], &module).unwrap_err();
+    assert!(err.contains("Can not save `thread` in snapshot, held by variable `t`"), "{}", err);
+
+    let mut rt = Runtime::new();
+    assert!(snapshot::restore(&mut rt, &mut &b"dyon"[..], &module).is_err());
+
+    // Truncated and changed snapshots give an error or restore, but never panic.
+    let data = SNAPSHOT.with(|s| s.borrow().clone());
+    for n in 0..data.len() {
+        let mut rt = Runtime::new();
+        assert!(snapshot::restore(&mut rt, &mut &data[..n], &module).is_err());
+    }
+    for i in 0..data.len() {
+        let mut data = data.clone();
+        data[i] ^= 0xff;
+        let mut rt = Runtime::new();
+        let _ = snapshot::restore(&mut rt, &mut &data[..], &module);
+    }
+
+    // Writes a snapshot with one call frame and the stack.
+    fn snapshot_data(index: u64, stack: &[u8]) -> Vec<u8> {
+        fn u64(data: &mut Vec<u8>, val: u64) {
+            for i in 0..8 { data.push((val >> (i * 8)) as u8); }
+        }
+        let mut data = b"dyon-snapshot-1\n".to_vec();
+        for _ in 0..4 { u64(&mut data, 1); }
+        u64(&mut data, 1);
+        u64(&mut data, 4);
+        data.extend_from_slice(b"main");
+        u64(&mut data, index);
+        data.push(0);
+        for _ in 0..4 { u64(&mut data, 0); }
+        u64(&mut data, 0);
+        u64(&mut data, 0);
+        u64(&mut data, 1);
+        data.extend_from_slice(stack);
+        data
+    }
+
+    let restore = |data: Vec<u8>| {
+        let mut rt = Runtime::new();
+        snapshot::restore(&mut rt, &mut &data[..], &module)
+    };
+    assert!(restore(snapshot_data(0, &[1])).is_ok());
+    let err = restore(snapshot_data(1000, &[1])).unwrap_err();
+    assert!(err.contains("outside the module"), "{}", err);
+    // An array holding a reference to index 5.
+    let err = restore(snapshot_data(0, &[6, 1, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]))
+        .unwrap_err();
+    assert!(err.contains("refers outside the stack"), "{}", err);
+    // Options nested deeper than allowed.
+    let mut nested = vec![];
+    for _ in 0..10_000 { nested.extend_from_slice(&[9, 1]); }
+    nested.push(1);
+    let err = restore(snapshot_data(0, &nested)).unwrap_err();
+    assert!(err.contains("nested too deeply"), "{}", err);
+}
+
+#[test]
+fn test_bytecode() {
+    use dyon::bytecode::Instruction;
+
+    let module = load_module("source/runtime/bytecode.dyon");
+    let evals = |name: &str| {
+        let f = module.functions.iter().find(|f| &**f.name == name).unwrap();
+        f.code.as_ref().unwrap().instructions.iter()
+            .filter(|i| if let Instruction::Eval(..) = **i { true } else { false })
+            .count()
+    };
+    // Objects, arrays, links, vec4 and indexing run natively.
+    assert_eq!(evals("native"), 0);
+    // Closures are evaluated with the AST.
+    assert_eq!(evals("closure"), 2);
+    run("source/runtime/bytecode.dyon").unwrap();
+}
+
+#[test]
+fn test_object_keys() {
+    run("source/runtime/object_keys.dyon").unwrap();
+}
+
+#[test]
+fn test_persistent_array() {
+    run("source/runtime/persistent_array.dyon").unwrap();
+}
+
+#[test]
+fn test_packed_array() {
+    use std::sync::Arc;
+
+    fn sum_packed(rt: &mut Runtime) -> Result<(), String> {
+        let arr: Arc<Vec<f64>> = try!(rt.pop());
+        rt.push(arr.iter().sum::<f64>());
+        Ok(())
+    }
+
+    fn double_packed(rt: &mut Runtime) -> Result<(), String> {
+        let mut arr: Arc<Vec<f64>> = try!(rt.pop());
+        for x in Arc::make_mut(&mut arr) { *x *= 2.0; }
+        rt.push(arr);
+        Ok(())
+    }
+
+    let mut module = Module::new();
+    module.add(Arc::new("sum_packed".into()), sum_packed, Dfn {
+        lts: vec![Lt::Default],
+        tys: vec![Type::Array(Box::new(Type::F64))],
+        ret: Type::F64
+    });
+    module.add(Arc::new("double_packed".into()), double_packed, Dfn {
+        lts: vec![Lt::Default],
+        tys: vec![Type::Array(Box::new(Type::F64))],
+        ret: Type::Array(Box::new(Type::F64))
+    });
+    load("source/runtime/packed_array.dyon", &mut module).unwrap();
+    let mut rt = Runtime::new();
+    rt.run(&Arc::new(module)).unwrap();
+}
+
+#[test]
+fn test_par() {
+    let module = load_module("source/runtime/par.dyon");
+    let mut rt = Runtime::new();
+    rt.par_threads = 4;
+    rt.run(&module).unwrap();
+
+    let err = rt.call_str("jump", &[], &module).unwrap_err();
+    assert!(err.contains("Can not return or jump out of a parallel loop"), "{}", err);
+
+    // Each part would change its own copy of variables outside the loop.
+    for source in &["source/runtime/par_mutate.dyon", "source/runtime/par_mutate_2.dyon"] {
+        let err = load(source, &mut Module::new()).unwrap_err();
+        assert!(err.contains("Can not mutate"), "{}", err);
+    }
+}
+
+#[test]
+fn test_scheduler() {
+    let module = load_module("source/runtime/scheduler.dyon");
+    for &n in &[1, 4] {
+        let mut rt = Runtime::new();
+        rt.go_threads = n;
+        rt.run(&module).unwrap();
+    }
+}
+
+#[test]
+fn test_channel() {
+    let module = load_module("source/runtime/channel.dyon");
+    // One worker runs the senders while the receiver waits.
+    for &n in &[1, 4] {
+        let mut rt = Runtime::new();
+        rt.go_threads = n;
+        rt.run(&module).unwrap();
+    }
+}
+
+#[test]
+fn test_channel_wait() {
+    let module = load_module("source/runtime/channel_wait.dyon");
+    // Threads waiting on a channel must not run threads from the queue.
+    for &n in &[1, 4] {
+        let mut rt = Runtime::new();
+        rt.go_threads = n;
+        rt.run(&module).unwrap();
+    }
+}
+
+#[test]
+fn test_thread_control() {
+    let module = load_module("source/runtime/thread_control.dyon");
+    for &n in &[1, 4] {
+        let mut rt = Runtime::new();
+        rt.go_threads = n;
+        rt.run(&module).unwrap();
+    }
+
+    let mut rt = Runtime::new();
+    for name in &["negative_timeout", "nan_timeout"] {
+        let err = rt.call_str(name, &[], &module).unwrap_err();
+        assert!(err.contains("Expected a non-negative timeout"), "{}", err);
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    use std::sync::Arc;
+
+    let load_with = |source: &str, capabilities: Capabilities| {
+        let mut module = Module::new_capabilities(capabilities);
+        load(source, &mut module).map(|_| Arc::new(module))
+    };
+    let run_with = |source: &str, capabilities: Capabilities| {
+        load_with(source, capabilities).and_then(|module| Runtime::new().run(&module))
+    };
+
+    let source = "source/runtime/capabilities/read_file.dyon";
+    let err = load_with(source, Capabilities::none()).err().unwrap();
+    assert!(err.contains("Could not find function `load_string__file`"), "{}", err);
+    load_with(source, Capabilities {read_file: true, ..Capabilities::none()}).unwrap();
+
+    // Capabilities can be changed after creating the module.
+    let mut module = Module::new();
+    module.capabilities = Capabilities::none();
+    let err = load(source, &mut module).err().unwrap();
+    assert!(err.contains("Could not find function `load_string__file`"), "{}", err);
+    module.capabilities = Capabilities::all();
+    load(source, &mut module).unwrap();
+
+    let err = load_with("source/runtime/capabilities/go.dyon", Capabilities::none()).err().unwrap();
+    assert!(err.contains("threads are not allowed"), "{}", err);
+
+    // Modules created with `load` inherit the capabilities.
+    let source = "source/runtime/capabilities/load.dyon";
+    run_with(source, Capabilities {network: false, ..Capabilities::all()}).unwrap();
+
+    // Without threads, `par` loops run on the current thread.
+    run_with("source/runtime/capabilities/par.dyon", Capabilities::none()).unwrap();
+}
+
+#[test]
+fn test_fetcher() {
+    use std::sync::Arc;
+
+    let mut fetcher = LocalFetcher::new();
+    fetcher.dir("http://example.com/", "source/runtime/fetch");
+    fetcher.insert("http://example.com/memory", "true");
+    let mut module = Module::new();
+    module.fetcher = Arc::new(fetcher);
+    load("source/runtime/fetch/main.dyon", &mut module).unwrap();
+    let module = Arc::new(module);
+    Runtime::new().run(&module).unwrap();
+
+    // Errors from writing the file are not reported as errors from fetching.
+    if cfg!(target_os = "linux") {
+        let err = Runtime::new().call_str("download_full", &[], &module).unwrap_err();
+        assert!(err.contains("IO Error when attempting to write `/dev/full`"), "{}", err);
+        assert!(!err.contains("Error fetching"), "{}", err);
+    }
+}
+
+#[test]
+fn test_write_path() {
+    use std::fs;
+
+    // Copy the script, such that the written files end up in the target directory.
+    let _ = fs::remove_dir_all("target/write_path");
+    fs::create_dir_all("target/write_path/out").unwrap();
+    fs::copy("source/runtime/write_path/main.dyon", "target/write_path/main.dyon").unwrap();
+    run("target/write_path/main.dyon").unwrap();
+    assert!(fs::metadata("target/write_path/out/saved.txt").unwrap().is_file());
+    assert!(fs::metadata("target/write_path/out/data.json").unwrap().is_file());
+}
+
+#[test]
+fn test_source_loader() {
+    use std::sync::Arc;
+
+    let mut loader = MemoryLoader::new();
+    loader.insert("scripts/main.dyon", include_str!("../source/runtime/source_loader/main.dyon"));
+    loader.insert("scripts/lib/util.dyon", include_str!("../source/runtime/source_loader/util.dyon"));
+    loader.insert("scripts/numbers.txt", "[1, 2, 3]");
+    loader.insert("syntax-data.txt", include_str!("../assets/syntax-data.txt"));
+    let mut module = Module::new();
+    module.loader = Arc::new(loader);
+    load("scripts/main.dyon", &mut module).unwrap();
+    Runtime::new().run(&Arc::new(module)).unwrap();
+}
+
+#[test]
+fn test_match() {
+    let module = load_module("source/runtime/match.dyon");
+    assert_eq!(module.warnings.len(), 0);
+    let mut rt = Runtime::new();
+    rt.run(&module).unwrap();
+
+    let err = rt.call_str("no_arm", &[], &module).unwrap_err();
+    assert!(err.contains("No arm matches `string`"), "{}", err);
+
+    // Non-exhaustive matches over options and results are warnings.
+    let module = load_module("source/typechk/match_3.dyon");
+    assert_eq!(module.warnings.len(), 2);
+    assert!(module.warnings[0].contains("Missing `none()`"), "{}", module.warnings[0]);
+    assert!(module.warnings[1].contains("Missing `ok(_)`"), "{}", module.warnings[1]);
+}
+
+#[test]
+fn test_interpolation() {
+    run("source/runtime/interpolation.dyon").unwrap();
+}
+
+#[test]
+fn test_record() {
+    run("source/runtime/record.dyon").unwrap();
+
+    // Each kind of mismatch has its own error code.
+    for &(source, code) in &[
+        ("source/typechk/record.dyon", "#2100"),
+        ("source/typechk/record_2.dyon", "#2200"),
+        ("source/typechk/record_3.dyon", "#2300"),
+        ("source/typechk/record_5.dyon", "#2150"),
+        ("source/typechk/record_6.dyon", "#2250"),
+    ] {
+        let err = load(source, &mut Module::new()).unwrap_err();
+        assert!(err.contains(code), "{}", err);
+    }
+}